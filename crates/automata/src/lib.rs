@@ -797,6 +797,39 @@ where
         }
     }
 
+    /// Iterate over every distinct transition label that is reachable from the
+    /// start state.
+    ///
+    /// A transition label is the pair of the data associated with the state
+    /// that the transition leaves, and the input the transition is taken on.
+    /// Each distinct pair is yielded exactly once, in depth-first order
+    /// starting from the start state. Transitions out of states without any
+    /// associated data are skipped.
+    pub fn transition_labels(&self) -> impl Iterator<Item = (&TState, &TAlphabet)> + '_ {
+        let mut labels = vec![];
+        let mut seen_labels = HashSet::new();
+        let mut seen_states = HashSet::new();
+        let mut stack = vec![self.start_state];
+
+        while let Some(state) = stack.pop() {
+            if !seen_states.insert(state) {
+                continue;
+            }
+
+            let state_data = self.state_data[state.0 as usize].as_ref();
+            for (input, (to_state, _output)) in &self.transitions[state.0 as usize] {
+                if let Some(data) = state_data {
+                    if seen_labels.insert((data, input)) {
+                        labels.push((data, input));
+                    }
+                }
+                stack.push(*to_state);
+            }
+        }
+
+        labels.into_iter()
+    }
+
     /// Check that the internal representaton is OK.
     ///
     /// Checks that we don't have any transitions to unknown states, that there
@@ -20,6 +20,36 @@ use std::marker::PhantomData;
 
 const SERIALIZATION_VERSION: u32 = 1;
 
+#[cfg(feature = "bytes")]
+impl<TAlphabet, TState, TOutput> Automaton<TAlphabet, TState, TOutput>
+where
+    TAlphabet: Serialize + for<'de> Deserialize<'de> + Clone + Eq + Hash + Ord,
+    TState: Serialize + for<'de> Deserialize<'de> + Clone + Eq + Hash,
+    TOutput: Serialize + for<'de> Deserialize<'de> + Output,
+{
+    /// Serialize this automaton to bytes.
+    ///
+    /// The bytes begin with a serialization version header, and can be loaded
+    /// back with [`from_bytes`][crate::Automaton::from_bytes].
+    ///
+    /// Requires that the `"bytes"` cargo feature is enabled.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing an automaton to memory cannot fail")
+    }
+
+    /// Deserialize an automaton from bytes produced by
+    /// [`to_bytes`][crate::Automaton::to_bytes].
+    ///
+    /// Returns an error if the bytes were written with a different
+    /// serialization version, or if they do not describe a well-formed
+    /// automaton.
+    ///
+    /// Requires that the `"bytes"` cargo feature is enabled.
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(bytes)
+    }
+}
+
 impl Serialize for State {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
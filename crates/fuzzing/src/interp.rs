@@ -106,8 +106,10 @@ pub fn interp(data: &[u8]) {
                                 TypeOrConditionCode::Type(ty) => match ty.kind {
                                     Kind::Int => Constant::Int(1, ty.bit_width).into(),
                                     Kind::Bool => Constant::Bool(false, ty.bit_width).into(),
-                                    Kind::Void | Kind::CpuFlags => {
-                                        unreachable!("void and cpu flags cannot be immediates")
+                                    Kind::Void | Kind::CpuFlags | Kind::Vector => {
+                                        unreachable!(
+                                            "void, cpu flags, and vectors cannot be immediates"
+                                        )
                                     }
                                 },
                             },
@@ -152,6 +154,7 @@ pub fn interp(data: &[u8]) {
                                             Constant::Bool(false, ty.bit_width),
                                             BitWidth::ThirtyTwo,
                                         ),
+                                        Kind::Vector => program.opaque(ty),
                                         Kind::CpuFlags => {
                                             unreachable!("cpu flags cannot be an argument")
                                         }
@@ -224,6 +227,10 @@ impl<'a> TypingContextTrait<'a> for TypingContext {
         TypeOrConditionCode::Type(Type::i32())
     }
 
+    fn vNN(&mut self, _: wast::Span) -> Self::TypeVariable {
+        TypeOrConditionCode::Type(Type::i32x4())
+    }
+
     fn cpu_flags(&mut self, _: wast::Span) -> Self::TypeVariable {
         TypeOrConditionCode::Type(Type::cpu_flags())
     }
@@ -240,6 +247,10 @@ impl<'a> TypingContextTrait<'a> for TypingContext {
         TypeOrConditionCode::Type(Type::b1())
     }
 
+    fn bool_or_int_t(&mut self, _: wast::Span) -> Self::TypeVariable {
+        TypeOrConditionCode::Type(Type::i32())
+    }
+
     fn any_t(&mut self, _: wast::Span) -> Self::TypeVariable {
         TypeOrConditionCode::Type(Type::i32())
     }
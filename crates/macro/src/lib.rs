@@ -61,7 +61,8 @@ pub(crate) struct PeepmaticOpts {
     immediates: Vec<syn::Ident>,
     params_paren: syn::token::Paren,
     params: Vec<syn::Ident>,
-    result: Option<syn::Ident>,
+    result_paren: syn::token::Paren,
+    result: Vec<syn::Ident>,
 }
 
 impl Parse for PeepmaticOpts {
@@ -69,7 +70,7 @@ impl Parse for PeepmaticOpts {
         enum Attr {
             Immediates(syn::token::Paren, Vec<syn::Ident>),
             Params(syn::token::Paren, Vec<syn::Ident>),
-            Result(syn::Ident),
+            Result(syn::token::Paren, Vec<syn::Ident>),
             NoIntoDynNode,
             SkipChild,
             Flatten,
@@ -87,7 +88,10 @@ impl Parse for PeepmaticOpts {
                     ret.params_paren = paren;
                     ret.params = ps;
                 }
-                Attr::Result(r) => ret.result = Some(r),
+                Attr::Result(paren, rs) => {
+                    ret.result_paren = paren;
+                    ret.result = rs;
+                }
                 Attr::NoIntoDynNode => ret.no_into_dyn_node = true,
                 Attr::SkipChild => ret.skip_child = true,
                 Attr::Flatten => ret.flatten = true,
@@ -113,8 +117,9 @@ impl Parse for PeepmaticOpts {
                 }
                 if attr == "result" {
                     let inner;
-                    syn::parenthesized!(inner in input);
-                    return Ok(Attr::Result(syn::Ident::parse(&inner)?));
+                    let paren = syn::parenthesized!(inner in input);
+                    let results = Punctuated::<_, syn::token::Comma>::parse_terminated(&inner)?;
+                    return Ok(Attr::Result(paren, results.into_iter().collect()));
                 }
                 if attr == "skip_child" {
                     return Ok(Attr::SkipChild);
@@ -76,6 +76,7 @@ struct OperatorVariant {
 fn create_arity(variants: &[OperatorVariant]) -> Result<impl quote::ToTokens> {
     let mut imm_arities = vec![];
     let mut params_arities = vec![];
+    let mut results_arities = vec![];
 
     for v in variants {
         let variant = &v.syn.ident;
@@ -105,6 +106,19 @@ fn create_arity(variants: &[OperatorVariant]) -> Result<impl quote::ToTokens> {
         params_arities.push(quote! {
             Self::#variant => #params_arity,
         });
+
+        let results_arity = v.opts.result.len();
+        if results_arity > std::u8::MAX as usize {
+            return Err(Error::new(
+                v.opts.result_paren.span,
+                "cannot have more than u8::MAX results",
+            ));
+        }
+        let results_arity = results_arity as u8;
+
+        results_arities.push(quote! {
+            Self::#variant => #results_arity,
+        });
     }
 
     Ok(quote! {
@@ -121,10 +135,18 @@ fn create_arity(variants: &[OperatorVariant]) -> Result<impl quote::ToTokens> {
                 #( #params_arities )*
             }
         }
+
+        /// Get the number of results that this operator produces.
+        pub fn results_arity(&self) -> u8 {
+            match *self {
+                #( #results_arities )*
+            }
+        }
     })
 }
 
 fn create_type_methods(variants: &[OperatorVariant]) -> impl quote::ToTokens {
+    let mut result_type = vec![];
     let mut result_types = vec![];
     let mut imm_types = vec![];
     let mut param_types = vec![];
@@ -132,18 +154,27 @@ fn create_type_methods(variants: &[OperatorVariant]) -> impl quote::ToTokens {
     for v in variants {
         let variant = &v.syn.ident;
 
-        let result_ty = v.opts.result.as_ref().unwrap_or_else(|| {
+        let result_ty = v.opts.result.first().unwrap_or_else(|| {
             panic!(
                 "must define #[peepmatic(result(..))] on operator `{}`",
                 variant
             )
         });
-        result_types.push(quote! {
+        result_type.push(quote! {
             Self::#variant => {
                 context.#result_ty(span)
             }
         });
 
+        let result_tys = v.opts.result.iter().map(|ty| {
+            quote! { ::std::iter::once(context.#ty(span)) }
+        });
+        result_types.push(quote! {
+            Self::#variant => {
+                #( types.extend(#result_tys); )*
+            }
+        });
+
         let imm_tys = match &v.opts.immediates[..] {
             [] => quote! {},
             [ty, rest @ ..] => {
@@ -181,12 +212,31 @@ fn create_type_methods(variants: &[OperatorVariant]) -> impl quote::ToTokens {
 
     quote! {
         /// Get the result type of this operator.
+        ///
+        /// For operators with multiple results, this is the type of the first
+        /// result.
         #[cfg(feature = "construct")]
         pub fn result_type<'a, C>(
             &self,
             context: &mut C,
             span: wast::Span,
         ) -> C::TypeVariable
+        where
+            C: 'a + TypingContext<'a>,
+        {
+            match *self {
+                #( #result_type )*
+            }
+        }
+
+        /// Get the types of all of this operator's results.
+        #[cfg(feature = "construct")]
+        pub fn result_types<'a, C>(
+            &self,
+            context: &mut C,
+            span: wast::Span,
+            types: &mut impl Extend<C::TypeVariable>,
+        )
         where
             C: 'a + TypingContext<'a>,
         {
@@ -48,6 +48,69 @@ pub enum ConditionCode {
     Nof,
 }
 
+impl ConditionCode {
+    /// Get the inverse of this condition code, i.e. the condition code that
+    /// is true exactly when this one is false.
+    pub fn inverse(self) -> Self {
+        match self {
+            Self::Eq => Self::Ne,
+            Self::Ne => Self::Eq,
+            Self::Slt => Self::Sge,
+            Self::Sge => Self::Slt,
+            Self::Ult => Self::Uge,
+            Self::Uge => Self::Ult,
+            Self::Sgt => Self::Sle,
+            Self::Sle => Self::Sgt,
+            Self::Ugt => Self::Ule,
+            Self::Ule => Self::Ugt,
+            Self::Of => Self::Nof,
+            Self::Nof => Self::Of,
+        }
+    }
+
+    /// Get the condition code to use when swapping the comparison's operands,
+    /// i.e. the condition code such that `a cc b` is `b cc' a`.
+    ///
+    /// Returns `None` for the overflow condition codes, which have no swapped
+    /// equivalent.
+    pub fn swap_operands(self) -> Option<Self> {
+        Some(match self {
+            Self::Eq => Self::Eq,
+            Self::Ne => Self::Ne,
+            Self::Slt => Self::Sgt,
+            Self::Sgt => Self::Slt,
+            Self::Sle => Self::Sge,
+            Self::Sge => Self::Sle,
+            Self::Ult => Self::Ugt,
+            Self::Ugt => Self::Ult,
+            Self::Ule => Self::Uge,
+            Self::Uge => Self::Ule,
+            Self::Of | Self::Nof => return None,
+        })
+    }
+
+    /// Get the condition code that is true exactly when either this condition
+    /// code or `other` is, when merging a strict ordering with `eq`, e.g. `slt`
+    /// and `eq` into `sle`.
+    ///
+    /// Returns `None` for every other pair, even when their disjunction happens
+    /// to be another condition code (`slt` or `sgt` is `ne`), so that merging
+    /// only ever relaxes an ordering.
+    pub fn combine(self, other: Self) -> Option<Self> {
+        let strict = match (self, other) {
+            (Self::Eq, strict) | (strict, Self::Eq) => strict,
+            _ => return None,
+        };
+        Some(match strict {
+            Self::Slt => Self::Sle,
+            Self::Ult => Self::Ule,
+            Self::Sgt => Self::Sge,
+            Self::Ugt => Self::Uge,
+            _ => return None,
+        })
+    }
+}
+
 impl TryFrom<u32> for ConditionCode {
     type Error = &'static str;
 
@@ -6,6 +6,38 @@ use crate::paths::Path;
 use crate::r#type::Type;
 use std::fmt::Debug;
 
+/// An instruction set's own opcodes.
+///
+/// Optimizations are written with `Operator`s, and the automata that match them
+/// switch on `Operator` numbers. An instruction set that numbers its opcodes
+/// differently, or that has opcodes no `Operator` corresponds to, translates
+/// between the two with this trait. `Operator` implements it too, for
+/// instruction sets that use it directly.
+pub trait Opcode: Copy + Debug + Eq {
+    /// Get the operator that corresponds to this opcode, or `None` if there is
+    /// no such operator.
+    ///
+    /// Instructions whose opcode has no operator never match.
+    fn to_operator(self) -> Option<Operator>;
+
+    /// Get the opcode that corresponds to the given operator, or `None` if the
+    /// instruction set has no such opcode.
+    ///
+    /// Optimizations that would make an instruction with an operator that has
+    /// no opcode never match.
+    fn from_operator(operator: Operator) -> Option<Self>;
+}
+
+impl Opcode for Operator {
+    fn to_operator(self) -> Option<Operator> {
+        Some(self)
+    }
+
+    fn from_operator(operator: Operator) -> Option<Self> {
+        Some(operator)
+    }
+}
+
 /// A trait for interfacing with actual instruction sequences.
 ///
 /// This trait enables both:
@@ -21,6 +53,10 @@ use std::fmt::Debug;
 /// new `MachInst` and vcode backend easier, since all that needs to be done is
 /// "just" implementing this trait. (And probably add/modify some
 /// `peepmatic_runtime::operation::Operation`s as well).
+///
+/// The methods that report facts about instructions, such as use counts and
+/// value ranges, have conservative default implementations that report
+/// nothing, so preconditions that need those facts never match.
 pub trait InstructionSet<'a> {
     /// Mutable context passed into all trait methods. Can be whatever you want!
     ///
@@ -31,6 +67,9 @@ pub trait InstructionSet<'a> {
     /// An instruction (or identifier for an instruction).
     type Instruction: Copy + Debug + Eq;
 
+    /// This instruction set's opcodes.
+    type Opcode: Opcode;
+
     /// Replace the `old` instruction with `new`.
     ///
     /// `new` is either a `Part::Instruction` or a constant `Part::Boolean` or
@@ -46,6 +85,40 @@ pub trait InstructionSet<'a> {
         new: Part<Self::Instruction>,
     ) -> Self::Instruction;
 
+    /// Replace each of the `old` instruction's results with the corresponding
+    /// part in `new`.
+    ///
+    /// `old` has as many results as there are parts in `new`. As with
+    /// `replace_instruction`, each part is either a `Part::Instruction` or a
+    /// constant that implementations should wrap in a new `iconst` or
+    /// `bconst`. A `Part::Instruction` refers to its first result.
+    ///
+    /// Returns the instruction that replaced `old`'s first result.
+    ///
+    /// This is only called when `old`'s opcode corresponds to an operator with
+    /// multiple results, such as `iadd_cout`, so instruction sets without any
+    /// such opcodes don't need to implement it. The default implementation
+    /// panics.
+    fn replace_instruction_results(
+        &self,
+        _context: &mut Self::Context,
+        _old: Self::Instruction,
+        _new: &[Part<Self::Instruction>],
+    ) -> Self::Instruction {
+        panic!("this instruction set does not implement `replace_instruction_results`")
+    }
+
+    /// Remove the given instruction entirely.
+    ///
+    /// This is only called for instructions whose results have no uses, as
+    /// reported by `instruction_use_count`.
+    ///
+    /// The default implementation panics, so instruction sets that override
+    /// `instruction_use_count` must override this too.
+    fn remove_instruction(&self, _context: &mut Self::Context, _inst: Self::Instruction) {
+        panic!("this instruction set does not implement `remove_instruction`")
+    }
+
     /// Get the instruction, constant, or condition code at the given path.
     ///
     /// If there is no such entity at the given path (e.g. we run into a
@@ -58,12 +131,15 @@ pub trait InstructionSet<'a> {
         path: Path,
     ) -> Option<Part<Self::Instruction>>;
 
-    /// Get the given instruction's operator.
+    /// Get the given instruction's opcode.
+    ///
+    /// Return `None` for anything that doesn't have an opcode of its own.
     ///
-    /// If the instruction's opcode does not have an associated
-    /// `peepmatic_runtime::operator::Operator` variant (i.e. that instruction
-    /// isn't supported by `peepmatic` yet) then `None` should be returned.
-    fn operator(&self, context: &mut Self::Context, instr: Self::Instruction) -> Option<Operator>;
+    /// The opcode doesn't need to be a `peepmatic_runtime::operator::Operator`,
+    /// or be numbered the same way: it is translated to and from the operators
+    /// that optimizations are written with by the `Opcode` trait. See
+    /// `crates/test/tests/toy_ir.rs` for an example.
+    fn opcode(&self, context: &mut Self::Context, inst: Self::Instruction) -> Option<Self::Opcode>;
 
     /// Make a unary instruction.
     ///
@@ -72,7 +148,7 @@ pub trait InstructionSet<'a> {
         &self,
         context: &mut Self::Context,
         root: Self::Instruction,
-        operator: Operator,
+        opcode: Self::Opcode,
         r#type: Type,
         a: Part<Self::Instruction>,
     ) -> Self::Instruction;
@@ -88,7 +164,7 @@ pub trait InstructionSet<'a> {
         &self,
         context: &mut Self::Context,
         root: Self::Instruction,
-        operator: Operator,
+        opcode: Self::Opcode,
         r#type: Type,
         a: Part<Self::Instruction>,
         b: Part<Self::Instruction>,
@@ -104,13 +180,55 @@ pub trait InstructionSet<'a> {
         &self,
         context: &mut Self::Context,
         root: Self::Instruction,
-        operator: Operator,
+        opcode: Self::Opcode,
         r#type: Type,
         a: Part<Self::Instruction>,
         b: Part<Self::Instruction>,
         c: Part<Self::Instruction>,
     ) -> Self::Instruction;
 
+    /// Make a `load` instruction of the given type from `addr` plus `offset`,
+    /// with the given memory flags.
+    ///
+    /// This is only called when `Self::Opcode` has an opcode for
+    /// `Operator::Load`, because optimizations that would make an instruction
+    /// without an opcode never match.
+    ///
+    /// The default implementation panics, so instruction sets with a `load`
+    /// opcode must override it.
+    fn make_load(
+        &self,
+        _context: &mut Self::Context,
+        _root: Self::Instruction,
+        _type: Type,
+        _addr: Part<Self::Instruction>,
+        _offset: i32,
+        _flags: u8,
+    ) -> Self::Instruction {
+        panic!("this instruction set does not implement `make_load`")
+    }
+
+    /// Make a `store` instruction of `value` to `addr` plus `offset`, with the
+    /// given memory flags.
+    ///
+    /// This is only called when `Self::Opcode` has an opcode for
+    /// `Operator::Store`, because optimizations that would make an instruction
+    /// without an opcode never match.
+    ///
+    /// The default implementation panics, so instruction sets with a `store`
+    /// opcode must override it.
+    fn make_store(
+        &self,
+        _context: &mut Self::Context,
+        _root: Self::Instruction,
+        _value: Part<Self::Instruction>,
+        _addr: Part<Self::Instruction>,
+        _offset: i32,
+        _flags: u8,
+    ) -> Self::Instruction {
+        panic!("this instruction set does not implement `make_store`")
+    }
+
     /// Try to resolve the given instruction into a constant value.
     ///
     /// If we can tell that the instruction returns a constant value, then
@@ -124,13 +242,150 @@ pub trait InstructionSet<'a> {
 
     /// Get the bit width of the given instruction's result.
     ///
-    /// Must be one of 1, 8, 16, 32, 64, or 128.
+    /// Must be one of 1, 8, 16, 32, 64, or 128. For a vector, this is the bit
+    /// width of each of its lanes, like a vector `Type`'s bit width.
     fn instruction_result_bit_width(
         &self,
         context: &mut Self::Context,
         inst: Self::Instruction,
     ) -> u8;
 
+    /// Get the memory flags of the given instruction, if it is a memory access
+    /// such as a `load` or a `store`.
+    ///
+    /// The bits of the result are defined in `peepmatic_runtime::mem_flags`.
+    /// Return `None` if the instruction does not access memory.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_mem_flags(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+    ) -> Option<u8> {
+        None
+    }
+
+    /// Get the immediate offset of the given instruction's address, if it is
+    /// a memory access such as a `load` or a `store`.
+    ///
+    /// Return `None` if the instruction does not access memory.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_mem_offset(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+    ) -> Option<i32> {
+        None
+    }
+
+    /// Get the `store` that the given `load` immediately follows, if any.
+    ///
+    /// Only return a store when nothing between it and the load may write to
+    /// memory, so that if they access the same address, the load reads back
+    /// exactly what the store wrote. Return `None` if there is no such store,
+    /// or if it is not known.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_preceding_store(
+        &self,
+        _context: &mut Self::Context,
+        _load: Self::Instruction,
+    ) -> Option<Self::Instruction> {
+        None
+    }
+
+    /// Get the number of predecessors of the block containing the given
+    /// instruction.
+    ///
+    /// Return `None` if the instruction is not in a block, or if its block's
+    /// predecessors are not known.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_block_predecessor_count(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+    ) -> Option<u32> {
+        None
+    }
+
+    /// Is the given instruction a call?
+    ///
+    /// The default implementation returns `false`.
+    fn instruction_is_call(&self, _context: &mut Self::Context, _inst: Self::Instruction) -> bool {
+        false
+    }
+
+    /// Get the number of uses of the given instruction's result.
+    ///
+    /// Return `None` if the number of uses is not known.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_use_count(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+    ) -> Option<u32> {
+        None
+    }
+
+    /// Get the unsigned range, `(min, max)` inclusive, that the given
+    /// instruction's result is known to be within.
+    ///
+    /// This is where range facts from e.g. a dominating comparison against a
+    /// constant come in. Return `None` if no range is known.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_value_range(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+    ) -> Option<(u128, u128)> {
+        None
+    }
+
+    /// Get an alignment, in bytes, that the given instruction's result is
+    /// known to be a multiple of.
+    ///
+    /// This is where alignment facts about e.g. stack slots and aligned
+    /// allocations come in. Returning a smaller power of two than the actual
+    /// alignment is fine, but the result must be a power of two. Return `None`
+    /// if no alignment is known.
+    ///
+    /// The default implementation returns `None`.
+    fn instruction_alignment(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+    ) -> Option<u32> {
+        None
+    }
+
+    /// Push a structural signature of the given instruction onto `shape`, for
+    /// the optimizer's shape cache to be keyed by, and return `true`.
+    ///
+    /// Two instructions with the same shape must match the same optimization,
+    /// or both match none, so the shape has to cover everything that the
+    /// optimizations' left-hand sides look at: the operators, types, and
+    /// immediates of the instruction and the operands below it, which of those
+    /// operands are the same value, and any facts about them, such as use
+    /// counts and value ranges. Shapes are compared in full, not by hash, so a
+    /// shape must not be a lossy digest of all that. Return `false` when the
+    /// shape can't cover everything for this instruction, and it will always
+    /// be matched against the optimizations. See
+    /// [`set_shape_cache_capacity`][crate::PeepholeOptimizer::set_shape_cache_capacity].
+    ///
+    /// The default implementation returns `false`.
+    fn instruction_shape(
+        &self,
+        _context: &mut Self::Context,
+        _inst: Self::Instruction,
+        _shape: &mut Vec<u64>,
+    ) -> bool {
+        false
+    }
+
     /// Get the size of a native word in bits.
     fn native_word_size_in_bits(&self, context: &mut Self::Context) -> u8;
 }
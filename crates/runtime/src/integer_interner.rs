@@ -1,9 +1,12 @@
 //! Interner for (potentially large) integer values.
 //!
-//! We support matching on integers that can be represented by `u64`, but only
+//! We support matching on integers that can be represented by `u128`, but only
 //! support automata results that fit in a `u32`. So we intern the (relatively
-//! few compared to the full range of `u64`) integers we are matching against
+//! few compared to the full range of `u128`) integers we are matching against
 //! here and then reference them by `IntegerId`.
+//!
+//! Sets of interned integers are interned here as well, so that match
+//! operations can reference a whole set by its `IntegerSetId`.
 
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -13,12 +16,17 @@ use std::convert::TryInto;
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct IntegerId(#[doc(hidden)] pub u32);
 
+/// An identifier for an interned set of integers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IntegerSetId(#[doc(hidden)] pub u32);
+
 /// An interner for integer values.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct IntegerInterner {
     // Note: we use `BTreeMap`s for deterministic serialization.
-    map: BTreeMap<u64, IntegerId>,
-    values: Vec<u64>,
+    map: BTreeMap<u128, IntegerId>,
+    values: Vec<u128>,
+    sets: Vec<Vec<IntegerId>>,
 }
 
 impl IntegerInterner {
@@ -31,7 +39,7 @@ impl IntegerInterner {
     /// Intern a value into this `IntegerInterner`, returning its canonical
     /// `IntegerId`.
     #[inline]
-    pub fn intern(&mut self, value: impl Into<u64>) -> IntegerId {
+    pub fn intern(&mut self, value: impl Into<u128>) -> IntegerId {
         debug_assert_eq!(self.map.len(), self.values.len());
 
         let value = value.into();
@@ -51,16 +59,53 @@ impl IntegerInterner {
 
     /// Get the id of an already-interned integer, or `None` if it has not been
     /// interned.
-    pub fn already_interned(&self, value: impl Into<u64>) -> Option<IntegerId> {
+    pub fn already_interned(&self, value: impl Into<u128>) -> Option<IntegerId> {
         let value = value.into();
         self.map.get(&value).copied()
     }
 
     /// Lookup a previously interned integer by id.
     #[inline]
-    pub fn lookup(&self, id: IntegerId) -> u64 {
+    pub fn lookup(&self, id: IntegerId) -> u128 {
         self.values[id.0 as usize]
     }
+
+    /// Intern a set of already-interned integers, returning its canonical
+    /// `IntegerSetId`.
+    ///
+    /// The order and multiplicity of the given integers does not matter.
+    pub fn intern_set(&mut self, set: impl IntoIterator<Item = IntegerId>) -> IntegerSetId {
+        let mut set: Vec<_> = set.into_iter().collect();
+        set.sort_by_key(|id| id.0);
+        set.dedup();
+
+        if let Some(i) = self.sets.iter().position(|s| *s == set) {
+            return IntegerSetId(i.try_into().unwrap());
+        }
+
+        let id = IntegerSetId(self.sets.len().try_into().unwrap());
+        self.sets.push(set);
+        id
+    }
+
+    /// Lookup a previously interned set of integers by id.
+    ///
+    /// The set's integers are sorted by id.
+    #[inline]
+    pub fn lookup_set(&self, id: IntegerSetId) -> &[IntegerId] {
+        &self.sets[id.0 as usize]
+    }
+
+    /// Iterate over the interned integers in id order: the n^th integer is the
+    /// one that was assigned the n^th id.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = u128> + 'a {
+        self.values.iter().copied()
+    }
+
+    /// Iterate over the interned sets of integers in id order.
+    pub fn iter_sets<'a>(&'a self) -> impl Iterator<Item = &'a [IntegerId]> + 'a {
+        self.sets.iter().map(|s| &s[..])
+    }
 }
 
 impl From<IntegerId> for u32 {
@@ -22,13 +22,19 @@ pub mod error;
 pub mod instruction_set;
 pub mod integer_interner;
 pub mod linear;
+pub mod mem_flags;
 pub mod operator;
 pub mod optimizations;
 pub mod optimizer;
 pub mod part;
 pub mod paths;
+pub mod pipeline;
+pub mod rewrite_log;
+pub mod shape_cache;
+pub mod stats;
 pub mod r#type;
 
 pub use error::{Error, Result};
 pub use optimizations::PeepholeOptimizations;
 pub use optimizer::PeepholeOptimizer;
+pub use pipeline::Pipeline;
@@ -6,11 +6,13 @@
 //! See also `src/linearize.rs` for the AST to linear IR translation pass.
 
 use crate::cc::ConditionCode;
-use crate::integer_interner::{IntegerId, IntegerInterner};
+use crate::integer_interner::{IntegerId, IntegerInterner, IntegerSetId};
 use crate::operator::{Operator, UnquoteOperator};
 use crate::paths::{PathId, PathInterner};
 use crate::r#type::{BitWidth, Type};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 
 /// A set of linear optimizations.
 #[derive(Debug)]
@@ -23,15 +25,396 @@ pub struct Optimizations {
 
     /// The integer literals referenced by these optimizations.
     pub integers: IntegerInterner,
+
+    /// The names of the custom predicates referenced by these optimizations,
+    /// indexed by `MatchOp::CustomPredicate`'s `predicate`.
+    pub predicates: Vec<String>,
+
+    /// The names of the host functions called by these optimizations'
+    /// right-hand sides, indexed by `Action::CallFunction`'s `function`.
+    pub functions: Vec<String>,
+}
+
+impl Optimizations {
+    /// Get the set of interned integers that are referenced by at least one of
+    /// these optimizations' match operations or actions.
+    pub fn referenced_integers(&self) -> HashSet<IntegerId> {
+        let mut referenced = HashSet::new();
+        for opt in &self.optimizations {
+            referenced.extend(opt.referenced_integers(&self.integers));
+        }
+        referenced
+    }
+
+    /// Remove any interned integers that are no longer referenced by these
+    /// optimizations (for example, after some optimizations were filtered
+    /// out), and remap the ids of the rest.
+    ///
+    /// The remaining integers keep their relative order.
+    pub fn gc_integers(&mut self) {
+        let mut referenced: Vec<_> = self.referenced_integers().into_iter().collect();
+        referenced.sort_by_key(|id| id.0);
+
+        let mut integers = IntegerInterner::new();
+        let remap: HashMap<IntegerId, IntegerId> = referenced
+            .into_iter()
+            .map(|old| (old, integers.intern(self.integers.lookup(old))))
+            .collect();
+
+        let old_integers = &self.integers;
+        for opt in &mut self.optimizations {
+            for inc in &mut opt.increments {
+                inc.for_each_integer_id_mut(|id| match id {
+                    IntegerIdMut::Integer(id) => *id = remap[id],
+                    IntegerIdMut::Set(set) => {
+                        let members = old_integers.lookup_set(*set).iter().map(|id| remap[id]);
+                        *set = integers.intern_set(members);
+                    }
+                });
+            }
+        }
+
+        self.integers = integers;
+    }
+
+    /// Get a copy of just the optimizations that are tagged with the given
+    /// phase, with their own compacted interners.
+    ///
+    /// This way, a single source file can be compiled into a specialized
+    /// automaton per phase. The copied optimizations keep their relative
+    /// order, so they are still sorted for automaton construction if these
+    /// optimizations were. They also keep checking their phase when matching.
+    pub fn filter_by_tag(&self, phase: Phase) -> Optimizations {
+        let optimizations = self
+            .optimizations
+            .iter()
+            .filter(|opt| {
+                opt.increments
+                    .iter()
+                    .any(|inc| inc.operation == MatchOp::InPhase { phase })
+            })
+            .cloned()
+            .collect();
+
+        let mut paths = PathInterner::new();
+        for p in self.paths.iter() {
+            paths.intern(p);
+        }
+
+        let mut filtered = Optimizations {
+            optimizations,
+            paths,
+            integers: self.integers.clone(),
+            predicates: self.predicates.clone(),
+            functions: self.functions.clone(),
+        };
+        filtered.gc_paths();
+        filtered.gc_integers();
+        filtered
+    }
+
+    /// Get each optimization's root operator, in order.
+    ///
+    /// An optimization's root operator is `None` when its left-hand side is
+    /// not an operation (e.g. just a variable), and therefore it might match
+    /// any instruction.
+    pub fn root_operators(&self) -> Vec<Option<Operator>> {
+        self.optimizations
+            .iter()
+            .map(|opt| {
+                opt.root_opcode()
+                    .map(|op| Operator::try_from(op).expect("should be a valid operator"))
+            })
+            .collect()
+    }
+
+    /// Get the largest number of right-hand side instructions that any one of
+    /// these optimizations builds.
+    ///
+    /// Only actions that make instructions are counted, not the actions that
+    /// reuse left-hand side values or define constants. This is useful for
+    /// sizing allocations up front that are reused across matches.
+    pub fn max_rhs_instructions(&self) -> usize {
+        self.optimizations
+            .iter()
+            .map(|opt| {
+                opt.increments
+                    .iter()
+                    .flat_map(|inc| &inc.actions)
+                    .filter(|a| a.made_operator().is_some())
+                    .count()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Get the set of interned paths that are referenced by at least one of
+    /// these optimizations' match operations or actions.
+    pub fn referenced_paths(&self) -> HashSet<PathId> {
+        let mut referenced = HashSet::new();
+        for opt in &self.optimizations {
+            referenced.extend(opt.referenced_paths());
+        }
+        referenced
+    }
+
+    /// Remove any interned paths that are no longer referenced by these
+    /// optimizations, and remap the ids of the rest.
+    ///
+    /// The remaining paths keep their relative order, and the root path is
+    /// always kept as `PathId::ROOT`.
+    pub fn gc_paths(&mut self) {
+        let mut referenced: Vec<_> = self.referenced_paths().into_iter().collect();
+        referenced.sort_by_key(|id| id.0);
+
+        let mut paths = PathInterner::new();
+        let remap: HashMap<PathId, PathId> = referenced
+            .into_iter()
+            .map(|old| (old, paths.intern(self.paths.lookup(old))))
+            .collect();
+
+        for opt in &mut self.optimizations {
+            for inc in &mut opt.increments {
+                inc.operation.for_each_path_mut(|p| *p = remap[p]);
+                for action in &mut inc.actions {
+                    action.for_each_path_mut(|p| *p = remap[p]);
+                }
+            }
+        }
+
+        self.paths = paths;
+    }
+
+    /// Compare these optimizations against `other`, for example the result of
+    /// compiling a changed version of the same rule set.
+    ///
+    /// Optimizations are compared structurally, by the paths, integers, and
+    /// names that they reference rather than by their ids, so the two sets
+    /// don't need to share interners, and their order doesn't matter. An
+    /// optimization in `other` without an identical one here is added, and one
+    /// here without an identical one in `other` is removed, except that an
+    /// added and a removed optimization that match exactly the same way, and
+    /// only differ in their actions, are reported as modified instead.
+    pub fn diff(&self, other: &Optimizations) -> OptimizationsDiff {
+        // Translate `other`'s optimizations to use our ids, interning anything
+        // that we don't have into copies of our interners.
+        let mut paths = PathInterner::new();
+        for p in self.paths.iter() {
+            paths.intern(p);
+        }
+        let mut integers = self.integers.clone();
+        let mut predicates = self.predicates.clone();
+        let mut functions = self.functions.clone();
+        fn index_of(names: &mut Vec<String>, name: &str) -> u32 {
+            let i = match names.iter().position(|n| n == name) {
+                Some(i) => i,
+                None => {
+                    names.push(name.to_string());
+                    names.len() - 1
+                }
+            };
+            u32::try_from(i).unwrap()
+        }
+
+        let translated: Vec<Optimization> = other
+            .optimizations
+            .iter()
+            .map(|opt| {
+                let mut opt = opt.clone();
+                for inc in &mut opt.increments {
+                    inc.for_each_integer_id_mut(|id| match id {
+                        IntegerIdMut::Integer(id) => {
+                            *id = integers.intern(other.integers.lookup(*id));
+                        }
+                        IntegerIdMut::Set(set) => {
+                            let members: Vec<_> = other
+                                .integers
+                                .lookup_set(*set)
+                                .iter()
+                                .map(|id| integers.intern(other.integers.lookup(*id)))
+                                .collect();
+                            *set = integers.intern_set(members);
+                        }
+                    });
+                    if let MatchOp::CustomPredicate { predicate, .. } = &mut inc.operation {
+                        *predicate =
+                            index_of(&mut predicates, &other.predicates[*predicate as usize]);
+                    }
+                    inc.operation
+                        .for_each_path_mut(|p| *p = paths.intern(other.paths.lookup(*p)));
+
+                    for action in &mut inc.actions {
+                        if let Action::CallFunction { function, .. } = action {
+                            *function =
+                                index_of(&mut functions, &other.functions[*function as usize]);
+                        }
+                        action.for_each_path_mut(|p| *p = paths.intern(other.paths.lookup(*p)));
+                    }
+                }
+                opt
+            })
+            .collect();
+
+        // First pair up identical optimizations, then pair up the rest that
+        // only differ in their actions.
+        let mut unmatched_old: Vec<usize> = (0..self.optimizations.len()).collect();
+        let mut unmatched_new = vec![];
+        for (i, opt) in translated.iter().enumerate() {
+            match unmatched_old
+                .iter()
+                .position(|&j| self.optimizations[j] == *opt)
+            {
+                Some(k) => {
+                    unmatched_old.remove(k);
+                }
+                None => unmatched_new.push(i),
+            }
+        }
+
+        let same_matching = |a: &Optimization, b: &Optimization| {
+            a.increments.len() == b.increments.len()
+                && a.increments
+                    .iter()
+                    .zip(&b.increments)
+                    .all(|(a, b)| a.operation == b.operation && a.expected == b.expected)
+        };
+        let mut diff = OptimizationsDiff::default();
+        for i in unmatched_new {
+            match unmatched_old
+                .iter()
+                .position(|&j| same_matching(&self.optimizations[j], &translated[i]))
+            {
+                Some(k) => diff.modified.push((unmatched_old.remove(k), i)),
+                None => diff.added.push(i),
+            }
+        }
+        diff.removed = unmatched_old;
+        diff
+    }
+}
+
+/// The differences between two sets of linear optimizations.
+///
+/// See `Optimizations::diff`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OptimizationsDiff {
+    /// The indices of the optimizations in the new set that were added.
+    pub added: Vec<usize>,
+
+    /// The indices of the optimizations in the old set that were removed.
+    pub removed: Vec<usize>,
+
+    /// The `(old, new)` indices of the optimizations that match the same way in
+    /// both sets, but whose actions changed.
+    pub modified: Vec<(usize, usize)>,
+}
+
+impl OptimizationsDiff {
+    /// Are the two sets of optimizations the same?
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
 }
 
 /// A linearized optimization.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 pub struct Optimization {
     /// The chain of increments for this optimization.
     pub increments: Vec<Increment>,
+
+    /// The right-hand side value that replaces the matched instruction.
+    ///
+    /// Pure forwarding optimizations like `(=> (iadd $x 0) $x)` designate
+    /// their single `GetLhs` as the result. This is `None` when the
+    /// optimization doesn't replace the root with a single value: when its
+    /// actions end with a `DeleteInstruction` or a `ReplaceResults`.
+    pub result: Option<RhsId>,
+
+    /// The doc comment attached to the source optimization, if any.
+    ///
+    /// This is purely informational, and is ignored when comparing
+    /// optimizations.
+    pub doc: Option<String>,
+
+    /// Where the source optimization was written, if known.
+    ///
+    /// Like `doc`, this is purely informational, and is ignored when comparing
+    /// optimizations.
+    pub location: Option<SourceLocation>,
+}
+
+/// A line and column in the DSL source text, both starting at 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SourceLocation {
+    /// The line number.
+    pub line: u32,
+    /// The column number, counted in characters.
+    pub column: u32,
+}
+
+impl Optimization {
+    /// Get the opcode of this optimization's left-hand side root, without
+    /// matching it against any instruction.
+    ///
+    /// This is the expected value of the first `MatchOp::Opcode` switch on the
+    /// root instruction, at `PathId::ROOT`, among this optimization's
+    /// increments.
+    /// It is `None` when there is no such switch, e.g. when the left-hand
+    /// side is just a variable pattern, which might match any instruction.
+    pub fn root_opcode(&self) -> Option<u32> {
+        self.increments
+            .iter()
+            .find_map(|inc| match (inc.operation, inc.expected) {
+                (MatchOp::Opcode { path: PathId::ROOT }, Some(op)) => Some(op),
+                _ => None,
+            })
+    }
+
+    /// Get the set of interned integers that are referenced by this
+    /// optimization's match operations or actions, including the members of
+    /// any integer sets it references.
+    pub fn referenced_integers(&self, integers: &IntegerInterner) -> HashSet<IntegerId> {
+        let mut referenced = HashSet::new();
+        for inc in &self.increments {
+            let mut inc = inc.clone();
+            inc.for_each_integer_id_mut(|id| match id {
+                IntegerIdMut::Integer(id) => {
+                    referenced.insert(*id);
+                }
+                IntegerIdMut::Set(set) => referenced.extend(integers.lookup_set(*set)),
+            });
+        }
+        referenced
+    }
+
+    /// Get the set of interned paths that are referenced by this
+    /// optimization's match operations or actions.
+    pub fn referenced_paths(&self) -> HashSet<PathId> {
+        let mut referenced = HashSet::new();
+        for inc in &self.increments {
+            let mut operation = inc.operation;
+            operation.for_each_path_mut(|p| {
+                referenced.insert(*p);
+            });
+            for action in &inc.actions {
+                let mut action = action.clone();
+                action.for_each_path_mut(|p| {
+                    referenced.insert(*p);
+                });
+            }
+        }
+        referenced
+    }
 }
 
+impl PartialEq for Optimization {
+    fn eq(&self, other: &Self) -> bool {
+        self.increments == other.increments && self.result == other.result
+    }
+}
+
+impl Eq for Optimization {}
+
 /// An increment is a matching operation, the expected result from that
 /// operation to continue to the next increment, and the actions to take to
 /// build up the LHS scope and RHS instructions given that we got the expected
@@ -53,11 +436,131 @@ pub struct Increment {
     pub actions: Vec<Action>,
 }
 
+impl Increment {
+    /// Call `f` on each of the interned integers and integer sets that this
+    /// increment's match operation, expected result, and actions refer to.
+    ///
+    /// The expected result of a `MatchOp::IntegerValue` is an interned
+    /// integer, so `f` may change it too.
+    pub fn for_each_integer_id_mut(&mut self, mut f: impl FnMut(IntegerIdMut)) {
+        match &mut self.operation {
+            MatchOp::IntegerValue { .. } => {
+                if let Some(x) = &mut self.expected {
+                    let mut id = IntegerId(*x);
+                    f(IntegerIdMut::Integer(&mut id));
+                    *x = id.into();
+                }
+            }
+            MatchOp::IntegerInSet { set, .. } => f(IntegerIdMut::Set(set)),
+            MatchOp::BoundedBy { lo, hi, .. } => {
+                f(IntegerIdMut::Integer(lo));
+                f(IntegerIdMut::Integer(hi));
+            }
+            MatchOp::IsScaledBy { factor, .. } => f(IntegerIdMut::Integer(factor)),
+            MatchOp::Opcode { .. }
+            | MatchOp::IsConst { .. }
+            | MatchOp::IsPowerOfTwo { .. }
+            | MatchOp::BitWidth { .. }
+            | MatchOp::BlockPredecessorCount { .. }
+            | MatchOp::FitsInNativeWord { .. }
+            | MatchOp::BooleanValue { .. }
+            | MatchOp::ConditionCode { .. }
+            | MatchOp::IcmpZero { .. }
+            | MatchOp::IsShiftByConst { .. }
+            | MatchOp::IsCallResult { .. }
+            | MatchOp::IsExtension { .. }
+            | MatchOp::SingleUse { .. }
+            | MatchOp::AllOperandsConst { .. }
+            | MatchOp::SelectConstArms { .. }
+            | MatchOp::IsFoldable { .. }
+            | MatchOp::IsForwardableLoad { .. }
+            | MatchOp::IsUnused { .. }
+            | MatchOp::IsAligned { .. }
+            | MatchOp::HighBitsZero { .. }
+            | MatchOp::KnownNonNegative { .. }
+            | MatchOp::IsEncodableLogicalImm { .. }
+            | MatchOp::IsAddOfNegConst { .. }
+            | MatchOp::IsShiftedImm { .. }
+            | MatchOp::IsShiftedImm8 { .. }
+            | MatchOp::IsByteMask { .. }
+            | MatchOp::IsMaskConst { .. }
+            | MatchOp::PopcountEq { .. }
+            | MatchOp::PopcountLe { .. }
+            | MatchOp::NegationFitsInBits { .. }
+            | MatchOp::FitsInBits { .. }
+            | MatchOp::IsOne { .. }
+            | MatchOp::ConstEqWidthMinus { .. }
+            | MatchOp::TrailingZeros { .. }
+            | MatchOp::MemFlagsMatch { .. }
+            | MatchOp::CustomPredicate { .. }
+            | MatchOp::Eq { .. }
+            | MatchOp::SameWidth { .. }
+            | MatchOp::ConstEq { .. }
+            | MatchOp::EquivalentValues { .. }
+            | MatchOp::IsOneLessThan { .. }
+            | MatchOp::NestedShiftCombinable { .. }
+            | MatchOp::IsDoubleInvolution { .. }
+            | MatchOp::RedundantMaskForShift { .. }
+            | MatchOp::ConditionCodesCombine { .. }
+            | MatchOp::InPhase { .. }
+            | MatchOp::IsEnabled
+            | MatchOp::Nop => {}
+        }
+
+        for action in &mut self.actions {
+            match action {
+                Action::MakeIntegerConst { value, .. }
+                | Action::MakeIconstMatchingWidth { value, .. } => f(IntegerIdMut::Integer(value)),
+                Action::GetLhs { .. }
+                | Action::UnaryUnquote { .. }
+                | Action::BinaryUnquote { .. }
+                | Action::MakeZero { .. }
+                | Action::MakeOne { .. }
+                | Action::MakeSignShiftAmount { .. }
+                | Action::FoldConst { .. }
+                | Action::GetStoredValue { .. }
+                | Action::MakeBooleanConst { .. }
+                | Action::MakeConditionCode { .. }
+                | Action::InvertConditionCode { .. }
+                | Action::CombineConditionCodes { .. }
+                | Action::MakeUnaryInst { .. }
+                | Action::MakeBinaryInst { .. }
+                | Action::MakeTernaryInst { .. }
+                | Action::MakeSelectSpectreGuard { .. }
+                | Action::MakeLoad { .. }
+                | Action::MakeStore { .. }
+                | Action::MakeExtractLane { .. }
+                | Action::MakeInsertLane { .. }
+                | Action::CallFunction { .. }
+                | Action::DeleteInstruction
+                | Action::ReplaceResults { .. }
+                | Action::Matched { .. } => {}
+            }
+        }
+    }
+}
+
+/// An interned integer or integer set that an increment refers to.
+///
+/// See [`Increment::for_each_integer_id_mut`].
+#[derive(Debug)]
+pub enum IntegerIdMut<'a> {
+    /// An interned integer.
+    Integer(&'a mut IntegerId),
+    /// An interned set of integers.
+    Set(&'a mut IntegerSetId),
+}
+
 /// A matching operation to be performed on some Cranelift instruction as part
 /// of determining whether an optimization is applicable.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum MatchOp {
     /// Switch on the opcode of an instruction.
+    ///
+    /// The result is the number of the `Operator` that the instruction set's
+    /// opcode corresponds to, as given by
+    /// `peepmatic_runtime::instruction_set::Opcode::to_operator`, so that the
+    /// same automata work for any instruction set.
     Opcode {
         /// The path to the instruction whose opcode we're switching on.
         path: PathId,
@@ -77,6 +580,145 @@ pub enum MatchOp {
         path: PathId,
     },
 
+    /// Is the value the constant one?
+    ///
+    /// Integers are compared at their bit width, and the boolean `true` counts
+    /// as one. Anything that isn't a constant is not one.
+    IsOne {
+        /// The path to the instruction (or immediate) that we are checking.
+        path: PathId,
+    },
+
+    /// Is the instruction a shift (`ishl`, `ushr`, or `sshr`, or one of their
+    /// `_imm` forms) by a constant amount?
+    ///
+    /// The amount itself is the shift's operand at index 1 (or its immediate at
+    /// index 0 for `_imm` forms), and may be matched further from there.
+    IsShiftByConst {
+        /// The path to the instruction.
+        path: PathId,
+    },
+
+    /// Is the constant value a non-zero mask of whole bytes, such as `0xff00`,
+    /// and if so, which bytes does it select?
+    ///
+    /// Evaluates to `Some(lanes)` for byte masks, where bit `i` of `lanes` is
+    /// set when byte `i` is all ones, so `0xff00` evaluates to `Some(0b10)`.
+    /// Evaluates to `Some(0)` for anything else. Bytes are considered at the
+    /// constant's bit width, so `0xff` is a byte mask in 8 bits, but `1` is not
+    /// a byte mask in any width.
+    IsByteMask {
+        /// The path to the instruction (or immediate).
+        path: PathId,
+    },
+
+    /// Is the value a mask of its `n` low bits, `2^n - 1`?
+    ///
+    /// This recognizes both a constant mask and the `(isub (ishl 1 n) 1)`
+    /// computation of one, where `n` is a constant less than the bit width.
+    /// Evaluates to `Some(n)` for masks, and to `Some(0)` for anything else.
+    IsMaskConst {
+        /// The path to the value.
+        path: PathId,
+    },
+
+    /// Is the value the result of a call instruction?
+    IsCallResult {
+        /// The path to the value.
+        path: PathId,
+    },
+
+    /// Is every operand (immediates included) of the instruction a constant?
+    ///
+    /// Constant instructions themselves, such as `iconst`, never match: there
+    /// is nothing left to fold.
+    AllOperandsConst {
+        /// The path to the instruction.
+        path: PathId,
+    },
+
+    /// Is the instruction a `select` whose "then" and "else" arms are both
+    /// constants?
+    ///
+    /// This recognizes idioms like `(select $c 1 0)`, regardless of the
+    /// arms' values, which can then be matched on individually.
+    SelectConstArms {
+        /// The path to the instruction.
+        path: PathId,
+    },
+
+    /// Can the instruction be constant folded by `Action::FoldConst`?
+    ///
+    /// This is true when the instruction is one of the operations that
+    /// `FoldConst` knows how to evaluate, all of its operands are constants,
+    /// and the result is defined. The linearizer inserts this check for every
+    /// `FoldConst` action, so that a matched optimization never fails to fold.
+    IsFoldable {
+        /// The path to the instruction.
+        path: PathId,
+    },
+
+    /// Can the value of the load be forwarded by `Action::GetStoredValue`?
+    ///
+    /// This is true when the instruction is a `load` that immediately follows
+    /// a `store` (see `InstructionSet::instruction_preceding_store`) to the
+    /// same address and offset, and the stored value has the same bit width as
+    /// the load's result. The linearizer inserts this check for every
+    /// `GetStoredValue` action.
+    IsForwardableLoad {
+        /// The path to the load.
+        path: PathId,
+    },
+
+    /// Is the instruction's result unused?
+    ///
+    /// Instructions whose use count is unknown are not considered unused. The
+    /// linearizer inserts this check for every `DeleteInstruction` action, so
+    /// that a still-used value is never deleted.
+    IsUnused {
+        /// The path to the instruction.
+        path: PathId,
+    },
+
+    /// Is the value used exactly once?
+    ///
+    /// Values whose use count is unknown, and constants, are not considered
+    /// single use.
+    SingleUse {
+        /// The path to the value.
+        path: PathId,
+    },
+
+    /// Switch on whether a value is the result of a `uextend` or `sextend`,
+    /// and if so, which one and from what width.
+    ///
+    /// Evaluates to `Some(0)` for anything that is not an extension, and to
+    /// `MatchOp::extension_value(signed, source_width)` for extensions.
+    IsExtension {
+        /// The path to the value.
+        path: PathId,
+    },
+
+    /// Does the constant value have exactly `count` bits set?
+    ///
+    /// Bits are counted at the constant's bit width.
+    PopcountEq {
+        /// The path to the instruction (or immediate).
+        path: PathId,
+        /// The expected number of set bits.
+        count: u8,
+    },
+
+    /// Does the constant value have at most `count` bits set?
+    ///
+    /// Bits are counted at the constant's bit width.
+    PopcountLe {
+        /// The path to the instruction (or immediate).
+        path: PathId,
+        /// The maximum number of set bits.
+        count: u8,
+    },
+
     /// Switch on the bit width of a value.
     BitWidth {
         /// The path to the instruction (or immediate) whose result's bit width
@@ -84,6 +726,70 @@ pub enum MatchOp {
         path: PathId,
     },
 
+    /// Do the values at the given paths have the same bit width?
+    ///
+    /// This links operations that were ascribed the same type variable, like
+    /// `(iadd{$T} ...)`, whose bit widths are not otherwise known.
+    SameWidth {
+        /// The path to the first instruction (or immediate).
+        path_a: PathId,
+        /// The path to the second instruction (or immediate).
+        path_b: PathId,
+    },
+
+    /// Switch on the number of predecessors of the block containing an
+    /// instruction.
+    BlockPredecessorCount {
+        /// The path to the instruction.
+        path: PathId,
+    },
+
+    /// Does the constant's negation fit in the given number of bits, as a
+    /// signed integer?
+    ///
+    /// The constant is negated with wrapping at its own bit width, so e.g. the
+    /// negation of the 8-bit `-128` is `-128` again.
+    NegationFitsInBits {
+        /// The path to the constant.
+        path: PathId,
+        /// The number of signed bits the negation must fit in.
+        bits: u8,
+    },
+
+    /// Does the constant fit in the given number of bits, as a signed
+    /// integer, at its own bit width?
+    ///
+    /// This is the check for whether a constant can be encoded in a
+    /// sign-extended immediate field of the given size.
+    FitsInBits {
+        /// The path to the constant.
+        path: PathId,
+        /// The number of signed bits the constant must fit in.
+        bits: u8,
+    },
+
+    /// Switch on the number of trailing zero bits in a constant, at its bit
+    /// width.
+    ///
+    /// Zero has as many trailing zeros as its width. This lets rules branch on
+    /// how aligned a constant offset or address is.
+    TrailingZeros {
+        /// The path to the constant.
+        path: PathId,
+    },
+
+    /// Is the constant equal to its own bit width minus `delta`?
+    ///
+    /// For example, with a `delta` of 1 this matches `31` at `i32` and `63`
+    /// at `i64`, which is useful for width-polymorphic rules about shifting
+    /// out everything but the sign bit.
+    ConstEqWidthMinus {
+        /// The path to the constant.
+        path: PathId,
+        /// The amount subtracted from the constant's bit width.
+        delta: u8,
+    },
+
     /// Does the value fit in our target architecture's native word size?
     FitsInNativeWord {
         /// The path to the instruction (or immediate) whose result we are
@@ -99,6 +805,246 @@ pub enum MatchOp {
         path_b: PathId,
     },
 
+    /// Are the instructions (or immediates) at the given paths both constants
+    /// with the same numeric value?
+    ///
+    /// Unlike `Eq`, two different `iconst` instructions with the same value
+    /// are equal, and unlike `EquivalentValues`, the values must be
+    /// constants. Integers are compared at their shared bit width, so the same
+    /// value with different bits above that width is still equal.
+    ConstEq {
+        /// The path to the first constant.
+        path_a: PathId,
+        /// The path to the second constant.
+        path_b: PathId,
+    },
+
+    /// Are the instructions (or immediates) at the given paths either the
+    /// same SSA value, or constants with the same value?
+    ///
+    /// Unlike `Eq`, this will consider two different instructions that both
+    /// produce the same constant to be equivalent.
+    EquivalentValues {
+        /// The path to the first instruction (or immediate).
+        path_a: PathId,
+        /// The path to the second instruction (or immediate).
+        path_b: PathId,
+    },
+
+    /// Is the first constant exactly one less than the second?
+    ///
+    /// Both constants are compared at their shared bit width, and the pair
+    /// never matches across wraparound: the all-ones value is not one less
+    /// than zero.
+    IsOneLessThan {
+        /// The path to the first constant.
+        path_a: PathId,
+        /// The path to the second constant.
+        path_b: PathId,
+    },
+
+    /// Is the first constant the second constant times `factor`?
+    ///
+    /// Both constants are compared at their shared bit width, and the
+    /// multiplication wraps around at that width.
+    IsScaledBy {
+        /// The path to the first constant.
+        path_a: PathId,
+        /// The path to the second constant.
+        path_b: PathId,
+        /// The factor that the second constant is multiplied by.
+        factor: IntegerId,
+    },
+
+    /// Are the outer and inner instructions the same shift by constant amounts,
+    /// such that the two shifts can be combined into one?
+    ///
+    /// Only `ishl`, `ushr`, and `sshr` combine. Each amount and their sum must
+    /// be less than the outer shift's bit width; otherwise the combined shift
+    /// would not be equivalent, and this does not match.
+    NestedShiftCombinable {
+        /// The path to the outer shift instruction.
+        outer: PathId,
+        /// The path to the inner shift instruction, which is shifted by the
+        /// outer one.
+        inner: PathId,
+    },
+
+    /// Does masking a value with the constant `mask` keep every bit that a
+    /// logical right shift by the constant `amount` keeps?
+    ///
+    /// When this matches, masking before the `ushr` is redundant. Both
+    /// constants are taken at the mask's bit width, and amounts that are not
+    /// less than that width do not match.
+    RedundantMaskForShift {
+        /// The path to the mask constant.
+        mask: PathId,
+        /// The path to the shift amount constant.
+        amount: PathId,
+    },
+
+    /// Are the outer and inner instructions the same involution, i.e. a unary
+    /// operation that undoes itself, like `bnot` or `ineg`?
+    ///
+    /// When this matches, applying the outer instruction to the inner one is
+    /// the same as the inner instruction's operand.
+    IsDoubleInvolution {
+        /// The path to the outer instruction.
+        outer: PathId,
+        /// The path to the inner instruction, which is the outer one's operand.
+        inner: PathId,
+    },
+
+    /// Are both instructions `icmp`s of the same operands, whose condition
+    /// codes combine into a single condition code?
+    ///
+    /// See `ConditionCode::combine` for which condition codes combine.
+    ConditionCodesCombine {
+        /// The path to the first `icmp` instruction.
+        path_a: PathId,
+        /// The path to the second `icmp` instruction.
+        path_b: PathId,
+    },
+
+    /// Does the embedder's custom predicate hold for the instruction?
+    ///
+    /// Predicates are registered with the optimizer by name, and an
+    /// unregistered predicate never holds.
+    CustomPredicate {
+        /// The path to the instruction.
+        path: PathId,
+        /// The index of the predicate's name in the optimizations' predicate
+        /// names.
+        predicate: u32,
+    },
+
+    /// Is the constant value one of the integers in the given set?
+    ///
+    /// The constant is compared at its bit width, just like `IntegerValue`. An
+    /// empty set never matches.
+    IntegerInSet {
+        /// The path to the constant.
+        path: PathId,
+        /// The interned set of integers.
+        set: IntegerSetId,
+    },
+
+    /// Is the value known to be within the unsigned range `[lo, hi]`?
+    ///
+    /// Constants are checked directly. For other values, this consults the
+    /// instruction set's range facts (see
+    /// `InstructionSet::instruction_value_range`), and values without a known
+    /// range do not match.
+    BoundedBy {
+        /// The path to the value.
+        path: PathId,
+        /// The inclusive lower bound.
+        lo: IntegerId,
+        /// The inclusive upper bound.
+        hi: IntegerId,
+    },
+
+    /// Is the value known to be a multiple of `align`?
+    ///
+    /// Constants are checked directly. For other values, this consults the
+    /// instruction set's alignment facts (see
+    /// `InstructionSet::instruction_alignment`), and values without a known
+    /// alignment do not match.
+    IsAligned {
+        /// The path to the value.
+        path: PathId,
+        /// The alignment, in bytes. Always a power of two.
+        align: u8,
+    },
+
+    /// Are all of the value's bits above its low `from_width` bits known to be
+    /// zero, i.e. is it known to be zero-extended from `from_width` bits?
+    ///
+    /// Constants are checked directly. For other values, this consults the
+    /// instruction set's range facts (see
+    /// `InstructionSet::instruction_value_range`), and values without a known
+    /// range do not match.
+    HighBitsZero {
+        /// The path to the value.
+        path: PathId,
+        /// The number of low bits that may be non-zero. Always less than 128.
+        from_width: u8,
+    },
+
+    /// Is the value known to be non-negative when interpreted as a signed
+    /// integer at its bit width, i.e. is its sign bit known to be zero?
+    ///
+    /// Constants are checked directly. For other values, this consults the
+    /// instruction set's range facts (see
+    /// `InstructionSet::instruction_value_range`), and values without a known
+    /// range do not match.
+    KnownNonNegative {
+        /// The path to the value.
+        path: PathId,
+    },
+
+    /// Is the constant encodable as a logical immediate, as taken by AArch64's
+    /// `and`, `orr`, and `eor` instructions, in a register of the given width?
+    ///
+    /// Those are the values made of a rotated run of ones, replicated across
+    /// the register in elements of 2, 4, 8, 16, 32, or 64 bits. Zero and all
+    /// ones are not encodable, and neither is a constant with bits set above
+    /// `width`.
+    IsEncodableLogicalImm {
+        /// The path to the constant.
+        path: PathId,
+        /// The register's bit width. Either 32 or 64.
+        width: u8,
+    },
+
+    /// Is the instruction an `iadd` whose second operand is a negative
+    /// constant, i.e. really a subtraction of that constant's magnitude?
+    ///
+    /// The constant is taken at the `iadd`'s bit width. Its magnitude is its
+    /// wrapping negation at that width, which is `$(neg $C)` in a right-hand
+    /// side.
+    IsAddOfNegConst {
+        /// The path to the `iadd` instruction.
+        path: PathId,
+    },
+
+    /// Is the constant expressible as `base << shift`, where `base` fits in
+    /// `bits` unsigned bits?
+    ///
+    /// The constant is taken at its bit width, and its low `shift` bits must
+    /// all be zero. Its base is `$(ushr $C shift)` in a right-hand side, which
+    /// is the form that shifted-immediate instructions, e.g. AArch64's `add`
+    /// with `lsl #12`, take.
+    IsShiftedImm {
+        /// The path to the constant.
+        path: PathId,
+        /// The number of bits that the base must fit in.
+        bits: u8,
+        /// The amount that the base is shifted left by.
+        shift: u8,
+    },
+
+    /// Is the constant expressible as `imm8 << k`, where `imm8` fits in a
+    /// byte and `k` is a multiple of eight?
+    ///
+    /// That is, does the constant have at most one non-zero byte? Its `imm8`
+    /// and `k` are `$(imm8 $C)` and `$(imm8_shift $C)` in a right-hand side.
+    IsShiftedImm8 {
+        /// The path to the constant.
+        path: PathId,
+    },
+
+    /// Do the memory flags of a load or store, masked by `flags_mask`, equal
+    /// `flags_value`?
+    MemFlagsMatch {
+        /// The path to the memory access instruction.
+        path: PathId,
+        /// The flags we care about. See `peepmatic_runtime::mem_flags`.
+        flags_mask: u8,
+        /// The expected value of the flags we care about.
+        flags_value: u8,
+    },
+
     /// Switch on the constant integer value of an instruction.
     IntegerValue {
         /// The path to the instruction.
@@ -117,6 +1063,31 @@ pub enum MatchOp {
         path: PathId,
     },
 
+    /// Switch on the condition code of an `icmp` whose second operand is the
+    /// constant zero.
+    ///
+    /// Evaluates to `None` for anything else, so a comparison against zero
+    /// with a particular condition code is matched by a single transition.
+    IcmpZero {
+        /// The path to the `icmp` instruction.
+        path: PathId,
+    },
+
+    /// Is the optimizer running the given phase?
+    ///
+    /// See `PeepholeOptimizer::set_phase`.
+    InPhase {
+        /// The phase that the optimization is tagged with.
+        phase: Phase,
+    },
+
+    /// Has the optimizer opted into optimizations that are disabled by
+    /// default?
+    ///
+    /// Only optimizations with a `(disabled)` annotation check this. See
+    /// `PeepholeOptimizer::set_enable_disabled`.
+    IsEnabled,
+
     /// No operation. Always evaluates to `None`.
     ///
     /// Exceedingly rare in real optimizations; nonetheless required to support
@@ -125,6 +1096,107 @@ pub enum MatchOp {
     Nop,
 }
 
+impl MatchOp {
+    /// The value that `MatchOp::IsExtension` evaluates to for a `sextend` (when
+    /// `signed`) or `uextend` of a `source_width`-bit value.
+    ///
+    /// The source width is in the low eight bits, and signedness is the ninth
+    /// bit. This is never zero, which is reserved for non-extensions.
+    pub fn extension_value(signed: bool, source_width: u8) -> u32 {
+        debug_assert!(source_width > 0);
+        (u32::from(signed) << 8) | u32::from(source_width)
+    }
+
+    /// Call `f` on each of the paths this match operation refers to.
+    pub fn for_each_path_mut(&mut self, mut f: impl FnMut(&mut PathId)) {
+        match self {
+            MatchOp::Opcode { path }
+            | MatchOp::IsConst { path }
+            | MatchOp::IsPowerOfTwo { path }
+            | MatchOp::BitWidth { path }
+            | MatchOp::BlockPredecessorCount { path }
+            | MatchOp::FitsInNativeWord { path }
+            | MatchOp::IntegerValue { path }
+            | MatchOp::BooleanValue { path }
+            | MatchOp::ConditionCode { path }
+            | MatchOp::IcmpZero { path }
+            | MatchOp::IsShiftByConst { path }
+            | MatchOp::IsCallResult { path }
+            | MatchOp::IsExtension { path }
+            | MatchOp::SingleUse { path }
+            | MatchOp::AllOperandsConst { path }
+            | MatchOp::SelectConstArms { path }
+            | MatchOp::IsFoldable { path }
+            | MatchOp::IsForwardableLoad { path }
+            | MatchOp::IsUnused { path }
+            | MatchOp::IntegerInSet { path, .. }
+            | MatchOp::BoundedBy { path, .. }
+            | MatchOp::IsAligned { path, .. }
+            | MatchOp::HighBitsZero { path, .. }
+            | MatchOp::KnownNonNegative { path }
+            | MatchOp::IsEncodableLogicalImm { path, .. }
+            | MatchOp::IsAddOfNegConst { path }
+            | MatchOp::IsShiftedImm { path, .. }
+            | MatchOp::IsShiftedImm8 { path }
+            | MatchOp::IsByteMask { path }
+            | MatchOp::IsMaskConst { path }
+            | MatchOp::PopcountEq { path, .. }
+            | MatchOp::PopcountLe { path, .. }
+            | MatchOp::NegationFitsInBits { path, .. }
+            | MatchOp::FitsInBits { path, .. }
+            | MatchOp::IsOne { path }
+            | MatchOp::ConstEqWidthMinus { path, .. }
+            | MatchOp::TrailingZeros { path }
+            | MatchOp::MemFlagsMatch { path, .. }
+            | MatchOp::CustomPredicate { path, .. } => f(path),
+            MatchOp::Eq { path_a, path_b }
+            | MatchOp::SameWidth { path_a, path_b }
+            | MatchOp::ConstEq { path_a, path_b }
+            | MatchOp::EquivalentValues { path_a, path_b }
+            | MatchOp::IsOneLessThan { path_a, path_b }
+            | MatchOp::IsScaledBy { path_a, path_b, .. }
+            | MatchOp::NestedShiftCombinable {
+                outer: path_a,
+                inner: path_b,
+            }
+            | MatchOp::IsDoubleInvolution {
+                outer: path_a,
+                inner: path_b,
+            }
+            | MatchOp::RedundantMaskForShift {
+                mask: path_a,
+                amount: path_b,
+            }
+            | MatchOp::ConditionCodesCombine { path_a, path_b } => {
+                f(path_a);
+                f(path_b);
+            }
+            MatchOp::InPhase { .. } | MatchOp::IsEnabled | MatchOp::Nop => {}
+        }
+    }
+}
+
+/// A pipeline phase that an optimization may be tagged with.
+///
+/// Untagged optimizations apply in every phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Phase {
+    /// Canonicalizing rewrites, typically run early and to a fixpoint.
+    Canonicalize,
+
+    /// Lowering rewrites, typically run once at the end.
+    Lowering,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Phase::Canonicalize => write!(f, "canonicalize"),
+            Phase::Lowering => write!(f, "lowering"),
+        }
+    }
+}
+
 /// A canonicalized identifier for a left-hand side value that was bound in a
 /// pattern.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
@@ -135,7 +1207,7 @@ pub struct LhsId(pub u32);
 pub struct RhsId(pub u32);
 
 /// An action to perform when transitioning between states in the automata.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Action {
     /// Implicitly define the n^th built up RHS instruction as something from
     /// the left-hand side.
@@ -170,6 +1242,60 @@ pub enum Action {
         bit_width: BitWidth,
     },
 
+    /// Implicitly define the n^th RHS as the integer constant zero.
+    ///
+    /// This is the same as a `MakeIntegerConst` of zero, but such a common
+    /// result that it doesn't need an interned integer.
+    MakeZero {
+        /// The bit width of this constant.
+        bit_width: BitWidth,
+    },
+
+    /// Implicitly define the n^th RHS as the integer constant one.
+    ///
+    /// This is the same as a `MakeIntegerConst` of one, but such a common
+    /// result that it doesn't need an interned integer.
+    MakeOne {
+        /// The bit width of this constant.
+        bit_width: BitWidth,
+    },
+
+    /// Implicitly define the n^th RHS as an integer constant whose bit width
+    /// is the same as the left-hand side value at the given path, as
+    /// determined when the optimization is applied.
+    MakeIconstMatchingWidth {
+        /// The constant integer value.
+        value: IntegerId,
+        /// The path to the left-hand side value whose bit width this constant
+        /// takes on.
+        like: PathId,
+    },
+
+    /// Implicitly define the n^th RHS as an integer constant that is one less
+    /// than its bit width, which is the amount that shifts a value's sign bit
+    /// down into its lowest bit.
+    MakeSignShiftAmount {
+        /// The bit width of this constant.
+        bit_width: BitWidth,
+    },
+
+    /// Implicitly define the n^th RHS as the constant that the pure
+    /// instruction at the given path evaluates to, given its constant
+    /// operands.
+    ///
+    /// The constant has the same bit width as the instruction's result.
+    FoldConst {
+        /// The path to the instruction to fold.
+        path: PathId,
+    },
+
+    /// Implicitly define the n^th RHS as the value that the store preceding
+    /// the load at the given path wrote, rather than reloading it.
+    GetStoredValue {
+        /// The path to the load.
+        path: PathId,
+    },
+
     /// Implicitly define the n^th RHS as a boolean constant.
     MakeBooleanConst {
         /// The constant boolean value.
@@ -184,6 +1310,22 @@ pub enum Action {
         cc: ConditionCode,
     },
 
+    /// Implicitly define the n^th RHS as the inverse of a condition code that
+    /// was already built up.
+    InvertConditionCode {
+        /// The condition code to invert.
+        operand: RhsId,
+    },
+
+    /// Implicitly define the n^th RHS as the combination of two condition
+    /// codes that were already built up.
+    ///
+    /// See `ConditionCode::combine`.
+    CombineConditionCodes {
+        /// The condition codes to combine.
+        operands: [RhsId; 2],
+    },
+
     /// Implicitly define the n^th RHS instruction by making a unary
     /// instruction.
     MakeUnaryInst {
@@ -216,4 +1358,222 @@ pub enum Action {
         /// The operands for this instruction.
         operands: [RhsId; 3],
     },
+
+    /// Implicitly define the n^th RHS instruction by making a
+    /// `select_spectre_guard` instruction.
+    ///
+    /// This is kept distinct from `MakeTernaryInst` so that a guarded select
+    /// is never accidentally built as a plain `select`, which could be
+    /// speculated around.
+    MakeSelectSpectreGuard {
+        /// The type of this instruction's result.
+        r#type: Type,
+        /// The condition, the value if true, and the value if false.
+        operands: [RhsId; 3],
+    },
+
+    /// Implicitly define the n^th RHS instruction by making a `load`.
+    ///
+    /// The new load's immediate offset is the root's offset plus `offset`, so
+    /// that folding address arithmetic into a load keeps the offset that it
+    /// already had. A root that isn't a memory access has an offset of zero.
+    MakeLoad {
+        /// The address to load from.
+        operand: RhsId,
+        /// The amount to add to the root's immediate offset.
+        offset: i32,
+        /// The memory flags for this load, or `None` to carry over the root's
+        /// flags. See `peepmatic_runtime::mem_flags`.
+        flags: Option<u8>,
+        /// The bit width of the loaded integer.
+        bit_width: BitWidth,
+    },
+
+    /// Implicitly define the n^th RHS instruction by making a `store`.
+    ///
+    /// Like `MakeLoad`, the new store's immediate offset is relative to the
+    /// root's.
+    MakeStore {
+        /// The value to store.
+        value: RhsId,
+        /// The address to store to.
+        addr: RhsId,
+        /// The amount to add to the root's immediate offset.
+        offset: i32,
+        /// The memory flags for this store, or `None` to carry over the root's
+        /// flags. See `peepmatic_runtime::mem_flags`.
+        flags: Option<u8>,
+    },
+
+    /// Implicitly define the n^th RHS instruction by making an `extractlane`.
+    ///
+    /// The new instruction's type is the lane type of `operand`, which must be
+    /// a vector instruction.
+    MakeExtractLane {
+        /// The vector to extract a lane from.
+        operand: RhsId,
+        /// The index of the lane to extract. This is always less than the
+        /// vector's number of lanes.
+        lane: u8,
+    },
+
+    /// Implicitly define the n^th RHS instruction by making an `insertlane`.
+    ///
+    /// The new instruction has the same vector type as `vector`, which must be
+    /// a vector instruction.
+    MakeInsertLane {
+        /// The vector to insert a lane into.
+        vector: RhsId,
+        /// The value to insert.
+        value: RhsId,
+        /// The index of the lane to replace. This is always less than the
+        /// vector's number of lanes.
+        lane: u8,
+    },
+
+    /// Implicitly define the n^th RHS as the constant returned by calling the
+    /// host function with the given operands.
+    ///
+    /// See `PeepholeOptimizer::register_function`.
+    CallFunction {
+        /// The function's index into `PeepholeOptimizations::functions`.
+        function: u32,
+        /// The function's operands, if any.
+        operands: [Option<RhsId>; 2],
+    },
+
+    /// Delete the root instruction entirely, rather than replacing it with a
+    /// new right-hand side.
+    ///
+    /// This does not define any RHS, and is only ever an optimization's sole
+    /// action.
+    DeleteInstruction,
+
+    /// Replace each of the root instruction's results with the given RHSes,
+    /// rather than replacing the root with a single new right-hand side.
+    ///
+    /// This does not define any RHS, and is only ever an optimization's last
+    /// action.
+    ReplaceResults {
+        /// The replacement for each of the root's results, in order.
+        results: Vec<RhsId>,
+    },
+
+    /// Record which optimization matched.
+    ///
+    /// This does not define any RHS. When building the automaton, this is
+    /// appended to each optimization's actions, so that the optimizer can tell
+    /// which optimization it matched (see
+    /// `PeepholeOptimizer::add_match_observer`). It never appears in linear
+    /// optimizations, and the optimizer removes it before evaluating actions.
+    Matched {
+        /// The optimization's index, in the same order as
+        /// `PeepholeOptimizations::root_operators`.
+        optimization: u32,
+    },
+}
+
+impl Action {
+    /// Call `f` on each of the left-hand side paths this action refers to.
+    pub fn for_each_path_mut(&mut self, mut f: impl FnMut(&mut PathId)) {
+        match self {
+            Action::GetLhs { path }
+            | Action::MakeIconstMatchingWidth { like: path, .. }
+            | Action::FoldConst { path }
+            | Action::GetStoredValue { path } => f(path),
+            Action::UnaryUnquote { .. }
+            | Action::BinaryUnquote { .. }
+            | Action::MakeIntegerConst { .. }
+            | Action::MakeZero { .. }
+            | Action::MakeOne { .. }
+            | Action::MakeSignShiftAmount { .. }
+            | Action::MakeBooleanConst { .. }
+            | Action::MakeConditionCode { .. }
+            | Action::InvertConditionCode { .. }
+            | Action::CombineConditionCodes { .. }
+            | Action::MakeUnaryInst { .. }
+            | Action::MakeBinaryInst { .. }
+            | Action::MakeTernaryInst { .. }
+            | Action::MakeSelectSpectreGuard { .. }
+            | Action::MakeLoad { .. }
+            | Action::MakeStore { .. }
+            | Action::MakeExtractLane { .. }
+            | Action::MakeInsertLane { .. }
+            | Action::CallFunction { .. }
+            | Action::DeleteInstruction
+            | Action::ReplaceResults { .. }
+            | Action::Matched { .. } => {}
+        }
+    }
+
+    /// Call `f` on each of the right-hand side values this action refers to.
+    pub fn for_each_rhs_id_mut(&mut self, mut f: impl FnMut(&mut RhsId)) {
+        match self {
+            Action::UnaryUnquote { operand, .. }
+            | Action::InvertConditionCode { operand }
+            | Action::MakeUnaryInst { operand, .. }
+            | Action::MakeLoad { operand, .. }
+            | Action::MakeExtractLane { operand, .. } => f(operand),
+            Action::BinaryUnquote { operands, .. }
+            | Action::CombineConditionCodes { operands }
+            | Action::MakeBinaryInst { operands, .. } => operands.iter_mut().for_each(f),
+            Action::MakeTernaryInst { operands, .. }
+            | Action::MakeSelectSpectreGuard { operands, .. } => operands.iter_mut().for_each(f),
+            Action::MakeStore { value, addr, .. } => {
+                f(value);
+                f(addr);
+            }
+            Action::MakeInsertLane { vector, value, .. } => {
+                f(vector);
+                f(value);
+            }
+            Action::CallFunction { operands, .. } => operands.iter_mut().flatten().for_each(f),
+            Action::ReplaceResults { results } => results.iter_mut().for_each(f),
+            Action::GetLhs { .. }
+            | Action::MakeIntegerConst { .. }
+            | Action::MakeIconstMatchingWidth { .. }
+            | Action::MakeZero { .. }
+            | Action::MakeOne { .. }
+            | Action::MakeSignShiftAmount { .. }
+            | Action::FoldConst { .. }
+            | Action::GetStoredValue { .. }
+            | Action::MakeBooleanConst { .. }
+            | Action::MakeConditionCode { .. }
+            | Action::DeleteInstruction
+            | Action::Matched { .. } => {}
+        }
+    }
+
+    /// Get the operator of the instruction that this action makes, if it makes
+    /// an instruction.
+    pub fn made_operator(&self) -> Option<Operator> {
+        match *self {
+            Action::MakeUnaryInst { operator, .. }
+            | Action::MakeBinaryInst { operator, .. }
+            | Action::MakeTernaryInst { operator, .. } => Some(operator),
+            Action::MakeSelectSpectreGuard { .. } => Some(Operator::SelectSpectreGuard),
+            Action::MakeLoad { .. } => Some(Operator::Load),
+            Action::MakeStore { .. } => Some(Operator::Store),
+            Action::MakeExtractLane { .. } => Some(Operator::Extractlane),
+            Action::MakeInsertLane { .. } => Some(Operator::Insertlane),
+            Action::GetLhs { .. }
+            | Action::UnaryUnquote { .. }
+            | Action::BinaryUnquote { .. }
+            | Action::MakeIntegerConst { .. }
+            | Action::MakeIconstMatchingWidth { .. }
+            | Action::MakeZero { .. }
+            | Action::MakeOne { .. }
+            | Action::MakeSignShiftAmount { .. }
+            | Action::FoldConst { .. }
+            | Action::GetStoredValue { .. }
+            | Action::MakeBooleanConst { .. }
+            | Action::MakeConditionCode { .. }
+            | Action::InvertConditionCode { .. }
+            | Action::CombineConditionCodes { .. }
+            | Action::CallFunction { .. }
+            | Action::DeleteInstruction
+            | Action::ReplaceResults { .. }
+            | Action::Matched { .. } => None,
+        }
+    }
 }
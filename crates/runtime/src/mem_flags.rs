@@ -0,0 +1,14 @@
+//! Flags for memory accesses.
+//!
+//! These mirror the bits of Cranelift's `MemFlags`, and are what
+//! `InstructionSet::instruction_mem_flags` is expected to return for loads and
+//! stores.
+
+/// The memory access will not trap.
+pub const NOTRAP: u8 = 1 << 0;
+
+/// The memory access is naturally aligned.
+pub const ALIGNED: u8 = 1 << 1;
+
+/// The memory being accessed is never written to.
+pub const READONLY: u8 = 1 << 2;
@@ -15,6 +15,31 @@ use serde::{Deserialize, Serialize};
 /// copy over the extra information.
 ///
 /// Affected operations: `brz`, `brnz`, `trapz`, `trapnz`.
+///
+/// ## Caveats for Memory Operators
+///
+/// The memory flags and offset of `load` and `store` are not operands or
+/// immediates in the DSL. They are only accessible via dedicated match
+/// operations and actions.
+///
+/// Affected operations: `load`, `store`.
+///
+/// ## Caveats for Operators with Multiple Results
+///
+/// When an instruction with multiple results is used as an operand, it refers
+/// to its first result. When it is the root of an optimization, the
+/// right-hand side must replace all of its results: either with a `results`
+/// expression, or with another operation that has the same results.
+///
+/// Affected operations: `iadd_cout`, `isub_bout`.
+///
+/// ## Caveats for Lane Operators
+///
+/// In a right-hand side, the lane index immediate must be an integer literal,
+/// and less than the vector's number of lanes. When the lane width is
+/// polymorphic, that is the smallest number of lanes any vector has: two.
+///
+/// Affected operations: `extractlane`, `insertlane`.
 #[derive(PeepmaticOperator, Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum Operator {
@@ -27,7 +52,7 @@ pub enum Operator {
     AdjustSpDownImm,
 
     /// `band`
-    #[peepmatic(params(iNN, iNN), result(iNN))]
+    #[peepmatic(params(bool_or_int_t, bool_or_int_t), result(bool_or_int_t))]
     Band,
 
     /// `band_imm`
@@ -42,8 +67,12 @@ pub enum Operator {
     #[peepmatic(params(bNN), result(iNN))]
     Bint,
 
+    /// `bnot`
+    #[peepmatic(params(bNN), result(bNN))]
+    Bnot,
+
     /// `bor`
-    #[peepmatic(params(iNN, iNN), result(iNN))]
+    #[peepmatic(params(bool_or_int_t, bool_or_int_t), result(bool_or_int_t))]
     Bor,
 
     /// `bor_imm`
@@ -59,17 +88,29 @@ pub enum Operator {
     Brz,
 
     /// `bxor`
-    #[peepmatic(params(iNN, iNN), result(iNN))]
+    #[peepmatic(params(bool_or_int_t, bool_or_int_t), result(bool_or_int_t))]
     Bxor,
 
     /// `bxor_imm`
     #[peepmatic(immediates(iNN), params(iNN), result(iNN))]
     BxorImm,
 
+    /// `extractlane`
+    #[peepmatic(immediates(iMM), params(vNN), result(iNN))]
+    Extractlane,
+
+    /// `iabs`
+    #[peepmatic(params(iNN), result(iNN))]
+    Iabs,
+
     /// `iadd`
     #[peepmatic(params(iNN, iNN), result(iNN))]
     Iadd,
 
+    /// `iadd_cout`
+    #[peepmatic(params(iNN, iNN), result(iNN, b1))]
+    IaddCout,
+
     /// `iadd_imm`
     #[peepmatic(immediates(iNN), params(iNN), result(iNN))]
     IaddImm,
@@ -94,6 +135,14 @@ pub enum Operator {
     #[peepmatic(immediates(iNN), params(iNN), result(cpu_flags))]
     IfcmpImm,
 
+    /// `imax`
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    Imax,
+
+    /// `imin`
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    Imin,
+
     /// `imul`
     #[peepmatic(params(iNN, iNN), result(iNN))]
     Imul,
@@ -102,6 +151,14 @@ pub enum Operator {
     #[peepmatic(immediates(iNN), params(iNN), result(iNN))]
     ImulImm,
 
+    /// `ineg`
+    #[peepmatic(params(iNN), result(iNN))]
+    Ineg,
+
+    /// `insertlane`
+    #[peepmatic(immediates(iMM), params(vNN, iNN), result(vNN))]
+    Insertlane,
+
     /// `ireduce`
     #[peepmatic(params(iNN), result(iMM))]
     Ireduce,
@@ -122,6 +179,14 @@ pub enum Operator {
     #[peepmatic(params(iNN, iNN), result(iNN))]
     Isub,
 
+    /// `isub_bout`
+    #[peepmatic(params(iNN, iNN), result(iNN, b1))]
+    IsubBout,
+
+    /// `load`
+    #[peepmatic(params(iNN), result(iMM))]
+    Load,
+
     /// `rotl`
     #[peepmatic(params(iNN, iNN), result(iNN))]
     Rotl,
@@ -150,6 +215,10 @@ pub enum Operator {
     #[peepmatic(params(bool_or_int, any_t, any_t), result(any_t))]
     Select,
 
+    /// `select_spectre_guard`
+    #[peepmatic(params(bool_or_int, any_t, any_t), result(any_t))]
+    SelectSpectreGuard,
+
     /// `sextend`
     #[peepmatic(params(iNN), result(iMM))]
     Sextend,
@@ -170,6 +239,10 @@ pub enum Operator {
     #[peepmatic(immediates(iNN), params(iNN), result(iNN))]
     SshrImm,
 
+    /// `store`
+    #[peepmatic(params(iMM, iNN), result(void))]
+    Store,
+
     /// `trapnz`
     #[peepmatic(params(bool_or_int), result(void))]
     Trapnz,
@@ -190,6 +263,14 @@ pub enum Operator {
     #[peepmatic(params(iNN), result(iMM))]
     Uextend,
 
+    /// `umax`
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    Umax,
+
+    /// `umin`
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    Umin,
+
     /// `urem`
     #[peepmatic(params(iNN, iNN), result(iNN))]
     Urem,
@@ -211,6 +292,10 @@ pub enum Operator {
 ///
 /// These are used in the right-hand side to perform compile-time evaluation of
 /// constants matched on the left-hand side.
+///
+/// Arithmetic (`iadd`, `isub`, `imul`, and `neg`) wraps at the bit width of its
+/// operands, and the result is sign-extended to 128 bits, the same way that
+/// negative integer literals are.
 #[derive(PeepmaticOperator, Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum UnquoteOperator {
@@ -234,6 +319,29 @@ pub enum UnquoteOperator {
     #[peepmatic(params(iNN, iNN), result(iNN))]
     Imul,
 
+    /// Compile-time `isub` of two constant values.
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    Isub,
+
+    /// Round the first constant down to a multiple of the second, which must
+    /// be a power of two, e.g. to compute an aligned address.
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    AlignDown,
+
+    /// Compile-time `ushr` of two constant values: a logical right shift of
+    /// the first, at its bit width, by the second.
+    #[peepmatic(params(iNN, iNN), result(iNN))]
+    Ushr,
+
+    /// Invert a condition code, e.g. turn `eq` into `ne`.
+    #[peepmatic(params(cc), result(cc))]
+    InvertCc,
+
+    /// Combine two condition codes into one that is true when either is, e.g.
+    /// turn `slt` and `eq` into `sle`.
+    #[peepmatic(params(cc, cc), result(cc))]
+    CombineCc,
+
     /// Take the base-2 log of a power of two integer.
     #[peepmatic(params(iNN), result(iNN))]
     Log2,
@@ -241,6 +349,15 @@ pub enum UnquoteOperator {
     /// Wrapping negation of an integer.
     #[peepmatic(params(iNN), result(iNN))]
     Neg,
+
+    /// The byte of a shifted 8-bit immediate, i.e. `imm8` in `imm8 << k`.
+    #[peepmatic(params(iNN), result(iNN))]
+    Imm8,
+
+    /// The shift amount of a shifted 8-bit immediate, i.e. `k` in
+    /// `imm8 << k`.
+    #[peepmatic(params(iNN), result(iNN))]
+    Imm8Shift,
 }
 
 /// A trait to represent a typing context.
@@ -274,6 +391,12 @@ pub trait TypingContext<'a> {
     #[allow(non_snake_case)]
     fn iMM(&mut self, span: wast::Span) -> Self::TypeVariable;
 
+    /// Create a vector type whose lanes are `iNN`.
+    ///
+    /// Each use of `vNN` by the same operator refers to the same type variable.
+    #[allow(non_snake_case)]
+    fn vNN(&mut self, span: wast::Span) -> Self::TypeVariable;
+
     /// Create the CPU flags type variable.
     fn cpu_flags(&mut self, span: wast::Span) -> Self::TypeVariable;
 
@@ -287,6 +410,12 @@ pub trait TypingContext<'a> {
     /// Create a type variable that may be either a boolean or an integer.
     fn bool_or_int(&mut self, span: wast::Span) -> Self::TypeVariable;
 
+    /// Create a type variable that may be either a boolean or an integer.
+    ///
+    /// Each use of `bool_or_int_t` by the same operator refers to the same type
+    /// variable.
+    fn bool_or_int_t(&mut self, span: wast::Span) -> Self::TypeVariable;
+
     /// Create a type variable that can be any type T.
     ///
     /// Each use of `any_t` by the same operator refers to the same type
@@ -3,11 +3,13 @@
 use crate::error::Result;
 use crate::instruction_set::InstructionSet;
 use crate::integer_interner::IntegerInterner;
-use crate::linear::{Action, MatchOp};
-use crate::optimizer::PeepholeOptimizer;
+use crate::linear::{Action, MatchOp, RhsId};
+use crate::operator::Operator;
+use crate::optimizer::{MatchState, PeepholeOptimizer, UnknownValues, DEFAULT_MAX_MATCH_DEPTH};
 use crate::paths::PathInterner;
 use peepmatic_automata::Automaton;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[cfg(feature = "construct")]
 use std::fs;
@@ -30,6 +32,42 @@ pub struct PeepholeOptimizations {
     /// The underlying automata for matching optimizations' left-hand sides, and
     /// building up the corresponding right-hand side.
     pub automata: Automaton<Option<u32>, MatchOp, Vec<Action>>,
+
+    /// Separate automata for the optimizations rooted at each operator, sorted
+    /// by operator.
+    ///
+    /// This is empty unless the optimizations were compiled partitioned by
+    /// root operator. When it isn't, each of these automata also contains the
+    /// optimizations that might match any instruction, and `automata` contains
+    /// only those, for matching instructions whose operator has no automaton
+    /// of its own.
+    pub opcode_automata: Vec<(Operator, Automaton<Option<u32>, MatchOp, Vec<Action>>)>,
+
+    /// The root operator of each optimization's left-hand side, or `None` if
+    /// the left-hand side is not an operation and might match any instruction.
+    ///
+    /// This lets us quickly rule out instructions that no optimization could
+    /// possibly match, without walking the automata.
+    pub root_operators: Vec<Option<Operator>>,
+
+    /// The right-hand side value that replaces each optimization's root, or
+    /// `None` if the optimization deletes its root or replaces each of the
+    /// root's results instead.
+    ///
+    /// Like `root_operators`, this is indexed by optimization.
+    pub results: Vec<Option<RhsId>>,
+
+    /// The names of the custom predicates that the optimizations use, indexed
+    /// by `MatchOp::CustomPredicate`'s `predicate`.
+    ///
+    /// See [`PeepholeOptimizer::register_predicate`][crate::PeepholeOptimizer::register_predicate].
+    pub predicates: Vec<String>,
+
+    /// The names of the host functions that the optimizations' right-hand
+    /// sides call, indexed by `Action::CallFunction`'s `function`.
+    ///
+    /// See [`PeepholeOptimizer::register_function`][crate::PeepholeOptimizer::register_function].
+    pub functions: Vec<String>,
 }
 
 impl PeepholeOptimizations {
@@ -49,6 +87,19 @@ impl PeepholeOptimizations {
         Ok(())
     }
 
+    /// Build an index from root operator to the indices (into
+    /// `root_operators`) of the optimizations with that root operator.
+    ///
+    /// Optimizations whose left-hand sides are not operations are indexed
+    /// under `None`.
+    pub fn root_operator_index(&self) -> HashMap<Option<Operator>, Vec<usize>> {
+        let mut index: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, op) in self.root_operators.iter().enumerate() {
+            index.entry(*op).or_default().push(i);
+        }
+        index
+    }
+
     /// Create a new peephole optimizer instance from this set of peephole
     /// optimizations.
     ///
@@ -59,16 +110,43 @@ impl PeepholeOptimizations {
     /// peephole optimizer instance allows the reuse of a few internal
     /// allocations.
     pub fn optimizer<'peep, 'ctx, I>(&'peep self, instr_set: I) -> PeepholeOptimizer<'peep, 'ctx, I>
+    where
+        I: InstructionSet<'ctx>,
+    {
+        self.optimizer_with_state(instr_set, MatchState::new())
+    }
+
+    /// Create a new peephole optimizer instance that reuses the given match
+    /// state's allocations.
+    ///
+    /// The match state usually comes from a previous optimizer's
+    /// [`PeepholeOptimizer::into_match_state`][crate::PeepholeOptimizer::into_match_state].
+    pub fn optimizer_with_state<'peep, 'ctx, I>(
+        &'peep self,
+        instr_set: I,
+        state: MatchState<I::Instruction>,
+    ) -> PeepholeOptimizer<'peep, 'ctx, I>
     where
         I: InstructionSet<'ctx>,
     {
         PeepholeOptimizer {
             peep_opt: self,
             instr_set,
-            left_hand_sides: vec![],
-            right_hand_sides: vec![],
-            actions: vec![],
-            backtracking_states: vec![],
+            state,
+            max_match_depth: DEFAULT_MAX_MATCH_DEPTH,
+            root_operator_index: self.root_operator_index(),
+            disallowed_operators: Default::default(),
+            phase: None,
+            enable_disabled: false,
+            match_observers: vec![],
+            record_matches: true,
+            max_rewrites_per_instruction: None,
+            unknown_values: UnknownValues::Skip,
+            match_stats: None,
+            shape_cache: None,
+            rewrite_log: None,
+            predicates: vec![],
+            functions: vec![],
         }
     }
 }
@@ -1,12 +1,19 @@
 //! An optimizer for a set of peephole optimizations.
 
-use crate::instruction_set::InstructionSet;
-use crate::linear::{Action, MatchOp};
-use crate::operator::UnquoteOperator;
+use crate::cc::ConditionCode;
+use crate::instruction_set::{InstructionSet, Opcode as _};
+use crate::integer_interner::IntegerId;
+use crate::linear::{Action, MatchOp, Phase};
+use crate::operator::{Operator, UnquoteOperator};
 use crate::optimizations::PeepholeOptimizations;
 use crate::part::{Constant, Part};
-use crate::r#type::{BitWidth, Type};
-use peepmatic_automata::State;
+use crate::paths::{Path, PathId};
+use crate::r#type::{BitWidth, Kind, Type};
+use crate::rewrite_log::{ReplayMismatch, Rewrite, RewriteLog};
+use crate::shape_cache::{CachedMatch, ShapeCache, ShapeCacheStats};
+use crate::stats::MatchStats;
+use peepmatic_automata::{Automaton, State};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Debug};
 use std::mem;
@@ -26,10 +33,120 @@ where
 {
     pub(crate) peep_opt: &'peep PeepholeOptimizations,
     pub(crate) instr_set: I,
-    pub(crate) left_hand_sides: Vec<Part<I::Instruction>>,
-    pub(crate) right_hand_sides: Vec<Part<I::Instruction>>,
+    pub(crate) state: MatchState<I::Instruction>,
+    pub(crate) max_match_depth: usize,
+    pub(crate) root_operator_index: HashMap<Option<Operator>, Vec<usize>>,
+    pub(crate) disallowed_operators: HashSet<Operator>,
+    pub(crate) phase: Option<Phase>,
+    pub(crate) enable_disabled: bool,
+    pub(crate) match_observers: Vec<Box<dyn FnMut(usize) + 'peep>>,
+    pub(crate) record_matches: bool,
+    pub(crate) max_rewrites_per_instruction: Option<usize>,
+    pub(crate) unknown_values: UnknownValues,
+    pub(crate) match_stats: Option<MatchStats>,
+    pub(crate) shape_cache: Option<ShapeCache>,
+    pub(crate) rewrite_log: Option<RewriteLog<I::Instruction>>,
+    #[allow(clippy::type_complexity)]
+    pub(crate) predicates:
+        Vec<Option<Box<dyn Fn(&mut I::Context, I::Instruction) -> bool + 'peep>>>,
+    #[allow(clippy::type_complexity)]
+    pub(crate) functions:
+        Vec<Option<Box<dyn Fn(&mut I::Context, u8, &[Part<I::Instruction>]) -> Constant + 'peep>>>,
+}
+
+/// Scratch buffers that a peephole optimizer uses to match left-hand sides and
+/// build up right-hand sides.
+///
+/// An optimizer clears these before matching each instruction and otherwise
+/// keeps them around, so that their allocations are reused. Embedders that
+/// create a new optimizer for each function can carry the buffers over to the
+/// next optimizer with
+/// [`PeepholeOptimizer::into_match_state`][crate::PeepholeOptimizer::into_match_state]
+/// and
+/// [`PeepholeOptimizations::optimizer_with_state`][crate::PeepholeOptimizations::optimizer_with_state].
+///
+/// Nothing in a `MatchState` carries over from one instruction to the next.
+#[derive(Debug)]
+pub struct MatchState<Inst> {
+    pub(crate) left_hand_sides: Vec<Part<Inst>>,
+    pub(crate) right_hand_sides: Vec<Part<Inst>>,
     pub(crate) actions: Vec<Action>,
-    pub(crate) backtracking_states: Vec<(State, usize)>,
+    pub(crate) matched_instructions: Vec<Inst>,
+    pub(crate) backtracking_states: Vec<(State, usize, usize)>,
+    pub(crate) matched_optimization: Option<u32>,
+}
+
+impl<Inst> Default for MatchState<Inst> {
+    fn default() -> Self {
+        MatchState {
+            left_hand_sides: vec![],
+            right_hand_sides: vec![],
+            actions: vec![],
+            matched_instructions: vec![],
+            backtracking_states: vec![],
+            matched_optimization: None,
+        }
+    }
+}
+
+impl<Inst> MatchState<Inst> {
+    /// Create a new, empty match state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clear all buffers, keeping their allocations.
+    fn clear(&mut self) {
+        self.left_hand_sides.clear();
+        self.right_hand_sides.clear();
+        self.actions.clear();
+        self.matched_instructions.clear();
+        self.backtracking_states.clear();
+        self.matched_optimization = None;
+    }
+}
+
+/// The default maximum depth, in operands below the root instruction, that a
+/// peephole optimizer will look at when matching left-hand sides.
+///
+/// See [`PeepholeOptimizer::set_max_match_depth`][crate::PeepholeOptimizer::set_max_match_depth].
+pub const DEFAULT_MAX_MATCH_DEPTH: usize = 64;
+
+/// The order in which
+/// [`PeepholeOptimizer::apply_in_order`][crate::PeepholeOptimizer::apply_in_order]
+/// visits instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyOrder {
+    /// Visit instructions in the order they were given, which is usually
+    /// layout order: definitions before their uses.
+    Forward,
+    /// Visit instructions in the reverse of the order they were given: uses
+    /// before the definitions they use.
+    ///
+    /// This lets a left-hand side that spans several instructions match before
+    /// a smaller optimization rewrites one of its inner instructions.
+    Reverse,
+}
+
+/// What a peephole optimizer does when matching runs into a value that it
+/// can't classify.
+///
+/// Such values are, for example, instructions whose operator the instruction
+/// set doesn't know, or condition codes where a precondition expects an
+/// integer.
+///
+/// See [`PeepholeOptimizer::set_unknown_values`][crate::PeepholeOptimizer::set_unknown_values].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownValues {
+    /// Conservatively treat the value as not constant, and as not matching.
+    ///
+    /// This is the default.
+    Skip,
+    /// Panic with a description of the value.
+    ///
+    /// This is useful for debugging an instruction set that doesn't classify
+    /// values that it should.
+    Panic,
 }
 
 impl<'peep, 'ctx, I> Debug for PeepholeOptimizer<'peep, 'ctx, I>
@@ -40,18 +157,46 @@ where
         let PeepholeOptimizer {
             peep_opt,
             instr_set: _,
-            left_hand_sides,
-            right_hand_sides,
-            actions,
-            backtracking_states,
+            state,
+            max_match_depth,
+            root_operator_index,
+            disallowed_operators,
+            phase,
+            enable_disabled,
+            match_observers,
+            record_matches,
+            max_rewrites_per_instruction,
+            unknown_values,
+            match_stats,
+            shape_cache,
+            rewrite_log,
+            predicates,
+            functions,
         } = self;
         f.debug_struct("PeepholeOptimizer")
             .field("peep_opt", peep_opt)
             .field("instr_set", &"_")
-            .field("left_hand_sides", left_hand_sides)
-            .field("right_hand_sides", right_hand_sides)
-            .field("actions", actions)
-            .field("backtracking_states", backtracking_states)
+            .field("state", state)
+            .field("max_match_depth", max_match_depth)
+            .field("root_operator_index", root_operator_index)
+            .field("disallowed_operators", disallowed_operators)
+            .field("phase", phase)
+            .field("enable_disabled", enable_disabled)
+            .field("match_observers", &match_observers.len())
+            .field("record_matches", record_matches)
+            .field("max_rewrites_per_instruction", max_rewrites_per_instruction)
+            .field("unknown_values", unknown_values)
+            .field("match_stats", match_stats)
+            .field("shape_cache", shape_cache)
+            .field("rewrite_log", rewrite_log)
+            .field(
+                "predicates",
+                &predicates.iter().filter(|p| p.is_some()).count(),
+            )
+            .field(
+                "functions",
+                &functions.iter().filter(|f| f.is_some()).count(),
+            )
             .finish()
     }
 }
@@ -60,7 +205,529 @@ impl<'peep, 'ctx, I> PeepholeOptimizer<'peep, 'ctx, I>
 where
     I: InstructionSet<'ctx>,
 {
-    fn eval_unquote_1(&self, operator: UnquoteOperator, a: Constant) -> Constant {
+    /// Set the maximum depth, in operands below the root instruction, that this
+    /// optimizer will look at when matching left-hand sides.
+    ///
+    /// Any match operation on a part of the instruction sequence that is
+    /// deeper than this is treated as if that part does not exist, and
+    /// therefore does not match. This bounds the work done for pathological
+    /// instruction sequences, regardless of how the `InstructionSet`
+    /// implementation walks paths.
+    ///
+    /// Defaults to [`DEFAULT_MAX_MATCH_DEPTH`][crate::optimizer::DEFAULT_MAX_MATCH_DEPTH].
+    pub fn set_max_match_depth(&mut self, max_match_depth: usize) {
+        self.max_match_depth = max_match_depth;
+        self.clear_shape_cache();
+    }
+
+    /// Never apply an optimization whose right-hand side would make an
+    /// instruction with the given operator.
+    ///
+    /// This is useful for targets that lack certain instructions. Optimizations
+    /// that would make a disallowed instruction are treated as if they did not
+    /// match.
+    pub fn disallow_operator(&mut self, operator: Operator) {
+        self.disallowed_operators.insert(operator);
+        self.clear_shape_cache();
+    }
+
+    /// Only apply optimizations that are tagged with the given phase, or that
+    /// are untagged.
+    ///
+    /// When the phase is `None`, which is the default, optimizations apply
+    /// regardless of their phase tag.
+    pub fn set_phase(&mut self, phase: Option<Phase>) {
+        self.phase = phase;
+        self.clear_shape_cache();
+    }
+
+    /// Also apply the optimizations that are annotated `(disabled)`, which
+    /// never apply by default.
+    ///
+    /// This lets experimental optimizations ship alongside the rest, and be
+    /// turned on where they are being tried out.
+    pub fn set_enable_disabled(&mut self, enable: bool) {
+        self.enable_disabled = enable;
+        self.clear_shape_cache();
+    }
+
+    /// Set the maximum number of times that
+    /// [`apply_all`][crate::PeepholeOptimizer::apply_all] will rewrite the
+    /// same instruction.
+    ///
+    /// Rewriting an instruction over and over is a sign of optimizations that
+    /// undo each other. Once the limit is reached, `apply_all` stops, and logs
+    /// a warning with the indices of the optimizations that applied.
+    ///
+    /// When the limit is `None`, which is the default, there is no limit.
+    pub fn set_max_rewrites_per_instruction(&mut self, max: Option<usize>) {
+        self.max_rewrites_per_instruction = max;
+    }
+
+    /// Set what to do when matching runs into a value that can't be
+    /// classified.
+    ///
+    /// By default, such values are skipped: they never match. See
+    /// [`UnknownValues`][crate::optimizer::UnknownValues].
+    pub fn set_unknown_values(&mut self, unknown_values: UnknownValues) {
+        self.unknown_values = unknown_values;
+        self.clear_shape_cache();
+    }
+
+    /// Add an observer that is called every time an optimization matches.
+    ///
+    /// The observer is given the index of the optimization that matched, in
+    /// the same order as
+    /// [`PeepholeOptimizations::root_operators`][crate::PeepholeOptimizations::root_operators],
+    /// so that it can e.g. measure which optimizations a test suite
+    /// exercises. Every match is observed, including matches that
+    /// `apply_batch` later drops because of a conflict, but previews by
+    /// `preview_one` aren't.
+    ///
+    /// Any number of observers can be added, and they are called in the order
+    /// they were added.
+    pub fn add_match_observer(&mut self, observer: impl FnMut(usize) + 'peep) {
+        self.match_observers.push(Box::new(observer));
+    }
+
+    /// Start recording how many times each optimization matches and fires,
+    /// discarding anything that was recorded before.
+    ///
+    /// See [`MatchStats`][crate::stats::MatchStats].
+    pub fn record_match_stats(&mut self) {
+        self.match_stats = Some(MatchStats::new(self.peep_opt.root_operators.len()));
+    }
+
+    /// Get the statistics recorded since
+    /// [`record_match_stats`][crate::PeepholeOptimizer::record_match_stats] was
+    /// last called, or `None` if it never was.
+    pub fn match_stats(&self) -> Option<&MatchStats> {
+        self.match_stats.as_ref()
+    }
+
+    /// Cache the result of matching each instruction shape, remembering up to
+    /// `capacity` of the most recently matched shapes, or stop caching if
+    /// `capacity` is `None`, which is the default.
+    ///
+    /// An instruction's shape is given by
+    /// [`InstructionSet::instruction_shape`][crate::instruction_set::InstructionSet::instruction_shape],
+    /// and instructions without a shape are always matched against the
+    /// automaton. This pays off for functions with many instructions of the
+    /// same shape, which would otherwise each walk the same states to the same
+    /// result.
+    ///
+    /// Applying an optimization may change what other instructions match, so
+    /// every rewrite clears the cache, as does changing any option or
+    /// predicate that affects matching. Embedders that change the function
+    /// themselves must call
+    /// [`clear_shape_cache`][crate::PeepholeOptimizer::clear_shape_cache].
+    /// `apply_batch` always matches against the automaton, since it needs to
+    /// know every instruction that a match targets.
+    pub fn set_shape_cache_capacity(&mut self, capacity: Option<usize>) {
+        self.shape_cache = capacity.map(ShapeCache::new);
+    }
+
+    /// Forget every cached match result.
+    ///
+    /// See [`set_shape_cache_capacity`][crate::PeepholeOptimizer::set_shape_cache_capacity].
+    pub fn clear_shape_cache(&mut self) {
+        if let Some(cache) = &mut self.shape_cache {
+            cache.clear();
+        }
+    }
+
+    /// Get how many times the shape cache was hit and missed since
+    /// [`set_shape_cache_capacity`][crate::PeepholeOptimizer::set_shape_cache_capacity]
+    /// was last called, or `None` if there is no shape cache.
+    pub fn shape_cache_stats(&self) -> Option<ShapeCacheStats> {
+        self.shape_cache.as_ref().map(|cache| cache.stats)
+    }
+
+    /// Start recording every rewrite that this optimizer applies, discarding
+    /// anything that was recorded before.
+    ///
+    /// See [`RewriteLog`][crate::rewrite_log::RewriteLog] and
+    /// [`replay`][crate::PeepholeOptimizer::replay].
+    pub fn record_rewrites(&mut self) {
+        self.rewrite_log = Some(RewriteLog::new());
+    }
+
+    /// Get the rewrites recorded since
+    /// [`record_rewrites`][crate::PeepholeOptimizer::record_rewrites] was last
+    /// called, or `None` if it never was.
+    pub fn rewrite_log(&self) -> Option<&RewriteLog<I::Instruction>> {
+        self.rewrite_log.as_ref()
+    }
+
+    /// Stop recording rewrites, and take the ones recorded so far.
+    pub fn take_rewrite_log(&mut self) -> Option<RewriteLog<I::Instruction>> {
+        self.rewrite_log.take()
+    }
+
+    /// Re-apply the rewrites in `log`, which was recorded by an optimizer for
+    /// the same optimizations starting from an identical function, checking
+    /// that each one turns out the same as it did then.
+    ///
+    /// Each logged root must match the same optimization as before, and be
+    /// replaced by the same instruction, or deleted just the same. Replaying
+    /// stops at the first rewrite that doesn't, which is still applied if
+    /// anything matched, and returns the mismatch.
+    pub fn replay(
+        &mut self,
+        context: &mut I::Context,
+        log: &RewriteLog<I::Instruction>,
+    ) -> Result<(), ReplayMismatch<I::Instruction>> {
+        log::trace!("PeepholeOptimizer::replay");
+
+        for (index, expected) in log.rewrites.iter().enumerate() {
+            let actual = if self.match_one(context, expected.root) {
+                let optimization = self.state.matched_optimization;
+                let result = self.rewrite(context, expected.root);
+                Some(Rewrite {
+                    root: expected.root,
+                    optimization,
+                    result,
+                })
+            } else {
+                None
+            };
+            if actual.as_ref() != Some(expected) {
+                return Err(ReplayMismatch {
+                    index,
+                    expected: *expected,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that the given optimization matched.
+    fn record_match(&mut self, optimization: u32) {
+        if !self.record_matches {
+            return;
+        }
+        if let Some(stats) = &mut self.match_stats {
+            stats.record_match(optimization as usize);
+        }
+        for observer in &mut self.match_observers {
+            observer(optimization as usize);
+        }
+    }
+
+    /// Record that the optimization that last matched was applied.
+    fn record_fire(&mut self, optimization: Option<u32>) {
+        if let (Some(stats), Some(optimization)) = (&mut self.match_stats, optimization) {
+            stats.record_fire(optimization as usize);
+        }
+    }
+
+    /// Register the custom predicate that `(predicate <name> ...)`
+    /// preconditions with the given name refer to.
+    ///
+    /// The predicate is given the instruction that the precondition's operand
+    /// is bound to. A predicate that was never registered never holds, and
+    /// registering a predicate that no optimization uses does nothing.
+    /// Registering the same name again replaces the previous predicate.
+    pub fn register_predicate(
+        &mut self,
+        name: &str,
+        predicate: impl Fn(&mut I::Context, I::Instruction) -> bool + 'peep,
+    ) {
+        let i = match self.peep_opt.predicates.iter().position(|p| p == name) {
+            Some(i) => i,
+            None => return,
+        };
+        if self.predicates.len() <= i {
+            self.predicates.resize_with(i + 1, || None);
+        }
+        self.predicates[i] = Some(Box::new(predicate));
+        self.clear_shape_cache();
+    }
+
+    /// Register the host function that `(call <name> ...)` right-hand sides
+    /// with the given name refer to.
+    ///
+    /// The function is given the bit width of the instruction being rewritten
+    /// and the parts bound to the call's operands, and returns the constant
+    /// to use in the right-hand side. A constant with a polymorphic bit width
+    /// takes on the rewritten instruction's width.
+    ///
+    /// The function must be deterministic, returning the same constant for
+    /// the same operands every time, and it must not modify `context`:
+    /// optimizations may be previewed without being applied, and applying
+    /// them should be reproducible. Every function that an optimization calls
+    /// must be registered before that optimization is applied, or applying it
+    /// panics. Registering a function that no optimization calls does
+    /// nothing, and registering the same name again replaces the previous
+    /// function.
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        function: impl Fn(&mut I::Context, u8, &[Part<I::Instruction>]) -> Constant + 'peep,
+    ) {
+        let i = match self.peep_opt.functions.iter().position(|f| f == name) {
+            Some(i) => i,
+            None => return,
+        };
+        if self.functions.len() <= i {
+            self.functions.resize_with(i + 1, || None);
+        }
+        self.functions[i] = Some(Box::new(function));
+    }
+
+    /// Consume this optimizer, returning its match state so that its
+    /// allocations can be reused by another optimizer.
+    ///
+    /// See [`MatchState`][crate::optimizer::MatchState].
+    pub fn into_match_state(self) -> MatchState<I::Instruction> {
+        self.state
+    }
+
+    /// Might any of our optimizations match the given root instruction?
+    ///
+    /// This is a cheap check that only considers the root instruction's
+    /// operator. When it returns `false`, no optimization can match; when it
+    /// returns `true`, one might.
+    pub fn might_match(&self, context: &mut I::Context, root: I::Instruction) -> bool {
+        if self.root_operator_index.contains_key(&None) {
+            return true;
+        }
+        match self.opcode_operator(context, root) {
+            Some(op) => self.root_operator_index.contains_key(&Some(op)),
+            None => false,
+        }
+    }
+
+    /// Get the automaton to match the given root instruction against.
+    ///
+    /// This is the root operator's own automaton, when the optimizations were
+    /// compiled partitioned by root operator, and the main automaton otherwise.
+    fn automaton_for(
+        &self,
+        context: &mut I::Context,
+        root: I::Instruction,
+    ) -> &'peep Automaton<Option<u32>, MatchOp, Vec<Action>> {
+        let peep_opt = self.peep_opt;
+        if peep_opt.opcode_automata.is_empty() {
+            return &peep_opt.automata;
+        }
+        self.opcode_operator(context, root)
+            .and_then(|op| {
+                peep_opt
+                    .opcode_automata
+                    .binary_search_by_key(&(op as u32), |(op, _)| *op as u32)
+                    .ok()
+            })
+            .map_or(&peep_opt.automata, |i| &peep_opt.opcode_automata[i].1)
+    }
+
+    /// Would the actions we've accumulated so far, followed by the given final
+    /// actions, make an instruction whose operator is disallowed, or that the
+    /// instruction set has no opcode for?
+    fn makes_disallowed_operator(&self, final_actions: &[Action]) -> bool {
+        self.state
+            .actions
+            .iter()
+            .chain(final_actions)
+            .filter_map(|a| a.made_operator())
+            .any(|op| {
+                self.disallowed_operators.contains(&op) || I::Opcode::from_operator(op).is_none()
+            })
+    }
+
+    /// Get the instruction set's opcode for an operator that an action makes.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the instruction set has no such opcode, which is checked when
+    /// matching.
+    fn opcode_for(operator: Operator) -> I::Opcode {
+        I::Opcode::from_operator(operator).expect("operators without opcodes never match")
+    }
+
+    /// Get the offset and flags of a load or store made by `MakeLoad` or
+    /// `MakeStore`: the root's offset plus `offset`, and either the given
+    /// flags or the root's.
+    ///
+    /// Returns `None` if the new offset overflows.
+    fn mem_args(
+        &self,
+        context: &mut I::Context,
+        root: I::Instruction,
+        offset: i32,
+        flags: Option<u8>,
+    ) -> Option<(i32, u8)> {
+        let root_offset = self.instr_set.instruction_mem_offset(context, root);
+        let offset = root_offset.unwrap_or(0).checked_add(offset)?;
+        let flags = flags.unwrap_or_else(|| {
+            self.instr_set
+                .instruction_mem_flags(context, root)
+                .unwrap_or(0)
+        });
+        Some((offset, flags))
+    }
+
+    /// Get the type with the given kind and the lane width of `vector`, for an
+    /// `extractlane` or `insertlane` of its `lane`th lane.
+    fn lane_type(
+        &self,
+        context: &mut I::Context,
+        vector: Part<I::Instruction>,
+        lane: u8,
+        kind: Kind,
+    ) -> Type {
+        let vector = vector
+            .as_instruction()
+            .expect("vectors are always instructions");
+        let bit_width =
+            BitWidth::try_from(self.instr_set.instruction_result_bit_width(context, vector))
+                .unwrap();
+        debug_assert!(
+            u16::from(lane) * (bit_width as u16) < 128,
+            "lane indices are checked in verification"
+        );
+        Type { kind, bit_width }
+    }
+
+    /// Would any load or store that the given final actions make have an
+    /// immediate offset that overflows?
+    fn overflows_mem_offset(
+        &self,
+        context: &mut I::Context,
+        root: I::Instruction,
+        final_actions: &[Action],
+    ) -> bool {
+        self.state
+            .actions
+            .iter()
+            .chain(final_actions)
+            .any(|a| match *a {
+                Action::MakeLoad { offset, flags, .. }
+                | Action::MakeStore { offset, flags, .. } => {
+                    self.mem_args(context, root, offset, flags).is_none()
+                }
+                _ => false,
+            })
+    }
+
+    /// Get the part at the given path, unless it is deeper than our maximum
+    /// match depth.
+    fn get_part_at_path(
+        &self,
+        context: &mut I::Context,
+        root: I::Instruction,
+        path: PathId,
+    ) -> Option<Part<I::Instruction>> {
+        let path = self.peep_opt.paths.lookup(path);
+
+        // The root is at depth zero and has the path `[0]`.
+        let depth = path.0.len() - 1;
+        if depth > self.max_match_depth {
+            log::trace!(
+                "Path {:?} is deeper than the max match depth of {}",
+                path,
+                self.max_match_depth
+            );
+            return None;
+        }
+
+        self.instr_set.get_part_at_path(context, root, path)
+    }
+
+    /// Handle a value that matching can't classify, as described by `what`,
+    /// according to our [`UnknownValues`] setting.
+    ///
+    /// Returns `None`, so that the value does not match, unless that setting
+    /// is to panic.
+    fn unknown_value<T>(&self, what: &str) -> Option<T> {
+        match self.unknown_values {
+            UnknownValues::Skip => {
+                log::trace!("Cannot classify {}; not matching", what);
+                None
+            }
+            UnknownValues::Panic => panic!("cannot classify {} while matching", what),
+        }
+    }
+
+    /// Get the given instruction's operator, handling instructions that the
+    /// instruction set doesn't know the operator of as unknown values.
+    fn operator(&self, context: &mut I::Context, inst: I::Instruction) -> Option<Operator> {
+        match self.opcode_operator(context, inst) {
+            Some(op) => Some(op),
+            None => self.unknown_value("an instruction with an unknown operator"),
+        }
+    }
+
+    /// Get the operator that the given instruction's opcode corresponds to, if
+    /// it has an opcode and there is such an operator.
+    fn opcode_operator(&self, context: &mut I::Context, inst: I::Instruction) -> Option<Operator> {
+        self.instr_set.opcode(context, inst)?.to_operator()
+    }
+
+    /// Get the integer constant at the given path, truncated to the constant's
+    /// bit width, along with that bit width.
+    fn int_at_path(
+        &self,
+        context: &mut I::Context,
+        root: I::Instruction,
+        path: PathId,
+    ) -> Option<(u128, u8)> {
+        let part = self.get_part_at_path(context, root, path)?;
+        let c = match part {
+            Part::Constant(c) => c,
+            Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+            Part::ConditionCode(_) => return self.unknown_value("a condition code as an integer"),
+        };
+
+        let root_width = self.instr_set.instruction_result_bit_width(context, root);
+        let width = c.bit_width(root_width);
+        Some((c.as_int()? & width_mask(width), width))
+    }
+
+    /// Get the bit width of the instruction's result (or immediate) at the
+    /// given path.
+    ///
+    /// Polymorphic constants have the same bit width as the root.
+    fn bit_width_at_path(
+        &self,
+        context: &mut I::Context,
+        root: I::Instruction,
+        path: PathId,
+    ) -> Option<u8> {
+        let part = self.get_part_at_path(context, root, path)?;
+        match part {
+            Part::Instruction(i) => Some(self.instr_set.instruction_result_bit_width(context, i)),
+            Part::Constant(Constant::Int(_, w)) | Part::Constant(Constant::Bool(_, w)) => Some(
+                w.fixed_width()
+                    .unwrap_or_else(|| self.instr_set.instruction_result_bit_width(context, root)),
+            ),
+            Part::ConditionCode(_) => self.unknown_value("a condition code"),
+        }
+    }
+
+    /// Look up the interned id of the integer constant `c`, if any.
+    ///
+    /// The constant is compared at its bit width: it matches an interned
+    /// integer that is equal to either its zero- or sign-extension from that
+    /// width. This way both `0xff` and `-1` match an 8-bit constant with all
+    /// bits set, however its upper bits happen to be represented.
+    fn interned_integer(&self, c: Constant, root_width: u8) -> Option<u32> {
+        let width = c.bit_width(root_width);
+        let zext = c.as_int()? & width_mask(width);
+        let sext = if 0 < width && width < 128 && (zext >> (width - 1)) & 1 == 1 {
+            zext | !width_mask(width)
+        } else {
+            zext
+        };
+        let integers = &self.peep_opt.integers;
+        integers
+            .already_interned(zext)
+            .or_else(|| integers.already_interned(sext))
+            .map(|id| id.0)
+    }
+
+    fn eval_unquote_1(&self, operator: UnquoteOperator, a: Constant, root_width: u8) -> Constant {
         use Constant::*;
 
         macro_rules! map_int {
@@ -74,16 +741,41 @@ where
 
         match operator {
             UnquoteOperator::Log2 => map_int!(a, |x| x.trailing_zeros() as _),
-            UnquoteOperator::Neg => map_int!(a, |x| x.wrapping_neg()),
+            UnquoteOperator::Neg => map_int!(a, |x| wrap(x.wrapping_neg(), a, root_width)),
+            UnquoteOperator::Imm8 => {
+                let width = a.bit_width(root_width);
+                map_int!(a, |x| shifted_imm8(x & width_mask(width)).0)
+            }
+            UnquoteOperator::Imm8Shift => {
+                let width = a.bit_width(root_width);
+                map_int!(a, |x| shifted_imm8(x & width_mask(width)).1.into())
+            }
             UnquoteOperator::Band
             | UnquoteOperator::Bor
             | UnquoteOperator::Bxor
             | UnquoteOperator::Iadd
-            | UnquoteOperator::Imul => unreachable!("not a unary unquote operator: {:?}", operator),
+            | UnquoteOperator::Imul
+            | UnquoteOperator::Isub
+            | UnquoteOperator::AlignDown
+            | UnquoteOperator::Ushr => {
+                unreachable!("not a unary unquote operator: {:?}", operator)
+            }
+            UnquoteOperator::InvertCc => {
+                unreachable!("condition codes are inverted with `Action::InvertConditionCode`")
+            }
+            UnquoteOperator::CombineCc => {
+                unreachable!("condition codes are combined with `Action::CombineConditionCodes`")
+            }
         }
     }
 
-    fn eval_unquote_2(&self, operator: UnquoteOperator, a: Constant, b: Constant) -> Constant {
+    fn eval_unquote_2(
+        &self,
+        operator: UnquoteOperator,
+        a: Constant,
+        b: Constant,
+        root_width: u8,
+    ) -> Constant {
         use Constant::*;
 
         macro_rules! fold_ints {
@@ -99,16 +791,139 @@ where
             UnquoteOperator::Band => fold_ints!(a, b, |x, y| x & y),
             UnquoteOperator::Bor => fold_ints!(a, b, |x, y| x | y),
             UnquoteOperator::Bxor => fold_ints!(a, b, |x, y| x ^ y),
-            UnquoteOperator::Iadd => fold_ints!(a, b, |x, y| x.wrapping_add(y)),
-            UnquoteOperator::Imul => fold_ints!(a, b, |x, y| x.wrapping_mul(y)),
-            UnquoteOperator::Log2 | UnquoteOperator::Neg => {
+            UnquoteOperator::Iadd => {
+                fold_ints!(a, b, |x, y| wrap(x.wrapping_add(y), a, root_width))
+            }
+            UnquoteOperator::Imul => {
+                fold_ints!(a, b, |x, y| wrap(x.wrapping_mul(y), a, root_width))
+            }
+            UnquoteOperator::Isub => {
+                fold_ints!(a, b, |x, y| wrap(x.wrapping_sub(y), a, root_width))
+            }
+            UnquoteOperator::AlignDown => fold_ints!(a, b, |x, y| x & !y.wrapping_sub(1)),
+            UnquoteOperator::Ushr => {
+                let width = a.bit_width(root_width);
+                fold_ints!(a, b, |x, y| if y < u128::from(width) {
+                    wrap((x & width_mask(width)) >> y, a, root_width)
+                } else {
+                    0
+                })
+            }
+            UnquoteOperator::Log2
+            | UnquoteOperator::Neg
+            | UnquoteOperator::Imm8
+            | UnquoteOperator::Imm8Shift
+            | UnquoteOperator::InvertCc => {
                 unreachable!("not a binary unquote operator: {:?}", operator)
             }
+            UnquoteOperator::CombineCc => {
+                unreachable!("condition codes are combined with `Action::CombineConditionCodes`")
+            }
+        }
+    }
+
+    /// Get the constant amount that the `ishl`, `ushr`, or `sshr` instruction
+    /// `inst` shifts by, if it is a constant.
+    fn shift_amount(&self, context: &mut I::Context, inst: I::Instruction) -> Option<u128> {
+        // The shift amount is the instruction's second operand.
+        self.int_operand(context, inst, 1)
+    }
+
+    /// Get the integer constant that is `inst`'s operand at `index`, if any.
+    fn int_operand(
+        &self,
+        context: &mut I::Context,
+        inst: I::Instruction,
+        index: u8,
+    ) -> Option<u128> {
+        let operand =
+            match self
+                .instr_set
+                .get_part_at_path(context, inst, Path::new(&[0, index]))?
+            {
+                Part::Constant(c) => c,
+                Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                Part::ConditionCode(_) => return None,
+            };
+        operand.as_int()
+    }
+
+    /// Evaluate the pure instruction `inst` over its constant operands.
+    ///
+    /// Returns `None` when `inst` is not an operation that we know how to
+    /// fold, when any of its operands is not a constant, or when its result is
+    /// not defined.
+    fn fold_instruction(&self, context: &mut I::Context, inst: I::Instruction) -> Option<Constant> {
+        let operator = self.opcode_operator(context, inst)?;
+        let arity = operator.immediates_arity() + operator.params_arity();
+        let mut cc = None;
+        let mut operands = Vec::with_capacity(arity as usize);
+        for i in 0..arity {
+            match self
+                .instr_set
+                .get_part_at_path(context, inst, Path::new(&[0, i]))?
+            {
+                Part::Constant(c) => operands.push(c),
+                Part::Instruction(i) => {
+                    operands.push(self.instr_set.instruction_to_constant(context, i)?)
+                }
+                Part::ConditionCode(c) => cc = Some(c),
+            }
+        }
+        let width = self.instr_set.instruction_result_bit_width(context, inst);
+        fold_const(operator, cc, &operands, width)
+    }
+
+    /// Get the value that the store preceding the load `inst` wrote, if the
+    /// load reads back exactly that value.
+    fn stored_value(
+        &self,
+        context: &mut I::Context,
+        inst: I::Instruction,
+    ) -> Option<Part<I::Instruction>> {
+        if self.opcode_operator(context, inst)? != Operator::Load {
+            return None;
+        }
+        let store = self.instr_set.instruction_preceding_store(context, inst)?;
+        if self.opcode_operator(context, store)? != Operator::Store {
+            return None;
+        }
+
+        // A load's only operand is its address, while a store's operands are
+        // its value and then its address.
+        let load_addr = self
+            .instr_set
+            .get_part_at_path(context, inst, Path::new(&[0, 0]))?;
+        let store_addr = self
+            .instr_set
+            .get_part_at_path(context, store, Path::new(&[0, 1]))?;
+        if load_addr != store_addr {
+            return None;
         }
+        let load_offset = self.instr_set.instruction_mem_offset(context, inst)?;
+        let store_offset = self.instr_set.instruction_mem_offset(context, store)?;
+        if load_offset != store_offset {
+            return None;
+        }
+
+        let value = self
+            .instr_set
+            .get_part_at_path(context, store, Path::new(&[0, 0]))?;
+        let load_width = self.instr_set.instruction_result_bit_width(context, inst);
+        let value_width = match value {
+            Part::Instruction(i) => self.instr_set.instruction_result_bit_width(context, i),
+            Part::Constant(c) => c.bit_width(load_width),
+            Part::ConditionCode(_) => return None,
+        };
+        if load_width != value_width {
+            return None;
+        }
+        Some(value)
     }
 
     fn eval_actions(&mut self, context: &mut I::Context, root: I::Instruction) {
-        let mut actions = mem::replace(&mut self.actions, vec![]);
+        self.clear_shape_cache();
+        let mut actions = mem::replace(&mut self.state.actions, vec![]);
 
         for action in actions.drain(..) {
             log::trace!("Evaluating action: {:?}", action);
@@ -119,10 +934,10 @@ where
                         .instr_set
                         .get_part_at_path(context, root, path)
                         .expect("should always get part at path OK by the time it is bound");
-                    self.right_hand_sides.push(lhs);
+                    self.state.right_hand_sides.push(lhs);
                 }
                 Action::UnaryUnquote { operator, operand } => {
-                    let operand = self.right_hand_sides[operand.0 as usize];
+                    let operand = self.state.right_hand_sides[operand.0 as usize];
                     let operand = match operand {
                         Part::Instruction(i) => self
                             .instr_set
@@ -133,11 +948,12 @@ where
                             panic!("cannot use a condition code as an unquote operand")
                         }
                     };
-                    let result = self.eval_unquote_1(operator, operand);
-                    self.right_hand_sides.push(result.into());
+                    let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                    let result = self.eval_unquote_1(operator, operand, root_width);
+                    self.state.right_hand_sides.push(result.into());
                 }
                 Action::BinaryUnquote { operator, operands } => {
-                    let a = self.right_hand_sides[operands[0].0 as usize];
+                    let a = self.state.right_hand_sides[operands[0].0 as usize];
                     let a = match a {
                         Part::Instruction(i) => self
                             .instr_set
@@ -149,7 +965,7 @@ where
                         }
                     };
 
-                    let b = self.right_hand_sides[operands[1].0 as usize];
+                    let b = self.state.right_hand_sides[operands[1].0 as usize];
                     let b = match b {
                         Part::Instruction(i) => self
                             .instr_set
@@ -161,8 +977,9 @@ where
                         }
                     };
 
-                    let result = self.eval_unquote_2(operator, a, b);
-                    self.right_hand_sides.push(result.into());
+                    let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                    let result = self.eval_unquote_2(operator, a, b, root_width);
+                    self.state.right_hand_sides.push(result.into());
                 }
                 Action::MakeIntegerConst {
                     value,
@@ -175,9 +992,104 @@ where
                         )
                         .unwrap();
                     }
-                    self.right_hand_sides
+                    self.state
+                        .right_hand_sides
+                        .push(Constant::Int(value, bit_width).into());
+                }
+                Action::MakeZero { mut bit_width } | Action::MakeOne { mut bit_width } => {
+                    let value = matches!(action, Action::MakeOne { .. }) as u128;
+                    if bit_width.is_polymorphic() {
+                        bit_width = BitWidth::try_from(
+                            self.instr_set.instruction_result_bit_width(context, root),
+                        )
+                        .unwrap();
+                    }
+                    self.state
+                        .right_hand_sides
+                        .push(Constant::Int(value, bit_width).into());
+                }
+                Action::MakeSignShiftAmount { mut bit_width } => {
+                    if bit_width.is_polymorphic() {
+                        bit_width = BitWidth::try_from(
+                            self.instr_set.instruction_result_bit_width(context, root),
+                        )
+                        .unwrap();
+                    }
+                    let amount = u128::from(bit_width.fixed_width().unwrap()) - 1;
+                    self.state
+                        .right_hand_sides
+                        .push(Constant::Int(amount, bit_width).into());
+                }
+                Action::CallFunction { function, operands } => {
+                    let name = &self.peep_opt.functions[function as usize];
+                    let f = match self.functions.get(function as usize) {
+                        Some(Some(f)) => f,
+                        _ => panic!("no function registered for `{}`", name),
+                    };
+                    let operands: Vec<_> = operands
+                        .iter()
+                        .flatten()
+                        .map(|id| self.state.right_hand_sides[id.0 as usize])
+                        .collect();
+                    let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                    let result = match f(context, root_width, &operands) {
+                        Constant::Int(x, w) if w.is_polymorphic() => {
+                            Constant::Int(x, BitWidth::try_from(root_width).unwrap())
+                        }
+                        Constant::Bool(b, w) if w.is_polymorphic() => {
+                            Constant::Bool(b, BitWidth::try_from(root_width).unwrap())
+                        }
+                        c => c,
+                    };
+                    self.state.right_hand_sides.push(result.into());
+                }
+                Action::MakeIconstMatchingWidth { value, like } => {
+                    let value = self.peep_opt.integers.lookup(value);
+                    let like = self.peep_opt.paths.lookup(like);
+                    let width = match self
+                        .instr_set
+                        .get_part_at_path(context, root, like)
+                        .expect("should always get part at path OK by the time it is bound")
+                    {
+                        Part::Instruction(i) => {
+                            self.instr_set.instruction_result_bit_width(context, i)
+                        }
+                        Part::Constant(c) => {
+                            c.bit_width(self.instr_set.instruction_result_bit_width(context, root))
+                        }
+                        Part::ConditionCode(_) => {
+                            panic!("cannot take the bit width of a condition code")
+                        }
+                    };
+                    let bit_width = BitWidth::try_from(width).unwrap();
+                    self.state
+                        .right_hand_sides
                         .push(Constant::Int(value, bit_width).into());
                 }
+                Action::FoldConst { path } => {
+                    let path = self.peep_opt.paths.lookup(path);
+                    let inst = self
+                        .instr_set
+                        .get_part_at_path(context, root, path)
+                        .and_then(|part| part.as_instruction())
+                        .expect("should always get an instruction to fold by the time it is bound");
+                    let folded = self
+                        .fold_instruction(context, inst)
+                        .expect("should have checked `MatchOp::IsFoldable` before folding");
+                    self.state.right_hand_sides.push(folded.into());
+                }
+                Action::GetStoredValue { path } => {
+                    let path = self.peep_opt.paths.lookup(path);
+                    let value = self
+                        .instr_set
+                        .get_part_at_path(context, root, path)
+                        .and_then(|part| part.as_instruction())
+                        .and_then(|inst| self.stored_value(context, inst))
+                        .expect(
+                            "should have checked `MatchOp::IsForwardableLoad` before forwarding",
+                        );
+                    self.state.right_hand_sides.push(value);
+                }
                 Action::MakeBooleanConst {
                     value,
                     mut bit_width,
@@ -188,11 +1100,33 @@ where
                         )
                         .unwrap();
                     }
-                    self.right_hand_sides
+                    self.state
+                        .right_hand_sides
                         .push(Constant::Bool(value, bit_width).into());
                 }
                 Action::MakeConditionCode { cc } => {
-                    self.right_hand_sides.push(Part::ConditionCode(cc));
+                    self.state.right_hand_sides.push(Part::ConditionCode(cc));
+                }
+                Action::InvertConditionCode { operand } => {
+                    let cc = self.state.right_hand_sides[operand.0 as usize]
+                        .as_condition_code()
+                        .expect("cannot invert a non-condition code");
+                    self.state
+                        .right_hand_sides
+                        .push(Part::ConditionCode(cc.inverse()));
+                }
+                Action::CombineConditionCodes { operands } => {
+                    let [a, b] = operands;
+                    let a = self.state.right_hand_sides[a.0 as usize]
+                        .as_condition_code()
+                        .expect("cannot combine a non-condition code");
+                    let b = self.state.right_hand_sides[b.0 as usize]
+                        .as_condition_code()
+                        .expect("cannot combine a non-condition code");
+                    let cc = a
+                        .combine(b)
+                        .expect("condition codes are checked to combine when matching");
+                    self.state.right_hand_sides.push(Part::ConditionCode(cc));
                 }
                 Action::MakeUnaryInst {
                     operator,
@@ -210,11 +1144,12 @@ where
                         .unwrap();
                     }
                     let ty = Type { kind, bit_width };
-                    let operand = self.right_hand_sides[operand.0 as usize];
+                    let operand = self.state.right_hand_sides[operand.0 as usize];
+                    let opcode = Self::opcode_for(operator);
                     let inst = self
                         .instr_set
-                        .make_inst_1(context, root, operator, ty, operand);
-                    self.right_hand_sides.push(Part::Instruction(inst));
+                        .make_inst_1(context, root, opcode, ty, operand);
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
                 }
                 Action::MakeBinaryInst {
                     operator,
@@ -232,12 +1167,11 @@ where
                         .unwrap();
                     }
                     let ty = Type { kind, bit_width };
-                    let a = self.right_hand_sides[operands[0].0 as usize];
-                    let b = self.right_hand_sides[operands[1].0 as usize];
-                    let inst = self
-                        .instr_set
-                        .make_inst_2(context, root, operator, ty, a, b);
-                    self.right_hand_sides.push(Part::Instruction(inst));
+                    let a = self.state.right_hand_sides[operands[0].0 as usize];
+                    let b = self.state.right_hand_sides[operands[1].0 as usize];
+                    let opcode = Self::opcode_for(operator);
+                    let inst = self.instr_set.make_inst_2(context, root, opcode, ty, a, b);
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
                 }
                 Action::MakeTernaryInst {
                     operator,
@@ -255,25 +1189,138 @@ where
                         .unwrap();
                     }
                     let ty = Type { kind, bit_width };
-                    let a = self.right_hand_sides[operands[0].0 as usize];
-                    let b = self.right_hand_sides[operands[1].0 as usize];
-                    let c = self.right_hand_sides[operands[2].0 as usize];
+                    let a = self.state.right_hand_sides[operands[0].0 as usize];
+                    let b = self.state.right_hand_sides[operands[1].0 as usize];
+                    let c = self.state.right_hand_sides[operands[2].0 as usize];
+                    let opcode = Self::opcode_for(operator);
                     let inst = self
                         .instr_set
-                        .make_inst_3(context, root, operator, ty, a, b, c);
-                    self.right_hand_sides.push(Part::Instruction(inst));
+                        .make_inst_3(context, root, opcode, ty, a, b, c);
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
                 }
-            }
-        }
-
-        // Reuse the heap elements allocation.
-        self.actions = actions;
-    }
-
-    fn eval_match_op(
-        &mut self,
-        context: &mut I::Context,
-        root: I::Instruction,
+                Action::MakeSelectSpectreGuard {
+                    r#type:
+                        Type {
+                            kind,
+                            mut bit_width,
+                        },
+                    operands,
+                } => {
+                    if bit_width.is_polymorphic() {
+                        bit_width = BitWidth::try_from(
+                            self.instr_set.instruction_result_bit_width(context, root),
+                        )
+                        .unwrap();
+                    }
+                    let ty = Type { kind, bit_width };
+                    let a = self.state.right_hand_sides[operands[0].0 as usize];
+                    let b = self.state.right_hand_sides[operands[1].0 as usize];
+                    let c = self.state.right_hand_sides[operands[2].0 as usize];
+                    let inst = self.instr_set.make_inst_3(
+                        context,
+                        root,
+                        Self::opcode_for(Operator::SelectSpectreGuard),
+                        ty,
+                        a,
+                        b,
+                        c,
+                    );
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
+                }
+                Action::MakeLoad {
+                    operand,
+                    offset,
+                    flags,
+                    mut bit_width,
+                } => {
+                    if bit_width.is_polymorphic() {
+                        bit_width = BitWidth::try_from(
+                            self.instr_set.instruction_result_bit_width(context, root),
+                        )
+                        .unwrap();
+                    }
+                    let ty = Type {
+                        kind: Kind::Int,
+                        bit_width,
+                    };
+                    let addr = self.state.right_hand_sides[operand.0 as usize];
+                    let (offset, flags) = self
+                        .mem_args(context, root, offset, flags)
+                        .expect("offset overflow is checked when matching");
+                    let inst = self
+                        .instr_set
+                        .make_load(context, root, ty, addr, offset, flags);
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
+                }
+                Action::MakeStore {
+                    value,
+                    addr,
+                    offset,
+                    flags,
+                } => {
+                    let value = self.state.right_hand_sides[value.0 as usize];
+                    let addr = self.state.right_hand_sides[addr.0 as usize];
+                    let (offset, flags) = self
+                        .mem_args(context, root, offset, flags)
+                        .expect("offset overflow is checked when matching");
+                    let inst = self
+                        .instr_set
+                        .make_store(context, root, value, addr, offset, flags);
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
+                }
+                Action::MakeExtractLane { operand, lane } => {
+                    let vector = self.state.right_hand_sides[operand.0 as usize];
+                    let ty = self.lane_type(context, vector, lane, Kind::Int);
+                    let lane = Part::Constant(Constant::Int(lane.into(), BitWidth::Eight));
+                    let inst = self.instr_set.make_inst_2(
+                        context,
+                        root,
+                        Self::opcode_for(Operator::Extractlane),
+                        ty,
+                        lane,
+                        vector,
+                    );
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
+                }
+                Action::MakeInsertLane {
+                    vector,
+                    value,
+                    lane,
+                } => {
+                    let vector = self.state.right_hand_sides[vector.0 as usize];
+                    let value = self.state.right_hand_sides[value.0 as usize];
+                    let ty = self.lane_type(context, vector, lane, Kind::Vector);
+                    let lane = Part::Constant(Constant::Int(lane.into(), BitWidth::Eight));
+                    let inst = self.instr_set.make_inst_3(
+                        context,
+                        root,
+                        Self::opcode_for(Operator::Insertlane),
+                        ty,
+                        lane,
+                        vector,
+                        value,
+                    );
+                    self.state.right_hand_sides.push(Part::Instruction(inst));
+                }
+                Action::DeleteInstruction => {
+                    self.instr_set.remove_instruction(context, root);
+                }
+                // This is evaluated by `rewrite`, once every result's RHS is
+                // built.
+                Action::ReplaceResults { .. } => {}
+                // These are removed when matching.
+                Action::Matched { .. } => {}
+            }
+        }
+
+        // Reuse the heap elements allocation.
+        self.state.actions = actions;
+    }
+
+    fn eval_match_op(
+        &mut self,
+        context: &mut I::Context,
+        root: I::Instruction,
         match_op: MatchOp,
     ) -> Option<u32> {
         use crate::linear::MatchOp::*;
@@ -281,14 +1328,12 @@ where
         log::trace!("Evaluating match operation: {:?}", match_op);
         let result = match match_op {
             Opcode { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
+                let part = self.get_part_at_path(context, root, path)?;
                 let inst = part.as_instruction()?;
-                self.instr_set.operator(context, inst).map(|op| op as u32)
+                self.operator(context, inst).map(|op| op as u32)
             }
             IsConst { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
+                let part = self.get_part_at_path(context, root, path)?;
                 let is_const = match part {
                     Part::Instruction(i) => {
                         self.instr_set.instruction_to_constant(context, i).is_some()
@@ -298,37 +1343,341 @@ where
                 Some(is_const as u32)
             }
             IsPowerOfTwo { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
+                let part = self.get_part_at_path(context, root, path)?;
                 match part {
-                    Part::Constant(c) => Some(c.as_int().unwrap().is_power_of_two() as u32),
+                    Part::Constant(c) => Some(c.as_int()?.is_power_of_two() as u32),
                     Part::Instruction(i) => {
                         let c = self.instr_set.instruction_to_constant(context, i)?;
-                        Some(c.as_int().unwrap().is_power_of_two() as u32)
+                        Some(c.as_int()?.is_power_of_two() as u32)
                     }
-                    Part::ConditionCode(_) => panic!("IsPowerOfTwo on a condition code"),
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
                 }
             }
-            BitWidth { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
-                let bit_width = match part {
-                    Part::Instruction(i) => self.instr_set.instruction_result_bit_width(context, i),
-                    Part::Constant(Constant::Int(_, w)) | Part::Constant(Constant::Bool(_, w)) => {
-                        w.fixed_width().unwrap_or_else(|| {
-                            self.instr_set.instruction_result_bit_width(context, root)
-                        })
+            IsOne { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let c = match part {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => {
+                        match self.instr_set.instruction_to_constant(context, i) {
+                            Some(c) => c,
+                            None => return Some(0),
+                        }
+                    }
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+                let is_one = match c {
+                    Constant::Int(x, _) => {
+                        let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                        x & width_mask(c.bit_width(root_width)) == 1
+                    }
+                    Constant::Bool(b, _) => b,
+                };
+                Some(is_one as u32)
+            }
+            IsShiftByConst { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let inst = part.as_instruction()?;
+                let is_shift_by_const = match self.operator(context, inst)? {
+                    Operator::IshlImm | Operator::UshrImm | Operator::SshrImm => true,
+                    Operator::Ishl | Operator::Ushr | Operator::Sshr => {
+                        // The shift amount is the instruction's second operand.
+                        match self
+                            .instr_set
+                            .get_part_at_path(context, inst, Path::new(&[0, 1]))
+                        {
+                            Some(Part::Constant(_)) => true,
+                            Some(Part::Instruction(i)) => {
+                                self.instr_set.instruction_to_constant(context, i).is_some()
+                            }
+                            Some(Part::ConditionCode(_)) | None => false,
+                        }
                     }
-                    Part::ConditionCode(_) => panic!("BitWidth on condition code"),
+                    _ => false,
                 };
+                Some(is_shift_by_const as u32)
+            }
+            NestedShiftCombinable { outer, inner } => {
+                let outer = self
+                    .get_part_at_path(context, root, outer)?
+                    .as_instruction()?;
+                let inner = self
+                    .get_part_at_path(context, root, inner)?
+                    .as_instruction()?;
+                let operator = self.operator(context, outer)?;
+                match operator {
+                    Operator::Ishl | Operator::Ushr | Operator::Sshr => {}
+                    _ => return Some(0),
+                }
+                if self.operator(context, inner) != Some(operator) {
+                    return Some(0);
+                }
+                let width = u128::from(self.instr_set.instruction_result_bit_width(context, outer));
+                let combinable = match (
+                    self.shift_amount(context, outer),
+                    self.shift_amount(context, inner),
+                ) {
+                    (Some(a), Some(b)) => a < width && b < width && a + b < width,
+                    _ => false,
+                };
+                Some(combinable as u32)
+            }
+            RedundantMaskForShift { mask, amount } => {
+                let (mask, width) = self.int_at_path(context, root, mask)?;
+                let (amount, _) = self.int_at_path(context, root, amount)?;
+                let redundant = amount < u128::from(width)
+                    && mask | width_mask(amount as u8) == width_mask(width);
+                Some(redundant as u32)
+            }
+            IsDoubleInvolution { outer, inner } => {
+                let outer = self
+                    .get_part_at_path(context, root, outer)?
+                    .as_instruction()?;
+                let inner = self
+                    .get_part_at_path(context, root, inner)?
+                    .as_instruction()?;
+                let operator = self.operator(context, outer)?;
+                let involution =
+                    is_involution(operator) && self.operator(context, inner)? == operator;
+                Some(involution as u32)
+            }
+            ConditionCodesCombine { path_a, path_b } => {
+                let a = self
+                    .get_part_at_path(context, root, path_a)?
+                    .as_instruction()?;
+                let b = self
+                    .get_part_at_path(context, root, path_b)?
+                    .as_instruction()?;
+                if self.operator(context, a) != Some(Operator::Icmp)
+                    || self.operator(context, b) != Some(Operator::Icmp)
+                {
+                    return Some(0);
+                }
+                // The condition code is the `icmp`'s first operand, and the
+                // values it compares are its second and third.
+                let mut part = |inst, i| {
+                    self.instr_set
+                        .get_part_at_path(context, inst, Path::new(&[0, i]))
+                };
+                let combines = match (part(a, 0), part(b, 0)) {
+                    (Some(Part::ConditionCode(x)), Some(Part::ConditionCode(y))) => {
+                        x.combine(y).is_some()
+                    }
+                    _ => false,
+                };
+                let same_operands = (1..3).all(|i| {
+                    let x = part(a, i);
+                    x.is_some() && x == part(b, i)
+                });
+                Some((combines && same_operands) as u32)
+            }
+            IsCallResult { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let is_call = match part {
+                    Part::Instruction(i) => self.instr_set.instruction_is_call(context, i),
+                    Part::Constant(_) | Part::ConditionCode(_) => false,
+                };
+                Some(is_call as u32)
+            }
+            AllOperandsConst { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let inst = match part {
+                    Part::Instruction(i) => i,
+                    Part::Constant(_) | Part::ConditionCode(_) => return Some(0),
+                };
+                let operator = match self.operator(context, inst) {
+                    None | Some(Operator::Iconst) | Some(Operator::Bconst) => return Some(0),
+                    Some(op) => op,
+                };
+                let arity = operator.immediates_arity() + operator.params_arity();
+                let all_const = (0..arity).all(|i| {
+                    match self
+                        .instr_set
+                        .get_part_at_path(context, inst, Path::new(&[0, i]))
+                    {
+                        Some(Part::Constant(_)) => true,
+                        Some(Part::Instruction(i)) => {
+                            self.instr_set.instruction_to_constant(context, i).is_some()
+                        }
+                        Some(Part::ConditionCode(_)) | None => false,
+                    }
+                });
+                Some(all_const as u32)
+            }
+            SelectConstArms { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let inst = match part {
+                    Part::Instruction(i) => i,
+                    Part::Constant(_) | Part::ConditionCode(_) => return Some(0),
+                };
+                if self.operator(context, inst) != Some(Operator::Select) {
+                    return Some(0);
+                }
+                let const_arms = self.int_operand(context, inst, 1).is_some()
+                    && self.int_operand(context, inst, 2).is_some();
+                Some(const_arms as u32)
+            }
+            IsFoldable { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let foldable = match part {
+                    Part::Instruction(i) => self.fold_instruction(context, i).is_some(),
+                    Part::Constant(_) | Part::ConditionCode(_) => false,
+                };
+                Some(foldable as u32)
+            }
+            IsForwardableLoad { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let forwardable = match part {
+                    Part::Instruction(i) => self.stored_value(context, i).is_some(),
+                    Part::Constant(_) | Part::ConditionCode(_) => false,
+                };
+                Some(forwardable as u32)
+            }
+            IsUnused { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let unused = match part {
+                    Part::Instruction(i) => {
+                        self.instr_set.instruction_use_count(context, i) == Some(0)
+                    }
+                    Part::Constant(_) | Part::ConditionCode(_) => false,
+                };
+                Some(unused as u32)
+            }
+            SingleUse { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let single_use = match part {
+                    Part::Instruction(i) => {
+                        self.instr_set.instruction_use_count(context, i) == Some(1)
+                    }
+                    Part::Constant(_) | Part::ConditionCode(_) => false,
+                };
+                Some(single_use as u32)
+            }
+            IsExtension { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let inst = match part {
+                    Part::Instruction(i) => i,
+                    Part::Constant(_) | Part::ConditionCode(_) => return Some(0),
+                };
+                let signed = match self.operator(context, inst) {
+                    Some(Operator::Sextend) => true,
+                    Some(Operator::Uextend) => false,
+                    _ => return Some(0),
+                };
+                let source_width =
+                    match self
+                        .instr_set
+                        .get_part_at_path(context, inst, Path::new(&[0, 0]))?
+                    {
+                        Part::Instruction(i) => {
+                            self.instr_set.instruction_result_bit_width(context, i)
+                        }
+                        Part::Constant(c) => {
+                            c.bit_width(self.instr_set.instruction_result_bit_width(context, root))
+                        }
+                        Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                    };
+                Some(MatchOp::extension_value(signed, source_width))
+            }
+            PopcountEq { path, count } => {
+                let (x, _) = self.int_at_path(context, root, path)?;
+                Some((x.count_ones() == u32::from(count)) as u32)
+            }
+            PopcountLe { path, count } => {
+                let (x, _) = self.int_at_path(context, root, path)?;
+                Some((x.count_ones() <= u32::from(count)) as u32)
+            }
+            NegationFitsInBits { path, bits } => {
+                let (x, width) = self.int_at_path(context, root, path)?;
+                let neg = sign_extend(x.wrapping_neg(), width);
+                let fits = bits >= 128 || {
+                    let limit = 1i128 << (bits - 1);
+                    -limit <= neg && neg < limit
+                };
+                Some(fits as u32)
+            }
+            FitsInBits { path, bits } => {
+                let (x, width) = self.int_at_path(context, root, path)?;
+                let x = sign_extend(x, width);
+                let fits = bits >= 128 || {
+                    let limit = 1i128 << (bits - 1);
+                    -limit <= x && x < limit
+                };
+                Some(fits as u32)
+            }
+            TrailingZeros { path } => {
+                let (x, width) = self.int_at_path(context, root, path)?;
+                Some(x.trailing_zeros().min(u32::from(width)))
+            }
+            ConstEqWidthMinus { path, delta } => {
+                let (x, width) = self.int_at_path(context, root, path)?;
+                Some((delta <= width && x == u128::from(width - delta)) as u32)
+            }
+            IsByteMask { path } => {
+                let (x, width) = self.int_at_path(context, root, path)?;
+                let mut lanes = 0;
+                for (i, b) in x.to_le_bytes()[..usize::from(width / 8)].iter().enumerate() {
+                    match *b {
+                        0 => {}
+                        0xff => lanes |= 1 << i,
+                        _ => return Some(0),
+                    }
+                }
+                Some(lanes)
+            }
+            IsMaskConst { path } => {
+                if let Some((x, _)) = self.int_at_path(context, root, path) {
+                    return Some(low_mask_bits(x));
+                }
+
+                // Otherwise, look for `(isub (ishl 1 n) 1)`.
+                let isub = match self.get_part_at_path(context, root, path)? {
+                    Part::Instruction(i) => i,
+                    Part::Constant(_) | Part::ConditionCode(_) => return Some(0),
+                };
+                if self.operator(context, isub) != Some(Operator::Isub)
+                    || self.int_operand(context, isub, 1) != Some(1)
+                {
+                    return Some(0);
+                }
+                let ishl =
+                    match self
+                        .instr_set
+                        .get_part_at_path(context, isub, Path::new(&[0, 0]))?
+                    {
+                        Part::Instruction(i) => i,
+                        Part::Constant(_) | Part::ConditionCode(_) => return Some(0),
+                    };
+                if self.operator(context, ishl) != Some(Operator::Ishl)
+                    || self.int_operand(context, ishl, 0) != Some(1)
+                {
+                    return Some(0);
+                }
+                let width = u128::from(self.instr_set.instruction_result_bit_width(context, isub));
+                match self.shift_amount(context, ishl) {
+                    Some(n) if 0 < n && n < width => Some(n as u32),
+                    _ => Some(0),
+                }
+            }
+            BitWidth { path } => {
+                let bit_width = self.bit_width_at_path(context, root, path)?;
                 Some(bit_width as u32)
             }
+            SameWidth { path_a, path_b } => {
+                let a = self.bit_width_at_path(context, root, path_a)?;
+                let b = self.bit_width_at_path(context, root, path_b)?;
+                Some((a == b) as u32)
+            }
+            BlockPredecessorCount { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let inst = part.as_instruction()?;
+                self.instr_set
+                    .instruction_block_predecessor_count(context, inst)
+            }
             FitsInNativeWord { path } => {
                 let native_word_size = self.instr_set.native_word_size_in_bits(context);
                 debug_assert!(native_word_size.is_power_of_two());
 
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
+                let part = self.get_part_at_path(context, root, path)?;
                 let fits = match part {
                     Part::Instruction(i) => {
                         let size = self.instr_set.instruction_result_bit_width(context, i);
@@ -339,15 +1688,13 @@ where
                         let size = c.bit_width(root_width);
                         size <= native_word_size
                     }
-                    Part::ConditionCode(_) => panic!("FitsInNativeWord on condition code"),
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
                 };
                 Some(fits as u32)
             }
             Eq { path_a, path_b } => {
-                let path_a = self.peep_opt.paths.lookup(path_a);
-                let part_a = self.instr_set.get_part_at_path(context, root, path_a)?;
-                let path_b = self.peep_opt.paths.lookup(path_b);
-                let part_b = self.instr_set.get_part_at_path(context, root, path_b)?;
+                let part_a = self.get_part_at_path(context, root, path_a)?;
+                let part_b = self.get_part_at_path(context, root, path_b)?;
                 let eq = match (part_a, part_b) {
                     (Part::Instruction(inst), Part::Constant(c1))
                     | (Part::Constant(c1), Part::Instruction(inst)) => {
@@ -360,45 +1707,329 @@ where
                 };
                 Some(eq as _)
             }
-            IntegerValue { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
-                match part {
+            ConstEq { path_a, path_b } => {
+                let part_a = self.get_part_at_path(context, root, path_a)?;
+                let part_b = self.get_part_at_path(context, root, path_b)?;
+                let c1 = match part_a {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+                let c2 = match part_b {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                let eq = match (c1, c2) {
+                    (Constant::Int(a, _), Constant::Int(b, _)) => {
+                        let mask = width_mask(c1.bit_width(root_width));
+                        a & mask == b & mask
+                    }
+                    (Constant::Bool(a, _), Constant::Bool(b, _)) => a == b,
+                    _ => false,
+                };
+                Some(eq as u32)
+            }
+            EquivalentValues { path_a, path_b } => {
+                let part_a = self.get_part_at_path(context, root, path_a)?;
+                let part_b = self.get_part_at_path(context, root, path_b)?;
+
+                // Check for SSA identity first, and only fall back to
+                // comparing constant values when that fails.
+                let equivalent = part_a == part_b || {
+                    let c1 = match part_a {
+                        Part::Constant(c) => Some(c),
+                        Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i),
+                        Part::ConditionCode(_) => None,
+                    };
+                    let c2 = match part_b {
+                        Part::Constant(c) => Some(c),
+                        Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i),
+                        Part::ConditionCode(_) => None,
+                    };
+                    match (c1, c2) {
+                        (Some(c1), Some(c2)) => c1 == c2,
+                        _ => false,
+                    }
+                };
+                Some(equivalent as _)
+            }
+            IsOneLessThan { path_a, path_b } => {
+                let part_a = self.get_part_at_path(context, root, path_a)?;
+                let part_b = self.get_part_at_path(context, root, path_b)?;
+                let c1 = match part_a {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+                let c2 = match part_b {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                let width = c1.bit_width(root_width);
+                debug_assert_eq!(width, c2.bit_width(root_width));
+                let mask = width_mask(width);
+
+                let a = c1.as_int()? & mask;
+                let b = c2.as_int()? & mask;
+                Some((a != mask && a + 1 == b) as u32)
+            }
+            IsScaledBy {
+                path_a,
+                path_b,
+                factor,
+            } => {
+                let (a, width) = self.int_at_path(context, root, path_a)?;
+                let (b, b_width) = self.int_at_path(context, root, path_b)?;
+                debug_assert_eq!(width, b_width);
+                let factor = self.peep_opt.integers.lookup(factor);
+                Some((a == (b.wrapping_mul(factor) & width_mask(width))) as u32)
+            }
+            IntegerInSet { path, set } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let c = match part {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                let in_set = self.interned_integer(c, root_width).map_or(false, |id| {
+                    self.peep_opt
+                        .integers
+                        .lookup_set(set)
+                        .contains(&IntegerId(id))
+                });
+                Some(in_set as u32)
+            }
+            BoundedBy { path, lo, hi } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                let masked =
+                    |c: Constant| c.as_int().map(|x| x & width_mask(c.bit_width(root_width)));
+                let range = match part {
+                    Part::Constant(c) => masked(c).map(|x| (x, x)),
+                    Part::Instruction(i) => {
+                        match self.instr_set.instruction_to_constant(context, i) {
+                            Some(c) => masked(c).map(|x| (x, x)),
+                            None => self.instr_set.instruction_value_range(context, i),
+                        }
+                    }
+                    Part::ConditionCode(_) => None,
+                };
+                let lo = self.peep_opt.integers.lookup(lo);
+                let hi = self.peep_opt.integers.lookup(hi);
+                let bounded = range.map_or(false, |(min, max)| lo <= min && max <= hi);
+                Some(bounded as u32)
+            }
+            IsAligned { path, align } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                let is_multiple = |c: Constant| {
+                    c.as_int().map_or(false, |x| {
+                        (x & width_mask(c.bit_width(root_width))) % u128::from(align) == 0
+                    })
+                };
+                let aligned = match part {
+                    Part::Constant(c) => is_multiple(c),
+                    Part::Instruction(i) => {
+                        match self.instr_set.instruction_to_constant(context, i) {
+                            Some(c) => is_multiple(c),
+                            None => self
+                                .instr_set
+                                .instruction_alignment(context, i)
+                                .map_or(false, |a| a % u32::from(align) == 0),
+                        }
+                    }
+                    Part::ConditionCode(_) => false,
+                };
+                Some(aligned as u32)
+            }
+            HighBitsZero { path, from_width } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                let masked =
+                    |c: Constant| c.as_int().map(|x| x & width_mask(c.bit_width(root_width)));
+                let max = match part {
+                    Part::Constant(c) => masked(c),
+                    Part::Instruction(i) => {
+                        match self.instr_set.instruction_to_constant(context, i) {
+                            Some(c) => masked(c),
+                            None => self
+                                .instr_set
+                                .instruction_value_range(context, i)
+                                .map(|(_min, max)| max),
+                        }
+                    }
+                    Part::ConditionCode(_) => None,
+                };
+                let zero = max.map_or(false, |max| max >> from_width == 0);
+                Some(zero as u32)
+            }
+            KnownNonNegative { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let (max, width) = match part {
                     Part::Constant(c) => {
-                        let x = c.as_int()?;
-                        self.peep_opt.integers.already_interned(x).map(|id| id.0)
+                        let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                        (c.as_int(), c.bit_width(root_width))
                     }
                     Part::Instruction(i) => {
-                        let c = self.instr_set.instruction_to_constant(context, i)?;
-                        let x = c.as_int()?;
-                        self.peep_opt.integers.already_interned(x).map(|id| id.0)
+                        let width = self.instr_set.instruction_result_bit_width(context, i);
+                        match self.instr_set.instruction_to_constant(context, i) {
+                            Some(c) => (c.as_int(), c.bit_width(width)),
+                            None => {
+                                let range = self.instr_set.instruction_value_range(context, i);
+                                (range.map(|(_min, max)| max), width)
+                            }
+                        }
                     }
-                    Part::ConditionCode(_) => panic!("IntegerValue on condition code"),
+                    Part::ConditionCode(_) => (None, 0),
+                };
+                let non_negative =
+                    max.map_or(false, |max| (max & width_mask(width)) >> (width - 1) == 0);
+                Some(non_negative as u32)
+            }
+            IsEncodableLogicalImm { path, width } => {
+                let (x, _) = self.int_at_path(context, root, path)?;
+                Some(is_logical_immediate(x, width) as u32)
+            }
+            IsAddOfNegConst { path } => {
+                let inst = self
+                    .get_part_at_path(context, root, path)?
+                    .as_instruction()?;
+                if self.operator(context, inst)? != Operator::Iadd {
+                    return Some(0);
                 }
+                let width = self.instr_set.instruction_result_bit_width(context, inst);
+                let negative = self
+                    .int_operand(context, inst, 1)
+                    .map_or(false, |c| (c & width_mask(width)) >> (width - 1) == 1);
+                Some(negative as u32)
+            }
+            IsShiftedImm { path, bits, shift } => {
+                let (x, width) = self.int_at_path(context, root, path)?;
+                let shifted = shift < width
+                    && x & width_mask(shift) == 0
+                    && (x >> shift) & !width_mask(bits) == 0;
+                Some(shifted as u32)
+            }
+            IsShiftedImm8 { path } => {
+                let (x, _) = self.int_at_path(context, root, path)?;
+                let (imm8, _) = shifted_imm8(x);
+                Some((imm8 <= 0xff) as u32)
+            }
+            MemFlagsMatch {
+                path,
+                flags_mask,
+                flags_value,
+            } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let inst = part.as_instruction()?;
+                let flags = self.instr_set.instruction_mem_flags(context, inst)?;
+                Some((flags & flags_mask == flags_value) as u32)
+            }
+            IntegerValue { path } => {
+                let part = self.get_part_at_path(context, root, path)?;
+                let c = match part {
+                    Part::Constant(c) => c,
+                    Part::Instruction(i) => self.instr_set.instruction_to_constant(context, i)?,
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
+                };
+                let root_width = self.instr_set.instruction_result_bit_width(context, root);
+                self.interned_integer(c, root_width)
             }
             BooleanValue { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
+                let part = self.get_part_at_path(context, root, path)?;
                 match part {
                     Part::Constant(c) => c.as_bool().map(|b| b as u32),
                     Part::Instruction(i) => {
                         let c = self.instr_set.instruction_to_constant(context, i)?;
                         c.as_bool().map(|b| b as u32)
                     }
-                    Part::ConditionCode(_) => panic!("IntegerValue on condition code"),
+                    Part::ConditionCode(_) => return self.unknown_value("a condition code"),
                 }
             }
             ConditionCode { path } => {
-                let path = self.peep_opt.paths.lookup(path);
-                let part = self.instr_set.get_part_at_path(context, root, path)?;
+                let part = self.get_part_at_path(context, root, path)?;
                 part.as_condition_code().map(|cc| cc as u32)
             }
+            IcmpZero { path } => {
+                let inst = self
+                    .get_part_at_path(context, root, path)?
+                    .as_instruction()?;
+                if self.operator(context, inst)? != Operator::Icmp
+                    || self.int_operand(context, inst, 2)? != 0
+                {
+                    return None;
+                }
+                self.instr_set
+                    .get_part_at_path(context, inst, Path::new(&[0, 0]))?
+                    .as_condition_code()
+                    .map(|cc| cc as u32)
+            }
+            InPhase { phase } => {
+                let in_phase = self.phase.map_or(true, |p| p == phase);
+                Some(in_phase as u32)
+            }
+            IsEnabled => Some(self.enable_disabled as u32),
+            CustomPredicate { path, predicate } => {
+                let inst = self
+                    .get_part_at_path(context, root, path)?
+                    .as_instruction()?;
+                let holds = match self.predicates.get(predicate as usize) {
+                    Some(Some(p)) => p(context, inst),
+                    _ => false,
+                };
+                Some(holds as u32)
+            }
             MatchOp::Nop => None,
         };
         log::trace!("Evaluated match operation: {:?} = {:?}", match_op, result);
         result
     }
 
+    /// Find the peephole optimization that would be applied to the given root
+    /// instruction, without applying it.
+    ///
+    /// If an optimization matches, then its index and the actions that would
+    /// build its right-hand side are returned as `Some`. The index is in the
+    /// same order as
+    /// [`PeepholeOptimizations::results`][crate::PeepholeOptimizations::results],
+    /// which says which of the values that the actions define would replace
+    /// `root`. Neither `root` nor anything else in `context` is modified: the
+    /// actions are only a description of the rewrite that
+    /// [`apply_one`][crate::PeepholeOptimizer::apply_one] would perform.
+    ///
+    /// If no optimization's left-hand side matches `root`, then `None` is
+    /// returned.
+    ///
+    /// Previewing isn't matching for real, so it doesn't count towards match
+    /// statistics, and match observers aren't called.
+    pub fn preview_one(
+        &mut self,
+        context: &mut I::Context,
+        root: I::Instruction,
+    ) -> Option<(u32, &[Action])> {
+        log::trace!("PeepholeOptimizer::preview_one");
+
+        let record_matches = mem::replace(&mut self.record_matches, false);
+        let matched = self.match_one(context, root);
+        self.record_matches = record_matches;
+        if matched {
+            let optimization = self
+                .state
+                .matched_optimization
+                .expect("every optimization's actions record that it matched");
+            Some((optimization, &self.state.actions))
+        } else {
+            None
+        }
+    }
+
     /// Attempt to apply a single peephole optimization to the given root
     /// instruction.
     ///
@@ -408,6 +2039,10 @@ where
     ///
     /// If no optimization's left-hand side matches `root`, then `root` is left
     /// untouched and `None` is returned.
+    ///
+    /// If the optimization's right-hand side is `(delete)`, then `root` is
+    /// removed rather than replaced, and `None` is returned since there is
+    /// nothing left to optimize further.
     pub fn apply_one(
         &mut self,
         context: &mut I::Context,
@@ -415,14 +2050,143 @@ where
     ) -> Option<I::Instruction> {
         log::trace!("PeepholeOptimizer::apply_one");
 
-        self.backtracking_states.clear();
-        self.actions.clear();
-        self.left_hand_sides.clear();
-        self.right_hand_sides.clear();
+        if !self.match_one(context, root) {
+            return None;
+        }
+        self.rewrite(context, root)
+    }
+
+    /// Apply the actions of the optimization that last matched `root`, which
+    /// are in `self.state.actions`, and replace `root` with their result.
+    ///
+    /// Returns the replacement, or `None` if the optimization deleted `root`.
+    fn rewrite(
+        &mut self,
+        context: &mut I::Context,
+        root: I::Instruction,
+    ) -> Option<I::Instruction> {
+        let optimization = self.state.matched_optimization;
+        // Evaluating the actions drains them, so first find the replacement
+        // that they declare for each of the root's results, if any. Its
+        // position among the actions doesn't matter.
+        let replace_results = self.state.actions.iter().find_map(|a| match a {
+            Action::ReplaceResults { results } => Some(results.clone()),
+            _ => None,
+        });
+        self.eval_actions(context, root);
+        self.record_fire(optimization);
+
+        // And finally, replace the old root instruction with the RHS that this
+        // optimization designates as its result! If there is no result, then
+        // the optimization deleted the old root instead. If the optimization
+        // replaces each of the root's results, then the RHS for each result was
+        // already built.
+        let rhs = &self.state.right_hand_sides;
+        let result = match replace_results {
+            Some(results) => {
+                let new: Vec<_> = results.iter().map(|id| rhs[id.0 as usize]).collect();
+                Some(
+                    self.instr_set
+                        .replace_instruction_results(context, root, &new),
+                )
+            }
+            None => optimization
+                .and_then(|o| self.peep_opt.results[o as usize])
+                .map(|id| {
+                    self.instr_set
+                        .replace_instruction(context, root, rhs[id.0 as usize])
+                }),
+        };
+        if let Some(log) = &mut self.rewrite_log {
+            log.rewrites.push(Rewrite {
+                root,
+                optimization,
+                result,
+            });
+        }
+        result
+    }
+
+    /// Like `match_one_uncached`, but reuse the shape cache's result for the
+    /// root's shape, if any, and otherwise cache the result.
+    ///
+    /// A result from the cache leaves `self.state.matched_instructions` empty.
+    fn match_one(&mut self, context: &mut I::Context, root: I::Instruction) -> bool {
+        let mut shape = match &mut self.shape_cache {
+            Some(cache) => mem::replace(&mut cache.scratch, vec![]),
+            None => return self.match_one_uncached(context, root),
+        };
+        shape.clear();
+        let matched = if self.instr_set.instruction_shape(context, root, &mut shape) {
+            self.match_one_with_shape(context, root, &shape)
+        } else {
+            self.match_one_uncached(context, root)
+        };
+
+        // Reuse the shape's allocation, unless the cache was disabled in the
+        // meantime.
+        if let Some(cache) = &mut self.shape_cache {
+            cache.scratch = shape;
+        }
+        matched
+    }
+
+    fn match_one_with_shape(
+        &mut self,
+        context: &mut I::Context,
+        root: I::Instruction,
+        shape: &[u64],
+    ) -> bool {
+        let cache = self.shape_cache.as_mut().unwrap();
+        match cache.get(shape) {
+            Some(CachedMatch::NoMatch) => {
+                self.state.clear();
+                false
+            }
+            Some(CachedMatch::Matched {
+                optimization,
+                actions,
+            }) => {
+                let optimization = *optimization;
+                self.state.clear();
+                self.state.actions.extend_from_slice(actions);
+                self.state.matched_optimization = optimization;
+                if let Some(optimization) = optimization {
+                    self.record_match(optimization);
+                }
+                true
+            }
+            None => {
+                let matched = self.match_one_uncached(context, root);
+                let cached = if matched {
+                    CachedMatch::Matched {
+                        optimization: self.state.matched_optimization,
+                        actions: self.state.actions.clone(),
+                    }
+                } else {
+                    CachedMatch::NoMatch
+                };
+                self.shape_cache.as_mut().unwrap().insert(shape, cached);
+                matched
+            }
+        }
+    }
+
+    /// Match the most specific optimization that we can against the given root
+    /// instruction, leaving its actions in `self.state.actions` and the instructions
+    /// whose operators its pattern matched in `self.state.matched_instructions`.
+    ///
+    /// Returns whether any optimization matched.
+    fn match_one_uncached(&mut self, context: &mut I::Context, root: I::Instruction) -> bool {
+        self.state.clear();
+        if !self.might_match(context, root) {
+            log::trace!("No optimization has the root's operator");
+            return false;
+        }
 
         let mut r#final = None;
 
-        let mut query = self.peep_opt.automata.query();
+        let mut query = self.automaton_for(context, root).query();
         loop {
             log::trace!("Current state: {:?}", query.current_state());
 
@@ -433,8 +2197,25 @@ where
                 // more-specific optimization that is also applicable if we keep
                 // going. And we always want to apply the most specific
                 // optimization that matches.
-                log::trace!("Found a match at state {:?}", query.current_state());
-                r#final = Some((query.current_state(), self.actions.len()));
+                let final_actions = query.clone().finish().unwrap();
+                if self.makes_disallowed_operator(final_actions) {
+                    log::trace!(
+                        "Ignoring match at state {:?} that makes a disallowed operator",
+                        query.current_state()
+                    );
+                } else if self.overflows_mem_offset(context, root, final_actions) {
+                    log::trace!(
+                        "Ignoring match at state {:?} whose memory offset overflows",
+                        query.current_state()
+                    );
+                } else {
+                    log::trace!("Found a match at state {:?}", query.current_state());
+                    r#final = Some((
+                        query.current_state(),
+                        self.state.actions.len(),
+                        self.state.matched_instructions.len(),
+                    ));
+                }
             }
 
             // Anything following a `None` transition doesn't care about the
@@ -443,8 +2224,11 @@ where
             // optimization, we want to be able to backtrack to this state and
             // then try taking the `None` transition.
             if query.has_transition_on(&None) {
-                self.backtracking_states
-                    .push((query.current_state(), self.actions.len()));
+                self.state.backtracking_states.push((
+                    query.current_state(),
+                    self.state.actions.len(),
+                    self.state.matched_instructions.len(),
+                ));
             }
 
             let match_op = match query.current_state_data() {
@@ -452,15 +2236,26 @@ where
                 Some(op) => op,
             };
 
-            let input = self.eval_match_op(context, root, *match_op);
+            let match_op = *match_op;
+            let input = self.eval_match_op(context, root, match_op);
 
             let actions = if let Some(actions) = query.next(&input) {
+                if let (MatchOp::Opcode { path }, Some(_)) = (match_op, input) {
+                    let inst = self
+                        .get_part_at_path(context, root, path)
+                        .and_then(|p| p.as_instruction())
+                        .expect("matched an opcode, so there must be an instruction");
+                    self.state.matched_instructions.push(inst);
+                }
                 actions
             } else if r#final.is_some() {
                 break;
-            } else if let Some((state, actions_len)) = self.backtracking_states.pop() {
+            } else if let Some((state, actions_len, matched_len)) =
+                self.state.backtracking_states.pop()
+            {
                 query.go_to_state(state);
-                self.actions.truncate(actions_len);
+                self.state.actions.truncate(actions_len);
+                self.state.matched_instructions.truncate(matched_len);
                 query
                     .next(&None)
                     .expect("backtracking states always have `None` transitions")
@@ -468,45 +2263,429 @@ where
                 break;
             };
 
-            self.actions.extend(actions.iter().copied());
+            self.state.actions.extend_from_slice(actions);
         }
 
         // If `final` is none, then we didn't encounter any final states, so
         // there are no applicable optimizations.
-        let (final_state, actions_len) = match r#final {
+        let (final_state, actions_len, matched_len) = match r#final {
             Some(f) => f,
             None => {
                 log::trace!("No optimizations matched");
-                return None;
+                return false;
             }
         };
 
-        // Go to the last final state we saw, reset the LHS and RHS to how
-        // they were at the time we saw the final state, and process the
-        // final actions.
-        self.actions.truncate(actions_len);
+        // Go to the last final state we saw, reset the actions to how they
+        // were at the time we saw the final state, and add the final actions.
+        self.state.actions.truncate(actions_len);
+        self.state.matched_instructions.truncate(matched_len);
         query.go_to_state(final_state);
         let final_actions = query.finish().expect("should be in a final state");
-        self.actions.extend(final_actions.iter().copied());
-        self.eval_actions(context, root);
+        self.state.actions.extend_from_slice(final_actions);
+
+        // The last action records which optimization this is.
+        if let Some(&Action::Matched { optimization }) = self.state.actions.last() {
+            self.state.actions.pop();
+            self.state.matched_optimization = Some(optimization);
+            self.record_match(optimization);
+        }
+        true
+    }
+
+    /// Apply peephole optimizations to a batch of root instructions at once.
+    ///
+    /// This happens in two phases. First, every root is matched against our
+    /// optimizations without modifying anything. Then the matches that don't
+    /// conflict are applied together.
+    ///
+    /// Two matches conflict when they target overlapping instructions, where a
+    /// match targets its root and every instruction whose operator its
+    /// left-hand side's pattern matched. Conflicts are resolved greedily:
+    /// matches that target more instructions take priority, ties go to the
+    /// match whose root comes first in `roots`, and a match that conflicts with
+    /// a higher-priority match is dropped.
+    ///
+    /// Applying a match can change what a later match's right-hand side or
+    /// preconditions read, even when their targets don't overlap: for example,
+    /// replacing an operand can make a `single-use` precondition false. So each
+    /// surviving match is matched again right before it is applied, and it is
+    /// dropped unless the same optimization still matches. Matching again
+    /// doesn't count towards match statistics or observers a second time.
+    ///
+    /// Returns each replaced root paired with the root of its replacement, in
+    /// the order that they were applied. Roots that were deleted by a
+    /// `(delete)` right-hand side have no replacement, and are not returned.
+    pub fn apply_batch(
+        &mut self,
+        context: &mut I::Context,
+        roots: impl IntoIterator<Item = I::Instruction>,
+    ) -> Vec<(I::Instruction, I::Instruction)> {
+        log::trace!("PeepholeOptimizer::apply_batch");
+
+        // Phase one: collect the candidate matches.
+        let mut candidates = vec![];
+        for root in roots {
+            if self.match_one_uncached(context, root) {
+                let mut targets = mem::replace(&mut self.state.matched_instructions, vec![]);
+                if !targets.contains(&root) {
+                    targets.push(root);
+                }
+                candidates.push((root, self.state.matched_optimization, targets));
+            }
+        }
+
+        // Resolve conflicts. This is a stable sort, so ties keep their order in
+        // `roots`.
+        candidates.sort_by(|(_, _, a), (_, _, b)| b.len().cmp(&a.len()));
+        let mut targeted: Vec<I::Instruction> = vec![];
+        candidates.retain(|(root, _, targets)| {
+            if targets.iter().any(|t| targeted.contains(t)) {
+                log::trace!("Dropping conflicting match rooted at {:?}", root);
+                false
+            } else {
+                targeted.extend(targets.iter().copied());
+                true
+            }
+        });
+
+        // Phase two: apply the surviving matches.
+        let mut replaced = Vec::with_capacity(candidates.len());
+        let record_matches = mem::replace(&mut self.record_matches, false);
+        for (root, optimization, _) in candidates {
+            let still_matches = self.match_one_uncached(context, root)
+                && self.state.matched_optimization == optimization;
+            if !still_matches {
+                log::trace!("Dropping stale match rooted at {:?}", root);
+                continue;
+            }
+            if let Some(new_root) = self.rewrite(context, root) {
+                replaced.push((root, new_root));
+            }
+        }
+        self.record_matches = record_matches;
+        replaced
+    }
+
+    /// Make a single pass over the given instructions, applying at most one
+    /// peephole optimization to each, visiting them in the given `order`.
+    ///
+    /// Rewriting an instruction invalidates it, so any later occurrence of a
+    /// replaced instruction in `insts` is visited as its replacement instead,
+    /// and later occurrences of a deleted instruction are skipped.
+    ///
+    /// Returns the number of optimizations that were applied.
+    pub fn apply_in_order(
+        &mut self,
+        context: &mut I::Context,
+        insts: impl IntoIterator<Item = I::Instruction>,
+        order: ApplyOrder,
+    ) -> usize {
+        log::trace!("PeepholeOptimizer::apply_in_order({:?})", order);
 
-        // And finally, the root of the RHS for this optimization is the
-        // last entry in `self.right_hand_sides`, so replace the old root
-        // instruction with this one!
-        let result = self.right_hand_sides.pop().unwrap();
-        let new_root = self.instr_set.replace_instruction(context, root, result);
-        Some(new_root)
+        let mut insts: Vec<_> = insts.into_iter().map(Some).collect();
+        if order == ApplyOrder::Reverse {
+            insts.reverse();
+        }
+
+        let mut applied = 0;
+        for i in 0..insts.len() {
+            let root = match insts[i] {
+                Some(root) => root,
+                None => continue,
+            };
+            if !self.match_one(context, root) {
+                continue;
+            }
+            applied += 1;
+
+            let new_root = self.rewrite(context, root);
+            for inst in &mut insts[i + 1..] {
+                if *inst == Some(root) {
+                    *inst = new_root;
+                }
+            }
+        }
+        applied
     }
 
     /// Keep applying peephole optimizations to the given instruction until none
     /// can be applied anymore.
+    ///
+    /// See also
+    /// [`set_max_rewrites_per_instruction`][crate::PeepholeOptimizer::set_max_rewrites_per_instruction].
     pub fn apply_all(&mut self, context: &mut I::Context, mut inst: I::Instruction) {
+        let mut rewrites = 0;
+        let mut matched = vec![];
         loop {
+            if self.max_rewrites_per_instruction == Some(rewrites) {
+                matched.sort_unstable();
+                matched.dedup();
+                log::warn!(
+                    "Stopped rewriting {:?} after {} rewrites by optimizations {:?}",
+                    inst,
+                    rewrites,
+                    matched
+                );
+                break;
+            }
             if let Some(new_inst) = self.apply_one(context, inst) {
+                rewrites += 1;
+                matched.extend(self.state.matched_optimization);
                 inst = new_inst;
             } else {
                 break;
             }
         }
     }
+
+    /// Keep applying peephole optimizations to the given instructions of a
+    /// function until none can be applied anymore, or until `max_rewrites`
+    /// optimizations have been applied in total.
+    ///
+    /// Each instruction is rewritten until nothing matches it anymore, and
+    /// then the instructions are visited again for as long as the previous
+    /// pass rewrote any of them. As with
+    /// [`apply_in_order`][crate::PeepholeOptimizer::apply_in_order], later
+    /// occurrences of a replaced instruction are visited as its replacement,
+    /// and later occurrences of a deleted instruction are skipped.
+    ///
+    /// Optimizations that undo each other, or adversarial inputs, could
+    /// otherwise keep this rewriting forever. Returns `true` if it stopped
+    /// because another optimization matched after `max_rewrites` had already
+    /// been applied, and `false` if it reached a fixpoint.
+    pub fn apply_to_function(
+        &mut self,
+        context: &mut I::Context,
+        insts: impl IntoIterator<Item = I::Instruction>,
+        max_rewrites: usize,
+    ) -> bool {
+        log::trace!("PeepholeOptimizer::apply_to_function({})", max_rewrites);
+
+        let mut insts: Vec<_> = insts.into_iter().map(Some).collect();
+        let (_, out_of_fuel) = self.apply_to_slots(context, &mut insts, max_rewrites, true);
+        out_of_fuel
+    }
+
+    /// Apply peephole optimizations to the given instructions, either until
+    /// none can be applied anymore when `fixpoint` is set, or in a single pass
+    /// that applies at most one optimization to each instruction otherwise.
+    ///
+    /// Each replaced instruction in `insts` is updated to its replacement, and
+    /// deleted instructions become `None`, so that the caller can keep working
+    /// with the rewritten function afterwards.
+    ///
+    /// Returns how many optimizations were applied, and whether it stopped
+    /// because another optimization matched after `max_rewrites` had already
+    /// been applied.
+    pub(crate) fn apply_to_slots(
+        &mut self,
+        context: &mut I::Context,
+        insts: &mut [Option<I::Instruction>],
+        max_rewrites: usize,
+        fixpoint: bool,
+    ) -> (usize, bool) {
+        let mut rewrites = 0;
+        loop {
+            let rewrites_before_pass = rewrites;
+            for i in 0..insts.len() {
+                while let Some(root) = insts[i] {
+                    if !self.match_one(context, root) {
+                        break;
+                    }
+                    if rewrites == max_rewrites {
+                        log::warn!("Stopped rewriting the function after {} rewrites", rewrites);
+                        return (rewrites, true);
+                    }
+                    rewrites += 1;
+
+                    let new_root = self.rewrite(context, root);
+                    for inst in &mut insts[i..] {
+                        if *inst == Some(root) {
+                            *inst = new_root;
+                        }
+                    }
+                    if !fixpoint {
+                        break;
+                    }
+                }
+            }
+            if !fixpoint || rewrites == rewrites_before_pass {
+                return (rewrites, false);
+            }
+        }
+    }
+}
+
+/// Is `operator` a unary operation that undoes itself when applied twice?
+fn is_involution(operator: Operator) -> bool {
+    matches!(operator, Operator::Bnot | Operator::Ineg)
+}
+
+/// Constant fold `operator` applied to the given operands (immediates first,
+/// then parameters), producing a result that is `width` bits wide.
+///
+/// Returns `None` for unsupported operators and operand combinations.
+fn fold_const(
+    operator: Operator,
+    cc: Option<ConditionCode>,
+    operands: &[Constant],
+    width: u8,
+) -> Option<Constant> {
+    use Constant::{Bool, Int};
+
+    let w = BitWidth::try_from(width).ok()?;
+    let mask = width_mask(width);
+    let int = |c: &Constant| match *c {
+        Int(x, _) => {
+            let width = c.bit_width(width);
+            Some((x & width_mask(width), width))
+        }
+        Bool(..) => None,
+    };
+
+    match (operator, operands) {
+        (Operator::Bnot, [Bool(x, _)]) => Some(Bool(!x, w)),
+        (Operator::Band, [Bool(x, _), Bool(y, _)]) => Some(Bool(*x && *y, w)),
+        (Operator::Bor, [Bool(x, _), Bool(y, _)]) => Some(Bool(*x || *y, w)),
+        (Operator::Bxor, [Bool(x, _), Bool(y, _)]) => Some(Bool(x != y, w)),
+        (Operator::Bint, [Bool(x, _)]) => Some(Int(*x as u128, w)),
+        (Operator::Bnot, [x]) => Some(Int(!int(x)?.0 & mask, w)),
+        (Operator::Ineg, [x]) => Some(Int(int(x)?.0.wrapping_neg() & mask, w)),
+        (Operator::Icmp, [a, b]) | (Operator::IcmpImm, [b, a]) => {
+            let ((a, a_width), (b, b_width)) = (int(a)?, int(b)?);
+            if a_width != b_width {
+                return None;
+            }
+            let (sa, sb) = (sign_extend(a, a_width), sign_extend(b, a_width));
+            let result = match cc? {
+                ConditionCode::Eq => a == b,
+                ConditionCode::Ne => a != b,
+                ConditionCode::Slt => sa < sb,
+                ConditionCode::Ult => a < b,
+                ConditionCode::Sge => sa >= sb,
+                ConditionCode::Uge => a >= b,
+                ConditionCode::Sgt => sa > sb,
+                ConditionCode::Ugt => a > b,
+                ConditionCode::Sle => sa <= sb,
+                ConditionCode::Ule => a <= b,
+                ConditionCode::Of | ConditionCode::Nof => return None,
+            };
+            Some(Bool(result, w))
+        }
+        (_, [a, b]) => {
+            let (a, b) = (int(a)?.0, int(b)?.0);
+            // The `*_imm` variants take their immediate first, but apply it as
+            // the right-hand operand (except for `irsub_imm`).
+            let (x, y) = match operator {
+                Operator::IaddImm
+                | Operator::ImulImm
+                | Operator::BandImm
+                | Operator::BorImm
+                | Operator::BxorImm
+                | Operator::IshlImm
+                | Operator::UshrImm
+                | Operator::SshrImm => (b, a),
+                _ => (a, b),
+            };
+            let shift = (y % u128::from(width)) as u32;
+            let result = match operator {
+                Operator::Iadd | Operator::IaddImm => x.wrapping_add(y),
+                Operator::Isub | Operator::IrsubImm => x.wrapping_sub(y),
+                Operator::Imul | Operator::ImulImm => x.wrapping_mul(y),
+                Operator::Band | Operator::BandImm => x & y,
+                Operator::Bor | Operator::BorImm => x | y,
+                Operator::Bxor | Operator::BxorImm => x ^ y,
+                Operator::Ishl | Operator::IshlImm => x << shift,
+                Operator::Ushr | Operator::UshrImm => x >> shift,
+                Operator::Sshr | Operator::SshrImm => (sign_extend(x, width) >> shift) as u128,
+                Operator::Imin if sign_extend(x, width) <= sign_extend(y, width) => x,
+                Operator::Imax if sign_extend(x, width) >= sign_extend(y, width) => x,
+                Operator::Imin | Operator::Imax => y,
+                Operator::Umin => x.min(y),
+                Operator::Umax => x.max(y),
+                _ => return None,
+            };
+            Some(Int(result & mask, w))
+        }
+        _ => None,
+    }
+}
+
+/// Sign extend the low `width` bits of `x`.
+fn sign_extend(x: u128, width: u8) -> i128 {
+    if width == 0 || width >= 128 {
+        x as i128
+    } else {
+        let shift = 128 - u32::from(width);
+        ((x << shift) as i128) >> shift
+    }
+}
+
+/// Wrap the result `x` of unquote arithmetic on `c` to `c`'s bit width, and
+/// sign-extend it to 128 bits.
+fn wrap(x: u128, c: Constant, root_width: u8) -> u128 {
+    sign_extend(x, c.bit_width(root_width)) as u128
+}
+
+/// A mask of the low `width` bits.
+fn width_mask(width: u8) -> u128 {
+    if width >= 128 {
+        std::u128::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+/// If `x` is a non-zero mask of its low `n` bits, then `n`, and otherwise zero.
+fn low_mask_bits(x: u128) -> u32 {
+    if x != 0 && x & x.wrapping_add(1) == 0 {
+        x.count_ones()
+    } else {
+        0
+    }
+}
+
+/// Is `x` a single, non-empty run of contiguous ones?
+fn is_shifted_mask(x: u128) -> bool {
+    x != 0 && low_mask_bits(x >> x.trailing_zeros()) != 0
+}
+
+/// Split `x` into `(imm, k)` such that `x == imm << k`, where `k` is the
+/// largest multiple of eight that keeps `x` intact.
+///
+/// `x` is a shifted 8-bit immediate when `imm <= 0xff`.
+fn shifted_imm8(x: u128) -> (u128, u8) {
+    if x == 0 {
+        return (0, 0);
+    }
+    let k = x.trailing_zeros() as u8 & !7;
+    (x >> k, k)
+}
+
+/// Is `x` encodable as an AArch64 logical immediate in a `width`-bit
+/// register?
+fn is_logical_immediate(x: u128, width: u8) -> bool {
+    let mask = width_mask(width);
+    if x == 0 || x == mask || x & !mask != 0 {
+        return false;
+    }
+
+    // Find the smallest element that `x` is a replication of.
+    let mut size = width;
+    while size > 2 {
+        let half = size / 2;
+        let half_mask = width_mask(half);
+        if x & half_mask != (x >> half) & half_mask {
+            break;
+        }
+        size = half;
+    }
+
+    // The element must be a run of ones, possibly rotated so that it wraps
+    // around the element's ends, in which case its complement is a run of
+    // ones instead.
+    let elem_mask = width_mask(size);
+    let elem = x & elem_mask;
+    is_shifted_mask(elem) || is_shifted_mask(!elem & elem_mask)
 }
@@ -8,13 +8,15 @@ use std::fmt::Debug;
 ///
 /// Whether an integer is interpreted as signed or unsigned depends on the
 /// operations applied to it.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Constant {
     /// A boolean of the given width.
     Bool(bool, BitWidth),
 
     /// An integer constant of the given width,
-    Int(u64, BitWidth),
+    ///
+    /// Only the low `width` bits of the value are significant.
+    Int(u128, BitWidth),
 }
 
 /// A part of an instruction, or a whole instruction itself.
@@ -84,9 +86,9 @@ macro_rules! accessors {
 }
 
 impl Constant {
-    /// If this is any kind of integer constant, get it as a 64-bit unsigned
+    /// If this is any kind of integer constant, get it as a 128-bit unsigned
     /// integer.
-    pub fn as_int(&self) -> Option<u64> {
+    pub fn as_int(&self) -> Option<u128> {
         match *self {
             Constant::Bool(..) => None,
             Constant::Int(x, _) => Some(x),
@@ -23,7 +23,7 @@
 // TODO: Make `[]` the path to the root, and get rid of this redundant leading
 // zero that is currently in every single path.
 
-use serde::de::{Deserializer, SeqAccess, Visitor};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
 use serde::ser::{SerializeSeq, Serializer};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -46,13 +46,19 @@ impl Path<'_> {
 
 /// An identifier for an interned path.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct PathId(u32);
+pub struct PathId(#[doc(hidden)] pub u32);
+
+impl PathId {
+    /// The id of the root path, `[0]`, which every `PathInterner` interns
+    /// first.
+    pub const ROOT: PathId = PathId(0);
+}
 
 /// An interner and de-duplicator for `Path`s.
 ///
 /// Can be serialized and deserialized while maintaining the same id to interned
 /// path mapping.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct PathInterner {
     // A map from a path (whose owned data is inside `arena`) to the canonical
     // `PathId` we assigned it when interning it.
@@ -68,8 +74,22 @@ pub struct PathInterner {
     arena: bumpalo::Bump,
 }
 
+impl Default for PathInterner {
+    fn default() -> Self {
+        let mut interner = PathInterner {
+            map: HashMap::new(),
+            paths: Vec::new(),
+            arena: bumpalo::Bump::new(),
+        };
+        let root = interner.intern(Path::new(&[0]));
+        debug_assert_eq!(root, PathId::ROOT);
+        interner
+    }
+}
+
 impl PathInterner {
-    /// Construct a new, empty `PathInterner`.
+    /// Construct a new `PathInterner`, in which only the root path is
+    /// interned, as `PathId::ROOT`.
     #[inline]
     pub fn new() -> Self {
         Self::default()
@@ -123,6 +143,12 @@ impl PathInterner {
         unsafe { unsafe_path.as_path() }
     }
 
+    /// Iterate over the interned paths in id order: the n^th path is the one
+    /// that was assigned the n^th id.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = Path<'a>> + 'a {
+        self.paths.iter().map(|p| unsafe { p.as_path() })
+    }
+
     #[inline(never)]
     fn lookup_failure() -> ! {
         panic!(
@@ -172,17 +198,18 @@ impl<'de> Visitor<'de> for PathInternerVisitor {
     where
         M: SeqAccess<'de>,
     {
-        const DEFAULT_CAPACITY: usize = 16;
-        let capacity = access.size_hint().unwrap_or(DEFAULT_CAPACITY);
-
-        let mut interner = PathInterner {
-            map: HashMap::with_capacity(capacity),
-            paths: Vec::with_capacity(capacity),
-            arena: bumpalo::Bump::new(),
-        };
+        let mut interner = PathInterner::new();
 
+        // The root is already interned, so the serialized paths must start
+        // with it for their ids to be preserved.
+        let mut num_paths = 0;
         while let Some(path) = access.next_element::<Path>()? {
-            interner.intern(path);
+            if interner.intern(path).0 != num_paths {
+                return Err(de::Error::custom(
+                    "paths must be unique and start with the root path",
+                ));
+            }
+            num_paths += 1;
         }
 
         Ok(interner)
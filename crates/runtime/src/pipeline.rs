@@ -0,0 +1,163 @@
+//! Running several peephole optimizers over a function, one after another.
+//!
+//! Embedders often have more than one set of peephole optimizations: for
+//! example, a canonicalization ruleset that should run until nothing matches
+//! anymore, and then a lowering ruleset that should make a single pass over
+//! the canonicalized function. A [`Pipeline`] sequences their optimizers as
+//! stages, each with its own fuel and fixpoint setting.
+
+use crate::instruction_set::InstructionSet;
+use crate::optimizer::PeepholeOptimizer;
+
+/// How a pipeline stage applies its optimizations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StageMode {
+    /// Keep applying optimizations until none can be applied anymore, like
+    /// [`PeepholeOptimizer::apply_to_function`][crate::PeepholeOptimizer::apply_to_function].
+    Fixpoint,
+    /// Make a single pass over the function, applying at most one optimization
+    /// to each instruction.
+    Once,
+}
+
+/// What happened in one stage of a pipeline.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StageStats {
+    /// The number of optimizations that this stage applied.
+    pub rewrites: usize,
+    /// Whether this stage stopped because it ran out of fuel, rather than
+    /// because it reached a fixpoint or finished its pass.
+    pub out_of_fuel: bool,
+}
+
+/// What happened in each stage of a pipeline, in the order the stages ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    /// Each stage's statistics.
+    pub stages: Vec<StageStats>,
+}
+
+impl PipelineStats {
+    /// The total number of optimizations that every stage applied.
+    pub fn rewrites(&self) -> usize {
+        self.stages.iter().map(|s| s.rewrites).sum()
+    }
+
+    /// Did any stage run out of fuel?
+    pub fn out_of_fuel(&self) -> bool {
+        self.stages.iter().any(|s| s.out_of_fuel)
+    }
+}
+
+#[derive(Debug)]
+struct Stage<'peep, 'ctx, I>
+where
+    I: InstructionSet<'ctx>,
+{
+    optimizer: PeepholeOptimizer<'peep, 'ctx, I>,
+    mode: StageMode,
+    max_rewrites: usize,
+}
+
+/// A sequence of peephole optimizers that are applied to a function one after
+/// another.
+///
+/// Each stage sees the function as the stages before it left it: instructions
+/// that an earlier stage replaced are visited as their replacements, and
+/// instructions that it deleted are skipped.
+#[derive(Debug)]
+pub struct Pipeline<'peep, 'ctx, I>
+where
+    I: InstructionSet<'ctx>,
+{
+    stages: Vec<Stage<'peep, 'ctx, I>>,
+}
+
+impl<'peep, 'ctx, I> Default for Pipeline<'peep, 'ctx, I>
+where
+    I: InstructionSet<'ctx>,
+{
+    fn default() -> Self {
+        Pipeline { stages: vec![] }
+    }
+}
+
+impl<'peep, 'ctx, I> Pipeline<'peep, 'ctx, I>
+where
+    I: InstructionSet<'ctx>,
+{
+    /// Create a new pipeline without any stages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a stage that runs the given optimizer after every stage added
+    /// before it.
+    ///
+    /// The stage applies at most `max_rewrites` optimizations. This bounds the
+    /// work done by optimizations that undo each other, or by adversarial
+    /// inputs, within a `Fixpoint` stage.
+    pub fn push_stage(
+        &mut self,
+        optimizer: PeepholeOptimizer<'peep, 'ctx, I>,
+        mode: StageMode,
+        max_rewrites: usize,
+    ) -> &mut Self {
+        self.stages.push(Stage {
+            optimizer,
+            mode,
+            max_rewrites,
+        });
+        self
+    }
+
+    /// The number of stages in this pipeline.
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Does this pipeline have zero stages?
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Get the optimizer of the stage at `index`, e.g. to look at its match
+    /// statistics.
+    pub fn optimizer(&self, index: usize) -> &PeepholeOptimizer<'peep, 'ctx, I> {
+        &self.stages[index].optimizer
+    }
+
+    /// Get the optimizer of the stage at `index`, e.g. to register a
+    /// predicate with it.
+    pub fn optimizer_mut(&mut self, index: usize) -> &mut PeepholeOptimizer<'peep, 'ctx, I> {
+        &mut self.stages[index].optimizer
+    }
+
+    /// Run each stage over the given instructions of a function, in the order
+    /// that the stages were added.
+    ///
+    /// A stage that runs out of fuel stops early, but the stages after it
+    /// still run.
+    pub fn apply_to_function(
+        &mut self,
+        context: &mut I::Context,
+        insts: impl IntoIterator<Item = I::Instruction>,
+    ) -> PipelineStats {
+        log::trace!("Pipeline::apply_to_function");
+
+        let mut insts: Vec<_> = insts.into_iter().map(Some).collect();
+        let mut stats = PipelineStats::default();
+        for stage in &mut self.stages {
+            let fixpoint = stage.mode == StageMode::Fixpoint;
+            let (rewrites, out_of_fuel) =
+                stage
+                    .optimizer
+                    .apply_to_slots(context, &mut insts, stage.max_rewrites, fixpoint);
+            stats.stages.push(StageStats {
+                rewrites,
+                out_of_fuel,
+            });
+        }
+        stats
+    }
+}
@@ -0,0 +1,91 @@
+//! Logs of the rewrites that a peephole optimizer applied.
+//!
+//! These are recorded by a peephole optimizer once
+//! [`PeepholeOptimizer::record_rewrites`][crate::PeepholeOptimizer::record_rewrites]
+//! is called. A log can be serialized into a bug report, and then replayed
+//! with [`PeepholeOptimizer::replay`][crate::PeepholeOptimizer::replay] against
+//! the same starting function to check that the optimizer does exactly the
+//! same thing again.
+
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A single applied rewrite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rewrite<Inst> {
+    /// The root instruction that was rewritten.
+    pub root: Inst,
+
+    /// The index of the optimization that was applied, in the same order as
+    /// [`PeepholeOptimizations::root_operators`][crate::PeepholeOptimizations::root_operators].
+    pub optimization: Option<u32>,
+
+    /// The instruction that replaced the root, or `None` if the root was
+    /// deleted.
+    pub result: Option<Inst>,
+}
+
+/// Every rewrite that a peephole optimizer applied, in the order they were
+/// applied.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RewriteLog<Inst> {
+    /// The applied rewrites.
+    pub rewrites: Vec<Rewrite<Inst>>,
+}
+
+impl<Inst> Default for RewriteLog<Inst> {
+    fn default() -> Self {
+        RewriteLog { rewrites: vec![] }
+    }
+}
+
+impl<Inst> RewriteLog<Inst> {
+    /// Create a new, empty rewrite log.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serialize this log into bytes.
+    pub fn serialize(&self) -> Result<Vec<u8>>
+    where
+        Inst: Serialize,
+    {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Deserialize a log from bytes.
+    pub fn deserialize(serialized: &[u8]) -> Result<Self>
+    where
+        Inst: DeserializeOwned,
+    {
+        Ok(bincode::deserialize(serialized)?)
+    }
+}
+
+/// A replayed rewrite that didn't turn out the same as the logged one.
+///
+/// Returned by [`PeepholeOptimizer::replay`][crate::PeepholeOptimizer::replay].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayMismatch<Inst> {
+    /// The index of the mismatched rewrite in the log.
+    pub index: usize,
+
+    /// The logged rewrite.
+    pub expected: Rewrite<Inst>,
+
+    /// The rewrite that replaying applied instead, or `None` if nothing
+    /// matched the logged root.
+    pub actual: Option<Rewrite<Inst>>,
+}
+
+impl<Inst: fmt::Debug> fmt::Display for ReplayMismatch<Inst> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "replayed rewrite {} does not match the log: expected {:?}, found {:?}",
+            self.index, self.expected, self.actual
+        )
+    }
+}
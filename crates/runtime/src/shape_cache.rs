@@ -0,0 +1,99 @@
+//! A cache of match results for recently matched instruction shapes.
+//!
+//! Functions often contain many instructions that look alike, and matching
+//! each of them walks the same states of the automaton to the same result. A
+//! peephole optimizer with a shape cache, enabled with
+//! [`PeepholeOptimizer::set_shape_cache_capacity`][crate::PeepholeOptimizer::set_shape_cache_capacity],
+//! remembers the result of matching each instruction shape, as defined by
+//! [`InstructionSet::instruction_shape`][crate::instruction_set::InstructionSet::instruction_shape],
+//! and reuses it for the next instruction with the same shape.
+
+use crate::linear::Action;
+use std::collections::HashMap;
+
+/// The result of matching an instruction shape.
+#[derive(Clone, Debug)]
+pub(crate) enum CachedMatch {
+    /// No optimization matched.
+    NoMatch,
+    /// The optimization with the given index matched, with the given actions.
+    ///
+    /// Actions only refer to parts of the instruction sequence by their path
+    /// from the root, so they apply to any root with the same shape.
+    Matched {
+        optimization: Option<u32>,
+        actions: Vec<Action>,
+    },
+}
+
+/// How many times a shape cache was consulted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShapeCacheStats {
+    /// The number of instructions whose match result came from the cache.
+    pub hits: u64,
+    /// The number of instructions that were matched against the automaton,
+    /// because their shape was not in the cache.
+    pub misses: u64,
+}
+
+/// A least-recently-used cache from instruction shapes to match results.
+#[derive(Debug)]
+pub(crate) struct ShapeCache {
+    capacity: usize,
+    /// Each shape's match result, and the tick that it was last used at.
+    entries: HashMap<Vec<u64>, (u64, CachedMatch)>,
+    tick: u64,
+    pub(crate) stats: ShapeCacheStats,
+    /// A buffer for the shape being looked up, kept to reuse its allocation.
+    pub(crate) scratch: Vec<u64>,
+}
+
+impl ShapeCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        ShapeCache {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            tick: 0,
+            stats: ShapeCacheStats::default(),
+            scratch: vec![],
+        }
+    }
+
+    /// Get the cached match result for the given shape, counting a hit or a
+    /// miss.
+    pub(crate) fn get(&mut self, shape: &[u64]) -> Option<&CachedMatch> {
+        self.tick += 1;
+        match self.entries.get_mut(shape) {
+            Some((last_used, cached)) => {
+                self.stats.hits += 1;
+                *last_used = self.tick;
+                Some(cached)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the match result for the given shape, evicting the least recently
+    /// used shape if the cache is full.
+    pub(crate) fn insert(&mut self, shape: &[u64], cached: CachedMatch) {
+        if self.entries.len() == self.capacity && !self.entries.contains_key(shape) {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(shape, _)| shape.clone())
+                .unwrap();
+            self.entries.remove(&lru);
+        }
+        self.entries.insert(shape.to_vec(), (self.tick, cached));
+    }
+
+    /// Forget every cached match result, keeping the statistics.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,87 @@
+//! Statistics about which optimizations match and fire.
+//!
+//! These are recorded by a peephole optimizer once
+//! [`PeepholeOptimizer::record_match_stats`][crate::PeepholeOptimizer::record_match_stats]
+//! is called, so that rule authors can profile which optimizations apply to
+//! real workloads.
+
+use std::io;
+
+/// How many times each optimization matched, and how many of those matches
+/// were applied.
+///
+/// Optimizations are identified by their index, in the same order as
+/// [`PeepholeOptimizations::root_operators`][crate::PeepholeOptimizations::root_operators].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MatchStats {
+    matched: Vec<u64>,
+    fired: Vec<u64>,
+}
+
+impl MatchStats {
+    /// Create new, all-zero statistics for the given number of optimizations.
+    pub fn new(num_optimizations: usize) -> Self {
+        MatchStats {
+            matched: vec![0; num_optimizations],
+            fired: vec![0; num_optimizations],
+        }
+    }
+
+    /// The number of optimizations these statistics are for.
+    pub fn len(&self) -> usize {
+        self.matched.len()
+    }
+
+    /// Are these statistics for zero optimizations?
+    pub fn is_empty(&self) -> bool {
+        self.matched.is_empty()
+    }
+
+    pub(crate) fn record_match(&mut self, optimization: usize) {
+        self.matched[optimization] += 1;
+    }
+
+    pub(crate) fn record_fire(&mut self, optimization: usize) {
+        self.fired[optimization] += 1;
+    }
+
+    /// The number of times that the given optimization was applied.
+    pub fn fire_count(&self, optimization: usize) -> u64 {
+        self.fired[optimization]
+    }
+
+    /// The number of times that the given optimization matched but was not
+    /// applied, e.g. because it lost a conflict in a batch.
+    ///
+    /// Previewing a match doesn't count.
+    pub fn skip_count(&self, optimization: usize) -> u64 {
+        self.matched[optimization] - self.fired[optimization]
+    }
+
+    /// Write these statistics as a tab-separated report, with a header row and
+    /// then one row per optimization.
+    ///
+    /// The columns are `rule-id`, `name`, `fire-count`, and `skip-count`. An
+    /// optimization's name is the first line of its entry in `names`, if any,
+    /// and is empty otherwise. Any tabs in a name are written as spaces.
+    pub fn write_tsv(&self, w: &mut impl io::Write, names: &[Option<String>]) -> io::Result<()> {
+        writeln!(w, "rule-id\tname\tfire-count\tskip-count")?;
+        for i in 0..self.len() {
+            let name = names
+                .get(i)
+                .and_then(|n| n.as_ref())
+                .and_then(|n| n.lines().next())
+                .unwrap_or("")
+                .replace('\t', " ");
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}",
+                i,
+                name,
+                self.fire_count(i),
+                self.skip_count(i)
+            )?;
+        }
+        Ok(())
+    }
+}
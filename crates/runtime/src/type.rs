@@ -45,6 +45,12 @@ pub enum Kind {
 
     /// Void kind.
     Void,
+
+    /// 128-bit SIMD vector kind, made of integer lanes.
+    ///
+    /// A vector type's bit width is the width of each of its lanes, so an
+    /// `i32x4` has a bit width of 32 and four lanes.
+    Vector,
 }
 
 /// A type a value or the result of an operation.
@@ -80,6 +86,12 @@ impl fmt::Display for Type {
         match self.kind {
             Kind::CpuFlags => return write!(f, "cpu-flags"),
             Kind::Void => return write!(f, "void"),
+            Kind::Vector => {
+                return match self.lane_count() {
+                    Some(n) => write!(f, "i{}x{}", self.bit_width as u8, n),
+                    None => write!(f, "iNNxN"),
+                }
+            }
             Kind::Int => write!(f, "i")?,
             Kind::Bool => write!(f, "b")?,
         }
@@ -150,6 +162,25 @@ impl Type {
         cpu_flags(CpuFlags, One);
         /// Get the void type.
         void(Void, One);
+        /// Get the `i8x16` vector type.
+        i8x16(Vector, Eight);
+        /// Get the `i16x8` vector type.
+        i16x8(Vector, Sixteen);
+        /// Get the `i32x4` vector type.
+        i32x4(Vector, ThirtyTwo);
+        /// Get the `i64x2` vector type.
+        i64x2(Vector, SixtyFour);
+    }
+
+    /// Get the number of lanes in this vector type.
+    ///
+    /// Returns `None` if this isn't a vector type, or if its lane width is
+    /// polymorphic.
+    pub fn lane_count(&self) -> Option<u8> {
+        match self.kind {
+            Kind::Vector => self.bit_width.fixed_width().map(|w| 128 / w),
+            _ => None,
+        }
     }
 }
 
@@ -168,6 +199,10 @@ mod tok {
     custom_keyword!(i32);
     custom_keyword!(i64);
     custom_keyword!(i128);
+    custom_keyword!(i8x16);
+    custom_keyword!(i16x8);
+    custom_keyword!(i32x4);
+    custom_keyword!(i64x2);
 }
 
 #[cfg(feature = "construct")]
@@ -257,6 +292,22 @@ impl<'a> wast::parser::Parse<'a> for Type {
                 bit_width: BitWidth::OneTwentyEight,
             });
         }
+        if p.peek::<tok::i8x16>() {
+            p.parse::<tok::i8x16>()?;
+            return Ok(Type::i8x16());
+        }
+        if p.peek::<tok::i16x8>() {
+            p.parse::<tok::i16x8>()?;
+            return Ok(Type::i16x8());
+        }
+        if p.peek::<tok::i32x4>() {
+            p.parse::<tok::i32x4>()?;
+            return Ok(Type::i32x4());
+        }
+        if p.peek::<tok::i64x2>() {
+            p.parse::<tok::i64x2>()?;
+            return Ok(Type::i64x2());
+        }
         Err(p.error("expected an ascribed type"))
     }
 }
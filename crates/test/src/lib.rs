@@ -9,23 +9,37 @@ use peepmatic_runtime::{
     part::{Constant, Part},
     paths::Path,
     r#type::{BitWidth, Kind, Type},
+    PeepholeOptimizations,
 };
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct Instruction(pub usize);
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct InstructionData {
     pub operator: Operator,
     pub r#type: Type,
     pub immediates: Vec<Immediate>,
     pub arguments: Vec<Instruction>,
+    /// Which result of its sole argument this is, or zero if this is not a
+    /// projection of another instruction's result.
+    pub result: u8,
+    pub mem_flags: u8,
+    pub mem_offset: i32,
+    pub block_predecessor_count: Option<u32>,
+    pub is_call: bool,
+    pub use_count: Option<u32>,
+    pub value_range: Option<(u128, u128)>,
+    pub alignment: Option<u32>,
+    pub preceding_store: Option<Instruction>,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Immediate {
     Constant(Constant),
     ConditionCode(ConditionCode),
@@ -73,11 +87,14 @@ impl TryFrom<Part<Instruction>> for Immediate {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Program {
     instr_counter: usize,
     instruction_data: BTreeMap<Instruction, InstructionData>,
     replacements: RefCell<BTreeMap<Instruction, Instruction>>,
+    /// The projection for each of a multi-result instruction's results after
+    /// its first, created by `Program::result`.
+    results: BTreeMap<(Instruction, u8), Instruction>,
 }
 
 impl Program {
@@ -114,6 +131,9 @@ impl Program {
         ensure_eq!(a.operator, b.operator);
         ensure_eq!(a.r#type, b.r#type);
         ensure_eq!(a.immediates, b.immediates);
+        ensure_eq!(a.result, b.result);
+        ensure_eq!(a.mem_flags, b.mem_flags);
+        ensure_eq!(a.mem_offset, b.mem_offset);
         ensure_eq!(a.arguments.len(), b.arguments.len());
         a.arguments
             .clone()
@@ -126,7 +146,47 @@ impl Program {
         self.instruction_data.iter().map(|(k, v)| (*k, v))
     }
 
+    /// Replace `old` with `new`.
+    ///
+    /// If both have the same multiple results, then each of `old`'s result
+    /// projections is replaced by the corresponding projection of `new`, too.
     pub fn replace_instruction(&mut self, old: Instruction, new: Instruction) {
+        let old = self.resolve(old);
+        let new = self.resolve(new);
+        let old_operator = self.data(old).operator;
+        let new_operator = self.data(new).operator;
+        let projections: Vec<_> = self
+            .results
+            .range((old, 1)..=(old, u8::MAX))
+            .map(|(&(_, n), &proj)| (n, proj))
+            .collect();
+
+        self.replace(old, new);
+
+        if projections.is_empty() || old_operator.results_arity() != new_operator.results_arity() {
+            return;
+        }
+        for (n, proj) in projections {
+            let r#type = self.data(proj).r#type;
+            let new_proj = self.result(new, n, r#type);
+            self.replace(proj, new_proj);
+        }
+    }
+
+    /// Replace each of `old`'s results with the corresponding instruction in
+    /// `new`.
+    pub fn replace_instruction_results(&mut self, old: Instruction, new: &[Instruction]) {
+        let old = self.resolve(old);
+        assert_eq!(self.data(old).operator.results_arity() as usize, new.len());
+        for (n, &new) in new.iter().enumerate().skip(1) {
+            if let Some(&proj) = self.results.get(&(old, n as u8)) {
+                self.replace(proj, new);
+            }
+        }
+        self.replace(old, new[0]);
+    }
+
+    fn replace(&mut self, old: Instruction, new: Instruction) {
         log::debug!("replacing {:?} with {:?}", old, new);
 
         let old = self.resolve(old);
@@ -143,6 +203,51 @@ impl Program {
         assert!(old_data.is_some());
     }
 
+    /// Get the projection of the `n`th result of the given multi-result
+    /// instruction, creating it with the given type if it doesn't exist yet.
+    ///
+    /// An instruction used as an argument is its first result, so `n` must be
+    /// at least one.
+    pub fn result(&mut self, inst: Instruction, n: u8, r#type: Type) -> Instruction {
+        let inst = self.resolve(inst);
+        assert!(n >= 1 && n < self.data(inst).operator.results_arity());
+        if let Some(&proj) = self.results.get(&(inst, n)) {
+            return proj;
+        }
+
+        let proj = Instruction(self.instr_counter);
+        self.instr_counter += 1;
+
+        let data = InstructionData {
+            operator: self.data(inst).operator,
+            r#type,
+            immediates: vec![],
+            arguments: vec![inst],
+            result: n,
+            mem_flags: 0,
+            mem_offset: 0,
+            block_predecessor_count: None,
+            is_call: false,
+            use_count: None,
+            value_range: None,
+            alignment: None,
+            preceding_store: None,
+        };
+
+        log::trace!("new result projection: {:?} = {:?}", proj, data);
+        self.instruction_data.insert(proj, data);
+        self.results.insert((inst, n), proj);
+        proj
+    }
+
+    pub fn remove_instruction(&mut self, inst: Instruction) {
+        log::debug!("removing {:?}", inst);
+
+        let inst = self.resolve(inst);
+        let old_data = self.instruction_data.remove(&inst);
+        assert!(old_data.is_some());
+    }
+
     pub fn resolve(&self, inst: Instruction) -> Instruction {
         let mut replacements = self.replacements.borrow_mut();
         let mut replacements_followed = 0;
@@ -167,6 +272,39 @@ impl Program {
         resolved
     }
 
+    /// Format the tree of instructions rooted at `inst` as an s-expression,
+    /// without any `Instruction` ids, so that structurally equivalent trees
+    /// format the same.
+    pub fn format_tree(&self, inst: Instruction) -> String {
+        let inst = self.resolve(inst);
+        let data = match self.instruction_data.get(&inst) {
+            Some(data) => data,
+            None => return "(deleted)".to_string(),
+        };
+        if data.result != 0 {
+            return format!(
+                "(result{{{}}} {} {})",
+                data.r#type,
+                data.result,
+                self.format_tree(data.arguments[0])
+            );
+        }
+        let mut tree = format!("({}{{{}}}", data.operator, data.r#type);
+        for imm in &data.immediates {
+            match imm {
+                Immediate::Constant(Constant::Int(x, _)) => tree.push_str(&format!(" {}", x)),
+                Immediate::Constant(Constant::Bool(b, _)) => tree.push_str(&format!(" {}", b)),
+                Immediate::ConditionCode(cc) => tree.push_str(&format!(" {}", cc)),
+            }
+        }
+        for arg in &data.arguments {
+            tree.push(' ');
+            tree.push_str(&self.format_tree(*arg));
+        }
+        tree.push(')');
+        tree
+    }
+
     pub fn data(&self, inst: Instruction) -> &InstructionData {
         let inst = self.resolve(inst);
         &self.instruction_data[&inst]
@@ -211,6 +349,15 @@ impl Program {
             r#type,
             immediates,
             arguments,
+            result: 0,
+            mem_flags: 0,
+            mem_offset: 0,
+            block_predecessor_count: None,
+            is_call: false,
+            use_count: None,
+            value_range: None,
+            alignment: None,
+            preceding_store: None,
         };
 
         log::trace!("new instruction: {:?} = {:?}", inst, data);
@@ -218,6 +365,71 @@ impl Program {
         inst
     }
 
+    /// Set the memory flags of the given `load` or `store` instruction.
+    pub fn set_mem_flags(&mut self, inst: Instruction, mem_flags: u8) {
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        assert!(matches!(data.operator, Operator::Load | Operator::Store));
+        data.mem_flags = mem_flags;
+    }
+
+    /// Set the immediate offset of the given `load` or `store` instruction.
+    pub fn set_mem_offset(&mut self, inst: Instruction, mem_offset: i32) {
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        assert!(matches!(data.operator, Operator::Load | Operator::Store));
+        data.mem_offset = mem_offset;
+    }
+
+    /// Set the `store` that the given `load` immediately follows, with no
+    /// other writes to memory in between.
+    pub fn set_preceding_store(&mut self, load: Instruction, store: Instruction) {
+        assert_eq!(self.data(store).operator, Operator::Store);
+        let load = self.resolve(load);
+        let data = self.instruction_data.get_mut(&load).unwrap();
+        assert_eq!(data.operator, Operator::Load);
+        data.preceding_store = Some(store);
+    }
+
+    /// Set the number of predecessors of the block containing the given
+    /// instruction.
+    pub fn set_block_predecessor_count(&mut self, inst: Instruction, count: u32) {
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        data.block_predecessor_count = Some(count);
+    }
+
+    /// Mark the given instruction as a call.
+    pub fn set_is_call(&mut self, inst: Instruction) {
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        data.is_call = true;
+    }
+
+    /// Set the number of uses of the given instruction's result.
+    pub fn set_use_count(&mut self, inst: Instruction, count: u32) {
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        data.use_count = Some(count);
+    }
+
+    /// Set the unsigned range, `(min, max)` inclusive, that the given
+    /// instruction's result is known to be within.
+    pub fn set_value_range(&mut self, inst: Instruction, min: u128, max: u128) {
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        data.value_range = Some((min, max));
+    }
+
+    /// Set an alignment, in bytes, that the given instruction's result is
+    /// known to be a multiple of.
+    pub fn set_alignment(&mut self, inst: Instruction, align: u32) {
+        assert!(align.is_power_of_two());
+        let inst = self.resolve(inst);
+        let data = self.instruction_data.get_mut(&inst).unwrap();
+        data.alignment = Some(align);
+    }
+
     pub fn r#const(&mut self, c: Constant, root_bit_width: BitWidth) -> Instruction {
         assert!(!root_bit_width.is_polymorphic());
         match c {
@@ -256,6 +468,40 @@ impl Program {
         }
     }
 
+    /// Make a new integer value of the given type that isn't a constant, for
+    /// optimizations to bind to variables.
+    ///
+    /// The value is a `load` from a constant address, which is out of reach
+    /// of arithmetic rewrites, like `examples/preopt.peepmatic`'s.
+    pub fn opaque(&mut self, r#type: Type) -> Instruction {
+        let w = BitWidth::SixtyFour;
+        let addr = self.r#const(Constant::Int(0x1000, w), w);
+        self.new_instruction(Operator::Load, r#type, vec![], vec![addr])
+    }
+
+    /// Evaluate the boolean expression rooted at the given instruction, whose
+    /// leaves are all `bconst`s.
+    ///
+    /// This is a reference interpreter, independent of the optimizer's own
+    /// constant folding, for checking that rewrites preserve the value of
+    /// constant inputs. Returns `None` for anything but `bconst`, `bnot`,
+    /// `band`, `bor`, and `bxor`.
+    pub fn eval_bool(&self, inst: Instruction) -> Option<bool> {
+        let data = self.data(inst);
+        let arg = |i: usize| self.eval_bool(data.arguments[i]);
+        match data.operator {
+            Operator::Bconst => match data.immediates[0] {
+                Immediate::Constant(Constant::Bool(b, _)) => Some(b),
+                _ => None,
+            },
+            Operator::Bnot => Some(!arg(0)?),
+            Operator::Band => Some(arg(0)? & arg(1)?),
+            Operator::Bor => Some(arg(0)? | arg(1)?),
+            Operator::Bxor => Some(arg(0)? ^ arg(1)?),
+            _ => None,
+        }
+    }
+
     fn instruction_to_constant(&mut self, inst: Instruction) -> Option<Constant> {
         match self.data(inst) {
             InstructionData {
@@ -302,7 +548,7 @@ impl Program {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct TestIsa {
     pub native_word_size_in_bits: u8,
 }
@@ -312,6 +558,8 @@ impl<'a> InstructionSet<'a> for TestIsa {
 
     type Instruction = Instruction;
 
+    type Opcode = Operator;
+
     fn replace_instruction(
         &self,
         program: &mut Program,
@@ -324,6 +572,26 @@ impl<'a> InstructionSet<'a> for TestIsa {
         new
     }
 
+    fn replace_instruction_results(
+        &self,
+        program: &mut Program,
+        old: Instruction,
+        new: &[Part<Instruction>],
+    ) -> Instruction {
+        log::debug!("replace_instruction_results({:?}, {:?})", old, new);
+        let new = new
+            .iter()
+            .map(|part| program.part_to_instruction(old, *part).unwrap())
+            .collect::<Vec<_>>();
+        program.replace_instruction_results(old, &new);
+        new[0]
+    }
+
+    fn remove_instruction(&self, program: &mut Program, inst: Instruction) {
+        log::debug!("remove_instruction({:?})", inst);
+        program.remove_instruction(inst);
+    }
+
     fn get_part_at_path(
         &self,
         program: &mut Program,
@@ -358,9 +626,13 @@ impl<'a> InstructionSet<'a> for TestIsa {
         Some(part)
     }
 
-    fn operator(&self, program: &mut Program, instr: Instruction) -> Option<Operator> {
-        log::debug!("operator({:?})", instr);
+    fn opcode(&self, program: &mut Program, instr: Instruction) -> Option<Operator> {
+        log::debug!("opcode({:?})", instr);
         let data = program.data(instr);
+        if data.result != 0 {
+            // Result projections aren't instructions of their own.
+            return None;
+        }
         Some(data.operator)
     }
 
@@ -454,7 +726,8 @@ impl<'a> InstructionSet<'a> for TestIsa {
         c: Part<Instruction>,
     ) -> Instruction {
         log::debug!(
-            "make_inst_3(\n\toperator = {:?},\n\ttype = {},\n\ta = {:?},\n\tb = {:?},\n\tc = {:?},\n)",
+            "make_inst_3(\n\toperator = {:?},\n\ttype = {},\n\ta = {:?},\n\tb = {:?},\n\tc = {:?},\
+             \n)",
             operator,
             r#type,
             a,
@@ -509,6 +782,54 @@ impl<'a> InstructionSet<'a> for TestIsa {
         program.new_instruction(operator, r#type, imms, args)
     }
 
+    fn make_load(
+        &self,
+        program: &mut Program,
+        root: Instruction,
+        r#type: Type,
+        addr: Part<Instruction>,
+        offset: i32,
+        flags: u8,
+    ) -> Instruction {
+        log::debug!(
+            "make_load(\n\ttype = {},\n\taddr = {:?},\n\toffset = {},\n\tflags = {:#x},\n)",
+            r#type,
+            addr,
+            offset,
+            flags,
+        );
+        let addr = program.part_to_instruction(root, addr).unwrap();
+        let inst = program.new_instruction(Operator::Load, r#type, vec![], vec![addr]);
+        program.set_mem_offset(inst, offset);
+        program.set_mem_flags(inst, flags);
+        inst
+    }
+
+    fn make_store(
+        &self,
+        program: &mut Program,
+        root: Instruction,
+        value: Part<Instruction>,
+        addr: Part<Instruction>,
+        offset: i32,
+        flags: u8,
+    ) -> Instruction {
+        log::debug!(
+            "make_store(\n\tvalue = {:?},\n\taddr = {:?},\n\toffset = {},\n\tflags = {:#x},\n)",
+            value,
+            addr,
+            offset,
+            flags,
+        );
+        let value = program.part_to_instruction(root, value).unwrap();
+        let addr = program.part_to_instruction(root, addr).unwrap();
+        let inst =
+            program.new_instruction(Operator::Store, Type::void(), vec![], vec![value, addr]);
+        program.set_mem_offset(inst, offset);
+        program.set_mem_flags(inst, flags);
+        inst
+    }
+
     fn instruction_to_constant(
         &self,
         program: &mut Program,
@@ -524,8 +845,485 @@ impl<'a> InstructionSet<'a> for TestIsa {
         ty.bit_width.fixed_width().unwrap()
     }
 
+    fn instruction_mem_flags(&self, program: &mut Program, inst: Instruction) -> Option<u8> {
+        log::debug!("instruction_mem_flags({:?})", inst);
+        let data = program.data(inst);
+        match data.operator {
+            Operator::Load | Operator::Store => Some(data.mem_flags),
+            _ => None,
+        }
+    }
+
+    fn instruction_mem_offset(&self, program: &mut Program, inst: Instruction) -> Option<i32> {
+        log::debug!("instruction_mem_offset({:?})", inst);
+        let data = program.data(inst);
+        match data.operator {
+            Operator::Load | Operator::Store => Some(data.mem_offset),
+            _ => None,
+        }
+    }
+
+    fn instruction_preceding_store(
+        &self,
+        program: &mut Program,
+        load: Instruction,
+    ) -> Option<Instruction> {
+        log::debug!("instruction_preceding_store({:?})", load);
+        program.data(load).preceding_store
+    }
+
+    fn instruction_block_predecessor_count(
+        &self,
+        program: &mut Program,
+        inst: Instruction,
+    ) -> Option<u32> {
+        log::debug!("instruction_block_predecessor_count({:?})", inst);
+        program.data(inst).block_predecessor_count
+    }
+
+    fn instruction_is_call(&self, program: &mut Program, inst: Instruction) -> bool {
+        log::debug!("instruction_is_call({:?})", inst);
+        program.data(inst).is_call
+    }
+
+    fn instruction_use_count(&self, program: &mut Program, inst: Instruction) -> Option<u32> {
+        log::debug!("instruction_use_count({:?})", inst);
+        program.data(inst).use_count
+    }
+
+    fn instruction_value_range(
+        &self,
+        program: &mut Program,
+        inst: Instruction,
+    ) -> Option<(u128, u128)> {
+        log::debug!("instruction_value_range({:?})", inst);
+        program.data(inst).value_range
+    }
+
+    fn instruction_alignment(&self, program: &mut Program, inst: Instruction) -> Option<u32> {
+        log::debug!("instruction_alignment({:?})", inst);
+        program.data(inst).alignment
+    }
+
+    /// The shape of an instruction is every instruction data field of it and
+    /// of everything below it, including preceding stores, with instructions
+    /// that appear more than once numbered in the order they are first seen.
+    /// Only instructions with at most `MAX_SHAPE_INSTRUCTIONS` of those have a
+    /// shape.
+    fn instruction_shape(
+        &self,
+        program: &mut Program,
+        inst: Instruction,
+        shape: &mut Vec<u64>,
+    ) -> bool {
+        log::debug!("instruction_shape({:?})", inst);
+        push_shape(program, inst, &mut vec![], shape)
+    }
+
     fn native_word_size_in_bits(&self, _program: &mut Program) -> u8 {
         log::debug!("native_word_size_in_bits");
         self.native_word_size_in_bits
     }
 }
+
+/// The most instructions that `TestIsa::instruction_shape` will describe.
+const MAX_SHAPE_INSTRUCTIONS: usize = 64;
+
+/// Push the shape of `inst` onto `shape`, given the instructions `seen` so far.
+fn push_shape(
+    program: &Program,
+    inst: Instruction,
+    seen: &mut Vec<Instruction>,
+    shape: &mut Vec<u64>,
+) -> bool {
+    fn push_u128(shape: &mut Vec<u64>, x: u128) {
+        shape.push(x as u64);
+        shape.push((x >> 64) as u64);
+    }
+
+    fn push_option(shape: &mut Vec<u64>, x: Option<u32>) {
+        match x {
+            Some(x) => shape.extend_from_slice(&[1, u64::from(x)]),
+            None => shape.push(0),
+        }
+    }
+
+    let inst = program.resolve(inst);
+    if let Some(i) = seen.iter().position(|&s| s == inst) {
+        shape.extend_from_slice(&[0, i as u64]);
+        return true;
+    }
+    if seen.len() == MAX_SHAPE_INSTRUCTIONS {
+        return false;
+    }
+    seen.push(inst);
+
+    let data = program.data(inst);
+    shape.extend_from_slice(&[
+        1,
+        data.operator as u64,
+        data.r#type.kind as u64,
+        data.r#type.bit_width as u64,
+        u64::from(data.result),
+        u64::from(data.mem_flags),
+        u64::from(data.mem_offset as u32),
+        u64::from(data.is_call),
+    ]);
+    push_option(shape, data.block_predecessor_count);
+    push_option(shape, data.use_count);
+    push_option(shape, data.alignment);
+    match data.value_range {
+        Some((lo, hi)) => {
+            shape.push(1);
+            push_u128(shape, lo);
+            push_u128(shape, hi);
+        }
+        None => shape.push(0),
+    }
+
+    shape.push(data.immediates.len() as u64);
+    for imm in &data.immediates {
+        match *imm {
+            Immediate::Constant(Constant::Int(x, w)) => {
+                shape.push(0);
+                push_u128(shape, x);
+                shape.push(w as u64);
+            }
+            Immediate::Constant(Constant::Bool(b, w)) => {
+                shape.extend_from_slice(&[1, u64::from(b), w as u64]);
+            }
+            Immediate::ConditionCode(cc) => shape.extend_from_slice(&[2, cc as u64]),
+        }
+    }
+
+    match data.preceding_store {
+        Some(store) => {
+            shape.push(1);
+            if !push_shape(program, store, seen, shape) {
+                return false;
+            }
+        }
+        None => shape.push(0),
+    }
+
+    shape.push(data.arguments.len() as u64);
+    data.arguments
+        .iter()
+        .all(|&arg| push_shape(program, arg, seen, shape))
+}
+
+/// An order to visit a function's instructions in, for `check_confluence`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraversalOrder {
+    /// Visit instructions in layout order: definitions before their uses.
+    TopDown,
+    /// Visit instructions in reverse layout order: uses before the definitions
+    /// they use.
+    BottomUp,
+    /// Visit instructions in a pseudo-random order, shuffled with the given
+    /// seed.
+    Shuffled(u64),
+}
+
+impl TraversalOrder {
+    fn arrange(self, insts: &mut [Instruction]) {
+        match self {
+            TraversalOrder::TopDown => {}
+            TraversalOrder::BottomUp => insts.reverse(),
+            TraversalOrder::Shuffled(seed) => {
+                // A Fisher-Yates shuffle driven by splitmix64, which is plenty
+                // random for picking visiting orders.
+                let mut state = seed;
+                let mut next = || {
+                    state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^ (z >> 31)
+                };
+                for i in (1..insts.len()).rev() {
+                    let j = (next() % (i as u64 + 1)) as usize;
+                    insts.swap(i, j);
+                }
+            }
+        }
+    }
+}
+
+/// The first difference found by `check_confluence`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The traversal order that the others were compared against.
+    pub expected_order: TraversalOrder,
+    /// The traversal order that rewrote the function differently.
+    pub actual_order: TraversalOrder,
+    /// The index of the first root whose rewritten tree differs.
+    pub root: usize,
+    /// That root's rewritten tree in `expected_order`.
+    pub expected: String,
+    /// That root's rewritten tree in `actual_order`.
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "root {} rewrites to {} when visited {:?}, but to {} when visited {:?}",
+            self.root, self.expected, self.expected_order, self.actual, self.actual_order
+        )
+    }
+}
+
+/// Check that a ruleset is confluent over a sample function: that rewriting the
+/// function until no optimization applies gives the same result no matter
+/// which order its instructions are visited in.
+///
+/// Each of the `orders` starts from a fresh copy of `program`, and rewrites
+/// `insts` (usually every instruction in the function, in layout order) with
+/// [`apply_to_function`][peepmatic_runtime::PeepholeOptimizer::apply_to_function],
+/// visiting them in that order. Then the trees rooted at each of `roots` are
+/// compared structurally against the result from the first order.
+///
+/// Returns the first difference, if any.
+pub fn check_confluence(
+    opts: &PeepholeOptimizations,
+    isa: TestIsa,
+    program: &Program,
+    insts: &[Instruction],
+    roots: &[Instruction],
+    orders: &[TraversalOrder],
+    max_rewrites: usize,
+) -> Result<(), Divergence> {
+    let rewrite = |order: TraversalOrder| {
+        let mut program = program.clone();
+        let mut insts = insts.to_vec();
+        order.arrange(&mut insts);
+        opts.optimizer(isa)
+            .apply_to_function(&mut program, insts, max_rewrites);
+        roots
+            .iter()
+            .map(|root| program.format_tree(*root))
+            .collect::<Vec<_>>()
+    };
+
+    let (expected_order, rest) = match orders.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let expected = rewrite(*expected_order);
+    for actual_order in rest {
+        let actual = rewrite(*actual_order);
+        if let Some(root) = (0..roots.len()).find(|i| expected[*i] != actual[*i]) {
+            return Err(Divergence {
+                expected_order: *expected_order,
+                actual_order: *actual_order,
+                root,
+                expected: expected[root].clone(),
+                actual: actual[root].clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A function before and after optimization, for `check_golden`.
+#[derive(Clone, Copy, Debug)]
+pub struct GoldenPair {
+    /// The name of this pair, for reporting mismatches.
+    pub name: &'static str,
+    /// Build the function to optimize into an empty program, returning its
+    /// root.
+    pub input: fn(&mut Program) -> Instruction,
+    /// Build the expected result of optimizing `input` into another empty
+    /// program, returning its root.
+    pub expected: fn(&mut Program) -> Instruction,
+}
+
+/// The first golden pair that `check_golden` found to optimize differently
+/// than expected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoldenMismatch {
+    /// The name of the mismatched pair.
+    pub name: &'static str,
+    /// The expected tree.
+    pub expected: String,
+    /// The tree that the input's root was actually optimized into.
+    pub actual: String,
+}
+
+impl fmt::Display for GoldenMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` optimizes to {}, but {} was expected",
+            self.name, self.actual, self.expected
+        )
+    }
+}
+
+/// Check that the given optimizations rewrite each golden pair's input into
+/// its expected output.
+///
+/// Every instruction of each input is rewritten, in layout order, with
+/// [`apply_to_function`][peepmatic_runtime::PeepholeOptimizer::apply_to_function].
+/// Then the tree rooted at the input's root is compared structurally against
+/// the tree rooted at the expected root.
+///
+/// Returns the first mismatch, if any.
+pub fn check_golden(
+    opts: &PeepholeOptimizations,
+    isa: TestIsa,
+    pairs: &[GoldenPair],
+    max_rewrites: usize,
+) -> Result<(), GoldenMismatch> {
+    for pair in pairs {
+        let mut program = Program::default();
+        let root = (pair.input)(&mut program);
+        let insts: Vec<_> = program.instructions().map(|(inst, _)| inst).collect();
+        opts.optimizer(isa)
+            .apply_to_function(&mut program, insts, max_rewrites);
+        let actual = program.format_tree(root);
+
+        let mut program = Program::default();
+        let root = (pair.expected)(&mut program);
+        let expected = program.format_tree(root);
+
+        if actual != expected {
+            return Err(GoldenMismatch {
+                name: pair.name,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Golden pairs for some of the common rewrites in
+/// `examples/preopt.peepmatic`, for use with `check_golden`.
+pub fn preopt_golden_pairs() -> Vec<GoldenPair> {
+    const W: BitWidth = BitWidth::ThirtyTwo;
+
+    fn iadd_imm(program: &mut Program, c: u128, x: Instruction) -> Instruction {
+        let c = Constant::Int(c, W).into();
+        program.new_instruction(Operator::IaddImm, Type::i32(), vec![c], vec![x])
+    }
+
+    vec![
+        GoldenPair {
+            name: "iadd-to-iadd-imm",
+            input: |program| {
+                let x = program.opaque(Type::i32());
+                let five = program.r#const(Constant::Int(5, W), W);
+                program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five])
+            },
+            expected: |program| {
+                let x = program.opaque(Type::i32());
+                iadd_imm(program, 5, x)
+            },
+        },
+        GoldenPair {
+            name: "fold-nested-iadd-imm",
+            input: |program| {
+                let x = program.opaque(Type::i32());
+                let one = program.r#const(Constant::Int(1, W), W);
+                let inner =
+                    program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, one]);
+                let two = program.r#const(Constant::Int(2, W), W);
+                program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![inner, two])
+            },
+            expected: |program| {
+                let x = program.opaque(Type::i32());
+                iadd_imm(program, 3, x)
+            },
+        },
+        GoldenPair {
+            name: "remove-isub-zero",
+            input: |program| {
+                let x = program.opaque(Type::i32());
+                let zero = program.r#const(Constant::Int(0, W), W);
+                program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![x, zero])
+            },
+            expected: |program| program.opaque(Type::i32()),
+        },
+        GoldenPair {
+            name: "shifts-to-uextend",
+            input: |program| {
+                let x = program.opaque(Type::i32());
+                let amount = program.r#const(Constant::Int(24, W), W);
+                let ishl =
+                    program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, amount]);
+                program.new_instruction(Operator::Ushr, Type::i32(), vec![], vec![ishl, amount])
+            },
+            expected: |program| {
+                let x = program.opaque(Type::i32());
+                let ireduce =
+                    program.new_instruction(Operator::Ireduce, Type::i8(), vec![], vec![x]);
+                program.new_instruction(Operator::Uextend, Type::i32(), vec![], vec![ireduce])
+            },
+        },
+    ]
+}
+
+/// Compile `source` down to linear optimizations, format them with
+/// `peepmatic::format_linear`, and check the result against the snapshot file
+/// at `snapshot`.
+///
+/// When the `PEEPMATIC_BLESS` environment variable is set, the snapshot file is
+/// (re)written with the current output instead. Use this after an intentional
+/// change to the linearizer or the linear passes, and review the diff.
+pub fn assert_linear_snapshot(source: &str, snapshot: &std::path::Path) {
+    let opts = compile_snapshot_source(source, snapshot);
+    let actual = peepmatic::format_linear(&opts);
+    assert_snapshot(&actual, snapshot, "linear optimizations");
+}
+
+/// Like `assert_linear_snapshot`, but check the linear optimizations exported
+/// with `peepmatic::linear_to_json`, so that the snapshot file is a golden copy
+/// of the JSON format.
+///
+/// Use `PEEPMATIC_BLESS` after an intentional change to the format, too, and
+/// bump `peepmatic::LINEAR_JSON_VERSION` along with it.
+pub fn assert_linear_json_snapshot(source: &str, snapshot: &std::path::Path) {
+    let opts = compile_snapshot_source(source, snapshot);
+    let actual = peepmatic::linear_to_json(&opts) + "\n";
+    assert_snapshot(&actual, snapshot, "exported linear optimizations");
+}
+
+fn compile_snapshot_source(
+    source: &str,
+    snapshot: &std::path::Path,
+) -> peepmatic_runtime::linear::Optimizations {
+    match peepmatic::compile_to_linear(source, snapshot) {
+        Ok(opts) => opts,
+        Err(e) => panic!("failed to compile {}:\n{}", snapshot.display(), e),
+    }
+}
+
+fn assert_snapshot(actual: &str, snapshot: &std::path::Path, what: &str) {
+    if std::env::var_os("PEEPMATIC_BLESS").is_some() {
+        std::fs::write(snapshot, actual)
+            .unwrap_or_else(|e| panic!("failed to write {}: {}", snapshot.display(), e));
+        return;
+    }
+
+    let expected = std::fs::read_to_string(snapshot).unwrap_or_else(|e| {
+        panic!(
+            "failed to read {}: {}; run with PEEPMATIC_BLESS=1 to create it",
+            snapshot.display(),
+            e
+        )
+    });
+    if actual != expected {
+        panic!(
+            "{} don't match the snapshot at {}; run with PEEPMATIC_BLESS=1 to update it\n\n\
+             expected:\n{}\n\
+             actual:\n{}",
+            what,
+            snapshot.display(),
+            expected,
+            actual
+        );
+    }
+}
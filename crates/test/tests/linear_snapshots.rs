@@ -0,0 +1,76 @@
+//! Snapshot tests for the linear optimizations that DSL source compiles to,
+//! and for their JSON export.
+//!
+//! Run with `PEEPMATIC_BLESS=1` to update the snapshots in `tests/snapshots`.
+
+use peepmatic_test::{assert_linear_json_snapshot, assert_linear_snapshot};
+use std::path::PathBuf;
+
+macro_rules! snapshot {
+    ($name:ident, $source:expr) => {
+        snapshot!($name, $source, "linear", assert_linear_snapshot);
+    };
+    ($name:ident, $source:expr, $extension:literal, $assert:ident) => {
+        #[test]
+        fn $name() {
+            let _ = env_logger::try_init();
+            let snapshot: PathBuf = [
+                env!("CARGO_MANIFEST_DIR"),
+                "tests",
+                "snapshots",
+                concat!(stringify!($name), ".", $extension),
+            ]
+            .iter()
+            .collect();
+            $assert($source, &snapshot);
+        }
+    };
+}
+
+snapshot!(iadd_zero, "(=> (iadd $x 0) $x)");
+
+snapshot!(
+    shared_prefix,
+    "
+(=> (iadd $x 0) $x)
+(=> (iadd $x $C) (iadd_imm $C $x))
+"
+);
+
+snapshot!(
+    precondition_and_unquote,
+    "
+(=> (when (imul $x $C)
+          (is-power-of-two $C))
+    (ishl $x $(log2 $C)))
+"
+);
+
+snapshot!(
+    condition_code_and_repeated_variable,
+    "(=> (bint (icmp eq $x $x)) 1)"
+);
+
+snapshot!(phase, "(=> (phase lowering) (bnot (bnot $x)) $x)");
+
+mod json {
+    use super::*;
+
+    snapshot!(
+        iadd_zero,
+        "(=> (iadd $x 0) $x)",
+        "json",
+        assert_linear_json_snapshot
+    );
+
+    snapshot!(
+        precondition_and_unquote,
+        "
+(=> (when (imul $x $C)
+          (is-power-of-two $C))
+    (ishl $x $(log2 $C)))
+",
+        "json",
+        assert_linear_json_snapshot
+    );
+}
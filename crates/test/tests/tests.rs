@@ -1,8 +1,11 @@
 use peepmatic_runtime::{
     cc::ConditionCode,
+    linear::{Action, MatchOp, Phase},
     operator::Operator,
-    part::Constant,
+    optimizer::ApplyOrder,
+    part::{Constant, Part},
     r#type::{BitWidth, Type},
+    stats::MatchStats,
 };
 use peepmatic_test::*;
 
@@ -64,6 +67,49 @@ fn constant() {
     assert!(replacement.is_none());
 }
 
+#[test]
+fn make_zero_and_one() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (bxor $x $x) 0)
+(=> (bint true) 1)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::SixtyFour;
+    let two = program.r#const(Constant::Int(2, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i64(), vec![], vec![three, two]);
+
+    // The results are built without interned integers, and take on the
+    // rewritten instruction's type.
+    let bxor = program.new_instruction(Operator::Bxor, Type::i64(), vec![], vec![x, x]);
+    let (optimization, actions) = optimizer.preview_one(&mut program, bxor).unwrap();
+    assert_eq!(optimization, 0);
+    assert!(actions.contains(&Action::MakeZero {
+        bit_width: BitWidth::Polymorphic
+    }));
+    let new = optimizer.apply_one(&mut program, bxor);
+    let new = new.expect("optimization should have applied");
+    let zero = program.r#const(Constant::Int(0, w), w);
+    assert!(program.structurally_eq(new, zero));
+
+    let t = program.r#const(Constant::Bool(true, BitWidth::One), BitWidth::One);
+    let bint = program.new_instruction(Operator::Bint, Type::i64(), vec![], vec![t]);
+    let (optimization, actions) = optimizer.preview_one(&mut program, bint).unwrap();
+    assert_eq!(optimization, 1);
+    assert!(actions.contains(&Action::MakeOne {
+        bit_width: BitWidth::Polymorphic
+    }));
+    let new = optimizer.apply_one(&mut program, bint);
+    let new = new.expect("optimization should have applied");
+    let one = program.r#const(Constant::Int(1, w), w);
+    assert!(program.structurally_eq(new, one));
+}
+
 #[test]
 fn boolean() {
     let opts;
@@ -244,6 +290,218 @@ fn unquote_neg() {
     assert!(program.structurally_eq(new, iadd_imm));
 }
 
+#[test]
+fn unquote_arithmetic_wraps() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd (iadd $x $C1) $C2)
+    (iadd $x $(iadd $C1 $C2)))
+(=> (isub (iadd $x $C1) $C2)
+    (iadd $x $(isub $C1 $C2)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::Eight;
+    let x = program.opaque(Type::i8());
+    let hundred = program.r#const(Constant::Int(100, w), w);
+    let seventy = program.r#const(Constant::Int(70, w), w);
+
+    // `100 + 100` wraps around to `-56` at `i8`.
+    let inner = program.new_instruction(Operator::Iadd, Type::i8(), vec![], vec![x, hundred]);
+    let outer = program.new_instruction(Operator::Iadd, Type::i8(), vec![], vec![inner, hundred]);
+    let sum = program.r#const(Constant::Int(-56 as _, w), w);
+    let expected = program.new_instruction(Operator::Iadd, Type::i8(), vec![], vec![x, sum]);
+    let new = optimizer.apply_one(&mut program, outer);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // And `70 - 100` is `-30`.
+    let inner = program.new_instruction(Operator::Iadd, Type::i8(), vec![], vec![x, seventy]);
+    let outer = program.new_instruction(Operator::Isub, Type::i8(), vec![], vec![inner, hundred]);
+    let difference = program.r#const(Constant::Int(-30 as _, w), w);
+    let expected = program.new_instruction(Operator::Iadd, Type::i8(), vec![], vec![x, difference]);
+    let new = optimizer.apply_one(&mut program, outer);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn negation_fits_in_bits() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd_imm $C $x) (negation-fits-in-bits $C 8))
+    (isub $x $(neg $C)))
+"
+    );
+
+    let mut program = Program::default();
+    let mut applies = |ty: Type, c: i128| {
+        let w = ty.bit_width;
+        let one = program.r#const(Constant::Int(1, w), w);
+        let x = program.new_instruction(Operator::Imul, ty, vec![], vec![one, one]);
+        let c = (c as u128) & (std::u128::MAX >> (128 - u32::from(w.fixed_width().unwrap())));
+        let iadd_imm = program.new_instruction(
+            Operator::IaddImm,
+            ty,
+            vec![Constant::Int(c, w).into()],
+            vec![x],
+        );
+        optimizer.apply_one(&mut program, iadd_imm).is_some()
+    };
+
+    assert!(applies(Type::i32(), 128));
+    assert!(!applies(Type::i32(), 129));
+    assert!(applies(Type::i32(), -127));
+    assert!(!applies(Type::i32(), -128));
+
+    // Negation wraps at the constant's bit width, so `-128` is its own
+    // negation at `i8`.
+    assert!(applies(Type::i8(), -128));
+    assert!(applies(Type::i8(), 127));
+}
+
+#[test]
+fn is_mask_const() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (when (band $x $m) (is-mask-const $m 8)) (band $x 255))"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let x = program.opaque(Type::i32());
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let seven = program.r#const(Constant::Int(7, w), w);
+    let eight = program.r#const(Constant::Int(8, w), w);
+    let ff = program.r#const(Constant::Int(0xff, w), w);
+    let expected = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, ff]);
+
+    // A direct mask constant.
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, ff]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // A mask computed as `(isub (ishl 1 8) 1)`.
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![one, eight]);
+    let mask = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![ishl, one]);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, mask]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // Masks of a different number of bits don't match, whichever way they are
+    // spelled.
+    let seven_f = program.r#const(Constant::Int(0x7f, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, seven_f]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![one, seven]);
+    let mask = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![ishl, one]);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, mask]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    // Nor do computations that aren't masks at all.
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![two, eight]);
+    let not_mask = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![ishl, one]);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, not_mask]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+    let ff_00 = program.r#const(Constant::Int(0xff00, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, ff_00]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn is_scaled_by() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd (imul $x $A) (imul $y $B))
+          (is-scaled-by $A $B 4))
+    (imul (iadd (imul $x 4) $y) $B))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let int = |program: &mut Program, x| program.r#const(Constant::Int(x, w), w);
+    let five = int(&mut program, 5);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five, five]);
+    let y = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, five]);
+    let scaled_sum = |program: &mut Program, a, b| {
+        let a = int(program, a);
+        let b = int(program, b);
+        let xa = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, a]);
+        let yb = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![y, b]);
+        program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![xa, yb])
+    };
+
+    // 8 = 2 * 4.
+    let iadd = scaled_sum(&mut program, 8, 2);
+    let four = int(&mut program, 4);
+    let two = int(&mut program, 2);
+    let x4 = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, four]);
+    let sum = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x4, y]);
+    let expected = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![sum, two]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // 8 != 3 * 4.
+    let iadd = scaled_sum(&mut program, 8, 3);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    // The multiplication wraps around: 0x4000_0000 * 4 is 0 in 32 bits.
+    let iadd = scaled_sum(&mut program, 0, 0x4000_0000);
+    assert!(optimizer.apply_one(&mut program, iadd).is_some());
+}
+
+#[test]
+fn is_width_minus() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (sshr $x $C) (is-width-minus $C 1))
+    (isub 0 (ushr $x $C)))
+"
+    );
+
+    let mut program = Program::default();
+    let mut check = |ty: Type, amount: u128, should_apply: bool| {
+        let w = ty.bit_width;
+        let one = program.r#const(Constant::Int(1, w), w);
+        let x = program.new_instruction(Operator::Iadd, ty, vec![], vec![one, one]);
+        let c = program.r#const(Constant::Int(amount, w), w);
+        let sshr = program.new_instruction(Operator::Sshr, ty, vec![], vec![x, c]);
+        let new = optimizer.apply_one(&mut program, sshr);
+        if !should_apply {
+            assert!(new.is_none());
+            return;
+        }
+        let zero = program.r#const(Constant::Int(0, w), w);
+        let ushr = program.new_instruction(Operator::Ushr, ty, vec![], vec![x, c]);
+        let expected = program.new_instruction(Operator::Isub, ty, vec![], vec![zero, ushr]);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, expected));
+    };
+
+    // The same rule matches the sign-bit shift at each width.
+    check(Type::i32(), 31, true);
+    check(Type::i64(), 63, true);
+
+    // But not the other width's sign-bit shift.
+    check(Type::i32(), 63, false);
+    check(Type::i64(), 31, false);
+}
+
 #[test]
 fn subsumption() {
     let opts;
@@ -391,3 +649,3322 @@ fn polymorphic_bit_widths() {
     let new = new.expect("optimization should have applied");
     assert!(program.structurally_eq(new, iadd_imm));
 }
+
+#[test]
+fn equivalent_values() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (isub $x $y)
+          (equivalent-values $x $y))
+    0)
+"
+    );
+
+    let mut program = Program::default();
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    // The same SSA value.
+    let a = program.r#const(Constant::Int(3, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let b = program.r#const(Constant::Int(7, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![a, b]);
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![imul, imul]);
+
+    let new = optimizer.apply_one(&mut program, isub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // Different SSA values that are equal constants.
+    let five_a = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let five_b = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five_a, five_b]);
+
+    let new = optimizer.apply_one(&mut program, isub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // Neither the same SSA value nor equal constants.
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![imul, five_a]);
+    let replacement = optimizer.apply_one(&mut program, isub);
+    assert!(replacement.is_none());
+
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five_a, a]);
+    let replacement = optimizer.apply_one(&mut program, isub);
+    assert!(replacement.is_none());
+}
+
+#[test]
+fn const_eq() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd (iconst $C) (imul $x (iconst $C))) (imul_imm $C (iadd_imm 1 $x)))
+(=> (isub $C $C) 0)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![three, three]);
+
+    // Different `iconst` instructions with the same value.
+    let five_a = program.r#const(Constant::Int(5, w), w);
+    let five_b = program.r#const(Constant::Int(5, w), w);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, five_b]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five_a, imul]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_some());
+
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five_a, five_b]);
+    let new = optimizer.apply_one(&mut program, isub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // Different values.
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, three]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five_a, imul]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five_a, three]);
+    assert!(optimizer.apply_one(&mut program, isub).is_none());
+
+    // Values that aren't constants don't match, even when they are the same.
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![x, x]);
+    assert!(optimizer.apply_one(&mut program, isub).is_none());
+}
+
+#[test]
+fn select_spectre_guard() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (select $c $x $y) (select_spectre_guard $c $x $y))"
+    );
+
+    let mut program = Program::default();
+    let c = program.r#const(Constant::Bool(true, BitWidth::One), BitWidth::One);
+    let x = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let y = program.r#const(Constant::Int(2, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let select = program.new_instruction(Operator::Select, Type::i32(), vec![], vec![c, x, y]);
+
+    let expected = program.new_instruction(
+        Operator::SelectSpectreGuard,
+        Type::i32(),
+        vec![],
+        vec![c, x, y],
+    );
+
+    let new = optimizer.apply_one(&mut program, select);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn max_match_depth() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (iadd (iadd (iadd (iadd $x $a) $b) $c) $d) $x)"
+    );
+
+    let mut program = Program::default();
+    let one = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let mut chain = Vec::with_capacity(10_000);
+    let mut prev = one;
+    for _ in 0..10_000 {
+        prev = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![prev, one]);
+        chain.push(prev);
+    }
+
+    // Matches within the default depth.
+    let new = optimizer.apply_one(&mut program, prev);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, chain[chain.len() - 5]));
+
+    // The deepest opcode check in the pattern is at depth 3.
+    optimizer.set_max_match_depth(2);
+    let replacement = optimizer.apply_one(&mut program, chain[chain.len() - 2]);
+    assert!(replacement.is_none());
+
+    optimizer.set_max_match_depth(3);
+    let new = optimizer.apply_one(&mut program, chain[chain.len() - 2]);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, chain[chain.len() - 6]));
+}
+
+#[test]
+fn one_of() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> ((one-of (opcode band) (opcode bor)) $x $x) $x)");
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let six = program.r#const(Constant::Int(6, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![five, five]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, five));
+
+    let bor = program.new_instruction(Operator::Bor, Type::i32(), vec![], vec![six, six]);
+    let new = optimizer.apply_one(&mut program, bor);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, six));
+
+    let bxor = program.new_instruction(Operator::Bxor, Type::i32(), vec![], vec![five, five]);
+    let replacement = optimizer.apply_one(&mut program, bxor);
+    assert!(replacement.is_none());
+
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![five, six]);
+    let replacement = optimizer.apply_one(&mut program, band);
+    assert!(replacement.is_none());
+}
+
+#[test]
+fn mem_flags() {
+    use peepmatic_runtime::mem_flags::{ALIGNED, READONLY};
+
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (when (iadd $l 0) (mem-flags $l 4 4)) $l)");
+
+    let mut program = Program::default();
+    let addr = program.r#const(Constant::Int(64, BitWidth::SixtyFour), BitWidth::SixtyFour);
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    let readonly_load = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![addr]);
+    program.set_mem_flags(readonly_load, READONLY | ALIGNED);
+    let add = program.new_instruction(
+        Operator::Iadd,
+        Type::i32(),
+        vec![],
+        vec![readonly_load, zero],
+    );
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, readonly_load));
+
+    let plain_load = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![addr]);
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![plain_load, zero]);
+    let replacement = optimizer.apply_one(&mut program, add);
+    assert!(replacement.is_none());
+
+    // Not a memory access at all.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![zero, zero]);
+    let replacement = optimizer.apply_one(&mut program, add);
+    assert!(replacement.is_none());
+}
+
+#[test]
+fn root_operators() {
+    use peepmatic_runtime::PeepholeOptimizations;
+
+    let opts = peepmatic::compile_str(
+        "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+(=> (imul $x 0) 0)
+",
+        std::path::Path::new("peepmatic-test"),
+    )
+    .unwrap();
+
+    let path = std::env::temp_dir().join("peepmatic-test-root-operators.bin");
+    opts.serialize_to_file(&path).unwrap();
+    let serialized = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    let opts = PeepholeOptimizations::deserialize(&serialized).unwrap();
+    let mut root_operators = opts.root_operators.clone();
+    root_operators.sort_by_key(|op| op.map(|op| op as u32));
+    assert_eq!(
+        root_operators,
+        vec![
+            Some(Operator::Iadd),
+            Some(Operator::Imul),
+            Some(Operator::Imul)
+        ]
+    );
+
+    let index = opts.root_operator_index();
+    assert_eq!(index.len(), 2);
+    assert_eq!(index[&Some(Operator::Iadd)].len(), 1);
+    assert_eq!(index[&Some(Operator::Imul)].len(), 2);
+
+    let mut optimizer = opts.optimizer(TEST_ISA);
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    assert!(optimizer.might_match(&mut program, add));
+
+    let sub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five, zero]);
+    assert!(!optimizer.might_match(&mut program, sub));
+    assert!(optimizer.apply_one(&mut program, sub).is_none());
+}
+
+#[test]
+fn root_operators_with_variable_root() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> $x $x)");
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let sub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five, five]);
+    assert!(optimizer.might_match(&mut program, sub));
+}
+
+#[test]
+fn fold_load_offset() {
+    use peepmatic_runtime::mem_flags::NOTRAP;
+
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (load (iadd_imm 8 $p)) (load offset=8 flags=1 $p))"
+    );
+
+    let mut program = Program::default();
+    let p = program.r#const(Constant::Int(64, BitWidth::SixtyFour), BitWidth::SixtyFour);
+    let addr = program.new_instruction(
+        Operator::IaddImm,
+        Type::i64(),
+        vec![Constant::Int(8, BitWidth::SixtyFour).into()],
+        vec![p],
+    );
+    let load = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![addr]);
+
+    let expected = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![p]);
+    program.set_mem_offset(expected, 8);
+    program.set_mem_flags(expected, NOTRAP);
+
+    let new = optimizer.apply_one(&mut program, load);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+    assert_eq!(program.data(new).mem_offset, 8);
+}
+
+#[test]
+fn fold_load_offset_keeps_matched_offset_and_flags() {
+    use peepmatic_runtime::mem_flags::{ALIGNED, NOTRAP, READONLY};
+
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (load (iadd_imm 8 $p)) (load offset=8 $p))
+(=> (store $x (iadd_imm 16 $p)) (store offset=16 $x $p))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::SixtyFour;
+    let p = program.r#const(Constant::Int(64, w), w);
+    let eight = Constant::Int(8, w).into();
+    let p_plus_8 = program.new_instruction(Operator::IaddImm, Type::i64(), vec![eight], vec![p]);
+
+    // The matched load's own offset and flags are kept, not replaced.
+    let load = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![p_plus_8]);
+    program.set_mem_offset(load, 4);
+    program.set_mem_flags(load, ALIGNED | READONLY);
+    let expected = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![p]);
+    program.set_mem_offset(expected, 12);
+    program.set_mem_flags(expected, ALIGNED | READONLY);
+    let new = optimizer.apply_one(&mut program, load);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // Likewise for stores, including negative offsets.
+    let sixteen = Constant::Int(16, w).into();
+    let p_plus_16 = program.new_instruction(Operator::IaddImm, Type::i64(), vec![sixteen], vec![p]);
+    let store = program.new_instruction(Operator::Store, Type::void(), vec![], vec![p, p_plus_16]);
+    program.set_mem_offset(store, -4);
+    program.set_mem_flags(store, NOTRAP);
+    let expected = program.new_instruction(Operator::Store, Type::void(), vec![], vec![p, p]);
+    program.set_mem_offset(expected, 12);
+    program.set_mem_flags(expected, NOTRAP);
+    let new = optimizer.apply_one(&mut program, store);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // Folding doesn't apply when the new offset would overflow.
+    let load = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![p_plus_8]);
+    program.set_mem_offset(load, std::i32::MAX - 4);
+    assert!(optimizer.apply_one(&mut program, load).is_none());
+}
+
+#[test]
+fn extract_lane_of_insert_lane() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (extractlane 0 (insertlane 0 $v $x)) $x)
+(=> (extractlane 1 (insertlane 0 $v $x)) (extractlane 1 $v))
+(=> (insertlane{i32x4} 3 (insertlane 3 $v $x) $y) (insertlane 3 $v $y))
+"
+    );
+
+    let mut program = Program::default();
+    let lane = |i| vec![Constant::Int(i, BitWidth::Eight).into()];
+    let v = program.opaque(Type::i32x4());
+    let x = program.opaque(Type::i32());
+    let y = program.opaque(Type::i32());
+    let inserted =
+        program.new_instruction(Operator::Insertlane, Type::i32x4(), lane(0), vec![v, x]);
+
+    let extracted =
+        program.new_instruction(Operator::Extractlane, Type::i32(), lane(0), vec![inserted]);
+    let new = optimizer.apply_one(&mut program, extracted);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    let extracted =
+        program.new_instruction(Operator::Extractlane, Type::i32(), lane(1), vec![inserted]);
+    let expected = program.new_instruction(Operator::Extractlane, Type::i32(), lane(1), vec![v]);
+    let new = optimizer.apply_one(&mut program, extracted);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    let inserted_3 =
+        program.new_instruction(Operator::Insertlane, Type::i32x4(), lane(3), vec![v, x]);
+    let reinserted = program.new_instruction(
+        Operator::Insertlane,
+        Type::i32x4(),
+        lane(3),
+        vec![inserted_3, y],
+    );
+    let expected =
+        program.new_instruction(Operator::Insertlane, Type::i32x4(), lane(3), vec![v, y]);
+    let new = optimizer.apply_one(&mut program, reinserted);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn forward_stored_value() {
+    use peepmatic_runtime::mem_flags::NOTRAP;
+
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (when $l (mem-flags $l 1 1)) (stored-value $l))");
+
+    let mut program = Program::default();
+    let p = program.r#const(Constant::Int(64, BitWidth::SixtyFour), BitWidth::SixtyFour);
+    let q = program.r#const(Constant::Int(128, BitWidth::SixtyFour), BitWidth::SixtyFour);
+    let value = program.opaque(Type::i32());
+    let store = program.new_instruction(Operator::Store, Type::void(), vec![], vec![value, p]);
+    program.set_mem_offset(store, 8);
+
+    let load = |program: &mut Program, ty, addr, offset, flags| {
+        let load = program.new_instruction(Operator::Load, ty, vec![], vec![addr]);
+        program.set_mem_offset(load, offset);
+        program.set_mem_flags(load, flags);
+        program.set_preceding_store(load, store);
+        load
+    };
+
+    // A load of what the store before it just wrote reuses the stored value.
+    let l = load(&mut program, Type::i32(), p, 8, NOTRAP);
+    let new = optimizer.apply_one(&mut program, l);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, value));
+
+    // But not when the load reads from a different address or offset, reads a
+    // different width, or doesn't have the flags the rule asks for.
+    let l = load(&mut program, Type::i32(), q, 8, NOTRAP);
+    assert!(optimizer.apply_one(&mut program, l).is_none());
+
+    let l = load(&mut program, Type::i32(), p, 0, NOTRAP);
+    assert!(optimizer.apply_one(&mut program, l).is_none());
+
+    let l = load(&mut program, Type::i64(), p, 8, NOTRAP);
+    assert!(optimizer.apply_one(&mut program, l).is_none());
+
+    let l = load(&mut program, Type::i32(), p, 8, 0);
+    assert!(optimizer.apply_one(&mut program, l).is_none());
+
+    // Nor when there is no known store before the load.
+    let l = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![p]);
+    program.set_mem_offset(l, 8);
+    program.set_mem_flags(l, NOTRAP);
+    assert!(optimizer.apply_one(&mut program, l).is_none());
+}
+
+#[test]
+fn fold_bnot_of_icmp() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (bnot (icmp $CC $a $b)) (icmp $(invert_cc $CC) $a $b))
+(=> (bxor (icmp $CC $a $b) true) (icmp $(invert_cc $CC) $a $b))
+"
+    );
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let six = program.r#const(Constant::Int(6, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let t = program.r#const(Constant::Bool(true, BitWidth::One), BitWidth::One);
+
+    for &cc in &[
+        ConditionCode::Eq,
+        ConditionCode::Ne,
+        ConditionCode::Slt,
+        ConditionCode::Ult,
+        ConditionCode::Sge,
+        ConditionCode::Uge,
+        ConditionCode::Sgt,
+        ConditionCode::Ugt,
+        ConditionCode::Sle,
+        ConditionCode::Ule,
+        ConditionCode::Of,
+        ConditionCode::Nof,
+    ] {
+        // Both `(bnot x)` and `(bxor x true)` negate a comparison.
+        let icmp =
+            program.new_instruction(Operator::Icmp, Type::b1(), vec![cc.into()], vec![five, six]);
+        let bnot = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![icmp]);
+        let bxor = program.new_instruction(Operator::Bxor, Type::b1(), vec![], vec![icmp, t]);
+
+        for &negation in &[bnot, bxor] {
+            let expected = program.new_instruction(
+                Operator::Icmp,
+                Type::b1(),
+                vec![cc.inverse().into()],
+                vec![five, six],
+            );
+
+            let new = optimizer.apply_one(&mut program, negation);
+            let new = new.expect("optimization should have applied");
+            assert!(program.structurally_eq(new, expected), "{}", cc);
+        }
+        assert_eq!(cc.inverse().inverse(), cc);
+    }
+}
+
+#[test]
+fn duplicate_left_hand_sides_prefer_source_order() {
+    for _ in 0..3 {
+        let opts;
+        let mut optimizer = optimizer!(
+            opts,
+            "
+(=> (iadd $x 0) $x)
+(=> (iadd $x 0) (iadd 0 $x))
+"
+        );
+
+        let mut program = Program::default();
+        let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+        let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+        let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+
+        let new = optimizer.apply_one(&mut program, add);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, five));
+    }
+}
+
+#[test]
+fn is_one_less_than() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (isub (iadd $x $C2) $C1)
+          (is-one-less-than $C1 $C2))
+    (iadd_imm 1 $x))
+"
+    );
+
+    let mut program = Program::default();
+    let isub_of_iadd = |program: &mut Program, c1: u128, c2: u128, ty: Type| {
+        let width = ty.bit_width;
+        let a = program.r#const(Constant::Int(3, width), width);
+        let b = program.r#const(Constant::Int(7, width), width);
+        let x = program.new_instruction(Operator::Imul, ty, vec![], vec![a, b]);
+        let c1 = program.r#const(Constant::Int(c1, width), width);
+        let c2 = program.r#const(Constant::Int(c2, width), width);
+        let iadd = program.new_instruction(Operator::Iadd, ty, vec![], vec![x, c2]);
+        let isub = program.new_instruction(Operator::Isub, ty, vec![], vec![iadd, c1]);
+        (x, isub)
+    };
+
+    let (x, isub) = isub_of_iadd(&mut program, 4, 5, Type::i32());
+    let expected = program.new_instruction(
+        Operator::IaddImm,
+        Type::i32(),
+        vec![Constant::Int(1, BitWidth::ThirtyTwo).into()],
+        vec![x],
+    );
+    let new = optimizer.apply_one(&mut program, isub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    let (_, isub) = isub_of_iadd(&mut program, 4, 6, Type::i32());
+    assert!(optimizer.apply_one(&mut program, isub).is_none());
+
+    // `0xff` is not one less than `0` at 8 bits, even though it would be with
+    // wraparound.
+    let (_, isub) = isub_of_iadd(&mut program, 0xff, 0, Type::i8());
+    assert!(optimizer.apply_one(&mut program, isub).is_none());
+
+    let (x, isub) = isub_of_iadd(&mut program, 0xfe, 0xff, Type::i8());
+    let expected = program.new_instruction(
+        Operator::IaddImm,
+        Type::i8(),
+        vec![Constant::Int(1, BitWidth::Eight).into()],
+        vec![x],
+    );
+    let new = optimizer.apply_one(&mut program, isub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn wide_integers() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (imul $x 0x1_0000_0000_0000_0000) (ishl $x 64))
+(=> (when (isub $x 1)
+          (bit-width $x 128))
+    (iadd $x 0xffff_ffff_ffff_ffff_ffff_ffff_ffff_ffff))
+(=> (band $x -1) $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::OneTwentyEight;
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.opaque(Type::i128());
+
+    // Constants beyond 64 bits match exactly...
+    let c = program.r#const(Constant::Int(1 << 64, w), w);
+    let imul = program.new_instruction(Operator::Imul, Type::i128(), vec![], vec![x, c]);
+    let sixty_four = program.r#const(Constant::Int(64, w), w);
+    let expected =
+        program.new_instruction(Operator::Ishl, Type::i128(), vec![], vec![x, sixty_four]);
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // ...and are not truncated to their low 64 bits.
+    let c = program.r#const(Constant::Int((1 << 64) | 1, w), w);
+    let imul = program.new_instruction(Operator::Imul, Type::i128(), vec![], vec![x, c]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+    let imul = program.new_instruction(Operator::Imul, Type::i128(), vec![], vec![x, three]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+
+    // Constants beyond 64 bits can be constructed.
+    let one = program.r#const(Constant::Int(1, w), w);
+    let isub = program.new_instruction(Operator::Isub, Type::i128(), vec![], vec![x, one]);
+    let all_ones = program.r#const(Constant::Int(std::u128::MAX, w), w);
+    let expected = program.new_instruction(Operator::Iadd, Type::i128(), vec![], vec![x, all_ones]);
+    let new = optimizer.apply_one(&mut program, isub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // Negative literals match all-ones constants at any width.
+    let w = BitWidth::Eight;
+    let y = program.opaque(Type::i8());
+    let ff = program.r#const(Constant::Int(0xff, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i8(), vec![], vec![y, ff]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, y));
+
+    let seven_f = program.r#const(Constant::Int(0x7f, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i8(), vec![], vec![y, seven_f]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn iconst_like() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (icmp ult $x 1)
+    (icmp eq $x (iconst-like $x 0)))
+"
+    );
+
+    let mut program = Program::default();
+    for &(w, ty) in &[
+        (BitWidth::ThirtyTwo, Type::i32()),
+        (BitWidth::SixtyFour, Type::i64()),
+    ] {
+        let x = program.opaque(ty);
+        let one = program.r#const(Constant::Int(1, w), BitWidth::One);
+        let icmp = program.new_instruction(
+            Operator::Icmp,
+            Type::b1(),
+            vec![ConditionCode::Ult.into()],
+            vec![x, one],
+        );
+
+        // The zero takes `$x`'s width, not the `b1` root's width.
+        let zero = program.r#const(Constant::Int(0, w), BitWidth::One);
+        let expected = program.new_instruction(
+            Operator::Icmp,
+            Type::b1(),
+            vec![ConditionCode::Eq.into()],
+            vec![x, zero],
+        );
+        let new = optimizer.apply_one(&mut program, icmp);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, expected));
+    }
+}
+
+#[test]
+fn is_extension() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (ushr $x 32)
+          (is-uextend $x 32))
+    0)
+(=> (when (sshr $x 32)
+          (is-sextend $x 32))
+    (sshr $x 63))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::SixtyFour;
+    let a = program.opaque(Type::i32());
+    let thirty_two = program.r#const(Constant::Int(32, w), w);
+
+    // A `sextend` matches with its signedness and source width.
+    let sext = program.new_instruction(Operator::Sextend, Type::i64(), vec![], vec![a]);
+    let sshr = program.new_instruction(Operator::Sshr, Type::i64(), vec![], vec![sext, thirty_two]);
+    let sixty_three = program.r#const(Constant::Int(63, w), w);
+    let expected =
+        program.new_instruction(Operator::Sshr, Type::i64(), vec![], vec![sext, sixty_three]);
+    let new = optimizer.apply_one(&mut program, sshr);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // ...but is not mistaken for a `uextend`.
+    let ushr = program.new_instruction(Operator::Ushr, Type::i64(), vec![], vec![sext, thirty_two]);
+    assert!(optimizer.apply_one(&mut program, ushr).is_none());
+
+    let uext = program.new_instruction(Operator::Uextend, Type::i64(), vec![], vec![a]);
+    let ushr = program.new_instruction(Operator::Ushr, Type::i64(), vec![], vec![uext, thirty_two]);
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let new = optimizer.apply_one(&mut program, ushr);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // Extensions from other widths don't match.
+    let b = program.new_instruction(Operator::Ireduce, Type::i16(), vec![], vec![a]);
+    let uext = program.new_instruction(Operator::Uextend, Type::i64(), vec![], vec![b]);
+    let ushr = program.new_instruction(Operator::Ushr, Type::i64(), vec![], vec![uext, thirty_two]);
+    assert!(optimizer.apply_one(&mut program, ushr).is_none());
+
+    // Plain values that aren't extensions don't match either.
+    let x = program.new_instruction(
+        Operator::Imul,
+        Type::i64(),
+        vec![],
+        vec![thirty_two, thirty_two],
+    );
+    let ushr = program.new_instruction(Operator::Ushr, Type::i64(), vec![], vec![x, thirty_two]);
+    assert!(optimizer.apply_one(&mut program, ushr).is_none());
+    let sshr = program.new_instruction(Operator::Sshr, Type::i64(), vec![], vec![x, thirty_two]);
+    assert!(optimizer.apply_one(&mut program, sshr).is_none());
+}
+
+#[test]
+fn single_use() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x $y)
+          (single-use $x))
+    (iadd $y $x))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let five = program.r#const(Constant::Int(5, w), w);
+
+    let x = program.opaque(Type::i32());
+    program.set_use_count(x, 1);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    let expected = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, x]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // Multiply-used values don't match.
+    let x = program.opaque(Type::i32());
+    program.set_use_count(x, 2);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    // Neither do values with an unknown number of uses.
+    let x = program.opaque(Type::i32());
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+}
+
+#[test]
+fn all_operands_const() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when $x
+          (all-operands-const $x))
+    $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+
+    // `(iadd 1 2)` matches.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, two]);
+    assert!(optimizer.preview_one(&mut program, iadd).is_some());
+
+    // `(iadd 1 x)` does not, when `x` isn't a constant.
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![one, two]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, x]);
+    assert!(optimizer.preview_one(&mut program, iadd).is_none());
+
+    // Nor do the constants themselves.
+    assert!(optimizer.preview_one(&mut program, one).is_none());
+}
+
+#[test]
+fn select_const_arms() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x $s)
+          (select-const-arms $s))
+    (iadd $s $x))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let c = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![one, one]);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![one, one]);
+
+    // `(select c 1 0)` has constant arms.
+    let select = program.new_instruction(Operator::Select, Type::i32(), vec![], vec![c, one, zero]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, select]);
+    assert!(optimizer.preview_one(&mut program, iadd).is_some());
+
+    // `(select c x 0)` does not.
+    let select = program.new_instruction(Operator::Select, Type::i32(), vec![], vec![c, x, zero]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, select]);
+    assert!(optimizer.preview_one(&mut program, iadd).is_none());
+
+    // Nor does an instruction that isn't a `select`.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, c]);
+    assert!(optimizer.preview_one(&mut program, iadd).is_none());
+}
+
+#[test]
+fn fold_const() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when $x
+          (all-operands-const $x))
+    (fold $x))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let six = program.r#const(Constant::Int(6, w), w);
+
+    // `(iadd 1 2)` folds to 3.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, two]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    let expected = program.r#const(Constant::Int(3, w), w);
+    assert!(program.structurally_eq(new, expected));
+
+    // `(band 6 3)` folds to 2.
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![six, three]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    let expected = program.r#const(Constant::Int(2, w), w);
+    assert!(program.structurally_eq(new, expected));
+
+    // Operations that we don't know how to fold are left alone.
+    let udiv = program.new_instruction(Operator::Udiv, Type::i32(), vec![], vec![six, three]);
+    assert!(optimizer.apply_one(&mut program, udiv).is_none());
+}
+
+#[test]
+fn swapped_icmp_operands() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (icmp ult $x 0) false)");
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), BitWidth::One);
+    let x = program.opaque(Type::i32());
+    let f = program.r#const(Constant::Bool(false, BitWidth::One), BitWidth::One);
+
+    let icmp_ult = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![x, zero],
+    );
+    let new = optimizer.apply_one(&mut program, icmp_ult);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, f));
+
+    // The same comparison, written the other way around, also matches.
+    let icmp_ugt = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ugt.into()],
+        vec![zero, x],
+    );
+    let new = optimizer.apply_one(&mut program, icmp_ugt);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, f));
+
+    // But swapping only the operands, or only the condition code, does not.
+    let icmp_ult = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![zero, x],
+    );
+    assert!(optimizer.apply_one(&mut program, icmp_ult).is_none());
+    let icmp_ugt = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ugt.into()],
+        vec![x, zero],
+    );
+    assert!(optimizer.apply_one(&mut program, icmp_ugt).is_none());
+}
+
+#[test]
+fn nested_shifts_combine() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (ishl (ishl $x $A) $B)
+          (shifts-combine $A $B))
+    (ishl $x $(iadd $A $B)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let x = program.opaque(Type::i32());
+
+    // 8 + 16 < 32, so the shifts combine.
+    let eight = program.r#const(Constant::Int(8, w), w);
+    let sixteen = program.r#const(Constant::Int(16, w), w);
+    let inner = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, eight]);
+    let outer = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![inner, sixteen]);
+    let twenty_four = program.r#const(Constant::Int(24, w), w);
+    let expected =
+        program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, twenty_four]);
+    let new = optimizer.apply_one(&mut program, outer);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // 16 + 20 overflows the 32-bit width, so they don't.
+    let twenty = program.r#const(Constant::Int(20, w), w);
+    let inner = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, sixteen]);
+    let outer = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![inner, twenty]);
+    assert!(optimizer.apply_one(&mut program, outer).is_none());
+}
+
+#[test]
+fn nested_shifts_combine_edge_cases() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (ishl (ishl $x $A) $B)
+          (shifts-combine $A $B))
+    (ishl $x $(iadd $A $B)))
+(=> (when (ushr (ushr $x $A) $B)
+          (shifts-combine $A $B))
+    (ushr $x $(iadd $A $B)))
+(=> (when (sshr (sshr $x $A) $B)
+          (shifts-combine $A $B))
+    (sshr $x $(iadd $A $B)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let x = program.opaque(Type::i32());
+    let shift = |program: &mut Program, operator, x, amount| {
+        let amount = program.r#const(Constant::Int(amount, w), w);
+        program.new_instruction(operator, Type::i32(), vec![], vec![x, amount])
+    };
+
+    // Right shifts combine the same way that left shifts do.
+    for &operator in &[Operator::Ushr, Operator::Sshr] {
+        let inner = shift(&mut program, operator, x, 1);
+        let outer = shift(&mut program, operator, inner, 30);
+        let expected = shift(&mut program, operator, x, 31);
+        let new = optimizer.apply_one(&mut program, outer);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, expected));
+    }
+
+    // Amounts that sum to exactly the bit width would shift by zero once the
+    // combined amount is masked, so they don't combine.
+    for &operator in &[Operator::Ishl, Operator::Ushr, Operator::Sshr] {
+        let inner = shift(&mut program, operator, x, 16);
+        let outer = shift(&mut program, operator, inner, 16);
+        assert!(optimizer.apply_one(&mut program, outer).is_none());
+    }
+
+    // Nor does an amount that is out of range on its own, even when the sum
+    // would wrap back around into range.
+    let inner = shift(&mut program, Operator::Ishl, x, 1);
+    let outer = shift(&mut program, Operator::Ishl, inner, std::u32::MAX as u128);
+    assert!(optimizer.apply_one(&mut program, outer).is_none());
+
+    // And shifts in different directions never combine.
+    let inner = shift(&mut program, Operator::Ushr, x, 1);
+    let outer = shift(&mut program, Operator::Ishl, inner, 2);
+    assert!(optimizer.apply_one(&mut program, outer).is_none());
+}
+
+#[test]
+fn disabled_optimization() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (disabled) (imul $x 2) (ishl $x 1))
+(=> (iadd $x 0) $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let three = program.r#const(Constant::Int(3, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![three, two]);
+
+    // The disabled optimization never fires by default, even though it is
+    // present, while the enabled one does.
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, two]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    // Until the optimizer opts into it.
+    optimizer.set_enable_disabled(true);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let expected = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, one]);
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn delete_dead_instruction() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (iadd $x $y) (delete))");
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+
+    // A dead `iadd` is removed from the program entirely.
+    let dead = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, two]);
+    program.set_use_count(dead, 0);
+    assert!(optimizer.apply_one(&mut program, dead).is_none());
+    assert!(program.instructions().all(|(inst, _)| inst != dead));
+
+    // But one whose result is still used, or whose uses are unknown, is kept.
+    let used = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, two]);
+    program.set_use_count(used, 1);
+    assert!(optimizer.apply_one(&mut program, used).is_none());
+    assert!(program.instructions().any(|(inst, _)| inst == used));
+
+    let unknown = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, two]);
+    assert!(optimizer.apply_one(&mut program, unknown).is_none());
+    assert!(program.instructions().any(|(inst, _)| inst == unknown));
+}
+
+#[test]
+fn bounded_by() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (when (icmp ult $x 256) (bounded-by $x 0 255)) true)"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let limit = program.r#const(Constant::Int(256, w), w);
+    let t = program.r#const(Constant::Bool(true, BitWidth::One), BitWidth::One);
+
+    // A value that is known to be in range makes the comparison always true.
+    let x = program.opaque(Type::i32());
+    program.set_value_range(x, 0, 100);
+    let icmp = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![x, limit],
+    );
+    let new = optimizer.apply_one(&mut program, icmp);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, t));
+
+    // But a value whose range is unknown, or too wide, does not match.
+    let y = program.opaque(Type::i32());
+    let icmp = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![y, limit],
+    );
+    assert!(optimizer.apply_one(&mut program, icmp).is_none());
+
+    let z = program.opaque(Type::i32());
+    program.set_value_range(z, 0, 300);
+    let icmp = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![z, limit],
+    );
+    assert!(optimizer.apply_one(&mut program, icmp).is_none());
+
+    // A negative constant is bounded by its value at its own bit width, not by
+    // its sign-extended bits.
+    let c = program.r#const(Constant::Int(-56i128 as u128, BitWidth::Eight), w);
+    let icmp = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![c, limit],
+    );
+    let new = optimizer.apply_one(&mut program, icmp);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, t));
+}
+
+#[test]
+fn is_aligned() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (when (band $p 7) (is-aligned $p 8)) 0)");
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let seven = program.r#const(Constant::Int(7, w), w);
+
+    // A constant address whose low bits are zero.
+    let p = program.r#const(Constant::Int(0x1000, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![p, seven]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // And a negative constant, whose sign-extended bits are ignored.
+    let p = program.r#const(Constant::Int(-16i128 as u128, BitWidth::Eight), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![p, seven]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // An address that is known to be aligned enough.
+    let p = program.opaque(Type::i32());
+    program.set_alignment(p, 16);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![p, seven]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+
+    // But a misaligned constant, or an address whose alignment is unknown or
+    // too small, does not match.
+    let p = program.r#const(Constant::Int(0x1004, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![p, seven]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    let p = program.opaque(Type::i32());
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![p, seven]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    let p = program.opaque(Type::i32());
+    program.set_alignment(p, 4);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![p, seven]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn high_bits_zero() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (when (band $x 255) (high-bits-zero $x 8)) $x)");
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let mask = program.r#const(Constant::Int(255, w), w);
+
+    // A value that is known to be zero-extended from 8 bits doesn't need
+    // masking.
+    let x = program.opaque(Type::i32());
+    program.set_value_range(x, 0, 200);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, mask]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    // Neither does a small enough constant.
+    let c = program.r#const(Constant::Int(0x7f, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![c, mask]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, c));
+
+    // Or a negative constant that is narrow enough, whose sign-extended bits
+    // are ignored.
+    let c = program.r#const(Constant::Int(-1i128 as u128, BitWidth::Eight), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![c, mask]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, c));
+
+    // But a value that may have high bits set, or whose range is unknown, does
+    // not match.
+    let y = program.opaque(Type::i32());
+    program.set_value_range(y, 0, 256);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![y, mask]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    let c = program.r#const(Constant::Int(0x1ff, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![c, mask]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    let z = program.opaque(Type::i32());
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![z, mask]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn icmp_zero() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (when (bint $c) (icmp-zero $c ne)) (bint $c))");
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![one, one]);
+    let icmp = |program: &mut Program, cc: ConditionCode, y| {
+        let icmp = program.new_instruction(Operator::Icmp, Type::b1(), vec![cc.into()], vec![x, y]);
+        program.new_instruction(Operator::Bint, Type::i32(), vec![], vec![icmp])
+    };
+
+    // `(icmp ne $x 0)` matches.
+    let bint = icmp(&mut program, ConditionCode::Ne, zero);
+    assert!(optimizer.preview_one(&mut program, bint).is_some());
+
+    // But not a comparison against anything else, or with another condition
+    // code.
+    let bint = icmp(&mut program, ConditionCode::Ne, one);
+    assert!(optimizer.preview_one(&mut program, bint).is_none());
+    let bint = icmp(&mut program, ConditionCode::Eq, zero);
+    assert!(optimizer.preview_one(&mut program, bint).is_none());
+}
+
+#[test]
+fn double_involution() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (bnot (bnot $x)) (is-double-involution $x)) $x)
+(=> (when ((one-of (opcode ineg) (opcode iabs)) ((one-of (opcode ineg) (opcode iabs)) $x))
+          (is-double-involution $x))
+    $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.opaque(Type::i32());
+
+    // Double `bnot` cancels out.
+    let cc = ConditionCode::Eq.into();
+    let b = program.new_instruction(Operator::Icmp, Type::b1(), vec![cc], vec![x, three]);
+    let inner = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![b]);
+    let outer = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![inner]);
+    let new = optimizer.apply_one(&mut program, outer);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, b));
+
+    // So does double `ineg`.
+    let inner = program.new_instruction(Operator::Ineg, Type::i32(), vec![], vec![x]);
+    let outer = program.new_instruction(Operator::Ineg, Type::i32(), vec![], vec![inner]);
+    let new = optimizer.apply_one(&mut program, outer);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    // But `iabs` isn't its own inverse, and neither is `ineg` of `iabs`.
+    let inner = program.new_instruction(Operator::Iabs, Type::i32(), vec![], vec![x]);
+    let outer = program.new_instruction(Operator::Iabs, Type::i32(), vec![], vec![inner]);
+    assert!(optimizer.apply_one(&mut program, outer).is_none());
+
+    let outer = program.new_instruction(Operator::Ineg, Type::i32(), vec![], vec![inner]);
+    assert!(optimizer.apply_one(&mut program, outer).is_none());
+}
+
+#[test]
+fn boolean_simplifications() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (bnot (bnot $x)) $x)
+(=> (bnot (band $a $b)) (bor (bnot $a) (bnot $b)))
+(=> (bnot (bor $a $b)) (band (bnot $a) (bnot $b)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::One;
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), w);
+    let six = program.r#const(Constant::Int(6, BitWidth::ThirtyTwo), w);
+    let cc = ConditionCode::Eq.into();
+    let a = program.new_instruction(Operator::Icmp, Type::b1(), vec![cc], vec![five, six]);
+    let b = program.new_instruction(Operator::Icmp, Type::b1(), vec![cc], vec![six, five]);
+
+    // Double negation is eliminated.
+    let bnot = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![a]);
+    let bnot = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![bnot]);
+    let new = optimizer.apply_one(&mut program, bnot);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, a));
+
+    // Negated conjunctions and disjunctions are pushed into their operands,
+    // swapping `band` and `bor`.
+    for &(from, to) in &[
+        (Operator::Band, Operator::Bor),
+        (Operator::Bor, Operator::Band),
+    ] {
+        let inner = program.new_instruction(from, Type::b1(), vec![], vec![a, b]);
+        let bnot = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![inner]);
+        let not_a = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![a]);
+        let not_b = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![b]);
+        let expected = program.new_instruction(to, Type::b1(), vec![], vec![not_a, not_b]);
+        let new = optimizer.apply_one(&mut program, bnot);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, expected));
+    }
+
+    // And every rewrite preserves the value of every combination of constant
+    // inputs.
+    for &x in &[false, true] {
+        for &y in &[false, true] {
+            let x = program.r#const(Constant::Bool(x, w), w);
+            let y = program.r#const(Constant::Bool(y, w), w);
+            let not_x = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![x]);
+            let band = program.new_instruction(Operator::Band, Type::b1(), vec![], vec![x, y]);
+            let bor = program.new_instruction(Operator::Bor, Type::b1(), vec![], vec![x, y]);
+            for &operand in &[not_x, band, bor] {
+                let bnot =
+                    program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![operand]);
+                let expected = program.eval_bool(bnot).unwrap();
+                let new = optimizer.apply_one(&mut program, bnot);
+                let new = new.expect("optimization should have applied");
+                assert_eq!(program.eval_bool(new), Some(expected));
+            }
+        }
+    }
+}
+
+#[test]
+fn match_observers() {
+    use std::cell::{Cell, RefCell};
+
+    // These must outlive the optimizer, which holds its observers.
+    let seen = RefCell::new(vec![]);
+    let count = Cell::new(0);
+
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+"
+    );
+    optimizer.add_match_observer(|index| seen.borrow_mut().push(index));
+    optimizer.add_match_observer(|_| count.set(count.get() + 1));
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![two, one]);
+
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_some());
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, one]);
+    assert!(optimizer.apply_one(&mut program, imul).is_some());
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, two]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+
+    // Each observer saw both matches, and only those, and the indices tell us
+    // which optimizations they were.
+    let seen: Vec<_> = seen
+        .borrow()
+        .iter()
+        .map(|&index| opts.root_operators[index])
+        .collect();
+    assert_eq!(seen, vec![Some(Operator::Iadd), Some(Operator::Imul)]);
+    assert_eq!(count.get(), 2);
+}
+
+#[test]
+fn replay_rewrite_log() {
+    use peepmatic_runtime::rewrite_log::RewriteLog;
+
+    fn sample_function(program: &mut Program) -> Vec<Instruction> {
+        let w = BitWidth::ThirtyTwo;
+        let zero = program.r#const(Constant::Int(0, w), w);
+        let one = program.r#const(Constant::Int(1, w), w);
+        let two = program.r#const(Constant::Int(2, w), w);
+        let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![two, one]);
+        let a = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, two]);
+        let b = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![a, zero]);
+        let c = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![b, one]);
+        let d = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![c, x]);
+        vec![x, a, b, c, d]
+    }
+
+    fn print(program: &Program) -> String {
+        format!("{:?}", program.instructions().collect::<Vec<_>>())
+    }
+
+    let source = "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+(=> (imul $x 2) (ishl $x 1))
+";
+
+    // Record the rewrites of an optimization run, and send them through a
+    // bug report.
+    let opts;
+    let mut optimizer = optimizer!(opts, source);
+    optimizer.record_rewrites();
+    let mut program = Program::default();
+    let insts = sample_function(&mut program);
+    assert!(!optimizer.apply_to_function(&mut program, insts, 100));
+    let expected = print(&program);
+    let log = optimizer.take_rewrite_log().unwrap();
+    assert_eq!(log.rewrites.len(), 3);
+    let log = RewriteLog::<Instruction>::deserialize(&log.serialize().unwrap()).unwrap();
+
+    // Replaying them against the same starting function reproduces the same
+    // output.
+    let mut optimizer = opts.optimizer(TEST_ISA);
+    let mut program = Program::default();
+    sample_function(&mut program);
+    assert_eq!(optimizer.replay(&mut program, &log), Ok(()));
+    assert_eq!(print(&program), expected);
+
+    // But against a different function, replaying reports the first rewrite
+    // that turned out differently.
+    // Here, an extra constant up front shifts every instruction's id by one.
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    program.r#const(Constant::Int(3, w), w);
+    sample_function(&mut program);
+    let mismatch = optimizer.replay(&mut program, &log).unwrap_err();
+    assert_eq!(mismatch.index, 0);
+    assert_eq!(mismatch.expected, log.rewrites[0]);
+    assert_eq!(mismatch.actual, None);
+}
+
+#[test]
+fn shape_cache() {
+    use peepmatic_runtime::shape_cache::ShapeCacheStats;
+
+    // A function with many instructions of the same shape that nothing
+    // matches, then one that is rewritten, then more that nothing matches.
+    fn repetitive_function(program: &mut Program) -> Vec<Instruction> {
+        let w = BitWidth::ThirtyTwo;
+        let zero = program.r#const(Constant::Int(0, w), w);
+        let five = program.r#const(Constant::Int(5, w), w);
+        let mut insts = vec![];
+        for i in 0..101 {
+            let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+            let c = if i == 50 { zero } else { five };
+            insts.push(program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, c]));
+        }
+        insts
+    }
+
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (iadd $x 0) $x)");
+
+    let mut program = Program::default();
+    let insts = repetitive_function(&mut program);
+    let applied = optimizer.apply_in_order(&mut program, insts, ApplyOrder::Forward);
+    assert_eq!(applied, 1);
+    assert_eq!(optimizer.shape_cache_stats(), None);
+
+    // With the cache, only the first instruction of each shape is matched
+    // against the automaton, until the rewrite clears the cache.
+    optimizer.set_shape_cache_capacity(Some(16));
+    let mut program = Program::default();
+    let insts = repetitive_function(&mut program);
+    let applied = optimizer.apply_in_order(&mut program, insts, ApplyOrder::Forward);
+    assert_eq!(applied, 1);
+    assert_eq!(
+        optimizer.shape_cache_stats(),
+        Some(ShapeCacheStats {
+            hits: 98,
+            misses: 3
+        })
+    );
+
+    // A cached match applies to any root of the same shape.
+    optimizer.set_shape_cache_capacity(Some(16));
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let five = program.r#const(Constant::Int(5, w), w);
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+    let y = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+    let a = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+    let b = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![y, zero]);
+    assert!(optimizer.preview_one(&mut program, a).is_some());
+    let new = optimizer.apply_one(&mut program, b);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, y));
+    assert_eq!(
+        optimizer.shape_cache_stats(),
+        Some(ShapeCacheStats { hits: 1, misses: 1 })
+    );
+}
+
+#[test]
+fn shape_cache_distinguishes_shared_operands() {
+    use peepmatic_runtime::shape_cache::ShapeCacheStats;
+
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (iadd $x $x) (ishl_imm 1 $x))");
+    optimizer.set_shape_cache_capacity(Some(16));
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let five = program.r#const(Constant::Int(5, w), w);
+    let a = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+    let b = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+
+    // `(iadd a a)` matches, and `(iadd a b)` looks just like it, operator for
+    // operator, but its operands are different values.
+    let iadd_a_a = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![a, a]);
+    let iadd_a_b = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![a, b]);
+    assert!(optimizer.preview_one(&mut program, iadd_a_a).is_some());
+    assert!(optimizer.apply_one(&mut program, iadd_a_b).is_none());
+    assert_eq!(
+        optimizer.shape_cache_stats(),
+        Some(ShapeCacheStats { hits: 0, misses: 2 })
+    );
+}
+
+#[test]
+fn specific_transition_wins_over_wildcard() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd $x $y) (iadd $y $x))
+(=> (iadd $x 0) $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let five = program.r#const(Constant::Int(5, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five, one]);
+
+    // The second operand's state has both a specific `0` transition and a
+    // wildcard transition, and the specific one is taken when it matches.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    // Otherwise, we fall back to the wildcard transition.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    let expected = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, x]);
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn ccs_combine() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (bor (icmp $C1 $a $b) (icmp $C2 $a $b))
+          (ccs-combine $C1 $C2))
+    (icmp $(combine_cc $C1 $C2) $a $b))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let a = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, two]);
+    let b = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![one, two]);
+    let icmp = |program: &mut Program, cc: ConditionCode| {
+        program.new_instruction(Operator::Icmp, Type::b1(), vec![cc.into()], vec![a, b])
+    };
+
+    // `a < b || a == b` is `a <= b`.
+    let lt = icmp(&mut program, ConditionCode::Slt);
+    let eq = icmp(&mut program, ConditionCode::Eq);
+    let bor = program.new_instruction(Operator::Bor, Type::b1(), vec![], vec![lt, eq]);
+    let expected = icmp(&mut program, ConditionCode::Sle);
+    let new = optimizer.apply_one(&mut program, bor);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // But `a < b || a > b` is left alone.
+    let lt = icmp(&mut program, ConditionCode::Slt);
+    let gt = icmp(&mut program, ConditionCode::Sgt);
+    let bor = program.new_instruction(Operator::Bor, Type::b1(), vec![], vec![lt, gt]);
+    assert!(optimizer.apply_one(&mut program, bor).is_none());
+}
+
+#[test]
+fn custom_predicate() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (when (iadd $x 0) (predicate is-hot $x)) $x)");
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let hot = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![two, one]);
+    let cold = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![two, one]);
+
+    // Unregistered predicates never hold.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![hot, zero]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    optimizer.register_predicate("is-hot", move |_, inst| inst == hot);
+
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, hot));
+
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![cold, zero]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+}
+
+#[test]
+fn call_function() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd_imm $C $x) (iadd_imm (call double $C) $x))
+(=> (bnot $x) (bxor $x (call mask-for-width)))
+"
+    );
+    optimizer.register_function("double", |_, _, operands| match operands {
+        [Part::Constant(Constant::Int(c, w))] => Constant::Int(c.wrapping_mul(2), *w),
+        _ => panic!("unexpected operands: {:?}", operands),
+    });
+    optimizer.register_function("mask-for-width", |_, width, operands| {
+        assert!(operands.is_empty());
+        Constant::Int(std::u128::MAX >> (128 - width), BitWidth::Polymorphic)
+    });
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![one, one]);
+
+    // The function is called with the bound constant.
+    let iadd_imm = program.new_instruction(
+        Operator::IaddImm,
+        Type::i32(),
+        vec![Constant::Int(5, w).into()],
+        vec![x],
+    );
+    let expected = program.new_instruction(
+        Operator::IaddImm,
+        Type::i32(),
+        vec![Constant::Int(10, w).into()],
+        vec![x],
+    );
+    let new = optimizer.apply_one(&mut program, iadd_imm);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // And a polymorphic result takes on the rewritten instruction's width.
+    let bnot = program.new_instruction(Operator::Bnot, Type::i32(), vec![], vec![x]);
+    let mask = program.r#const(Constant::Int(0xffff_ffff, w), w);
+    let expected = program.new_instruction(Operator::Bxor, Type::i32(), vec![], vec![x, mask]);
+    let new = optimizer.apply_one(&mut program, bnot);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn expand_iabs() {
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (iabs $x) (expand-iabs $x))");
+
+    let mut program = Program::default();
+    for &(w, ty, sign_shift) in &[
+        (BitWidth::ThirtyTwo, Type::i32(), 31),
+        (BitWidth::SixtyFour, Type::i64(), 63),
+    ] {
+        let x = program.opaque(ty);
+        let iabs = program.new_instruction(Operator::Iabs, ty, vec![], vec![x]);
+
+        // `(isub (bxor x s) s)` where `s = (sshr x width-1)`.
+        let amount = program.r#const(Constant::Int(sign_shift, w), w);
+        let s = program.new_instruction(Operator::Sshr, ty, vec![], vec![x, amount]);
+        let flipped = program.new_instruction(Operator::Bxor, ty, vec![], vec![x, s]);
+        let expected = program.new_instruction(Operator::Isub, ty, vec![], vec![flipped, s]);
+
+        let new = optimizer.apply_one(&mut program, iabs);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, expected));
+    }
+}
+
+#[test]
+fn in_set() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (imul $x $C)
+          (in-set $C 1 2 4 8))
+    (imul_imm $C $x))
+(=> (when (iadd $x $C)
+          (in-set $C))
+    $x)
+"
+    );
+
+    let mut program = Program::default();
+    let three = program.r#const(Constant::Int(3, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let four = program.r#const(Constant::Int(4, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let x = program.opaque(Type::i32());
+
+    // A value in the set.
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, four]);
+    let expected = program.new_instruction(
+        Operator::ImulImm,
+        Type::i32(),
+        vec![Constant::Int(4, BitWidth::ThirtyTwo).into()],
+        vec![x],
+    );
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // A value outside the set.
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, three]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+
+    // The empty set never matches.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, four]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+}
+
+#[test]
+fn popcount() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (imul $x $C)
+          (popcount-eq $C 1))
+    (ishl $x $(log2 $C)))
+
+(=> (when (band $x $C)
+          (popcount-le $C 2))
+    (band_imm $C $x))
+"
+    );
+
+    let mut program = Program::default();
+    let konst =
+        |program: &mut Program, x: u128, w: BitWidth| program.r#const(Constant::Int(x, w), w);
+
+    // Single-bit constants.
+    let x = konst(&mut program, 5, BitWidth::ThirtyTwo);
+    let eight = konst(&mut program, 8, BitWidth::ThirtyTwo);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, eight]);
+    let three = konst(&mut program, 3, BitWidth::ThirtyTwo);
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, three]);
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, ishl));
+
+    let x = konst(&mut program, 5, BitWidth::SixtyFour);
+    let high_bit = konst(&mut program, 1 << 63, BitWidth::SixtyFour);
+    let imul = program.new_instruction(Operator::Imul, Type::i64(), vec![], vec![x, high_bit]);
+    let sixty_three = konst(&mut program, 63, BitWidth::SixtyFour);
+    let ishl = program.new_instruction(Operator::Ishl, Type::i64(), vec![], vec![x, sixty_three]);
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, ishl));
+
+    // Multi-bit constants.
+    let x = konst(&mut program, 5, BitWidth::ThirtyTwo);
+    let six = konst(&mut program, 6, BitWidth::ThirtyTwo);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, six]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+
+    let x = konst(&mut program, 5, BitWidth::Eight);
+    let mask = konst(&mut program, 0x81, BitWidth::Eight);
+    let band = program.new_instruction(Operator::Band, Type::i8(), vec![], vec![x, mask]);
+    let band_imm = program.new_instruction(
+        Operator::BandImm,
+        Type::i8(),
+        vec![Constant::Int(0x81, BitWidth::Eight).into()],
+        vec![x],
+    );
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, band_imm));
+
+    // All ones has eight bits set at 8 bits wide, and sixty-four at 64 bits
+    // wide; both are too many.
+    let x = konst(&mut program, 5, BitWidth::Eight);
+    let all_ones = konst(&mut program, 0xff, BitWidth::Eight);
+    let band = program.new_instruction(Operator::Band, Type::i8(), vec![], vec![x, all_ones]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    let x = konst(&mut program, 5, BitWidth::SixtyFour);
+    let all_ones = konst(&mut program, u128::from(std::u64::MAX), BitWidth::SixtyFour);
+    let band = program.new_instruction(Operator::Band, Type::i64(), vec![], vec![x, all_ones]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn is_shift_by_const() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x $y)
+          (is-shift-by-const $x))
+    (iadd $y $x))
+"
+    );
+
+    let mut program = Program::default();
+    let x = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let y = program.r#const(Constant::Int(7, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let z = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, y]);
+
+    // `(ishl x 3)` is a shift by a constant.
+    let three = program.r#const(Constant::Int(3, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, three]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![ishl, z]);
+    let expected = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![z, ishl]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // `(ishl x y)` is not, because `y` is not a constant.
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, z]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![ishl, z]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    // Neither is a non-shift.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![z, z]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+}
+
+#[test]
+fn phases() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (phase canonicalize) (iadd $C $x) (iadd $x $C))
+(=> (phase lowering) (iadd $x $C) (iadd_imm $C $x))
+(=> (imul $x 1) $x)
+"
+    );
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let one = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+
+    optimizer.set_phase(Some(Phase::Canonicalize));
+
+    // Canonicalization rules apply...
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, x]);
+    let canonical = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, canonical));
+
+    // ...but lowering rules don't.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    // Untagged rules apply in every phase.
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, one]);
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    optimizer.set_phase(Some(Phase::Lowering));
+
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    let iadd_imm = program.new_instruction(
+        Operator::IaddImm,
+        Type::i32(),
+        vec![Constant::Int(5, BitWidth::ThirtyTwo).into()],
+        vec![x],
+    );
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, iadd_imm));
+
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, x]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+}
+
+#[test]
+fn apply_batch() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd $x 0) $x)
+(=> (imul (iadd $x 0) 2) (ishl $x 1))
+"
+    );
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let one = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let two = program.r#const(Constant::Int(2, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+
+    // Both optimizations match and target `iadd`, so they conflict. The
+    // multiply's optimization targets more instructions, so it wins.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![iadd, two]);
+    let iadd_copy = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+
+    // An unrelated addition doesn't conflict with either.
+    let y = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, two]);
+    let other = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![y, zero]);
+
+    let replaced = optimizer.apply_batch(&mut program, vec![iadd, imul, other]);
+    assert_eq!(replaced.len(), 2);
+    assert_eq!(replaced[0].0, imul);
+    assert_eq!(replaced[1].0, other);
+
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, one]);
+    assert!(program.structurally_eq(imul, ishl));
+    assert!(program.structurally_eq(iadd, iadd_copy));
+    assert!(program.structurally_eq(other, y));
+}
+
+#[test]
+fn apply_batch_drops_stale_matches() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (imul $x 1) $x)
+(=> (when (iadd $x $y)
+          (single-use $x))
+    (iadd $y $x))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let five = program.r#const(Constant::Int(5, w), w);
+    let a = program.opaque(Type::i32());
+    program.set_use_count(a, 2);
+
+    // The two matches target different instructions, so they don't conflict.
+    // But removing the multiply replaces the addition's single-use operand with
+    // a multiply-used one, so the addition no longer matches.
+    let x = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![a, one]);
+    program.set_use_count(x, 1);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    let expected = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![a, five]);
+
+    let replaced = optimizer.apply_batch(&mut program, vec![x, iadd]);
+    assert_eq!(replaced, vec![(x, a)]);
+    assert!(program.structurally_eq(iadd, expected));
+}
+
+#[test]
+fn apply_in_order() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd $x 0) $x)
+(=> (imul (iadd $x 0) 2) (ishl $x 1))
+"
+    );
+
+    let new_program = || {
+        let mut program = Program::default();
+        let x = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+        let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+        let two = program.r#const(Constant::Int(2, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+        let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+        let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![iadd, two]);
+        (program, x, iadd, imul)
+    };
+
+    // In layout order, rewriting the addition first hides it from the
+    // multiply's optimization.
+    let (mut program, _, iadd, imul) = new_program();
+    let applied = optimizer.apply_in_order(&mut program, vec![iadd, imul], ApplyOrder::Forward);
+    assert_eq!(applied, 1);
+
+    // In reverse, both get rewritten. The multiply appears twice, and its
+    // second occurrence is visited as its replacement, which doesn't match.
+    let (mut program, x, iadd, imul) = new_program();
+    let applied =
+        optimizer.apply_in_order(&mut program, vec![iadd, imul, imul], ApplyOrder::Reverse);
+    assert_eq!(applied, 2);
+
+    let one = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![x, one]);
+    assert!(program.structurally_eq(imul, ishl));
+    assert!(program.structurally_eq(iadd, x));
+}
+
+#[test]
+fn max_rewrites_per_instruction() {
+    use std::cell::Cell;
+
+    // This must outlive the optimizer, which holds its observers.
+    let count = Cell::new(0);
+
+    // This optimization keeps matching its own result, and would otherwise
+    // rewrite forever.
+    let opts;
+    let mut optimizer = optimizer!(opts, "(=> (iadd $x $y) (iadd $y $x))");
+    optimizer.add_match_observer(|_| count.set(count.get() + 1));
+    optimizer.set_max_rewrites_per_instruction(Some(3));
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![two, one]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, one]);
+    optimizer.apply_all(&mut program, iadd);
+    assert_eq!(count.get(), 3);
+
+    // After an odd number of swaps, the operands end up swapped.
+    let swapped = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, x]);
+    assert!(program.structurally_eq(iadd, swapped));
+
+    // The limit applies to each instruction separately.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, two]);
+    optimizer.apply_all(&mut program, iadd);
+    assert_eq!(count.get(), 6);
+}
+
+#[test]
+fn apply_to_function_max_rewrites() {
+    use std::cell::Cell;
+
+    // This must outlive the optimizer, which holds its observers.
+    let count = Cell::new(0);
+
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+(=> (isub $x $y) (isub $y $x))
+"
+    );
+    optimizer.add_match_observer(|_| count.set(count.get() + 1));
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let x = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![two, one]);
+
+    // A function that reaches a fixpoint within the limit doesn't hit it.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![iadd, one]);
+    assert!(!optimizer.apply_to_function(&mut program, vec![imul, iadd], 2));
+    assert_eq!(count.get(), 2);
+    assert!(program.structurally_eq(imul, x));
+
+    // But the swapping optimization never reaches one, and stops at the limit.
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![x, one]);
+    assert!(optimizer.apply_to_function(&mut program, vec![isub], 5));
+    assert_eq!(count.get(), 7);
+}
+
+#[test]
+fn match_stats_tsv() {
+    let source = "
+;; Adding zero is a no-op.
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+;; Multiplying by two is a shift.
+;;
+;; It is cheaper on every target we know of.
+(=> (imul $x 2) (ishl $x 1))
+";
+    let path = std::path::Path::new("match_stats_tsv");
+    let names: Vec<_> = peepmatic::compile_to_linear(source, path)
+        .unwrap()
+        .optimizations
+        .into_iter()
+        .map(|o| o.doc)
+        .collect();
+    let opts;
+    let mut optimizer = optimizer!(opts, source);
+    optimizer.record_match_stats();
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![three, two]);
+
+    // Two additions of zero are rewritten. A multiply by two is batched twice,
+    // so its second match conflicts with the first and is skipped.
+    for _ in 0..2 {
+        let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]);
+        assert!(optimizer.apply_one(&mut program, iadd).is_some());
+    }
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, two]);
+    let (optimization, _) = optimizer.preview_one(&mut program, imul).unwrap();
+    let name = names[optimization as usize].as_ref().unwrap();
+    assert!(name.starts_with("Multiplying by two is a shift."));
+    assert_eq!(
+        optimizer.apply_batch(&mut program, vec![imul, imul]).len(),
+        1
+    );
+
+    let mut tsv = vec![];
+    optimizer
+        .match_stats()
+        .unwrap()
+        .write_tsv(&mut tsv, &names)
+        .unwrap();
+    let tsv = String::from_utf8(tsv).unwrap();
+    let mut lines = tsv.lines();
+    assert_eq!(lines.next(), Some("rule-id\tname\tfire-count\tskip-count"));
+
+    // Rows are in optimization order, which needn't be source order, so sort
+    // them by name.
+    let mut rows: Vec<Vec<&str>> = lines.map(|l| l.split('\t').collect()).collect();
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(row[0], i.to_string());
+    }
+    rows.sort_by_key(|row| row[1]);
+    let rows: Vec<_> = rows.iter().map(|row| &row[1..]).collect();
+    assert_eq!(
+        rows,
+        vec![
+            &["", "0", "0"][..],
+            &["Adding zero is a no-op.", "2", "0"][..],
+            &["Multiplying by two is a shift.", "1", "1"][..],
+        ]
+    );
+}
+
+#[test]
+fn disallow_operator() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (ishl $x 1) (imul $x 2))
+(=> (iadd $x 0) $x)
+"
+    );
+    optimizer.disallow_operator(Operator::Imul);
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let one = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    // The multiply-producing optimization doesn't fire.
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![five, one]);
+    assert!(optimizer.apply_one(&mut program, ishl).is_none());
+
+    // But others still do.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, five));
+}
+
+#[test]
+fn block_predecessors() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x 0)
+          (block-predecessors 1))
+    $x)
+"
+    );
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    // A single-predecessor block.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    program.set_block_predecessor_count(add, 1);
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, five));
+
+    // A multi-predecessor block.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    program.set_block_predecessor_count(add, 2);
+    assert!(optimizer.apply_one(&mut program, add).is_none());
+
+    // An instruction whose block's predecessors are unknown.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    assert!(optimizer.apply_one(&mut program, add).is_none());
+}
+
+#[test]
+fn is_call_result() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x 0)
+          (is-call-result $x))
+    $x)
+"
+    );
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let zero = program.r#const(Constant::Int(0, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+
+    // The test ISA has no call operator, so mark an instruction as a call.
+    let call = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+    program.set_is_call(call);
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![call, zero]);
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, call));
+
+    // An arithmetic result.
+    let mul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![mul, zero]);
+    assert!(optimizer.apply_one(&mut program, add).is_none());
+
+    // A constant.
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    assert!(optimizer.apply_one(&mut program, add).is_none());
+}
+
+#[test]
+fn is_byte_mask() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (band $x $C)
+          (is-byte-mask $C 2))
+    (band_imm $C $x))
+(=> (when (band $x $C)
+          (is-byte-mask $C 3))
+    (band_imm $C $x))
+"
+    );
+
+    let mut program = Program::default();
+    let band_of = |program: &mut Program, mask: u128, ty: Type| {
+        let w = ty.bit_width;
+        let x = program.r#const(Constant::Int(5, w), w);
+        let mask = program.r#const(Constant::Int(mask, w), w);
+        program.new_instruction(Operator::Band, ty, vec![], vec![x, mask])
+    };
+
+    // Selects byte 1.
+    let band = band_of(&mut program, 0xff00, Type::i32());
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let band_imm = program.new_instruction(
+        Operator::BandImm,
+        Type::i32(),
+        vec![Constant::Int(0xff00, BitWidth::ThirtyTwo).into()],
+        vec![five],
+    );
+    assert!(program.structurally_eq(new, band_imm));
+
+    // All bytes of an `i16`.
+    let band = band_of(&mut program, 0xffff, Type::i16());
+    assert!(optimizer.apply_one(&mut program, band).is_some());
+
+    // A byte mask that selects other bytes.
+    let band = band_of(&mut program, 0x00ff, Type::i32());
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+    let band = band_of(&mut program, 0xffff, Type::i32());
+    assert!(optimizer.apply_one(&mut program, band).is_some());
+    let band = band_of(&mut program, 0xff_ff00, Type::i32());
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    // Not byte-aligned.
+    let band = band_of(&mut program, 0x0f00, Type::i32());
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+
+    // Doesn't select any bytes.
+    let band = band_of(&mut program, 0, Type::i32());
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn preview_one() {
+    use std::cell::Cell;
+
+    // This must outlive the optimizer, which holds its observers.
+    let count = Cell::new(0);
+
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (imul $x 2) (ishl $x 1))
+"
+    );
+    optimizer.record_match_stats();
+    optimizer.add_match_observer(|_| count.set(count.get() + 1));
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let two = program.r#const(Constant::Int(2, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, two]);
+
+    let one = program.r#const(Constant::Int(1, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let ishl = program.new_instruction(Operator::Ishl, Type::i32(), vec![], vec![five, one]);
+
+    // Previewing describes the rewrite...
+    let (optimization, actions) = optimizer.preview_one(&mut program, imul).unwrap();
+    let actions = actions.to_vec();
+    assert_eq!(optimization, 0);
+    assert_eq!(
+        actions.last().and_then(|a| a.made_operator()),
+        Some(Operator::Ishl)
+    );
+
+    // ...without performing it, or counting it as a match.
+    assert!(!program.structurally_eq(imul, ishl));
+    assert_eq!(optimizer.match_stats(), Some(&MatchStats::new(1)));
+    assert_eq!(count.get(), 0);
+
+    // And applying performs exactly the previewed rewrite.
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, ishl));
+    assert!(program.structurally_eq(imul, ishl));
+    let stats = optimizer.match_stats().unwrap();
+    assert_eq!((stats.fire_count(0), stats.skip_count(0)), (1, 0));
+    assert_eq!(count.get(), 1);
+
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, two]);
+    assert!(optimizer.preview_one(&mut program, add).is_none());
+}
+
+#[test]
+fn select_with_boolean_arms() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (select $c true false) (bit-width $c 1)) $c)
+(=> (when (select $c false true) (bit-width $c 1)) (bnot $c))
+(=> (when (select $c $a false) (bit-width $c 1)) (band $c $a))
+(=> (when (select $c true $a) (bit-width $c 1)) (bor $c $a))
+(=> (when (select $c $a true) (bit-width $c 1)) (bor (bnot $c) $a))
+(=> (when (select $c false $a) (bit-width $c 1)) (band (bnot $c) $a))
+"
+    );
+
+    let mut program = Program::default();
+    let five = program.r#const(Constant::Int(5, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let six = program.r#const(Constant::Int(6, BitWidth::ThirtyTwo), BitWidth::ThirtyTwo);
+    let c = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Eq.into()],
+        vec![five, six],
+    );
+    let a = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ne.into()],
+        vec![five, six],
+    );
+    let t = program.r#const(Constant::Bool(true, BitWidth::One), BitWidth::One);
+    let f = program.r#const(Constant::Bool(false, BitWidth::One), BitWidth::One);
+
+    let bnot_c = program.new_instruction(Operator::Bnot, Type::b1(), vec![], vec![c]);
+    let band = program.new_instruction(Operator::Band, Type::b1(), vec![], vec![c, a]);
+    let bor = program.new_instruction(Operator::Bor, Type::b1(), vec![], vec![c, a]);
+    let bor_not = program.new_instruction(Operator::Bor, Type::b1(), vec![], vec![bnot_c, a]);
+    let band_not = program.new_instruction(Operator::Band, Type::b1(), vec![], vec![bnot_c, a]);
+
+    for &(then, els, expected) in &[
+        (t, f, c),
+        (f, t, bnot_c),
+        (a, f, band),
+        (t, a, bor),
+        (a, t, bor_not),
+        (f, a, band_not),
+    ] {
+        let select =
+            program.new_instruction(Operator::Select, Type::b1(), vec![], vec![c, then, els]);
+        let new = optimizer.apply_one(&mut program, select);
+        let new = new.expect("optimization should have applied");
+        assert!(program.structurally_eq(new, expected));
+    }
+
+    // Selects of non-boolean conditions are left alone.
+    let select = program.new_instruction(Operator::Select, Type::i32(), vec![], vec![five, t, f]);
+    assert!(optimizer.apply_one(&mut program, select).is_none());
+}
+
+#[test]
+fn match_state_reuse() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (imul $x 2) (ishl $x 1))
+(=> (iadd $x 0) $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let five = program.r#const(Constant::Int(5, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let zero = program.r#const(Constant::Int(0, w), w);
+
+    // Nothing from the previous instruction's match is left behind when the
+    // next instruction doesn't match.
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, two]);
+    assert!(optimizer.preview_one(&mut program, imul).is_some());
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, five]);
+    assert!(optimizer.preview_one(&mut program, imul).is_none());
+    let isub = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![five, two]);
+    assert!(optimizer.preview_one(&mut program, isub).is_none());
+
+    let add = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, zero]);
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, five));
+
+    // A match state handed over to another optimizer, with different
+    // optimizations, works the same as a fresh one.
+    let state = optimizer.into_match_state();
+    let other_opts =
+        peepmatic::compile_str("(=> (bor $x 0) $x)", std::path::Path::new("peepmatic-test"))
+            .unwrap();
+    let mut optimizer = other_opts.optimizer_with_state(TEST_ISA, state);
+
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![five, two]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+
+    let bor = program.new_instruction(Operator::Bor, Type::i32(), vec![], vec![five, zero]);
+    let new = optimizer.apply_one(&mut program, bor);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, five));
+}
+
+#[test]
+fn automata_round_trip() {
+    let _ = env_logger::try_init();
+    let source = "
+(=> (iadd $x 0) $x)
+(=> (when (imul $x $C)
+          (is-power-of-two $C))
+    (ishl $x $(log2 $C)))
+(=> (imul $x 1) $x)
+";
+    let fresh = peepmatic::compile_str(source, std::path::Path::new("peepmatic-test")).unwrap();
+    let mut loaded =
+        peepmatic::compile_str(source, std::path::Path::new("peepmatic-test")).unwrap();
+    let bytes = fresh.automata.to_bytes();
+    loaded.automata = peepmatic_automata::Automaton::from_bytes(&bytes).unwrap();
+
+    let mut fresh = fresh.optimizer(TEST_ISA);
+    let mut loaded = loaded.optimizer(TEST_ISA);
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let eight = program.r#const(Constant::Int(8, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![three, one]);
+
+    let insts = vec![
+        program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]),
+        program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, one]),
+        program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, eight]),
+        program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, three]),
+        program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, one]),
+    ];
+    for inst in insts {
+        let expected = fresh
+            .preview_one(&mut program, inst)
+            .map(|(o, a)| (o, a.to_vec()));
+        let actual = loaded
+            .preview_one(&mut program, inst)
+            .map(|(o, a)| (o, a.to_vec()));
+        assert_eq!(expected, actual);
+    }
+
+    // Bytes from a different serialization version are rejected.
+    let mut bad = bytes.clone();
+    bad[0] = bad[0].wrapping_add(1);
+    assert!(
+        peepmatic_automata::Automaton::<Option<u32>, MatchOp, Vec<Action>>::from_bytes(&bad)
+            .is_err()
+    );
+}
+
+#[test]
+fn trailing_zeros() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd_imm $C $p) (trailing-zeros $C 2))
+    (iadd_imm 4 (iadd_imm $(align_down $C 8) $p)))
+(=> (when (iadd_imm $C $p) (trailing-zeros $C 3))
+    (iadd_imm 8 (iadd_imm $(align_down $C 16) $p)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::SixtyFour;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let p = program.new_instruction(Operator::Imul, Type::i64(), vec![], vec![one, one]);
+    let iadd_imm = |program: &mut Program, c: u128, x| {
+        program.new_instruction(
+            Operator::IaddImm,
+            Type::i64(),
+            vec![Constant::Int(c, w).into()],
+            vec![x],
+        )
+    };
+
+    // A 4-byte aligned offset is split into an 8-byte aligned offset plus 4.
+    let before = iadd_imm(&mut program, 20, p);
+    let new = optimizer.apply_one(&mut program, before).unwrap();
+    let aligned = iadd_imm(&mut program, 16, p);
+    let expected = iadd_imm(&mut program, 4, aligned);
+    assert!(program.structurally_eq(new, expected));
+
+    // An 8-byte aligned offset is split into a 16-byte aligned offset plus 8.
+    let before = iadd_imm(&mut program, 24, p);
+    let new = optimizer.apply_one(&mut program, before).unwrap();
+    let aligned = iadd_imm(&mut program, 16, p);
+    let expected = iadd_imm(&mut program, 8, aligned);
+    assert!(program.structurally_eq(new, expected));
+
+    // Neither rule matches offsets with any other alignment.
+    let before = iadd_imm(&mut program, 16, p);
+    assert!(optimizer.apply_one(&mut program, before).is_none());
+    let before = iadd_imm(&mut program, 5, p);
+    assert!(optimizer.apply_one(&mut program, before).is_none());
+}
+
+#[test]
+fn branchless_min_max() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (select (icmp slt $a $b) $a $b) (imin $a $b))
+(=> (select (icmp sgt $a $b) $a $b) (imax $a $b))
+(=> (select (icmp ult $a $b) $a $b) (umin $a $b))
+(=> (select (icmp ugt $a $b) $a $b) (umax $a $b))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let a = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![one, one]);
+    let b = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![one, one]);
+
+    for (cc, expected) in vec![
+        (ConditionCode::Slt, Operator::Imin),
+        (ConditionCode::Sgt, Operator::Imax),
+        (ConditionCode::Ult, Operator::Umin),
+        (ConditionCode::Ugt, Operator::Umax),
+    ] {
+        let icmp = program.new_instruction(Operator::Icmp, Type::b1(), vec![cc.into()], vec![a, b]);
+        let select =
+            program.new_instruction(Operator::Select, Type::i32(), vec![], vec![icmp, a, b]);
+        let new = optimizer.apply_one(&mut program, select).unwrap();
+        assert_eq!(program.data(new).operator, expected);
+        assert_eq!(program.data(new).arguments, vec![a, b]);
+    }
+
+    // The select must pick the compared values in the same order.
+    let icmp = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Slt.into()],
+        vec![a, b],
+    );
+    let select = program.new_instruction(Operator::Select, Type::i32(), vec![], vec![icmp, b, a]);
+    assert!(optimizer.apply_one(&mut program, select).is_none());
+}
+
+#[test]
+fn cond_on_immediate_magnitude() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd $x (iconst $C))
+    (cond ((fits-in-imm12 $C) (iadd_imm $C $x))
+          (iadd $x (iconst $C))))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::SixtyFour;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let x = program.new_instruction(Operator::Imul, Type::i64(), vec![], vec![one, one]);
+    let mut rewrite = |c: i64| {
+        let c = Constant::Int(c as u64 as u128, w);
+        let iconst = program.r#const(c, w);
+        let iadd = program.new_instruction(Operator::Iadd, Type::i64(), vec![], vec![x, iconst]);
+        let new = optimizer.apply_one(&mut program, iadd).unwrap();
+        let imm = program.new_instruction(Operator::IaddImm, Type::i64(), vec![c.into()], vec![x]);
+        let iconst = program.r#const(c, w);
+        let materialized =
+            program.new_instruction(Operator::Iadd, Type::i64(), vec![], vec![x, iconst]);
+        (
+            program.structurally_eq(new, imm),
+            program.structurally_eq(new, materialized),
+        )
+    };
+
+    // A constant that fits in 12 signed bits uses the `*_imm` form.
+    assert_eq!(rewrite(2047), (true, false));
+    assert_eq!(rewrite(-2048), (true, false));
+
+    // A larger constant is materialized with an `iconst` first.
+    assert_eq!(rewrite(2048), (false, true));
+    assert_eq!(rewrite(-2049), (false, true));
+    assert_eq!(rewrite(1 << 40), (false, true));
+}
+
+#[test]
+fn is_one() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (imul $x $y) (is-one $y)) $x)
+(=> (when (band $x $y) (is-one $y)) $x)
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![three, three]);
+
+    // Integer one matches.
+    let one = program.r#const(Constant::Int(1, w), w);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, one]);
+    let new = optimizer.apply_one(&mut program, imul);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+
+    // But two, or a value that isn't constant, does not.
+    let two = program.r#const(Constant::Int(2, w), w);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, two]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, x]);
+    assert!(optimizer.apply_one(&mut program, imul).is_none());
+
+    // Boolean `true` counts as one, and `false` does not.
+    let b = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Eq.into()],
+        vec![x, three],
+    );
+    let t = program.r#const(Constant::Bool(true, BitWidth::One), BitWidth::One);
+    let band = program.new_instruction(Operator::Band, Type::b1(), vec![], vec![b, t]);
+    let new = optimizer.apply_one(&mut program, band);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, b));
+    let f = program.r#const(Constant::Bool(false, BitWidth::One), BitWidth::One);
+    let band = program.new_instruction(Operator::Band, Type::b1(), vec![], vec![b, f]);
+    assert!(optimizer.apply_one(&mut program, band).is_none());
+}
+
+#[test]
+fn confluence() {
+    let orders = [
+        TraversalOrder::TopDown,
+        TraversalOrder::BottomUp,
+        TraversalOrder::Shuffled(1),
+        TraversalOrder::Shuffled(2),
+    ];
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let five = program.r#const(Constant::Int(5, w), w);
+    let x = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![five, five]);
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let imul = program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, one]);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![imul, zero]);
+    let root = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![iadd, x]);
+    let insts: Vec<_> = program.instructions().map(|(inst, _)| inst).collect();
+
+    // Removing identities gives the same function in any order.
+    let opts = peepmatic::compile_str(
+        "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+",
+        std::path::Path::new("confluent"),
+    )
+    .unwrap();
+    assert_eq!(
+        check_confluence(&opts, TEST_ISA, &program, &insts, &[root], &orders, 100),
+        Ok(())
+    );
+
+    // But factoring out `$x` only happens if it runs before the multiplication
+    // by one is removed.
+    let opts = peepmatic::compile_str(
+        "
+(=> (imul $x 1) $x)
+(=> (iadd (imul $x $y) $x) (imul $x (iadd_imm 1 $y)))
+",
+        std::path::Path::new("non-confluent"),
+    )
+    .unwrap();
+    let root = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![imul, x]);
+    let insts: Vec<_> = program.instructions().map(|(inst, _)| inst).collect();
+    let divergence =
+        check_confluence(&opts, TEST_ISA, &program, &insts, &[root], &orders, 100).unwrap_err();
+    assert_eq!(divergence.expected_order, TraversalOrder::TopDown);
+    assert_eq!(divergence.actual_order, TraversalOrder::BottomUp);
+    assert_eq!(divergence.root, 0);
+    assert_eq!(
+        divergence.expected,
+        "(iadd{i32} (iadd{i32} (iconst{i32} 5) (iconst{i32} 5)) \
+         (iadd{i32} (iconst{i32} 5) (iconst{i32} 5)))"
+    );
+    assert_eq!(
+        divergence.actual,
+        "(imul{i32} (iadd{i32} (iconst{i32} 5) (iconst{i32} 5)) \
+         (iadd_imm{i32} 1 (iconst{i32} 1)))"
+    );
+}
+
+#[test]
+fn partitioned_automata() {
+    let _ = env_logger::try_init();
+    let source = "
+(=> (iadd $x 0) $x)
+(=> (when (imul $x $C)
+          (is-power-of-two $C))
+    (ishl $x $(log2 $C)))
+(=> (imul $x 1) $x)
+(=> (bor $x 0) $x)
+(=> (when $x
+          (all-operands-const $x))
+    (fold $x))
+";
+    let single = peepmatic::compile_str(source, std::path::Path::new("peepmatic-test")).unwrap();
+    let partitioned =
+        peepmatic::compile_str_partitioned(source, std::path::Path::new("peepmatic-test")).unwrap();
+    assert!(single.opcode_automata.is_empty());
+    let operators: Vec<_> = partitioned
+        .opcode_automata
+        .iter()
+        .map(|(op, _)| *op)
+        .collect();
+    assert_eq!(operators, [Operator::Bor, Operator::Iadd, Operator::Imul]);
+
+    let mut single = single.optimizer(TEST_ISA);
+    let mut partitioned = partitioned.optimizer(TEST_ISA);
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let one = program.r#const(Constant::Int(1, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let eight = program.r#const(Constant::Int(8, w), w);
+    let x = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![three, one]);
+
+    let insts = vec![
+        program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, zero]),
+        program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![three, zero]),
+        program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, one]),
+        program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, eight]),
+        program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, three]),
+        program.new_instruction(Operator::Imul, Type::i32(), vec![], vec![x, one]),
+        program.new_instruction(Operator::Bor, Type::i32(), vec![], vec![x, zero]),
+        program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, zero]),
+        program.new_instruction(Operator::Band, Type::i32(), vec![], vec![one, three]),
+        x,
+    ];
+    for inst in insts {
+        let mut expected_program = program.clone();
+        let expected = single
+            .apply_one(&mut expected_program, inst)
+            .map(|new| expected_program.format_tree(new));
+        let mut actual_program = program.clone();
+        let actual = partitioned
+            .apply_one(&mut actual_program, inst)
+            .map(|new| actual_program.format_tree(new));
+        assert_eq!(expected, actual);
+    }
+}
+
+#[test]
+fn known_non_negative() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (sdiv $x $C)
+          (known-non-negative $x)
+          (is-power-of-two $C))
+    (ushr $x $(log2 $C)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let two = program.r#const(Constant::Int(2, w), w);
+    let four = program.r#const(Constant::Int(4, w), w);
+
+    // Dividing a value that is known to be non-negative by a power of two is
+    // just a shift.
+    let x = program.opaque(Type::i32());
+    program.set_value_range(x, 0, 0x7fff_ffff);
+    let sdiv = program.new_instruction(Operator::Sdiv, Type::i32(), vec![], vec![x, four]);
+    let new = optimizer.apply_one(&mut program, sdiv);
+    let new = new.expect("optimization should have applied");
+    let expected = program.new_instruction(Operator::Ushr, Type::i32(), vec![], vec![x, two]);
+    assert!(program.structurally_eq(new, expected));
+
+    // But not when the value might be negative, or its range is unknown.
+    let y = program.opaque(Type::i32());
+    program.set_value_range(y, 0, 0x8000_0000);
+    let sdiv = program.new_instruction(Operator::Sdiv, Type::i32(), vec![], vec![y, four]);
+    assert!(optimizer.apply_one(&mut program, sdiv).is_none());
+
+    let z = program.opaque(Type::i32());
+    let sdiv = program.new_instruction(Operator::Sdiv, Type::i32(), vec![], vec![z, four]);
+    assert!(optimizer.apply_one(&mut program, sdiv).is_none());
+
+    // Constants are checked directly.
+    let minus_eight = program.r#const(Constant::Int(-8i32 as u32 as u128, w), w);
+    let sdiv =
+        program.new_instruction(Operator::Sdiv, Type::i32(), vec![], vec![minus_eight, four]);
+    assert!(optimizer.apply_one(&mut program, sdiv).is_none());
+}
+
+#[test]
+fn redundant_mask_for_shift() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (ushr (band $x $MASK) $N)
+          (redundant-mask-for-shift $MASK $N))
+    (ushr $x $N))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let eight = program.r#const(Constant::Int(8, w), w);
+    let x = program.opaque(Type::i32());
+
+    // The mask only clears bits that the shift discards anyway.
+    let mask = program.r#const(Constant::Int(0xffff_ff00, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, mask]);
+    let ushr = program.new_instruction(Operator::Ushr, Type::i32(), vec![], vec![band, eight]);
+    let new = optimizer.apply_one(&mut program, ushr);
+    let new = new.expect("optimization should have applied");
+    let expected = program.new_instruction(Operator::Ushr, Type::i32(), vec![], vec![x, eight]);
+    assert!(program.structurally_eq(new, expected));
+
+    // This mask also clears bits that the shift keeps.
+    let mask = program.r#const(Constant::Int(0x00ff_ff00, w), w);
+    let band = program.new_instruction(Operator::Band, Type::i32(), vec![], vec![x, mask]);
+    let ushr = program.new_instruction(Operator::Ushr, Type::i32(), vec![], vec![band, eight]);
+    assert!(optimizer.apply_one(&mut program, ushr).is_none());
+}
+
+#[test]
+fn replace_each_result() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (iadd_cout $x 0) (results $x false))
+(=> (isub_bout $x $x) (results 0 false))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let zero = program.r#const(Constant::Int(0, w), w);
+    let x = program.opaque(Type::i32());
+    let no_carry = program.r#const(Constant::Bool(false, BitWidth::One), w);
+
+    // Adding zero is the same value, and never carries out.
+    let add = program.new_instruction(Operator::IaddCout, Type::i32(), vec![], vec![x, zero]);
+    let carry = program.result(add, 1, Type::b1());
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, x));
+    assert!(program.structurally_eq(carry, no_carry));
+
+    // Subtracting a value from itself is zero, and never borrows.
+    let sub = program.new_instruction(Operator::IsubBout, Type::i32(), vec![], vec![x, x]);
+    let borrow = program.result(sub, 1, Type::b1());
+    let new = optimizer.apply_one(&mut program, sub);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, zero));
+    assert!(program.structurally_eq(borrow, no_carry));
+}
+
+#[test]
+fn replace_each_result_with_earlier_values() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (iadd_cout $x $y) (results (iadd $x $y) (icmp ult (iadd $x $y) $x)))"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let five = program.r#const(Constant::Int(5, w), w);
+    let x = program.opaque(Type::i32());
+    let add = program.new_instruction(Operator::IaddCout, Type::i32(), vec![], vec![x, five]);
+    let carry = program.result(add, 1, Type::b1());
+
+    // The sum is built before the carry's own `iadd` and `icmp`, so it is not
+    // the last value the right-hand side builds, but it still replaces the
+    // first result.
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    let sum = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    assert!(program.structurally_eq(new, sum));
+    let expected_carry = program.new_instruction(
+        Operator::Icmp,
+        Type::b1(),
+        vec![ConditionCode::Ult.into()],
+        vec![sum, x],
+    );
+    assert!(program.structurally_eq(carry, expected_carry));
+}
+
+#[test]
+fn replace_multi_result_instruction() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "(=> (iadd_cout (iconst $C) $x) (iadd_cout $x (iconst $C)))"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let five = program.r#const(Constant::Int(5, w), w);
+    let x = program.opaque(Type::i32());
+    let add = program.new_instruction(Operator::IaddCout, Type::i32(), vec![], vec![five, x]);
+    let carry = program.result(add, 1, Type::b1());
+
+    // The commuted addition replaces both of the original's results.
+    let new = optimizer.apply_one(&mut program, add);
+    let new = new.expect("optimization should have applied");
+    let expected = program.new_instruction(Operator::IaddCout, Type::i32(), vec![], vec![x, five]);
+    assert!(program.structurally_eq(new, expected));
+    let expected_carry = program.result(expected, 1, Type::b1());
+    assert!(program.structurally_eq(carry, expected_carry));
+    assert_eq!(program.resolve(carry), program.result(new, 1, Type::b1()));
+}
+
+#[test]
+fn is_encodable_logical_imm() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (band $x $C)
+          (bit-width $x 32)
+          (is-encodable-logical-imm $C 32))
+    (band_imm $C $x))
+(=> (when (band $x $C)
+          (bit-width $x 64)
+          (is-encodable-logical-imm $C 64))
+    (band_imm $C $x))
+"
+    );
+
+    let mut program = Program::default();
+    let mut check = |ty: Type, mask: u128, encodable: bool| {
+        let w = ty.bit_width;
+        let x = program.opaque(ty);
+        let c = program.r#const(Constant::Int(mask, w), w);
+        let band = program.new_instruction(Operator::Band, ty, vec![], vec![x, c]);
+        match optimizer.apply_one(&mut program, band) {
+            Some(new) => {
+                assert!(encodable, "{:#x} should not be encodable", mask);
+                let imm = Constant::Int(mask, w).into();
+                let expected = program.new_instruction(Operator::BandImm, ty, vec![imm], vec![x]);
+                assert!(program.structurally_eq(new, expected));
+            }
+            None => assert!(!encodable, "{:#x} should be encodable", mask),
+        }
+    };
+
+    check(Type::i32(), 0xff, true);
+    check(Type::i32(), 0x0ff0, true);
+    check(Type::i32(), 0x5555_5555, true);
+    check(Type::i32(), 0x00ff_00ff, true);
+    check(Type::i32(), 0xf000_000f, true);
+    check(Type::i32(), 0x7fff_ffff, true);
+    check(Type::i32(), 0, false);
+    check(Type::i32(), 0xffff_ffff, false);
+    check(Type::i32(), 0x5, false);
+    check(Type::i32(), 0x1234_5678, false);
+    check(Type::i32(), 0x00ff_0f0f, false);
+
+    check(Type::i64(), 0xffff_ffff, true);
+    check(Type::i64(), 0x00ff_00ff_00ff_00ff, true);
+    check(Type::i64(), 0xffff_ffff_0000_0000, true);
+    check(Type::i64(), 0x8000_0000_0000_0001, true);
+    check(Type::i64(), 0x3333_3333_3333_3333, true);
+    check(Type::i64(), 0, false);
+    check(Type::i64(), 0xffff_ffff_ffff_ffff, false);
+    check(Type::i64(), 0x1234, false);
+    check(Type::i64(), 0x00ff_00ff_00ff_00fe, false);
+}
+
+#[test]
+fn is_add_of_neg_const() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x $C)
+          (is-add-of-neg-const $C))
+    (isub $x $(neg $C)))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let x = program.opaque(Type::i32());
+
+    // `x + -5` is `x - 5`.
+    let minus_five = program.r#const(Constant::Int(-5i32 as u32 as u128, w), w);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, minus_five]);
+    let five = program.r#const(Constant::Int(5, w), w);
+    let expected = program.new_instruction(Operator::Isub, Type::i32(), vec![], vec![x, five]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+
+    // But `x + 5` is not a subtraction.
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, five]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    // The sign is taken at the `iadd`'s width: `0xfb` is `-5` at `i8`, but
+    // positive at `i32`.
+    let fb = program.r#const(Constant::Int(0xfb, w), w);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, fb]);
+    assert!(optimizer.apply_one(&mut program, iadd).is_none());
+
+    let w = BitWidth::Eight;
+    let y = program.opaque(Type::i8());
+    let fb = program.r#const(Constant::Int(0xfb, w), w);
+    let iadd = program.new_instruction(Operator::Iadd, Type::i8(), vec![], vec![y, fb]);
+    let five = program.r#const(Constant::Int(5, w), w);
+    let expected = program.new_instruction(Operator::Isub, Type::i8(), vec![], vec![y, five]);
+    let new = optimizer.apply_one(&mut program, iadd);
+    let new = new.expect("optimization should have applied");
+    assert!(program.structurally_eq(new, expected));
+}
+
+#[test]
+fn is_shifted_imm() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x $C)
+          (is-shifted-imm $C 12 12))
+    (iadd $x (ishl_imm 12 (iconst $(ushr $C 12)))))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let x = program.opaque(Type::i32());
+
+    let mut check = |c: u128, base: Option<u128>| {
+        let k = program.r#const(Constant::Int(c, w), w);
+        let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, k]);
+        match (optimizer.apply_one(&mut program, iadd), base) {
+            (Some(new), Some(base)) => {
+                let base = Constant::Int(base, w).into();
+                let base =
+                    program.new_instruction(Operator::Iconst, Type::i32(), vec![base], vec![]);
+                let twelve = Constant::Int(12, w).into();
+                let shifted = program.new_instruction(
+                    Operator::IshlImm,
+                    Type::i32(),
+                    vec![twelve],
+                    vec![base],
+                );
+                let expected =
+                    program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, shifted]);
+                assert!(program.structurally_eq(new, expected));
+            }
+            (None, None) => {}
+            (Some(_), None) => panic!("{:#x} should not decompose", c),
+            (None, Some(_)) => panic!("{:#x} should decompose", c),
+        }
+    };
+
+    // Constants that are a 12-bit base shifted left by 12.
+    check(0x5000, Some(0x5));
+    check(0xfff000, Some(0xfff));
+    check(0x1000, Some(0x1));
+
+    // The low 12 bits aren't all zero.
+    check(0x5001, None);
+    check(0x800, None);
+    // The base doesn't fit in 12 bits.
+    check(0x100_0000, None);
+    check(0xffff_f000, None);
+}
+
+#[test]
+fn is_shifted_imm8() {
+    let opts;
+    let mut optimizer = optimizer!(
+        opts,
+        "
+(=> (when (iadd $x $C)
+          (is-shifted-imm8 $C))
+    (iadd $x (ishl_imm $(imm8_shift $C) (iconst $(imm8 $C)))))
+"
+    );
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let x = program.opaque(Type::i32());
+
+    let mut check = |c: u128, imm8_and_k: Option<(u128, u128)>| {
+        let k = program.r#const(Constant::Int(c, w), w);
+        let iadd = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, k]);
+        match (optimizer.apply_one(&mut program, iadd), imm8_and_k) {
+            (Some(new), Some((imm8, k))) => {
+                let imm8 = Constant::Int(imm8, w).into();
+                let imm8 =
+                    program.new_instruction(Operator::Iconst, Type::i32(), vec![imm8], vec![]);
+                let k = Constant::Int(k, w).into();
+                let shifted =
+                    program.new_instruction(Operator::IshlImm, Type::i32(), vec![k], vec![imm8]);
+                let expected =
+                    program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, shifted]);
+                assert!(program.structurally_eq(new, expected));
+            }
+            (None, None) => {}
+            (Some(_), None) => panic!("{:#x} should not be a shifted imm8", c),
+            (None, Some(_)) => panic!("{:#x} should be a shifted imm8", c),
+        }
+    };
+
+    check(0xff00, Some((0xff, 8)));
+    check(0x42, Some((0x42, 0)));
+    check(0x10_0000, Some((0x10, 16)));
+    check(0xab00_0000, Some((0xab, 24)));
+
+    // `0x1fe` is `0xff << 1`, but the shift must be a multiple of eight.
+    check(0x1fe, None);
+    check(0x1_0001, None);
+    check(0xffff, None);
+}
+
+#[test]
+fn pipeline() {
+    use peepmatic_runtime::pipeline::{Pipeline, StageMode, StageStats};
+
+    let canonicalize_opts;
+    let canonicalize = optimizer!(
+        canonicalize_opts,
+        "(=> (iadd (iadd $x $C) $D) (iadd $x $(iadd $C $D)))"
+    );
+    let lower_opts;
+    let lower = optimizer!(lower_opts, "(=> (iadd $x $C) (iadd_imm $C $x))");
+
+    let mut pipeline = Pipeline::new();
+    pipeline
+        .push_stage(canonicalize, StageMode::Fixpoint, 100)
+        .push_stage(lower, StageMode::Once, 100);
+    assert_eq!(pipeline.len(), 2);
+
+    let mut program = Program::default();
+    let w = BitWidth::ThirtyTwo;
+    let one = program.r#const(Constant::Int(1, w), w);
+    let two = program.r#const(Constant::Int(2, w), w);
+    let three = program.r#const(Constant::Int(3, w), w);
+    let x = program.opaque(Type::i32());
+    let a = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![x, one]);
+    let b = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![a, two]);
+    let c = program.new_instruction(Operator::Iadd, Type::i32(), vec![], vec![b, three]);
+
+    // Canonicalization folds `b` and then `c` into a single `iadd` of `x`, and
+    // then lowering sees those `iadd`s, rather than the ones they replaced.
+    let stats = pipeline.apply_to_function(&mut program, vec![x, a, b, c]);
+    assert_eq!(
+        stats.stages,
+        vec![
+            StageStats {
+                rewrites: 2,
+                out_of_fuel: false,
+            },
+            StageStats {
+                rewrites: 3,
+                out_of_fuel: false,
+            },
+        ]
+    );
+    assert_eq!(stats.rewrites(), 5);
+    assert!(!stats.out_of_fuel());
+
+    let six = Constant::Int(6, w).into();
+    let expected = program.new_instruction(Operator::IaddImm, Type::i32(), vec![six], vec![x]);
+    assert!(program.structurally_eq(c, expected));
+    let one = Constant::Int(1, w).into();
+    let expected = program.new_instruction(Operator::IaddImm, Type::i32(), vec![one], vec![x]);
+    assert!(program.structurally_eq(a, expected));
+}
+
+#[test]
+fn golden_preopt() {
+    let _ = env_logger::try_init();
+    let opts = peepmatic::compile_str(
+        include_str!("../../../examples/preopt.peepmatic"),
+        std::path::Path::new("preopt.peepmatic"),
+    )
+    .unwrap();
+    if let Err(mismatch) = check_golden(&opts, TEST_ISA, &preopt_golden_pairs(), 100) {
+        panic!("{}", mismatch);
+    }
+
+    // A pair whose expected output is wrong is reported as a mismatch.
+    let wrong = GoldenPair {
+        name: "wrong",
+        input: |program| {
+            let w = BitWidth::ThirtyTwo;
+            let x = program.r#const(Constant::Int(0x1000, w), w);
+            let x = program.new_instruction(Operator::Load, Type::i32(), vec![], vec![x]);
+            let zero = program.r#const(Constant::Int(0, w), w);
+            program.new_instruction(Operator::Bor, Type::i32(), vec![], vec![x, zero])
+        },
+        expected: |program| {
+            let w = BitWidth::ThirtyTwo;
+            program.r#const(Constant::Int(0, w), w)
+        },
+    };
+    assert_eq!(
+        check_golden(&opts, TEST_ISA, &[wrong], 100),
+        Err(GoldenMismatch {
+            name: "wrong",
+            expected: "(iconst{i32} 0)".into(),
+            actual: "(load{i32} (iconst{i32} 4096))".into(),
+        })
+    );
+}
@@ -0,0 +1,271 @@
+//! Optimizing a toy IR that has its own, four-opcode instruction set.
+//!
+//! The toy IR doesn't use `peepmatic_runtime::operator::Operator` for its
+//! opcodes. Instead, its `Opcode` implementation translates between its own
+//! opcodes and the operators that the DSL is written in, which is all that the
+//! automata needs to switch on them.
+
+use peepmatic_runtime::{
+    instruction_set::{InstructionSet, Opcode},
+    operator::Operator,
+    optimizer::UnknownValues,
+    part::{Constant, Part},
+    paths::Path,
+    r#type::{BitWidth, Type},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ToyOpcode {
+    Const,
+    Add,
+    Mul,
+    /// An instruction that has no corresponding `Operator`, so optimizations
+    /// can't classify it.
+    Opaque,
+}
+
+impl Opcode for ToyOpcode {
+    fn to_operator(self) -> Option<Operator> {
+        match self {
+            ToyOpcode::Const => Some(Operator::Iconst),
+            ToyOpcode::Add => Some(Operator::Iadd),
+            ToyOpcode::Mul => Some(Operator::Imul),
+            ToyOpcode::Opaque => None,
+        }
+    }
+
+    fn from_operator(operator: Operator) -> Option<Self> {
+        match operator {
+            Operator::Iconst => Some(ToyOpcode::Const),
+            Operator::Iadd => Some(ToyOpcode::Add),
+            Operator::Imul => Some(ToyOpcode::Mul),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Node {
+    opcode: ToyOpcode,
+    args: [usize; 2],
+    /// The value of a `Const`, and zero for anything else.
+    value: u64,
+}
+
+#[derive(Debug, Default)]
+struct Toy {
+    nodes: Vec<Node>,
+}
+
+impl Toy {
+    fn constant(&mut self, value: u64) -> usize {
+        self.nodes.push(Node {
+            opcode: ToyOpcode::Const,
+            args: [0, 0],
+            value,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn push(&mut self, opcode: ToyOpcode, args: [usize; 2]) -> usize {
+        self.nodes.push(Node {
+            opcode,
+            args,
+            value: 0,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn part_to_node(&mut self, part: Part<usize>) -> usize {
+        match part {
+            Part::Instruction(i) => i,
+            Part::Constant(Constant::Int(value, _)) => self.constant(value as u64),
+            Part::Constant(Constant::Bool(..)) | Part::ConditionCode(_) => {
+                panic!("the toy IR only has integers")
+            }
+        }
+    }
+}
+
+struct ToyIsa;
+
+impl<'a> InstructionSet<'a> for ToyIsa {
+    type Context = Toy;
+    type Instruction = usize;
+    type Opcode = ToyOpcode;
+
+    fn replace_instruction(&self, toy: &mut Toy, old: usize, new: Part<usize>) -> usize {
+        let new = toy.part_to_node(new);
+        toy.nodes[old] = toy.nodes[new];
+        old
+    }
+
+    fn get_part_at_path(&self, toy: &mut Toy, root: usize, path: Path) -> Option<Part<usize>> {
+        assert_eq!(path.0[0], 0);
+        let mut part = Part::Instruction(root);
+        for p in &path.0[1..] {
+            let node = match part {
+                Part::Instruction(i) => toy.nodes[i],
+                _ => return None,
+            };
+            part = match (node.opcode, *p) {
+                (ToyOpcode::Const, 0) => {
+                    Part::Constant(Constant::Int(node.value.into(), BitWidth::ThirtyTwo))
+                }
+                (ToyOpcode::Add, p) | (ToyOpcode::Mul, p) if p < 2 => {
+                    Part::Instruction(node.args[p as usize])
+                }
+                _ => return None,
+            };
+        }
+        Some(part)
+    }
+
+    fn opcode(&self, toy: &mut Toy, inst: usize) -> Option<ToyOpcode> {
+        Some(toy.nodes[inst].opcode)
+    }
+
+    fn make_inst_1(
+        &self,
+        toy: &mut Toy,
+        _root: usize,
+        opcode: ToyOpcode,
+        _: Type,
+        a: Part<usize>,
+    ) -> usize {
+        match (opcode, a) {
+            (ToyOpcode::Const, Part::Constant(Constant::Int(value, _))) => {
+                toy.constant(value as u64)
+            }
+            _ => panic!("the toy IR has no unary `{:?}` instruction", opcode),
+        }
+    }
+
+    fn make_inst_2(
+        &self,
+        toy: &mut Toy,
+        _root: usize,
+        opcode: ToyOpcode,
+        _: Type,
+        a: Part<usize>,
+        b: Part<usize>,
+    ) -> usize {
+        match opcode {
+            ToyOpcode::Add | ToyOpcode::Mul => {
+                let args = [toy.part_to_node(a), toy.part_to_node(b)];
+                toy.push(opcode, args)
+            }
+            _ => panic!("the toy IR has no binary `{:?}` instruction", opcode),
+        }
+    }
+
+    fn make_inst_3(
+        &self,
+        _: &mut Toy,
+        _: usize,
+        _: ToyOpcode,
+        _: Type,
+        _: Part<usize>,
+        _: Part<usize>,
+        _: Part<usize>,
+    ) -> usize {
+        panic!("the toy IR has no ternary instructions")
+    }
+
+    fn instruction_to_constant(&self, toy: &mut Toy, inst: usize) -> Option<Constant> {
+        let node = toy.nodes[inst];
+        match node.opcode {
+            ToyOpcode::Const => Some(Constant::Int(node.value.into(), BitWidth::ThirtyTwo)),
+            ToyOpcode::Add | ToyOpcode::Mul | ToyOpcode::Opaque => None,
+        }
+    }
+
+    fn instruction_result_bit_width(&self, _: &mut Toy, _: usize) -> u8 {
+        32
+    }
+
+    fn native_word_size_in_bits(&self, _: &mut Toy) -> u8 {
+        32
+    }
+}
+
+#[test]
+fn toy_ir() {
+    let _ = env_logger::try_init();
+    let opts = peepmatic::compile_str(
+        "
+(=> (imul $x 2) (iadd $x $x))
+(=> (iadd $x 0) $x)
+",
+        std::path::Path::new("toy_ir"),
+    )
+    .unwrap();
+    let mut optimizer = opts.optimizer(ToyIsa);
+
+    let mut toy = Toy::default();
+    let three = toy.constant(3);
+    let four = toy.constant(4);
+    let x = toy.push(ToyOpcode::Mul, [three, four]);
+
+    // `(imul x 2)` is rewritten into `(iadd x x)`.
+    let two = toy.constant(2);
+    let mul = toy.push(ToyOpcode::Mul, [x, two]);
+    let new = optimizer.apply_one(&mut toy, mul);
+    let new = new.expect("optimization should have applied");
+    assert_eq!(
+        toy.nodes[new],
+        Node {
+            opcode: ToyOpcode::Add,
+            args: [x, x],
+            value: 0,
+        }
+    );
+
+    // `(iadd x 0)` is rewritten into `x`.
+    let zero = toy.constant(0);
+    let add = toy.push(ToyOpcode::Add, [x, zero]);
+    let new = optimizer.apply_one(&mut toy, add);
+    let new = new.expect("optimization should have applied");
+    assert_eq!(toy.nodes[new], toy.nodes[x]);
+
+    // And `(iadd x 1)` is left alone.
+    let one = toy.constant(1);
+    let add = toy.push(ToyOpcode::Add, [x, one]);
+    assert!(optimizer.apply_one(&mut toy, add).is_none());
+}
+
+#[test]
+fn opaque_values_do_not_match() {
+    let _ = env_logger::try_init();
+    let opts = peepmatic::compile_str(
+        "(=> (iadd (imul $x 2) $y) (iadd (iadd $x $x) $y))",
+        std::path::Path::new("opaque_values_do_not_match"),
+    )
+    .unwrap();
+    let mut optimizer = opts.optimizer(ToyIsa);
+
+    let mut toy = Toy::default();
+    let one = toy.constant(1);
+    let opaque = toy.push(ToyOpcode::Opaque, [one, one]);
+    let add = toy.push(ToyOpcode::Add, [opaque, one]);
+    assert!(optimizer.apply_one(&mut toy, add).is_none());
+}
+
+#[test]
+#[should_panic(expected = "cannot classify an instruction with an unknown operator")]
+fn opaque_values_can_panic() {
+    let _ = env_logger::try_init();
+    let opts = peepmatic::compile_str(
+        "(=> (iadd (imul $x 2) $y) (iadd (iadd $x $x) $y))",
+        std::path::Path::new("opaque_values_can_panic"),
+    )
+    .unwrap();
+    let mut optimizer = opts.optimizer(ToyIsa);
+    optimizer.set_unknown_values(UnknownValues::Panic);
+
+    let mut toy = Toy::default();
+    let one = toy.constant(1);
+    let opaque = toy.push(ToyOpcode::Opaque, [one, one]);
+    let add = toy.push(ToyOpcode::Add, [opaque, one]);
+    optimizer.apply_one(&mut toy, add);
+}
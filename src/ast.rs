@@ -22,6 +22,7 @@
 
 use peepmatic_macro::Ast;
 use peepmatic_runtime::{
+    linear::{Phase, SourceLocation},
     operator::{Operator, UnquoteOperator},
     r#type::{BitWidth, Type},
 };
@@ -80,6 +81,42 @@ pub enum DynAstRef<'a> {
 
     /// A reference to an `RhsOperation`.
     RhsOperation(&'a Operation<'a, Rhs<'a>>),
+
+    /// A reference to a `Let`.
+    Let(&'a Let<'a>),
+
+    /// A reference to a `LetBinding`.
+    LetBinding(&'a LetBinding<'a>),
+
+    /// A reference to an `IconstLike`.
+    IconstLike(&'a IconstLike<'a>),
+
+    /// A reference to a `Fold`.
+    Fold(&'a Fold<'a>),
+
+    /// A reference to a `Delete`.
+    Delete(&'a Delete<'a>),
+
+    /// A reference to an `ExpandIabs`.
+    ExpandIabs(&'a ExpandIabs<'a>),
+
+    /// A reference to a `Call`.
+    Call(&'a Call<'a>),
+
+    /// A reference to a `Template`.
+    Template(&'a Template<'a>),
+
+    /// A reference to a `StoredValue`.
+    StoredValue(&'a StoredValue<'a>),
+
+    /// A reference to a `Cond`.
+    Cond(&'a Cond<'a>),
+
+    /// A reference to a `CondArm`.
+    CondArm(&'a CondArm<'a>),
+
+    /// A reference to a `Results`.
+    Results(&'a Results<'a>),
 }
 
 impl<'a, 'b> ChildNodes<'a, 'b> for DynAstRef<'a> {
@@ -101,6 +138,18 @@ impl<'a, 'b> ChildNodes<'a, 'b> for DynAstRef<'a> {
             Self::ConditionCode(x) => x.child_nodes(sink),
             Self::Unquote(x) => x.child_nodes(sink),
             Self::RhsOperation(x) => x.child_nodes(sink),
+            Self::Let(x) => x.child_nodes(sink),
+            Self::LetBinding(x) => x.child_nodes(sink),
+            Self::IconstLike(x) => x.child_nodes(sink),
+            Self::Fold(x) => x.child_nodes(sink),
+            Self::Delete(x) => x.child_nodes(sink),
+            Self::ExpandIabs(x) => x.child_nodes(sink),
+            Self::Call(x) => x.child_nodes(sink),
+            Self::Template(x) => x.child_nodes(sink),
+            Self::StoredValue(x) => x.child_nodes(sink),
+            Self::Cond(x) => x.child_nodes(sink),
+            Self::CondArm(x) => x.child_nodes(sink),
+            Self::Results(x) => x.child_nodes(sink),
         }
     }
 }
@@ -165,6 +214,28 @@ pub struct Optimization<'a> {
     #[peepmatic(skip_child)]
     pub span: wast::Span,
 
+    /// The `;;` comment lines immediately preceding this optimization, if any.
+    ///
+    /// See `attach_doc_comments`.
+    #[peepmatic(skip_child)]
+    pub doc: Option<String>,
+
+    /// The line and column where this optimization starts in the source text.
+    ///
+    /// See `attach_source_locations`.
+    #[peepmatic(skip_child)]
+    pub location: Option<SourceLocation>,
+
+    /// The pipeline phase this optimization is tagged with, if any, from a
+    /// `(phase ...)` annotation.
+    #[peepmatic(skip_child)]
+    pub phase: Option<Phase>,
+
+    /// Whether this optimization applies by default. This is `false` when it
+    /// has a `(disabled)` annotation.
+    #[peepmatic(skip_child)]
+    pub enabled: bool,
+
     /// The left-hand side that matches when this optimization applies.
     pub lhs: Lhs<'a>,
 
@@ -238,10 +309,10 @@ pub struct Integer<'a> {
 
     /// The integer value.
     ///
-    /// Note that although Cranelift allows 128 bits wide values, the widest
-    /// supported constants as immediates are 64 bits.
+    /// Wide enough for 128-bit constants, whether written as signed or
+    /// unsigned.
     #[peepmatic(skip_child)]
-    pub value: i64,
+    pub value: i128,
 
     /// The bit width of this integer.
     ///
@@ -383,10 +454,37 @@ where
     #[peepmatic(skip_child)]
     pub operator: Operator,
 
+    /// Any additional operators this operation may match, when it was written
+    /// with `one-of`, e.g. `((one-of (opcode band) (opcode bor)) $x $x)`.
+    ///
+    /// Only allowed in left-hand side patterns, and usually empty.
+    #[peepmatic(skip_child)]
+    pub alternatives: Vec<Operator>,
+
     /// An optional ascribed or inferred type for the operator.
     #[peepmatic(skip_child)]
     pub r#type: Cell<Option<Type>>,
 
+    /// An optional type variable ascribed to the operator, when written like
+    /// `(iadd{$T} $x $y)`.
+    ///
+    /// Every operation ascribed with the same type variable must have the same
+    /// type, whatever that type turns out to be.
+    #[peepmatic(skip_child)]
+    pub type_variable: Option<Id<'a>>,
+
+    /// The amount to add to the matched root's immediate offset for a `load`
+    /// or `store` in a right-hand side, when written like
+    /// `(load offset=8 $p)`.
+    #[peepmatic(skip_child)]
+    pub mem_offset: Option<i32>,
+
+    /// The memory flags of a `load` or `store` in a right-hand side, when
+    /// written like `(load flags=4 $p)`. Without this, the matched root's
+    /// flags are carried over.
+    #[peepmatic(skip_child)]
+    pub mem_flags: Option<u8>,
+
     /// This operation's operands.
     ///
     /// When `Operation` is used in a pattern, these are the sub-patterns for
@@ -429,6 +527,11 @@ pub struct Precondition<'a> {
     /// The operands of the constraint.
     #[peepmatic(flatten)]
     pub operands: Vec<ConstraintOperand<'a>>,
+
+    /// The name of the custom predicate, for `Constraint::Predicate`
+    /// preconditions.
+    #[peepmatic(skip_child)]
+    pub predicate: Option<&'a str>,
 }
 
 /// Contraint operators.
@@ -437,11 +540,141 @@ pub enum Constraint {
     /// Is the operand a power of two?
     IsPowerOfTwo,
 
+    /// Is the constant operand a mask of whole bytes, selecting exactly the
+    /// bytes whose bits are set in the given integer?
+    IsByteMask,
+
+    /// Is the operand a mask of the given number of low bits, either as a
+    /// constant or computed by `(isub (ishl 1 n) 1)`?
+    IsMaskConst,
+
+    /// Is the operand a shift by a constant amount?
+    IsShiftByConst,
+
+    /// Is the operand the result of a call instruction?
+    IsCallResult,
+
+    /// Is the operand used exactly once?
+    SingleUse,
+
+    /// Are all of the operand instruction's own operands constants?
+    AllOperandsConst,
+
+    /// Is the operand a `select` instruction whose arms are both constants?
+    SelectConstArms,
+
+    /// Is the operand a `sextend` from the given bit width?
+    IsSextend,
+
+    /// Is the operand a `uextend` from the given bit width?
+    IsUextend,
+
+    /// Does the constant operand have exactly the given number of bits set?
+    PopcountEq,
+
+    /// Does the constant operand have at most the given number of bits set?
+    PopcountLe,
+
     /// Check the bit width of a value.
     BitWidth,
 
+    /// Does the block containing the instruction being rewritten have the given
+    /// number of predecessors?
+    BlockPredecessors,
+
+    /// Does the negation of the constant operand fit in the given number of
+    /// signed bits, at the constant's bit width?
+    NegationFitsInBits,
+
+    /// Is the constant operand equal to its bit width minus the given delta?
+    IsWidthMinus,
+
     /// Does the argument fit within our target architecture's native word size?
     FitsInNativeWord,
+
+    /// Are the two operands the same value, either by identity or because
+    /// they are equal constants?
+    EquivalentValues,
+
+    /// Is the first constant operand exactly one less than the second?
+    IsOneLessThan,
+
+    /// Is the first constant operand the second times the given factor?
+    IsScaledBy,
+
+    /// Are the two constant operands the amounts of an inner and an outer
+    /// shift that can be combined into a single shift?
+    ShiftsCombine,
+
+    /// Are the two constant operands the condition codes of `icmp`s of the
+    /// same values, and do they combine into a single condition code?
+    CcsCombine,
+
+    /// Is the constant operand equal to one of the given integers?
+    InSet,
+
+    /// Is the operand known to be within the given unsigned range, inclusive?
+    BoundedBy,
+
+    /// Is the operand known to be a multiple of the given alignment, in bytes?
+    IsAligned,
+
+    /// Are the operand's bits above the given width known to be zero?
+    HighBitsZero,
+
+    /// Is the variable operand wrapped in two of the same involution, like
+    /// `$x` in `(bnot (bnot $x))`?
+    IsDoubleInvolution,
+
+    /// Is the variable operand an `icmp` of a value against the constant zero,
+    /// with the given condition code?
+    IcmpZero,
+
+    /// Does the constant operand have exactly the given number of trailing
+    /// zero bits?
+    TrailingZeros,
+
+    /// Does the constant operand fit in a 12-bit signed immediate field, at
+    /// its bit width?
+    FitsInImm12,
+
+    /// Does the constant operand fit in a 32-bit signed immediate field, at
+    /// its bit width?
+    FitsInImm32,
+
+    /// Is the operand the constant one, or the boolean `true`?
+    IsOne,
+
+    /// Is the operand known to be non-negative, as a signed integer?
+    KnownNonNegative,
+
+    /// Is masking with the first operand redundant before a logical right
+    /// shift by the second?
+    RedundantMaskForShift,
+
+    /// Is the constant operand encodable as an AArch64 logical immediate in a
+    /// register of the given width?
+    IsEncodableLogicalImm,
+
+    /// Is the constant operand the negative second operand of an `iadd`, so
+    /// that the `iadd` is really a subtraction?
+    IsAddOfNegConst,
+
+    /// Is the constant operand expressible as a base that fits in the given
+    /// number of bits, shifted left by the given amount?
+    IsShiftedImm,
+
+    /// Is the constant operand expressible as a byte shifted left by a
+    /// multiple of eight?
+    IsShiftedImm8,
+
+    /// Does the memory access bound to the first operand have the given flags,
+    /// after masking?
+    MemFlags,
+
+    /// Does the embedder's custom predicate with the precondition's name hold
+    /// for the operand?
+    Predicate,
 }
 
 /// An operand of a precondition's constraint.
@@ -480,6 +713,324 @@ pub enum Rhs<'a> {
     /// A compound right-hand side consisting of an operation and subsequent
     /// right-hand side operands.
     Operation(Operation<'a, Rhs<'a>>),
+
+    /// A `let` expression that binds intermediate right-hand sides to names,
+    /// so that they can be reused without being built more than once.
+    Let(Let<'a>),
+
+    /// An integer constant that takes its bit width from a value bound in the
+    /// left-hand side, rather than from its context in the right-hand side.
+    IconstLike(IconstLike<'a>),
+
+    /// The constant that a matched instruction evaluates to.
+    Fold(Fold<'a>),
+
+    /// Delete the matched instruction entirely.
+    Delete(Delete<'a>),
+
+    /// The absolute value of an operand, expanded into instructions that
+    /// don't need a native `iabs`.
+    ExpandIabs(ExpandIabs<'a>),
+
+    /// A constant computed by calling a host function.
+    Call(Call<'a>),
+
+    /// A built-in idiom, built out of its operands.
+    Template(Template<'a>),
+
+    /// The value that a matched load reads back from the store before it.
+    StoredValue(StoredValue<'a>),
+
+    /// A choice between right-hand sides, depending on the magnitude of a
+    /// matched constant.
+    Cond(Cond<'a>),
+
+    /// A replacement for each of a multi-result instruction's results.
+    Results(Results<'a>),
+}
+
+/// A `let` expression in a right-hand side.
+///
+/// For example, `(let (($t (iadd $x $y))) (imul $t $t))` builds the `iadd`
+/// instruction once, and then uses it as both operands of the `imul`.
+///
+/// Each binding is in scope for the bindings that follow it, as well as the
+/// `let`'s body.
+#[derive(Debug, Ast)]
+pub struct Let<'a> {
+    /// Where this `Let` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The bindings introduced by this `let`, in order.
+    #[peepmatic(flatten)]
+    pub bindings: Vec<LetBinding<'a>>,
+
+    /// The body of this `let`, which is the value of the whole expression.
+    pub body: Box<Rhs<'a>>,
+}
+
+impl<'a> From<&'a Box<Rhs<'a>>> for DynAstRef<'a> {
+    #[inline]
+    fn from(rhs: &'a Box<Rhs<'a>>) -> DynAstRef<'a> {
+        DynAstRef::Rhs(rhs)
+    }
+}
+
+/// A single `(<variable> <rhs>)` binding inside a `let` expression.
+#[derive(Debug, Ast)]
+pub struct LetBinding<'a> {
+    /// Where this `LetBinding` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The name being bound.
+    pub var: Variable<'a>,
+
+    /// The right-hand side that the name is bound to.
+    pub value: Rhs<'a>,
+}
+
+/// An `(iconst-like $x <integer>)` right-hand side.
+///
+/// This builds an integer constant with the same bit width that `$x` has when
+/// the optimization is applied. A plain integer literal in a right-hand side
+/// takes on the root's bit width, which is wrong when the root is, for
+/// example, an `icmp` producing a `b1`:
+///
+/// ```lisp
+/// (=> (icmp ult $x 1)
+///     (icmp eq $x (iconst-like $x 0)))
+/// ```
+#[derive(Debug, Ast)]
+pub struct IconstLike<'a> {
+    /// Where this `IconstLike` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The left-hand side variable whose bit width the constant takes on.
+    pub like: Variable<'a>,
+
+    /// The constant's value.
+    pub value: Integer<'a>,
+}
+
+/// A `(fold $x)` right-hand side.
+///
+/// This evaluates the pure instruction bound to `$x` over its constant
+/// operands, and builds the resulting constant. Rather than writing one rule
+/// per opcode, a single rule can fold any supported operation:
+///
+/// ```lisp
+/// (=> (when $x (all-operands-const $x))
+///     (fold $x))
+/// ```
+///
+/// The optimization only matches when `$x` can actually be folded.
+#[derive(Debug, Ast)]
+pub struct Fold<'a> {
+    /// Where this `Fold` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The left-hand side variable bound to the instruction to fold.
+    pub value: Variable<'a>,
+}
+
+/// A `(delete)` right-hand side.
+///
+/// Rather than replacing the matched instruction with a new value, this
+/// removes it entirely. This is only valid as the whole right-hand side, and
+/// the optimization only matches when the instruction's result is unused:
+///
+/// ```lisp
+/// ;; Remove dead additions.
+/// (=> (iadd $x $y) (delete))
+/// ```
+#[derive(Debug, Ast)]
+pub struct Delete<'a> {
+    /// Where this `Delete` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    #[allow(missing_docs)]
+    #[peepmatic(skip_child)]
+    pub marker: PhantomData<&'a ()>,
+}
+
+/// An `(expand-iabs <rhs>)` right-hand side.
+///
+/// This builds the absolute value of its operand without an `iabs`
+/// instruction, for targets that lack one. `(expand-iabs $x)` expands into
+/// the branch-free
+///
+/// ```lisp
+/// (isub (bxor $x $s) $s)
+/// ```
+///
+/// where `$s` is `(sshr $x <width - 1>)`, which is all ones when `$x` is
+/// negative and zero otherwise.
+#[derive(Debug, Ast)]
+pub struct ExpandIabs<'a> {
+    /// Where this `ExpandIabs` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The inferred type of the operand and of its absolute value.
+    #[peepmatic(skip_child)]
+    pub r#type: Cell<Option<Type>>,
+
+    /// The value to take the absolute value of.
+    pub operand: Box<Rhs<'a>>,
+}
+
+/// A `(call <name> <rhs>*)` right-hand side.
+///
+/// This is the constant returned by the host function registered with the
+/// given name (see `PeepholeOptimizer::register_function`), when it is called
+/// with the values bound to the operands. The operands must be bound in the
+/// left-hand side, and there may be at most two of them:
+///
+/// ```lisp
+/// (=> (band $x $C)
+///     (band $x (call mask-for-width $C)))
+/// ```
+#[derive(Debug, Ast)]
+pub struct Call<'a> {
+    /// Where this `Call` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The name of the function to call.
+    #[peepmatic(skip_child)]
+    pub name: &'a str,
+
+    /// The operands to pass to the function.
+    #[peepmatic(flatten)]
+    pub operands: Vec<Rhs<'a>>,
+}
+
+/// A `(template <name> <rhs>*)` right-hand side.
+///
+/// This builds the named built-in idiom out of its operands, so that
+/// optimizations producing the same idiom don't each have to spell it out. For
+/// example, `(template negate $x)` builds `(isub 0 $x)`:
+///
+/// ```lisp
+/// (=> (imul $x -1)
+///     (template negate $x))
+/// ```
+///
+/// The available templates are `negate`, `sign-mask`, and `uavg-floor`. Their
+/// operands and results all have the same integer type.
+#[derive(Debug, Ast)]
+pub struct Template<'a> {
+    /// Where this `Template` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The name of the template to build.
+    #[peepmatic(skip_child)]
+    pub name: &'a str,
+
+    /// The inferred type of the operands and of the result.
+    #[peepmatic(skip_child)]
+    pub r#type: Cell<Option<Type>>,
+
+    /// The values to build the template out of.
+    #[peepmatic(flatten)]
+    pub operands: Vec<Rhs<'a>>,
+}
+
+/// A `(stored-value $l)` right-hand side.
+///
+/// When the load bound to `$l` immediately follows a store of the same width
+/// to the same address, this is the value that the store wrote, so the load
+/// can reuse it rather than reading it back from memory:
+///
+/// ```lisp
+/// ;; Forward stored values to notrap loads.
+/// (=> (when $l (mem-flags $l 1 1))
+///     (stored-value $l))
+/// ```
+///
+/// The optimization only matches when the value can actually be forwarded.
+#[derive(Debug, Ast)]
+pub struct StoredValue<'a> {
+    /// Where this `StoredValue` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The left-hand side variable bound to the load.
+    pub load: Variable<'a>,
+}
+
+/// A `(cond (<guard> <rhs>)* <rhs>)` right-hand side.
+///
+/// This picks the right-hand side of the first arm whose guard holds, or the
+/// last right-hand side if none of them do. The guards are magnitude checks on
+/// constants bound in the left-hand side, so that a constant that fits in an
+/// immediate field can use an `*_imm` form, while a larger one is materialized
+/// with an `iconst` first:
+///
+/// ```lisp
+/// (=> (iadd $x (iconst $C))
+///     (cond ((fits-in-imm12 $C) (iadd_imm $C $x))
+///           (iadd $x (iconst $C))))
+/// ```
+///
+/// A `cond` is only valid as the whole right-hand side. It is linearized into
+/// one linear optimization per arm.
+#[derive(Debug, Ast)]
+pub struct Cond<'a> {
+    /// Where this `Cond` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The guarded arms, in the order they are tried.
+    #[peepmatic(flatten)]
+    pub arms: Vec<CondArm<'a>>,
+
+    /// The right-hand side to use when none of the guards hold.
+    pub default: Box<Rhs<'a>>,
+}
+
+/// A single `(<guard> <rhs>)` arm of a `cond`.
+#[derive(Debug, Ast)]
+pub struct CondArm<'a> {
+    /// Where this `CondArm` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The magnitude check that must hold for this arm to be used.
+    pub guard: Precondition<'a>,
+
+    /// The right-hand side to use when the guard holds.
+    pub rhs: Rhs<'a>,
+}
+
+/// A `(results <rhs>*)` right-hand side.
+///
+/// This replaces each of the matched instruction's results with the
+/// corresponding right-hand side, for instructions that produce more than one
+/// result. For example, adding zero never carries out:
+///
+/// ```lisp
+/// (=> (iadd_cout $x 0)
+///     (results $x false))
+/// ```
+///
+/// A `results` is only valid as the whole right-hand side, and must have one
+/// right-hand side for each of the matched instruction's results.
+#[derive(Debug, Ast)]
+pub struct Results<'a> {
+    /// Where this `Results` was defined.
+    #[peepmatic(skip_child)]
+    pub span: wast::Span,
+
+    /// The replacement for each result, in order.
+    #[peepmatic(flatten)]
+    pub values: Vec<Rhs<'a>>,
 }
 
 /// An unquote operation.
@@ -2,18 +2,91 @@
 
 use peepmatic_automata::{Automaton, Builder};
 use peepmatic_runtime::linear;
+use peepmatic_runtime::operator::Operator;
+
+pub(crate) type LinearAutomaton = Automaton<Option<u32>, linear::MatchOp, Vec<linear::Action>>;
 
 /// Construct an automaton from a set of linear optimizations.
+///
+/// Returns an error if an optimization neither designates a result nor deletes
+/// or replaces each result of its root.
 pub fn automatize(
     opts: &linear::Optimizations,
-) -> Automaton<Option<u32>, linear::MatchOp, Vec<linear::Action>> {
+) -> anyhow::Result<Automaton<Option<u32>, linear::MatchOp, Vec<linear::Action>>> {
+    automatize_subset(opts, 0..opts.optimizations.len())
+}
+
+/// Construct a separate automaton for each root operator of a set of linear
+/// optimizations.
+///
+/// Returns an automaton of just the optimizations that might match any
+/// instruction, and then an automaton for each root operator, sorted by
+/// operator. Each of the latter also contains the optimizations that might
+/// match any instruction. Every automaton refers to optimizations by their
+/// index in `opts`, just like `automatize`'s, and fails in the same cases.
+pub fn automatize_by_root_operator(
+    opts: &linear::Optimizations,
+) -> anyhow::Result<(
+    Automaton<Option<u32>, linear::MatchOp, Vec<linear::Action>>,
+    Vec<(
+        Operator,
+        Automaton<Option<u32>, linear::MatchOp, Vec<linear::Action>>,
+    )>,
+)> {
+    let root_operators = opts.root_operators();
+    let subset = |root: Option<Operator>| {
+        let indices = root_operators
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.is_none() || **op == root)
+            .map(|(i, _)| i);
+        automatize_subset(opts, indices)
+    };
+
+    let mut operators: Vec<Operator> = root_operators.iter().filter_map(|op| *op).collect();
+    operators.sort_by_key(|op| *op as u32);
+    operators.dedup();
+
+    let any = subset(None)?;
+    let by_operator = operators
+        .into_iter()
+        .map(|op| Ok((op, subset(Some(op))?)))
+        .collect::<anyhow::Result<_>>()?;
+    Ok((any, by_operator))
+}
+
+/// Construct an automaton from the optimizations at the given indices, which
+/// must be in increasing order.
+fn automatize_subset(
+    opts: &linear::Optimizations,
+    indices: impl IntoIterator<Item = usize>,
+) -> anyhow::Result<LinearAutomaton> {
     debug_assert!(crate::linear_passes::is_sorted_lexicographically(opts));
 
     let mut builder = Builder::<Option<u32>, linear::MatchOp, Vec<linear::Action>>::new();
 
-    for opt in &opts.optimizations {
+    let mut prev: Option<&linear::Optimization> = None;
+    for index in indices {
+        let opt = &opts.optimizations[index];
+        let replaces_root_otherwise = opt.increments.iter().flat_map(|i| &i.actions).any(|a| {
+            matches!(
+                a,
+                linear::Action::DeleteInstruction | linear::Action::ReplaceResults { .. }
+            )
+        });
+        if opt.result.is_none() && !replaces_root_otherwise {
+            anyhow::bail!(
+                "optimization {} must designate a right-hand side result",
+                index
+            );
+        }
+        if let Some(prev) = prev {
+            check_wildcard_order(prev, opt);
+        }
+        prev = Some(opt);
+
         let mut insertion = builder.insert();
-        for inc in &opt.increments {
+        for (i, inc) in opt.increments.iter().enumerate() {
             // Ensure that this state's associated data is this increment's
             // match operation.
             if let Some(op) = insertion.get_state_data() {
@@ -22,10 +95,121 @@ pub fn automatize(
                 insertion.set_state_data(inc.operation);
             }
 
-            insertion.next(inc.expected, inc.actions.clone());
+            let mut actions = inc.actions.clone();
+            if i == opt.increments.len() - 1 {
+                // Record which optimization this is, so that the optimizer can
+                // report it to match observers.
+                actions.push(linear::Action::Matched {
+                    optimization: index as u32,
+                });
+            }
+            insertion.next(inc.expected, actions);
         }
         insertion.finish();
     }
 
-    builder.finish()
+    Ok(builder.finish())
+}
+
+/// Check that `opt`, which is inserted right after `prev`, does not add a
+/// specific transition out of a state that `prev` already left through that
+/// state's wildcard (`None`) transition.
+///
+/// The optimizer tries a state's specific transitions first, and only falls
+/// back to its wildcard transition when they fail to match. Building every
+/// specific transition's optimizations before the wildcard's keeps the
+/// automaton's construction in that same order.
+fn check_wildcard_order(prev: &linear::Optimization, opt: &linear::Optimization) {
+    let divergence = prev
+        .increments
+        .iter()
+        .zip(opt.increments.iter())
+        .find(|(a, b)| a.expected != b.expected);
+    if let Some((a, b)) = divergence {
+        assert!(
+            a.expected.is_some() || b.expected.is_none(),
+            "a specific transition was inserted after its state's wildcard transition"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peepmatic_runtime::{integer_interner::IntegerInterner, paths::PathInterner};
+
+    #[test]
+    fn missing_result() {
+        let opts = linear::Optimizations {
+            paths: PathInterner::new(),
+            integers: IntegerInterner::new(),
+            predicates: vec![],
+            functions: vec![],
+            optimizations: vec![linear::Optimization {
+                increments: vec![linear::Increment {
+                    operation: linear::MatchOp::Nop,
+                    expected: None,
+                    actions: vec![],
+                }],
+                result: None,
+                doc: None,
+                location: None,
+            }],
+        };
+        let err = automatize(&opts).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "optimization 0 must designate a right-hand side result"
+        );
+    }
+
+    #[test]
+    fn deletion_has_no_result() {
+        let mut paths = PathInterner::new();
+        let root = paths.intern(peepmatic_runtime::paths::Path::new(&[0]));
+        let opts = linear::Optimizations {
+            paths,
+            integers: IntegerInterner::new(),
+            predicates: vec![],
+            functions: vec![],
+            optimizations: vec![linear::Optimization {
+                increments: vec![linear::Increment {
+                    operation: linear::MatchOp::IsUnused { path: root },
+                    expected: Some(1),
+                    actions: vec![linear::Action::DeleteInstruction],
+                }],
+                result: None,
+                doc: None,
+                location: None,
+            }],
+        };
+        automatize(&opts).unwrap();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "a specific transition was inserted after its state's wildcard transition"
+    )]
+    fn wildcard_before_specific() {
+        let mut paths = PathInterner::new();
+        let root = paths.intern(peepmatic_runtime::paths::Path::new(&[0]));
+        let opt = |expected| linear::Optimization {
+            increments: vec![linear::Increment {
+                operation: linear::MatchOp::IntegerValue { path: root },
+                expected,
+                actions: vec![linear::Action::GetLhs { path: root }],
+            }],
+            result: Some(linear::RhsId(0)),
+            doc: None,
+            location: None,
+        };
+        let opts = linear::Optimizations {
+            paths,
+            integers: IntegerInterner::new(),
+            predicates: vec![],
+            functions: vec![],
+            optimizations: vec![opt(None), opt(Some(0))],
+        };
+        check_wildcard_order(&opts.optimizations[0], &opts.optimizations[1]);
+    }
 }
@@ -1,16 +1,12 @@
 //! Formatting a peephole optimizer's automata for GraphViz Dot.
 //!
-//! See also `crates/automata/src/dot.rs`.
+//! See also `crates/automata/src/dot.rs` and `src/linear_fmt.rs`.
 
+use crate::linear_fmt;
 use peepmatic_automata::dot::DotFmt;
 use peepmatic_runtime::{
-    cc::ConditionCode,
-    integer_interner::{IntegerId, IntegerInterner},
-    linear,
-    operator::Operator,
-    paths::{PathId, PathInterner},
+    integer_interner::IntegerInterner, linear, operator::Operator, paths::PathInterner,
 };
-use std::convert::TryFrom;
 use std::io::{self, Write};
 
 #[derive(Debug)]
@@ -25,49 +21,15 @@ impl DotFmt<Option<u32>, linear::MatchOp, Vec<linear::Action>> for PeepholeDotFm
         _to: Option<&linear::MatchOp>,
     ) -> io::Result<()> {
         let from = from.expect("we should have match op for every state");
-        if let Some(x) = input {
-            match from {
-                linear::MatchOp::Opcode { .. } => {
-                    let opcode =
-                        Operator::try_from(*x).expect("we shouldn't generate non-opcode edges");
-                    write!(w, "{}", opcode)
-                }
-                linear::MatchOp::ConditionCode { .. } => {
-                    let cc =
-                        ConditionCode::try_from(*x).expect("we shouldn't generate non-CC edges");
-                    write!(w, "{}", cc)
-                }
-                linear::MatchOp::IntegerValue { .. } => {
-                    let x = self.1.lookup(IntegerId(*x));
-                    write!(w, "{}", x)
-                }
-                _ => write!(w, "{}", x),
-            }
-        } else {
-            write!(w, "(else)")
-        }
+        let mut s = String::new();
+        linear_fmt::fmt_expected(&mut s, self.1, from, *input).unwrap();
+        write!(w, "{}", s)
     }
 
     fn fmt_state(&self, w: &mut impl Write, op: &linear::MatchOp) -> io::Result<()> {
-        use linear::MatchOp::*;
-
-        write!(w, r#"<font face="monospace">"#)?;
-
-        let p = p(self.0);
-        match op {
-            Opcode { path } => write!(w, "opcode @ {}", p(path))?,
-            IsConst { path } => write!(w, "is-const? @ {}", p(path))?,
-            IsPowerOfTwo { path } => write!(w, "is-power-of-two? @ {}", p(path))?,
-            BitWidth { path } => write!(w, "bit-width @ {}", p(path))?,
-            FitsInNativeWord { path } => write!(w, "fits-in-native-word @ {}", p(path))?,
-            Eq { path_a, path_b } => write!(w, "{} == {}", p(path_a), p(path_b))?,
-            IntegerValue { path } => write!(w, "integer-value @ {}", p(path))?,
-            BooleanValue { path } => write!(w, "boolean-value @ {}", p(path))?,
-            ConditionCode { path } => write!(w, "condition-code @ {}", p(path))?,
-            Nop => write!(w, "nop")?,
-        }
-
-        writeln!(w, "</font>")
+        let mut s = String::new();
+        linear_fmt::fmt_match_op(&mut s, self.0, self.1, op).unwrap();
+        writeln!(w, r#"<font face="monospace">{}</font>"#, s)
     }
 
     fn fmt_output(&self, w: &mut impl Write, actions: &Vec<linear::Action>) -> io::Result<()> {
@@ -79,7 +41,7 @@ impl DotFmt<Option<u32>, linear::MatchOp, Vec<linear::Action>> for PeepholeDotFm
 
         write!(w, r#"<font face="monospace">"#)?;
 
-        let p = p(self.0);
+        let p = linear_fmt::p(self.0);
 
         for a in actions {
             match a {
@@ -96,11 +58,25 @@ impl DotFmt<Option<u32>, linear::MatchOp, Vec<linear::Action>> for PeepholeDotFm
                     value,
                     bit_width: _,
                 } => write!(w, "make {}<br/>", self.1.lookup(*value))?,
+                MakeIconstMatchingWidth { value, like } => {
+                    write!(w, "make {} like @ {}<br/>", self.1.lookup(*value), p(like))?
+                }
+                MakeZero { bit_width: _ } => write!(w, "make zero<br/>")?,
+                MakeOne { bit_width: _ } => write!(w, "make one<br/>")?,
+                MakeSignShiftAmount { bit_width: _ } => write!(w, "make sign-shift-amount<br/>")?,
+                FoldConst { path } => write!(w, "fold @ {}<br/>", p(path))?,
+                GetStoredValue { path } => write!(w, "get stored value @ {}<br/>", p(path))?,
                 MakeBooleanConst {
                     value,
                     bit_width: _,
                 } => write!(w, "make {}<br/>", value)?,
                 MakeConditionCode { cc } => write!(w, "{}<br/>", cc)?,
+                InvertConditionCode { operand } => write!(w, "invert-cc $rhs{}<br/>", operand.0)?,
+                CombineConditionCodes { operands } => write!(
+                    w,
+                    "combine-cc $rhs{}, $rhs{}<br/>",
+                    operands[0].0, operands[1].0,
+                )?,
                 MakeUnaryInst {
                     operand,
                     operator,
@@ -124,19 +100,74 @@ impl DotFmt<Option<u32>, linear::MatchOp, Vec<linear::Action>> for PeepholeDotFm
                     "make {} $rhs{}, $rhs{}, $rhs{}<br/>",
                     operator, operands[0].0, operands[1].0, operands[2].0,
                 )?,
+                MakeSelectSpectreGuard {
+                    operands,
+                    r#type: _,
+                } => write!(
+                    w,
+                    "make {} $rhs{}, $rhs{}, $rhs{}<br/>",
+                    Operator::SelectSpectreGuard,
+                    operands[0].0,
+                    operands[1].0,
+                    operands[2].0,
+                )?,
+                MakeLoad {
+                    operand,
+                    offset,
+                    flags,
+                    bit_width: _,
+                } => {
+                    write!(w, "make load offset{:+}", offset)?;
+                    if let Some(flags) = flags {
+                        write!(w, " flags={:#x}", flags)?;
+                    }
+                    write!(w, " $rhs{}<br/>", operand.0)?;
+                }
+                MakeStore {
+                    value,
+                    addr,
+                    offset,
+                    flags,
+                } => {
+                    write!(w, "make store offset{:+}", offset)?;
+                    if let Some(flags) = flags {
+                        write!(w, " flags={:#x}", flags)?;
+                    }
+                    write!(w, " $rhs{}, $rhs{}<br/>", value.0, addr.0)?;
+                }
+                MakeExtractLane { operand, lane } => {
+                    write!(w, "make extractlane {}, $rhs{}<br/>", lane, operand.0)?
+                }
+                MakeInsertLane {
+                    vector,
+                    value,
+                    lane,
+                } => write!(
+                    w,
+                    "make insertlane {}, $rhs{}, $rhs{}<br/>",
+                    lane, vector.0, value.0
+                )?,
+                CallFunction { function, operands } => {
+                    write!(w, "call #{}", function)?;
+                    for (i, operand) in operands.iter().flatten().enumerate() {
+                        let sep = if i == 0 { " " } else { ", " };
+                        write!(w, "{}$rhs{}", sep, operand.0)?;
+                    }
+                    write!(w, "<br/>")?
+                }
+                DeleteInstruction => write!(w, "delete<br/>")?,
+                ReplaceResults { results } => {
+                    write!(w, "results")?;
+                    for (i, id) in results.iter().enumerate() {
+                        let sep = if i == 0 { " " } else { ", " };
+                        write!(w, "{}$rhs{}", sep, id.0)?;
+                    }
+                    writeln!(w, "<br/>")?
+                }
+                Matched { optimization } => write!(w, "matched #{}<br/>", optimization)?,
             }
         }
 
         writeln!(w, "</font>")
     }
 }
-
-fn p<'a>(paths: &'a PathInterner) -> impl Fn(&PathId) -> String + 'a {
-    move |path: &PathId| {
-        let mut s = vec![];
-        for b in paths.lookup(*path).0 {
-            s.push(b.to_string());
-        }
-        s.join(".")
-    }
-}
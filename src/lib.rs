@@ -13,16 +13,22 @@ the set of optimizations into an efficient peephole optimizer.
 mod ast;
 mod automatize;
 mod dot_fmt;
+mod linear_fmt;
+mod linear_json;
 mod linear_passes;
+mod linear_stream;
 mod linearize;
 mod parser;
+mod templates;
 mod traversals;
 mod verify;
 pub use self::{
-    ast::*, automatize::*, linear_passes::*, linearize::*, parser::*, traversals::*, verify::*,
+    ast::*, automatize::*, linear_fmt::*, linear_json::*, linear_passes::*, linear_stream::*,
+    linearize::*, parser::*, traversals::*, verify::*,
 };
 
-use peepmatic_runtime::PeepholeOptimizations;
+use crate::automatize::LinearAutomaton;
+use peepmatic_runtime::{linear, operator::Operator, PeepholeOptimizations};
 use std::fs;
 use std::path::Path;
 
@@ -88,14 +94,103 @@ pub fn compile_file(filename: &Path) -> anyhow::Result<PeepholeOptimizations> {
 /// `PEEPMATIC_DOT` environment variable to a file path. A [GraphViz
 /// Dot]((https://graphviz.gitlab.io/_pages/pdf/dotguide.pdf)) file showing the
 /// peephole optimizer's automaton will be written to that file path.
+///
+/// Compilation is deterministic: the same source always produces the same
+/// automaton, and therefore the same Dot file and serialized bytes.
 pub fn compile_str(source: &str, filename: &Path) -> anyhow::Result<PeepholeOptimizations> {
+    let opts = compile_to_linear(source, filename)?;
+    let automata = automatize(&opts)?;
+    Ok(finish_compile(opts, automata, vec![]))
+}
+
+/// Compile the given DSL source text like [compile_str][crate::compile_str],
+/// but into a separate automaton for each root operator.
+///
+/// The optimizer picks the automaton to match an instruction against by the
+/// instruction's operator, so each match only walks the optimizations that
+/// could apply to it. Optimizations whose left-hand sides are not operations
+/// are included in every automaton. The rewrites are exactly the same as with
+/// a single automaton.
+///
+/// When `PEEPMATIC_DOT` is set, only the automaton of optimizations that might
+/// match any instruction is written.
+pub fn compile_str_partitioned(
+    source: &str,
+    filename: &Path,
+) -> anyhow::Result<PeepholeOptimizations> {
+    let opts = compile_to_linear(source, filename)?;
+    let (automata, opcode_automata) = automatize_by_root_operator(&opts)?;
+    Ok(finish_compile(opts, automata, opcode_automata))
+}
+
+/// Package up linear optimizations and their automata, ready for the runtime.
+fn finish_compile(
+    opts: linear::Optimizations,
+    automata: LinearAutomaton,
+    opcode_automata: Vec<(Operator, LinearAutomaton)>,
+) -> PeepholeOptimizations {
+    let root_operators = opts.root_operators();
+    let results = opts.optimizations.iter().map(|opt| opt.result).collect();
+    let paths = opts.paths;
+    let integers = opts.integers;
+    let predicates = opts.predicates;
+    let functions = opts.functions;
+
+    if let Ok(path) = std::env::var("PEEPMATIC_DOT") {
+        let f = dot_fmt::PeepholeDotFmt(&paths, &integers);
+        if let Err(e) = automata.write_dot_file(&f, &path) {
+            panic!(
+                "failed to write GraphViz Dot file to PEEPMATIC_DOT={}; error: {}",
+                path, e
+            );
+        }
+    }
+
+    PeepholeOptimizations {
+        paths,
+        integers,
+        automata,
+        opcode_automata,
+        root_operators,
+        results,
+        predicates,
+        functions,
+    }
+}
+
+/// Compile the given DSL source text into linear optimizations, stopping just
+/// before building the automaton.
+///
+/// The result has been through all of the linear passes, so it is sorted and
+/// deduplicated exactly as [compile_str][crate::compile_str] would see it. This
+/// is mostly useful for inspecting or snapshot testing the linearizer; see
+/// [format_linear][crate::format_linear].
+pub fn compile_to_linear(source: &str, filename: &Path) -> anyhow::Result<linear::Optimizations> {
     let buf = wast::parser::ParseBuffer::new(source).map_err(|mut e| {
         e.set_path(filename);
         e.set_text(source);
         e
     })?;
 
-    let opts = wast::parser::parse::<Optimizations>(&buf).map_err(|mut e| {
+    let mut opts = wast::parser::parse::<Optimizations>(&buf).map_err(|mut e| {
+        e.set_path(filename);
+        e.set_text(source);
+        e
+    })?;
+    attach_doc_comments(source, &mut opts);
+    attach_source_locations(source, &mut opts);
+
+    check_rhs_depth(&opts, DEFAULT_MAX_RHS_DEPTH).map_err(|mut e| {
+        e.set_path(filename);
+        e.set_text(source);
+        e
+    })?;
+    check_operand_arity(&opts).map_err(|mut e| {
+        e.set_path(filename);
+        e.set_text(source);
+        e
+    })?;
+    check_type_variables(&opts).map_err(|mut e| {
         e.set_path(filename);
         e.set_text(source);
         e
@@ -108,30 +203,14 @@ pub fn compile_str(source: &str, filename: &Path) -> anyhow::Result<PeepholeOpti
     })?;
 
     let mut opts = crate::linearize(&opts);
+    remove_redundant_get_lhs(&mut opts);
     sort_least_to_most_general(&mut opts);
     remove_unnecessary_nops(&mut opts);
     match_in_same_order(&mut opts);
     sort_lexicographically(&mut opts);
+    remove_duplicate_left_hand_sides(&mut opts);
 
-    let automata = automatize(&opts);
-    let paths = opts.paths;
-    let integers = opts.integers;
-
-    if let Ok(path) = std::env::var("PEEPMATIC_DOT") {
-        let f = dot_fmt::PeepholeDotFmt(&paths, &integers);
-        if let Err(e) = automata.write_dot_file(&f, &path) {
-            panic!(
-                "failed to write GraphViz Dot file to PEEPMATIC_DOT={}; error: {}",
-                path, e
-            );
-        }
-    }
-
-    Ok(PeepholeOptimizations {
-        paths,
-        integers,
-        automata,
-    })
+    Ok(opts)
 }
 
 #[cfg(test)]
@@ -147,4 +226,190 @@ mod tests {
     fn compile_preopt() {
         compile_file(Path::new("examples/preopt.peepmatic")).unwrap();
     }
+
+    #[test]
+    fn compilation_is_deterministic() {
+        let filename = Path::new("examples/preopt.peepmatic");
+        let source = fs::read_to_string(filename).unwrap();
+
+        let dot = |opts: &PeepholeOptimizations| {
+            let f = dot_fmt::PeepholeDotFmt(&opts.paths, &opts.integers);
+            let mut buf = vec![];
+            opts.automata.write_dot(&f, &mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        let first = compile_str(&source, filename).unwrap();
+        let expected = dot(&first);
+        assert!(!expected.is_empty());
+
+        for _ in 0..5 {
+            let opts = compile_str(&source, filename).unwrap();
+            assert_eq!(dot(&opts), expected);
+            assert_eq!(opts.root_operators, first.root_operators);
+        }
+    }
+
+    #[test]
+    fn serialization_is_deterministic() {
+        let serialize = |source: &str, name: &str| {
+            let opts = compile_str(source, Path::new(name)).unwrap();
+            let path = std::env::temp_dir().join(format!("peepmatic-{}.bin", name));
+            opts.serialize_to_file(&path).unwrap();
+            let serialized = fs::read(&path).unwrap();
+            let _ = fs::remove_file(&path);
+            serialized
+        };
+
+        // These optimizations intern the same paths in the same order, and no
+        // integers, so once they are sorted, the order that they are written
+        // in makes no difference either.
+        let source = "
+(=> (band $x $x) $x)
+(=> (bor $x $x) $x)
+(=> (bxor $x $y) (bxor $y $x))
+";
+        let reordered = "
+(=> (bxor $x $y) (bxor $y $x))
+(=> (band $x $x) $x)
+(=> (bor $x $x) $x)
+";
+        let expected = serialize(source, "serialization-is-deterministic");
+        assert!(!expected.is_empty());
+        assert_eq!(
+            serialize(source, "serialization-is-deterministic-again"),
+            expected
+        );
+        assert_eq!(
+            serialize(reordered, "serialization-is-deterministic-reordered"),
+            expected
+        );
+    }
+
+    #[test]
+    fn format_linear_with_locations() {
+        let opts = compile_to_linear(
+            "
+;; Adding zero is a no-op.
+(=> (iadd $x 0) $x)
+
+    (=> (imul $x 1) $x)
+",
+            Path::new("format_linear_with_locations"),
+        )
+        .unwrap();
+        let text = super::format_linear_with_locations(&opts);
+
+        // Each optimization's header points back at the rule it came from,
+        // whichever order the optimizations were sorted into.
+        let headers: Vec<_> = text
+            .split("optimization ")
+            .skip(1)
+            .map(|opt| {
+                let mut lines = opt.lines();
+                let header = lines.next().unwrap();
+                let opcode = lines.next().unwrap().trim();
+                (opcode, &header[header.find(";;").unwrap()..])
+            })
+            .collect();
+        assert_eq!(headers.len(), 2);
+        assert!(headers.contains(&("opcode @ 0 == iadd", ";; line 3, column 1")));
+        assert!(headers.contains(&("opcode @ 0 == imul", ";; line 5, column 5")));
+
+        // The plain format doesn't include them.
+        assert!(!format_linear(&opts).contains(";;"));
+    }
+
+    #[test]
+    fn diff_optimizations() {
+        let old = compile_to_linear(
+            "
+;; add zero
+(=> (iadd $x 0) $x)
+;; double
+(=> (imul $x 2) (iadd $x $x))
+;; sub zero
+(=> (isub $x 0) $x)
+",
+            Path::new("diff_optimizations_old"),
+        )
+        .unwrap();
+        let new = compile_to_linear(
+            "
+;; mul one
+(=> (imul $x 1) $x)
+;; double
+(=> (imul $x 2) (ishl $x 1))
+;; add zero
+(=> (iadd $x 0) $x)
+",
+            Path::new("diff_optimizations_new"),
+        )
+        .unwrap();
+
+        let diff = old.diff(&new);
+        let old_doc = |i: usize| old.optimizations[i].doc.as_deref().unwrap();
+        let new_doc = |i: usize| new.optimizations[i].doc.as_deref().unwrap();
+        let added: Vec<_> = diff.added.iter().map(|&i| new_doc(i)).collect();
+        let removed: Vec<_> = diff.removed.iter().map(|&i| old_doc(i)).collect();
+        let modified: Vec<_> = diff
+            .modified
+            .iter()
+            .map(|&(i, j)| (old_doc(i), new_doc(j)))
+            .collect();
+        assert_eq!(added, vec!["mul one"]);
+        assert_eq!(removed, vec!["sub zero"]);
+        assert_eq!(modified, vec![("double", "double")]);
+
+        // Nothing changed between a set and itself, even when it was compiled
+        // separately with its rules in a different order.
+        assert!(old.diff(&old).is_empty());
+        let reordered = compile_to_linear(
+            "
+(=> (isub $x 0) $x)
+(=> (imul $x 2) (iadd $x $x))
+(=> (iadd $x 0) $x)
+",
+            Path::new("diff_optimizations_reordered"),
+        )
+        .unwrap();
+        assert!(old.diff(&reordered).is_empty());
+    }
+
+    #[test]
+    fn transition_labels() {
+        use peepmatic_runtime::{linear::MatchOp, operator::Operator, paths::Path as LhsPath};
+        use std::collections::HashSet;
+
+        let mut opts = compile_str(
+            "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+",
+            Path::new("transition_labels"),
+        )
+        .unwrap();
+
+        let root = opts.paths.intern(LhsPath::new(&[0]));
+        let rhs_operand = opts.paths.intern(LhsPath::new(&[0, 1]));
+        let zero = opts.integers.already_interned(0u64).unwrap();
+        let one = opts.integers.already_interned(1u64).unwrap();
+
+        let expected: HashSet<_> = vec![
+            (MatchOp::Opcode { path: root }, Some(Operator::Iadd as u32)),
+            (MatchOp::Opcode { path: root }, Some(Operator::Imul as u32)),
+            (MatchOp::IntegerValue { path: rhs_operand }, Some(zero.into())),
+            (MatchOp::IntegerValue { path: rhs_operand }, Some(one.into())),
+        ]
+        .into_iter()
+        .collect();
+
+        let actual: Vec<_> = opts
+            .automata
+            .transition_labels()
+            .map(|(op, input)| (*op, *input))
+            .collect();
+        assert_eq!(actual.len(), expected.len(), "labels are distinct");
+        assert_eq!(actual.into_iter().collect::<HashSet<_>>(), expected);
+    }
 }
@@ -0,0 +1,396 @@
+//! Formatting linear optimizations as canonical text.
+//!
+//! This is mostly useful for snapshot testing the linearizer and the linear
+//! passes: the same linear optimizations always format to the same text, and
+//! paths and interned integers are printed by value rather than by id, so that
+//! unrelated changes to interning order don't show up as differences.
+//!
+//! See also `src/dot_fmt.rs`, which shares the match operation formatting.
+
+use peepmatic_runtime::{
+    cc::ConditionCode,
+    integer_interner::{IntegerId, IntegerInterner},
+    linear,
+    operator::Operator,
+    paths::{PathId, PathInterner},
+};
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+
+/// Format the given linear optimizations as canonical text.
+///
+/// Each optimization is printed as a header line, followed by one line per
+/// increment: the match operation and its expected result. The actions of an
+/// increment, if any, are printed on the lines after it, indented one level
+/// further.
+///
+/// ```text
+/// optimization 0
+///   opcode @ 0 == iadd
+///     get-lhs @ 0.0
+///   integer-value @ 0.1 == 0
+/// ```
+pub fn format_linear(opts: &linear::Optimizations) -> String {
+    let mut s = String::new();
+    write_linear(&mut s, opts, false).expect("writing to a `String` cannot fail");
+    s
+}
+
+/// Format the given linear optimizations as canonical text, annotating each
+/// optimization with the line and column of the DSL source that it was
+/// compiled from.
+///
+/// This is the same as [format_linear][crate::format_linear], except that each
+/// optimization's header line ends with a comment pointing back to its source,
+/// when that is known:
+///
+/// ```text
+/// optimization 0 ;; line 3, column 1
+///   opcode @ 0 == iadd
+///     get-lhs @ 0.0
+///   integer-value @ 0.1 == 0
+/// ```
+pub fn format_linear_with_locations(opts: &linear::Optimizations) -> String {
+    let mut s = String::new();
+    write_linear(&mut s, opts, true).expect("writing to a `String` cannot fail");
+    s
+}
+
+fn write_linear(w: &mut impl Write, opts: &linear::Optimizations, locations: bool) -> fmt::Result {
+    let paths = &opts.paths;
+    let integers = &opts.integers;
+    for (i, opt) in opts.optimizations.iter().enumerate() {
+        write!(w, "optimization {}", i)?;
+        match opt.location {
+            Some(l) if locations => writeln!(w, " ;; line {}, column {}", l.line, l.column)?,
+            _ => writeln!(w)?,
+        }
+        for inc in &opt.increments {
+            write!(w, "  ")?;
+            fmt_match_op(w, paths, integers, &inc.operation)?;
+            write!(w, " == ")?;
+            fmt_expected(w, integers, &inc.operation, inc.expected)?;
+            writeln!(w)?;
+            for action in &inc.actions {
+                write!(w, "    ")?;
+                fmt_action(w, paths, integers, action)?;
+                writeln!(w)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Format a match operation's expected result, or `(else)` for `None`.
+pub(crate) fn fmt_expected(
+    w: &mut impl Write,
+    integers: &IntegerInterner,
+    op: &linear::MatchOp,
+    expected: Option<u32>,
+) -> fmt::Result {
+    let x = match expected {
+        Some(x) => x,
+        None => return write!(w, "(else)"),
+    };
+    match op {
+        linear::MatchOp::Opcode { .. } => {
+            let opcode = Operator::try_from(x).expect("we shouldn't generate non-opcode edges");
+            write!(w, "{}", opcode)
+        }
+        linear::MatchOp::ConditionCode { .. } => {
+            let cc = ConditionCode::try_from(x).expect("we shouldn't generate non-CC edges");
+            write!(w, "{}", cc)
+        }
+        linear::MatchOp::IntegerValue { .. } => write!(w, "{}", integers.lookup(IntegerId(x))),
+        _ => write!(w, "{}", x),
+    }
+}
+
+/// Format a match operation, without its expected result.
+pub(crate) fn fmt_match_op(
+    w: &mut impl Write,
+    paths: &PathInterner,
+    integers: &IntegerInterner,
+    op: &linear::MatchOp,
+) -> fmt::Result {
+    use linear::MatchOp::*;
+
+    let p = p(paths);
+    match op {
+        Opcode { path } => write!(w, "opcode @ {}", p(path)),
+        IsConst { path } => write!(w, "is-const? @ {}", p(path)),
+        IsPowerOfTwo { path } => write!(w, "is-power-of-two? @ {}", p(path)),
+        IsOne { path } => write!(w, "is-one? @ {}", p(path)),
+        IsShiftByConst { path } => write!(w, "is-shift-by-const? @ {}", p(path)),
+        IsCallResult { path } => write!(w, "is-call-result? @ {}", p(path)),
+        IsExtension { path } => write!(w, "is-extension @ {}", p(path)),
+        SingleUse { path } => write!(w, "single-use? @ {}", p(path)),
+        AllOperandsConst { path } => write!(w, "all-operands-const? @ {}", p(path)),
+        SelectConstArms { path } => write!(w, "select-const-arms? @ {}", p(path)),
+        IsFoldable { path } => write!(w, "is-foldable? @ {}", p(path)),
+        IsForwardableLoad { path } => write!(w, "is-forwardable-load? @ {}", p(path)),
+        IsUnused { path } => write!(w, "is-unused? @ {}", p(path)),
+        IsByteMask { path } => write!(w, "is-byte-mask? @ {}", p(path)),
+        IsMaskConst { path } => write!(w, "is-mask-const @ {}", p(path)),
+        PopcountEq { path, count } => write!(w, "popcount == {} @ {}", count, p(path)),
+        PopcountLe { path, count } => write!(w, "popcount <= {} @ {}", count, p(path)),
+        BitWidth { path } => write!(w, "bit-width @ {}", p(path)),
+        SameWidth { path_a, path_b } => {
+            write!(w, "same-width? @ {}, {}", p(path_a), p(path_b))
+        }
+        BlockPredecessorCount { path } => write!(w, "block-predecessor-count @ {}", p(path)),
+        NegationFitsInBits { path, bits } => {
+            write!(w, "negation-fits-in-bits? {} @ {}", bits, p(path))
+        }
+        FitsInBits { path, bits } => write!(w, "fits-in-bits? {} @ {}", bits, p(path)),
+        TrailingZeros { path } => write!(w, "trailing-zeros @ {}", p(path)),
+        ConstEqWidthMinus { path, delta } => {
+            write!(w, "is-width-minus? {} @ {}", delta, p(path))
+        }
+        FitsInNativeWord { path } => write!(w, "fits-in-native-word @ {}", p(path)),
+        Eq { path_a, path_b } => write!(w, "{} == {}", p(path_a), p(path_b)),
+        ConstEq { path_a, path_b } => write!(w, "const-eq? @ {}, {}", p(path_a), p(path_b)),
+        EquivalentValues { path_a, path_b } => {
+            write!(w, "equivalent-values? @ {}, {}", p(path_a), p(path_b))
+        }
+        IsOneLessThan { path_a, path_b } => {
+            write!(w, "is-one-less-than? @ {}, {}", p(path_a), p(path_b))
+        }
+        IsScaledBy {
+            path_a,
+            path_b,
+            factor,
+        } => write!(
+            w,
+            "is-scaled-by? {} @ {}, {}",
+            integers.lookup(*factor),
+            p(path_a),
+            p(path_b)
+        ),
+        NestedShiftCombinable { outer, inner } => {
+            write!(w, "shifts-combine? @ {}, {}", p(outer), p(inner))
+        }
+        IsDoubleInvolution { outer, inner } => {
+            write!(w, "is-double-involution? @ {}, {}", p(outer), p(inner))
+        }
+        RedundantMaskForShift { mask, amount } => {
+            write!(w, "redundant-mask-for-shift? @ {}, {}", p(mask), p(amount))
+        }
+        ConditionCodesCombine { path_a, path_b } => {
+            write!(w, "ccs-combine? @ {}, {}", p(path_a), p(path_b))
+        }
+        CustomPredicate { path, predicate } => {
+            write!(w, "predicate? #{} @ {}", predicate, p(path))
+        }
+        IntegerInSet { path, set } => {
+            let set: Vec<_> = integers
+                .lookup_set(*set)
+                .iter()
+                .map(|id| integers.lookup(*id).to_string())
+                .collect();
+            write!(w, "in-set? {{{}}} @ {}", set.join(", "), p(path))
+        }
+        BoundedBy { path, lo, hi } => write!(
+            w,
+            "bounded-by? [{}, {}] @ {}",
+            integers.lookup(*lo),
+            integers.lookup(*hi),
+            p(path)
+        ),
+        IsAligned { path, align } => write!(w, "is-aligned? {} @ {}", align, p(path)),
+        HighBitsZero { path, from_width } => {
+            write!(w, "high-bits-zero? {} @ {}", from_width, p(path))
+        }
+        KnownNonNegative { path } => write!(w, "known-non-negative? @ {}", p(path)),
+        IsEncodableLogicalImm { path, width } => {
+            write!(w, "is-encodable-logical-imm? {} @ {}", width, p(path))
+        }
+        IsAddOfNegConst { path } => write!(w, "is-add-of-neg-const? @ {}", p(path)),
+        IsShiftedImm { path, bits, shift } => {
+            write!(w, "is-shifted-imm? {} << {} @ {}", bits, shift, p(path))
+        }
+        IsShiftedImm8 { path } => write!(w, "is-shifted-imm8? @ {}", p(path)),
+        MemFlagsMatch {
+            path,
+            flags_mask,
+            flags_value,
+        } => write!(
+            w,
+            "mem-flags & {:#x} == {:#x} @ {}",
+            flags_mask,
+            flags_value,
+            p(path)
+        ),
+        IntegerValue { path } => write!(w, "integer-value @ {}", p(path)),
+        BooleanValue { path } => write!(w, "boolean-value @ {}", p(path)),
+        ConditionCode { path } => write!(w, "condition-code @ {}", p(path)),
+        IcmpZero { path } => write!(w, "icmp-zero @ {}", p(path)),
+        InPhase { phase } => write!(w, "in-phase {}?", phase),
+        IsEnabled => write!(w, "enabled?"),
+        Nop => write!(w, "nop"),
+    }
+}
+
+fn fmt_action(
+    w: &mut impl Write,
+    paths: &PathInterner,
+    integers: &IntegerInterner,
+    action: &linear::Action,
+) -> fmt::Result {
+    use linear::Action::*;
+
+    let p = p(paths);
+    match action {
+        GetLhs { path } => write!(w, "get-lhs @ {}", p(path)),
+        UnaryUnquote { operator, operand } => write!(w, "eval {} $rhs{}", operator, operand.0),
+        BinaryUnquote { operator, operands } => write!(
+            w,
+            "eval {} $rhs{}, $rhs{}",
+            operator, operands[0].0, operands[1].0
+        ),
+        MakeIntegerConst { value, bit_width } => {
+            write!(w, "make {}", integers.lookup(*value))?;
+            fmt_bit_width(w, *bit_width)
+        }
+        MakeIconstMatchingWidth { value, like } => {
+            write!(w, "make {} like @ {}", integers.lookup(*value), p(like))
+        }
+        MakeZero { bit_width } => {
+            write!(w, "make zero")?;
+            fmt_bit_width(w, *bit_width)
+        }
+        MakeOne { bit_width } => {
+            write!(w, "make one")?;
+            fmt_bit_width(w, *bit_width)
+        }
+        MakeSignShiftAmount { bit_width } => {
+            write!(w, "make sign-shift-amount")?;
+            fmt_bit_width(w, *bit_width)
+        }
+        FoldConst { path } => write!(w, "fold @ {}", p(path)),
+        GetStoredValue { path } => write!(w, "get stored value @ {}", p(path)),
+        MakeBooleanConst { value, bit_width } => {
+            write!(w, "make {}", value)?;
+            fmt_bit_width(w, *bit_width)
+        }
+        MakeConditionCode { cc } => write!(w, "make {}", cc),
+        InvertConditionCode { operand } => write!(w, "invert-cc $rhs{}", operand.0),
+        CombineConditionCodes { operands } => {
+            write!(w, "combine-cc $rhs{}, $rhs{}", operands[0].0, operands[1].0)
+        }
+        MakeUnaryInst {
+            operator,
+            r#type,
+            operand,
+        } => write!(w, "make {}{{{}}} $rhs{}", operator, r#type, operand.0),
+        MakeBinaryInst {
+            operator,
+            r#type,
+            operands,
+        } => write!(
+            w,
+            "make {}{{{}}} $rhs{}, $rhs{}",
+            operator, r#type, operands[0].0, operands[1].0
+        ),
+        MakeTernaryInst {
+            operator,
+            r#type,
+            operands,
+        } => write!(
+            w,
+            "make {}{{{}}} $rhs{}, $rhs{}, $rhs{}",
+            operator, r#type, operands[0].0, operands[1].0, operands[2].0
+        ),
+        MakeSelectSpectreGuard { r#type, operands } => write!(
+            w,
+            "make {}{{{}}} $rhs{}, $rhs{}, $rhs{}",
+            Operator::SelectSpectreGuard,
+            r#type,
+            operands[0].0,
+            operands[1].0,
+            operands[2].0
+        ),
+        MakeLoad {
+            operand,
+            offset,
+            flags,
+            bit_width,
+        } => {
+            write!(w, "make load")?;
+            fmt_bit_width(w, *bit_width)?;
+            write!(w, " offset{:+}", offset)?;
+            fmt_mem_flags(w, *flags)?;
+            write!(w, " $rhs{}", operand.0)
+        }
+        MakeStore {
+            value,
+            addr,
+            offset,
+            flags,
+        } => {
+            write!(w, "make store offset{:+}", offset)?;
+            fmt_mem_flags(w, *flags)?;
+            write!(w, " $rhs{}, $rhs{}", value.0, addr.0)
+        }
+        MakeExtractLane { operand, lane } => {
+            write!(w, "make extractlane {}, $rhs{}", lane, operand.0)
+        }
+        MakeInsertLane {
+            vector,
+            value,
+            lane,
+        } => write!(
+            w,
+            "make insertlane {}, $rhs{}, $rhs{}",
+            lane, vector.0, value.0
+        ),
+        CallFunction { function, operands } => {
+            write!(w, "call #{}", function)?;
+            for (i, operand) in operands.iter().flatten().enumerate() {
+                let sep = if i == 0 { " " } else { ", " };
+                write!(w, "{}$rhs{}", sep, operand.0)?;
+            }
+            Ok(())
+        }
+        DeleteInstruction => write!(w, "delete"),
+        ReplaceResults { results } => {
+            write!(w, "results")?;
+            for (i, id) in results.iter().enumerate() {
+                let sep = if i == 0 { " " } else { ", " };
+                write!(w, "{}$rhs{}", sep, id.0)?;
+            }
+            Ok(())
+        }
+        Matched { optimization } => write!(w, "matched #{}", optimization),
+    }
+}
+
+/// Format a constant's bit width as `{N}`, or `{NN}` when it is polymorphic.
+fn fmt_bit_width(
+    w: &mut impl Write,
+    bit_width: peepmatic_runtime::r#type::BitWidth,
+) -> fmt::Result {
+    match bit_width.fixed_width() {
+        Some(n) => write!(w, "{{{}}}", n),
+        None => write!(w, "{{NN}}"),
+    }
+}
+
+/// Format explicit memory flags, or nothing when the root's flags are carried
+/// over.
+fn fmt_mem_flags(w: &mut impl Write, flags: Option<u8>) -> fmt::Result {
+    match flags {
+        Some(flags) => write!(w, " flags={:#x}", flags),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn p<'a>(paths: &'a PathInterner) -> impl Fn(&PathId) -> String + 'a {
+    move |path: &PathId| {
+        let mut s = vec![];
+        for b in paths.lookup(*path).0 {
+            s.push(b.to_string());
+        }
+        s.join(".")
+    }
+}
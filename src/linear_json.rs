@@ -0,0 +1,706 @@
+//! Exporting linear optimizations as JSON, for tools that aren't written in
+//! Rust.
+//!
+//! Unlike the compact binary serialization of `PeepholeOptimizations`, this
+//! format is meant for inspection, and it is versioned: any change to the
+//! format, including to the names or fields of match operations and actions,
+//! bumps [LINEAR_JSON_VERSION][crate::LINEAR_JSON_VERSION].
+//!
+//! Version 2 spells out paths, integers, and names where version 1 referred to
+//! them by id, and adds custom predicates, host functions, and optimizations'
+//! source locations and results. Version 3 writes the expected results of
+//! operations that switch on an operator or a condition code as its name,
+//! rather than its number. Documents of any other version, older or newer, are
+//! rejected rather than migrated, because version 1 documents don't record
+//! which action defines an optimization's result.
+//!
+//! ## Format
+//!
+//! A document is a single object:
+//!
+//! ```json
+//! {
+//!   "version": 3,
+//!   "optimizations": [
+//!     {
+//!       "doc": null,
+//!       "location": { "line": 1, "column": 1 },
+//!       "result": 0,
+//!       "increments": [
+//!         {
+//!           "operation": { "Opcode": { "path": [0] } },
+//!           "expected": "Iadd",
+//!           "actions": [{ "GetLhs": { "path": [0, 0] } }]
+//!         },
+//!         {
+//!           "operation": { "IntegerValue": { "path": [0, 1] } },
+//!           "expected": 0,
+//!           "actions": []
+//!         }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! * Each optimization is its doc comment and source location, if any, the
+//!   right-hand side id of its result, if any, and its increments in order.
+//!
+//! * Operations and actions are objects with a single key, the name of the
+//!   `peepmatic_runtime::linear::MatchOp` or `peepmatic_runtime::linear::Action`
+//!   variant, whose value holds its fields, or just the name for variants
+//!   without fields.
+//!
+//! * Nothing refers to an interned value by its id. A path is written as the
+//!   operand indices leading to it from the root, an integer as its value, an
+//!   integer set as the values in it, and a custom predicate or host function
+//!   as its name.
+//!
+//! * An `IntegerValue` operation's `expected` result is likewise the integer
+//!   value. An `Opcode` operation's is the name of the `Operator`, and a
+//!   `ConditionCode` or `IcmpZero` operation's is the name of the
+//!   `ConditionCode`, both written the same way as in actions. An `expected`
+//!   result of `null` is a wildcard.
+//!
+//! * An increment's `operation` comes before its `expected` result, since what
+//!   the result means depends on the operation.
+//!
+//! * A right-hand side id `n`, in an action's operands or an optimization's
+//!   result, is the value defined by the optimization's `n`th action, counting
+//!   the actions of every increment in order.
+
+use peepmatic_runtime::{
+    cc::ConditionCode,
+    integer_interner::{IntegerId, IntegerInterner, IntegerSetId},
+    linear,
+    operator::{Operator, UnquoteOperator},
+    paths::{Path, PathId, PathInterner},
+    r#type::{BitWidth, Type},
+};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The version of the format written by [linear_to_json][crate::linear_to_json].
+pub const LINEAR_JSON_VERSION: u32 = 3;
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Document {
+    version: u32,
+    optimizations: Vec<Optimization>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Optimization {
+    doc: Option<String>,
+    location: Option<linear::SourceLocation>,
+    result: Option<linear::RhsId>,
+    increments: Vec<Increment>,
+}
+
+#[derive(Serialize)]
+struct Increment {
+    operation: MatchOp,
+    expected: Option<Expected>,
+    actions: Vec<Action>,
+}
+
+/// An increment's expected result, as it is written in JSON.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Expected {
+    Integer(u128),
+    Operator(Operator),
+    ConditionCode(ConditionCode),
+}
+
+impl Expected {
+    fn resolve(inc: &linear::Increment, opts: &linear::Optimizations) -> Option<Self> {
+        let x = inc.expected?;
+        Some(match inc.operation {
+            linear::MatchOp::IntegerValue { .. } => {
+                Expected::Integer(opts.integers.lookup(IntegerId(x)))
+            }
+            linear::MatchOp::Opcode { .. } => Expected::Operator(
+                Operator::try_from(x).expect("opcodes only ever expect operators"),
+            ),
+            linear::MatchOp::ConditionCode { .. } | linear::MatchOp::IcmpZero { .. } => {
+                Expected::ConditionCode(
+                    ConditionCode::try_from(x)
+                        .expect("condition codes only ever expect condition codes"),
+                )
+            }
+            _ => Expected::Integer(x.into()),
+        })
+    }
+}
+
+const INCREMENT_FIELDS: &[&str] = &["operation", "expected", "actions"];
+
+impl<'de> Deserialize<'de> for Increment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("Increment", INCREMENT_FIELDS, IncrementVisitor)
+    }
+}
+
+/// Reads an increment's `expected` result as whatever its operation switches
+/// on, which is why the operation has to come first.
+struct IncrementVisitor;
+
+impl<'de> Visitor<'de> for IncrementVisitor {
+    type Value = Increment;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an increment")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Increment, A::Error> {
+        let mut operation = None;
+        let mut expected = None;
+        let mut actions = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "operation" => operation = Some(map.next_value()?),
+                "expected" => {
+                    let value = match &operation {
+                        None => {
+                            return Err(de::Error::custom(
+                                "an increment's `operation` must come before its `expected`",
+                            ))
+                        }
+                        Some(MatchOp::Opcode { .. }) => map
+                            .next_value::<Option<Operator>>()?
+                            .map(Expected::Operator),
+                        Some(MatchOp::ConditionCode { .. }) | Some(MatchOp::IcmpZero { .. }) => map
+                            .next_value::<Option<ConditionCode>>()?
+                            .map(Expected::ConditionCode),
+                        Some(_) => map.next_value::<Option<u128>>()?.map(Expected::Integer),
+                    };
+                    expected = Some(value);
+                }
+                "actions" => actions = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, INCREMENT_FIELDS)),
+            }
+        }
+        Ok(Increment {
+            operation: operation.ok_or_else(|| de::Error::missing_field("operation"))?,
+            // Like any other optional field, a missing `expected` is `null`.
+            expected: expected.flatten(),
+            actions: actions.ok_or_else(|| de::Error::missing_field("actions"))?,
+        })
+    }
+}
+
+/// A field of a match operation or action, as it is written in JSON.
+trait Field: Sized {
+    /// The type of this field in `linear::MatchOp` or `linear::Action`.
+    type Linear;
+
+    /// Write the given field by value, looking up whatever it refers to.
+    fn resolve(value: &Self::Linear, opts: &linear::Optimizations) -> Self;
+
+    /// Read this field back, interning whatever it refers to.
+    fn intern(self, opts: &mut linear::Optimizations) -> Self::Linear;
+}
+
+/// Fields that don't refer to anything are written as they are.
+macro_rules! verbatim_fields {
+    ( $( $ty:ty ),* $(,)? ) => {
+        $(
+            impl Field for $ty {
+                type Linear = $ty;
+
+                fn resolve(value: &$ty, _: &linear::Optimizations) -> Self {
+                    *value
+                }
+
+                fn intern(self, _: &mut linear::Optimizations) -> $ty {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+verbatim_fields!(
+    bool,
+    u8,
+    u32,
+    i32,
+    Option<u8>,
+    BitWidth,
+    Type,
+    Operator,
+    UnquoteOperator,
+    ConditionCode,
+    linear::Phase,
+    linear::RhsId,
+    [linear::RhsId; 2],
+    [linear::RhsId; 3],
+    [Option<linear::RhsId>; 2],
+);
+
+/// Like the verbatim fields, but cloned rather than copied.
+impl Field for Vec<linear::RhsId> {
+    type Linear = Vec<linear::RhsId>;
+
+    fn resolve(value: &Vec<linear::RhsId>, _: &linear::Optimizations) -> Self {
+        value.clone()
+    }
+
+    fn intern(self, _: &mut linear::Optimizations) -> Vec<linear::RhsId> {
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PathValue(Vec<u8>);
+
+impl Field for PathValue {
+    type Linear = PathId;
+
+    fn resolve(id: &PathId, opts: &linear::Optimizations) -> Self {
+        PathValue(opts.paths.lookup(*id).0.to_vec())
+    }
+
+    fn intern(self, opts: &mut linear::Optimizations) -> PathId {
+        opts.paths.intern(Path::new(&self.0))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IntegerValue(u128);
+
+impl Field for IntegerValue {
+    type Linear = IntegerId;
+
+    fn resolve(id: &IntegerId, opts: &linear::Optimizations) -> Self {
+        IntegerValue(opts.integers.lookup(*id))
+    }
+
+    fn intern(self, opts: &mut linear::Optimizations) -> IntegerId {
+        opts.integers.intern(self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IntegerSetValue(Vec<u128>);
+
+impl Field for IntegerSetValue {
+    type Linear = IntegerSetId;
+
+    fn resolve(id: &IntegerSetId, opts: &linear::Optimizations) -> Self {
+        let set = opts.integers.lookup_set(*id);
+        IntegerSetValue(set.iter().map(|x| opts.integers.lookup(*x)).collect())
+    }
+
+    fn intern(self, opts: &mut linear::Optimizations) -> IntegerSetId {
+        let ids: Vec<_> = self
+            .0
+            .into_iter()
+            .map(|x| opts.integers.intern(x))
+            .collect();
+        opts.integers.intern_set(ids)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PredicateName(String);
+
+impl Field for PredicateName {
+    type Linear = u32;
+
+    fn resolve(predicate: &u32, opts: &linear::Optimizations) -> Self {
+        PredicateName(opts.predicates[*predicate as usize].clone())
+    }
+
+    fn intern(self, opts: &mut linear::Optimizations) -> u32 {
+        index_of(&mut opts.predicates, self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FunctionName(String);
+
+impl Field for FunctionName {
+    type Linear = u32;
+
+    fn resolve(function: &u32, opts: &linear::Optimizations) -> Self {
+        FunctionName(opts.functions[*function as usize].clone())
+    }
+
+    fn intern(self, opts: &mut linear::Optimizations) -> u32 {
+        index_of(&mut opts.functions, self.0)
+    }
+}
+
+/// Get the index of `name` in `names`, adding it to the end if it isn't there
+/// yet.
+fn index_of(names: &mut Vec<String>, name: String) -> u32 {
+    let i = match names.iter().position(|n| *n == name) {
+        Some(i) => i,
+        None => {
+            names.push(name);
+            names.len() - 1
+        }
+    };
+    u32::try_from(i).unwrap()
+}
+
+/// Define a mirror of a `linear` enum whose fields are written by value, and
+/// its conversions to and from the original.
+///
+/// Every variant of the original must be listed, with the JSON type of each of
+/// its fields, in the same order.
+macro_rules! json_enum {
+    (
+        enum $name:ident = linear::$linear:ident {
+            $( $variant:ident $( { $( $field:ident: $ty:ty ),* $(,)? } )? ),* $(,)?
+        }
+    ) => {
+        #[derive(Serialize, Deserialize)]
+        enum $name {
+            $( $variant $( { $( $field: $ty ),* } )? ),*
+        }
+
+        impl $name {
+            fn resolve(x: &linear::$linear, opts: &linear::Optimizations) -> Self {
+                match x {
+                    $(
+                        linear::$linear::$variant $( { $( $field ),* } )? => {
+                            $name::$variant $( {
+                                $( $field: <$ty as Field>::resolve($field, opts) ),*
+                            } )?
+                        }
+                    )*
+                }
+            }
+
+            fn intern(self, opts: &mut linear::Optimizations) -> linear::$linear {
+                match self {
+                    $(
+                        $name::$variant $( { $( $field ),* } )? => {
+                            linear::$linear::$variant $( {
+                                $( $field: $field.intern(opts) ),*
+                            } )?
+                        }
+                    )*
+                }
+            }
+        }
+    };
+}
+
+json_enum! {
+    enum MatchOp = linear::MatchOp {
+        Opcode { path: PathValue },
+        IsConst { path: PathValue },
+        IsPowerOfTwo { path: PathValue },
+        IsOne { path: PathValue },
+        IsShiftByConst { path: PathValue },
+        IsByteMask { path: PathValue },
+        IsMaskConst { path: PathValue },
+        IsCallResult { path: PathValue },
+        AllOperandsConst { path: PathValue },
+        SelectConstArms { path: PathValue },
+        IsFoldable { path: PathValue },
+        IsForwardableLoad { path: PathValue },
+        IsUnused { path: PathValue },
+        SingleUse { path: PathValue },
+        IsExtension { path: PathValue },
+        PopcountEq { path: PathValue, count: u8 },
+        PopcountLe { path: PathValue, count: u8 },
+        BitWidth { path: PathValue },
+        SameWidth { path_a: PathValue, path_b: PathValue },
+        BlockPredecessorCount { path: PathValue },
+        NegationFitsInBits { path: PathValue, bits: u8 },
+        FitsInBits { path: PathValue, bits: u8 },
+        TrailingZeros { path: PathValue },
+        ConstEqWidthMinus { path: PathValue, delta: u8 },
+        FitsInNativeWord { path: PathValue },
+        Eq { path_a: PathValue, path_b: PathValue },
+        ConstEq { path_a: PathValue, path_b: PathValue },
+        EquivalentValues { path_a: PathValue, path_b: PathValue },
+        IsOneLessThan { path_a: PathValue, path_b: PathValue },
+        IsScaledBy { path_a: PathValue, path_b: PathValue, factor: IntegerValue },
+        NestedShiftCombinable { outer: PathValue, inner: PathValue },
+        RedundantMaskForShift { mask: PathValue, amount: PathValue },
+        IsDoubleInvolution { outer: PathValue, inner: PathValue },
+        ConditionCodesCombine { path_a: PathValue, path_b: PathValue },
+        CustomPredicate { path: PathValue, predicate: PredicateName },
+        IntegerInSet { path: PathValue, set: IntegerSetValue },
+        BoundedBy { path: PathValue, lo: IntegerValue, hi: IntegerValue },
+        IsAligned { path: PathValue, align: u8 },
+        HighBitsZero { path: PathValue, from_width: u8 },
+        KnownNonNegative { path: PathValue },
+        IsEncodableLogicalImm { path: PathValue, width: u8 },
+        IsAddOfNegConst { path: PathValue },
+        IsShiftedImm { path: PathValue, bits: u8, shift: u8 },
+        IsShiftedImm8 { path: PathValue },
+        MemFlagsMatch { path: PathValue, flags_mask: u8, flags_value: u8 },
+        IntegerValue { path: PathValue },
+        BooleanValue { path: PathValue },
+        ConditionCode { path: PathValue },
+        IcmpZero { path: PathValue },
+        InPhase { phase: linear::Phase },
+        IsEnabled,
+        Nop,
+    }
+}
+
+json_enum! {
+    enum Action = linear::Action {
+        GetLhs { path: PathValue },
+        UnaryUnquote { operator: UnquoteOperator, operand: linear::RhsId },
+        BinaryUnquote { operator: UnquoteOperator, operands: [linear::RhsId; 2] },
+        MakeIntegerConst { value: IntegerValue, bit_width: BitWidth },
+        MakeZero { bit_width: BitWidth },
+        MakeOne { bit_width: BitWidth },
+        MakeIconstMatchingWidth { value: IntegerValue, like: PathValue },
+        MakeSignShiftAmount { bit_width: BitWidth },
+        FoldConst { path: PathValue },
+        GetStoredValue { path: PathValue },
+        MakeBooleanConst { value: bool, bit_width: BitWidth },
+        MakeConditionCode { cc: ConditionCode },
+        InvertConditionCode { operand: linear::RhsId },
+        CombineConditionCodes { operands: [linear::RhsId; 2] },
+        MakeUnaryInst { operand: linear::RhsId, r#type: Type, operator: Operator },
+        MakeBinaryInst { operator: Operator, r#type: Type, operands: [linear::RhsId; 2] },
+        MakeTernaryInst { operator: Operator, r#type: Type, operands: [linear::RhsId; 3] },
+        MakeSelectSpectreGuard { r#type: Type, operands: [linear::RhsId; 3] },
+        MakeLoad { operand: linear::RhsId, offset: i32, flags: Option<u8>, bit_width: BitWidth },
+        MakeStore { value: linear::RhsId, addr: linear::RhsId, offset: i32, flags: Option<u8> },
+        MakeExtractLane { operand: linear::RhsId, lane: u8 },
+        MakeInsertLane { vector: linear::RhsId, value: linear::RhsId, lane: u8 },
+        CallFunction { function: FunctionName, operands: [Option<linear::RhsId>; 2] },
+        DeleteInstruction,
+        ReplaceResults { results: Vec<linear::RhsId> },
+        Matched { optimization: u32 },
+    }
+}
+
+/// Export the given linear optimizations as JSON.
+///
+/// See the module documentation of `src/linear_json.rs` for the format.
+pub fn linear_to_json(opts: &linear::Optimizations) -> String {
+    let doc = Document {
+        version: LINEAR_JSON_VERSION,
+        optimizations: opts
+            .optimizations
+            .iter()
+            .map(|opt| Optimization {
+                doc: opt.doc.clone(),
+                location: opt.location,
+                result: opt.result,
+                increments: opt
+                    .increments
+                    .iter()
+                    .map(|inc| Increment {
+                        operation: MatchOp::resolve(&inc.operation, opts),
+                        expected: Expected::resolve(inc, opts),
+                        actions: inc
+                            .actions
+                            .iter()
+                            .map(|a| Action::resolve(a, opts))
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    serde_json::to_string_pretty(&doc).expect("serializing to a `String` cannot fail")
+}
+
+/// Import linear optimizations from JSON that was exported with
+/// [linear_to_json][crate::linear_to_json].
+///
+/// Paths, integers, and names are interned in the order that they first
+/// appear, so their ids may differ from those of the exported optimizations,
+/// but everything they refer to is the same.
+///
+/// Returns an error if the JSON is malformed or has a different version.
+pub fn linear_from_json(json: &str) -> anyhow::Result<linear::Optimizations> {
+    // Check the version before anything else, since the rest of a document of
+    // another version may not parse.
+    let version: Version = serde_json::from_str(json)?;
+    if version.version != LINEAR_JSON_VERSION {
+        anyhow::bail!(
+            "unsupported linear JSON version {}; expected version {}",
+            version.version,
+            LINEAR_JSON_VERSION
+        );
+    }
+    let doc: Document = serde_json::from_str(json)?;
+
+    let mut opts = linear::Optimizations {
+        optimizations: vec![],
+        paths: PathInterner::new(),
+        integers: IntegerInterner::new(),
+        predicates: vec![],
+        functions: vec![],
+    };
+    for opt in doc.optimizations {
+        let mut increments = Vec::with_capacity(opt.increments.len());
+        for inc in opt.increments {
+            let operation = inc.operation.intern(&mut opts);
+            let expected = match (operation, inc.expected) {
+                (linear::MatchOp::IntegerValue { .. }, Some(Expected::Integer(x))) => {
+                    Some(opts.integers.intern(x).into())
+                }
+                (_, Some(Expected::Integer(x))) => match u32::try_from(x) {
+                    Ok(x) => Some(x),
+                    Err(_) => anyhow::bail!("expected result {} is out of range", x),
+                },
+                (_, Some(Expected::Operator(op))) => Some(op as u32),
+                (_, Some(Expected::ConditionCode(cc))) => Some(cc as u32),
+                (_, None) => None,
+            };
+            let actions = inc
+                .actions
+                .into_iter()
+                .map(|a| a.intern(&mut opts))
+                .collect();
+            increments.push(linear::Increment {
+                operation,
+                expected,
+                actions,
+            });
+        }
+        opts.optimizations.push(linear::Optimization {
+            increments,
+            result: opt.result,
+            doc: opt.doc,
+            location: opt.location,
+        });
+    }
+    Ok(opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let source = "
+;; Adding zero is a no-op.
+(=> (iadd $x 0) $x)
+(=> (when (imul $x $C) (in-set $C 2 4 8)) (ishl $x $(log2 $C)))
+(=> (when (isub $x $y) (equivalent-values $x $y)) 0)
+(=> (bor (icmp eq $a $b) (icmp slt $a $b)) (icmp sle $a $b))
+(=> (when (bnot $x) (predicate is-hot $x)) $x)
+(=> (extractlane 1 (insertlane 0 $v $x)) (extractlane 1 $v))
+";
+        let opts = crate::compile_to_linear(source, std::path::Path::new("round-trip")).unwrap();
+        let json = linear_to_json(&opts);
+        let round_tripped = linear_from_json(&json).unwrap();
+
+        // Ids may be renumbered, but every optimization refers to the same
+        // paths, integers, and names as before.
+        assert!(opts.diff(&round_tripped).is_empty());
+        let docs = |opts: &linear::Optimizations| -> Vec<_> {
+            opts.optimizations.iter().map(|o| o.doc.clone()).collect()
+        };
+        assert_eq!(docs(&opts), docs(&round_tripped));
+        let locations = |opts: &linear::Optimizations| -> Vec<_> {
+            opts.optimizations.iter().map(|o| o.location).collect()
+        };
+        assert_eq!(locations(&opts), locations(&round_tripped));
+        assert_eq!(opts.predicates, round_tripped.predicates);
+
+        // So the formatted optimizations are identical too.
+        assert_eq!(
+            crate::format_linear(&opts),
+            crate::format_linear(&round_tripped)
+        );
+        assert_eq!(json, linear_to_json(&round_tripped));
+    }
+
+    #[test]
+    fn wrong_version() {
+        let opts =
+            crate::compile_to_linear("(=> (iadd $x 0) $x)", std::path::Path::new("v")).unwrap();
+        let json = linear_to_json(&opts);
+        for version in &[0, LINEAR_JSON_VERSION + 1] {
+            let json = json.replacen(
+                &format!("\"version\": {}", LINEAR_JSON_VERSION),
+                &format!("\"version\": {}", version),
+                1,
+            );
+            let err = linear_from_json(&json).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "unsupported linear JSON version {}; expected version {}",
+                    version, LINEAR_JSON_VERSION
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn expected_before_operation() {
+        let opts =
+            crate::compile_to_linear("(=> (iadd $x 0) $x)", std::path::Path::new("e")).unwrap();
+        let json = linear_to_json(&opts);
+        assert!(json.contains(r#""expected": "Iadd""#));
+
+        // The opcode's name only means something once the operation is known.
+        let json = json.replacen(
+            r#""operation": {
+            "Opcode": {
+              "path": [
+                0
+              ]
+            }
+          },
+          "expected": "Iadd","#,
+            r#""expected": "Iadd",
+          "operation": { "Opcode": { "path": [0] } },"#,
+            1,
+        );
+        let err = linear_from_json(&json).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("an increment's `operation` must come before its `expected`"));
+    }
+
+    #[test]
+    fn version_1_document() {
+        // A version 1 document is rejected for its version, even though it
+        // doesn't have the fields of the current version.
+        let json = r#"{
+  "version": 1,
+  "paths": [[0], [0, 0], [0, 1]],
+  "integers": [0],
+  "integer_sets": [],
+  "optimizations": [
+    {
+      "doc": null,
+      "increments": [
+        {
+          "operation": { "Opcode": { "path": 0 } },
+          "expected": 15,
+          "actions": [{ "GetLhs": { "path": 1 } }]
+        },
+        {
+          "operation": { "IntegerValue": { "path": 2 } },
+          "expected": 0,
+          "actions": []
+        }
+      ]
+    }
+  ]
+}"#;
+        let err = linear_from_json(json).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported linear JSON version 1"));
+    }
+}
@@ -5,6 +5,7 @@ use peepmatic_runtime::{
     paths::{PathId, PathInterner},
 };
 use std::cmp::Ordering;
+use std::collections::HashMap;
 
 /// Sort a set of optimizations from least to most general.
 ///
@@ -109,10 +110,92 @@ fn compare_match_op_generality(
         (ConditionCode { .. }, _) => Ordering::Less,
         (_, ConditionCode { .. }) => Ordering::Greater,
 
+        (IcmpZero { path: a }, IcmpZero { path: b }) => compare_paths(paths, a, b),
+        (IcmpZero { .. }, _) => Ordering::Less,
+        (_, IcmpZero { .. }) => Ordering::Greater,
+
         (IsConst { path: a }, IsConst { path: b }) => compare_paths(paths, a, b),
         (IsConst { .. }, _) => Ordering::Less,
         (_, IsConst { .. }) => Ordering::Greater,
 
+        (IntegerInSet { path: a, set: sa }, IntegerInSet { path: b, set: sb }) => {
+            compare_paths(paths, a, b).then(sa.0.cmp(&sb.0))
+        }
+        (IntegerInSet { .. }, _) => Ordering::Less,
+        (_, IntegerInSet { .. }) => Ordering::Greater,
+
+        (
+            BoundedBy {
+                path: a,
+                lo: la,
+                hi: ha,
+            },
+            BoundedBy {
+                path: b,
+                lo: lb,
+                hi: hb,
+            },
+        ) => compare_paths(paths, a, b)
+            .then(la.0.cmp(&lb.0))
+            .then(ha.0.cmp(&hb.0)),
+        (BoundedBy { .. }, _) => Ordering::Less,
+        (_, BoundedBy { .. }) => Ordering::Greater,
+
+        (IsAligned { path: a, align: aa }, IsAligned { path: b, align: ab }) => {
+            compare_paths(paths, a, b).then(aa.cmp(&ab))
+        }
+        (IsAligned { .. }, _) => Ordering::Less,
+        (_, IsAligned { .. }) => Ordering::Greater,
+
+        (
+            HighBitsZero {
+                path: a,
+                from_width: wa,
+            },
+            HighBitsZero {
+                path: b,
+                from_width: wb,
+            },
+        ) => compare_paths(paths, a, b).then(wa.cmp(&wb)),
+        (HighBitsZero { .. }, _) => Ordering::Less,
+        (_, HighBitsZero { .. }) => Ordering::Greater,
+
+        (KnownNonNegative { path: a }, KnownNonNegative { path: b }) => compare_paths(paths, a, b),
+        (KnownNonNegative { .. }, _) => Ordering::Less,
+        (_, KnownNonNegative { .. }) => Ordering::Greater,
+
+        (
+            IsEncodableLogicalImm { path: a, width: wa },
+            IsEncodableLogicalImm { path: b, width: wb },
+        ) => compare_paths(paths, a, b).then(wa.cmp(&wb)),
+        (IsEncodableLogicalImm { .. }, _) => Ordering::Less,
+        (_, IsEncodableLogicalImm { .. }) => Ordering::Greater,
+
+        (IsAddOfNegConst { path: a }, IsAddOfNegConst { path: b }) => compare_paths(paths, a, b),
+        (IsAddOfNegConst { .. }, _) => Ordering::Less,
+        (_, IsAddOfNegConst { .. }) => Ordering::Greater,
+
+        (
+            IsShiftedImm {
+                path: a,
+                bits: ba,
+                shift: sa,
+            },
+            IsShiftedImm {
+                path: b,
+                bits: bb,
+                shift: sb,
+            },
+        ) => compare_paths(paths, a, b)
+            .then(ba.cmp(&bb))
+            .then(sa.cmp(&sb)),
+        (IsShiftedImm { .. }, _) => Ordering::Less,
+        (_, IsShiftedImm { .. }) => Ordering::Greater,
+
+        (IsShiftedImm8 { path: a }, IsShiftedImm8 { path: b }) => compare_paths(paths, a, b),
+        (IsShiftedImm8 { .. }, _) => Ordering::Less,
+        (_, IsShiftedImm8 { .. }) => Ordering::Greater,
+
         (
             Eq {
                 path_a: pa1,
@@ -126,18 +209,270 @@ fn compare_match_op_generality(
         (Eq { .. }, _) => Ordering::Less,
         (_, Eq { .. }) => Ordering::Greater,
 
+        (
+            ConstEq {
+                path_a: pa1,
+                path_b: pb1,
+            },
+            ConstEq {
+                path_a: pa2,
+                path_b: pb2,
+            },
+        ) => compare_paths(paths, pa1, pa2).then(compare_paths(paths, pb1, pb2)),
+        (ConstEq { .. }, _) => Ordering::Less,
+        (_, ConstEq { .. }) => Ordering::Greater,
+
+        (
+            EquivalentValues {
+                path_a: pa1,
+                path_b: pb1,
+            },
+            EquivalentValues {
+                path_a: pa2,
+                path_b: pb2,
+            },
+        ) => compare_paths(paths, pa1, pa2).then(compare_paths(paths, pb1, pb2)),
+        (EquivalentValues { .. }, _) => Ordering::Less,
+        (_, EquivalentValues { .. }) => Ordering::Greater,
+
+        (
+            IsOneLessThan {
+                path_a: pa1,
+                path_b: pb1,
+            },
+            IsOneLessThan {
+                path_a: pa2,
+                path_b: pb2,
+            },
+        ) => compare_paths(paths, pa1, pa2).then(compare_paths(paths, pb1, pb2)),
+        (IsOneLessThan { .. }, _) => Ordering::Less,
+        (_, IsOneLessThan { .. }) => Ordering::Greater,
+
+        (
+            IsScaledBy {
+                path_a: pa1,
+                path_b: pb1,
+                factor: f1,
+            },
+            IsScaledBy {
+                path_a: pa2,
+                path_b: pb2,
+                factor: f2,
+            },
+        ) => compare_paths(paths, pa1, pa2)
+            .then(compare_paths(paths, pb1, pb2))
+            .then(f1.0.cmp(&f2.0)),
+        (IsScaledBy { .. }, _) => Ordering::Less,
+        (_, IsScaledBy { .. }) => Ordering::Greater,
+
+        (
+            NestedShiftCombinable {
+                outer: o1,
+                inner: i1,
+            },
+            NestedShiftCombinable {
+                outer: o2,
+                inner: i2,
+            },
+        ) => compare_paths(paths, o1, o2).then(compare_paths(paths, i1, i2)),
+        (NestedShiftCombinable { .. }, _) => Ordering::Less,
+        (_, NestedShiftCombinable { .. }) => Ordering::Greater,
+
+        (
+            RedundantMaskForShift {
+                mask: m1,
+                amount: a1,
+            },
+            RedundantMaskForShift {
+                mask: m2,
+                amount: a2,
+            },
+        ) => compare_paths(paths, m1, m2).then(compare_paths(paths, a1, a2)),
+        (RedundantMaskForShift { .. }, _) => Ordering::Less,
+        (_, RedundantMaskForShift { .. }) => Ordering::Greater,
+
+        (
+            IsDoubleInvolution {
+                outer: o1,
+                inner: i1,
+            },
+            IsDoubleInvolution {
+                outer: o2,
+                inner: i2,
+            },
+        ) => compare_paths(paths, o1, o2).then(compare_paths(paths, i1, i2)),
+        (IsDoubleInvolution { .. }, _) => Ordering::Less,
+        (_, IsDoubleInvolution { .. }) => Ordering::Greater,
+
+        (
+            ConditionCodesCombine {
+                path_a: pa1,
+                path_b: pb1,
+            },
+            ConditionCodesCombine {
+                path_a: pa2,
+                path_b: pb2,
+            },
+        ) => compare_paths(paths, pa1, pa2).then(compare_paths(paths, pb1, pb2)),
+        (ConditionCodesCombine { .. }, _) => Ordering::Less,
+        (_, ConditionCodesCombine { .. }) => Ordering::Greater,
+
+        (
+            CustomPredicate {
+                path: p1,
+                predicate: x1,
+            },
+            CustomPredicate {
+                path: p2,
+                predicate: x2,
+            },
+        ) => compare_paths(paths, p1, p2).then(x1.cmp(&x2)),
+        (CustomPredicate { .. }, _) => Ordering::Less,
+        (_, CustomPredicate { .. }) => Ordering::Greater,
+
         (IsPowerOfTwo { path: a }, IsPowerOfTwo { path: b }) => compare_paths(paths, a, b),
         (IsPowerOfTwo { .. }, _) => Ordering::Less,
         (_, IsPowerOfTwo { .. }) => Ordering::Greater,
 
+        (IsOne { path: a }, IsOne { path: b }) => compare_paths(paths, a, b),
+        (IsOne { .. }, _) => Ordering::Less,
+        (_, IsOne { .. }) => Ordering::Greater,
+
+        (IsShiftByConst { path: a }, IsShiftByConst { path: b }) => compare_paths(paths, a, b),
+        (IsShiftByConst { .. }, _) => Ordering::Less,
+        (_, IsShiftByConst { .. }) => Ordering::Greater,
+
+        (IsCallResult { path: a }, IsCallResult { path: b }) => compare_paths(paths, a, b),
+        (IsCallResult { .. }, _) => Ordering::Less,
+        (_, IsCallResult { .. }) => Ordering::Greater,
+
+        (AllOperandsConst { path: a }, AllOperandsConst { path: b }) => compare_paths(paths, a, b),
+        (AllOperandsConst { .. }, _) => Ordering::Less,
+        (_, AllOperandsConst { .. }) => Ordering::Greater,
+
+        (SelectConstArms { path: a }, SelectConstArms { path: b }) => compare_paths(paths, a, b),
+        (SelectConstArms { .. }, _) => Ordering::Less,
+        (_, SelectConstArms { .. }) => Ordering::Greater,
+
+        (IsFoldable { path: a }, IsFoldable { path: b }) => compare_paths(paths, a, b),
+        (IsFoldable { .. }, _) => Ordering::Less,
+        (_, IsFoldable { .. }) => Ordering::Greater,
+
+        (IsForwardableLoad { path: a }, IsForwardableLoad { path: b }) => {
+            compare_paths(paths, a, b)
+        }
+        (IsForwardableLoad { .. }, _) => Ordering::Less,
+        (_, IsForwardableLoad { .. }) => Ordering::Greater,
+
+        (IsUnused { path: a }, IsUnused { path: b }) => compare_paths(paths, a, b),
+        (IsUnused { .. }, _) => Ordering::Less,
+        (_, IsUnused { .. }) => Ordering::Greater,
+
+        (SingleUse { path: a }, SingleUse { path: b }) => compare_paths(paths, a, b),
+        (SingleUse { .. }, _) => Ordering::Less,
+        (_, SingleUse { .. }) => Ordering::Greater,
+
+        (IsExtension { path: a }, IsExtension { path: b }) => compare_paths(paths, a, b),
+        (IsExtension { .. }, _) => Ordering::Less,
+        (_, IsExtension { .. }) => Ordering::Greater,
+
+        (IsByteMask { path: a }, IsByteMask { path: b }) => compare_paths(paths, a, b),
+        (IsByteMask { .. }, _) => Ordering::Less,
+        (_, IsByteMask { .. }) => Ordering::Greater,
+
+        (IsMaskConst { path: a }, IsMaskConst { path: b }) => compare_paths(paths, a, b),
+        (IsMaskConst { .. }, _) => Ordering::Less,
+        (_, IsMaskConst { .. }) => Ordering::Greater,
+
+        (PopcountEq { path: a, count: ca }, PopcountEq { path: b, count: cb }) => {
+            compare_paths(paths, a, b).then(ca.cmp(&cb))
+        }
+        (PopcountEq { .. }, _) => Ordering::Less,
+        (_, PopcountEq { .. }) => Ordering::Greater,
+
+        (PopcountLe { path: a, count: ca }, PopcountLe { path: b, count: cb }) => {
+            // Lower limits are more specific.
+            compare_paths(paths, a, b).then(ca.cmp(&cb))
+        }
+        (PopcountLe { .. }, _) => Ordering::Less,
+        (_, PopcountLe { .. }) => Ordering::Greater,
+
+        (NegationFitsInBits { path: a, bits: ba }, NegationFitsInBits { path: b, bits: bb }) => {
+            // Fewer bits are more specific.
+            compare_paths(paths, a, b).then(ba.cmp(&bb))
+        }
+        (NegationFitsInBits { .. }, _) => Ordering::Less,
+        (_, NegationFitsInBits { .. }) => Ordering::Greater,
+
+        (FitsInBits { path: a, bits: ba }, FitsInBits { path: b, bits: bb }) => {
+            // Fewer bits are more specific.
+            compare_paths(paths, a, b).then(ba.cmp(&bb))
+        }
+        (FitsInBits { .. }, _) => Ordering::Less,
+        (_, FitsInBits { .. }) => Ordering::Greater,
+
+        (ConstEqWidthMinus { path: a, delta: da }, ConstEqWidthMinus { path: b, delta: db }) => {
+            compare_paths(paths, a, b).then(da.cmp(&db))
+        }
+        (ConstEqWidthMinus { .. }, _) => Ordering::Less,
+        (_, ConstEqWidthMinus { .. }) => Ordering::Greater,
+
+        (TrailingZeros { path: a }, TrailingZeros { path: b }) => compare_paths(paths, a, b),
+        (TrailingZeros { .. }, _) => Ordering::Less,
+        (_, TrailingZeros { .. }) => Ordering::Greater,
+
         (BitWidth { path: a }, BitWidth { path: b }) => compare_paths(paths, a, b),
         (BitWidth { .. }, _) => Ordering::Less,
         (_, BitWidth { .. }) => Ordering::Greater,
 
+        (
+            SameWidth {
+                path_a: pa1,
+                path_b: pb1,
+            },
+            SameWidth {
+                path_a: pa2,
+                path_b: pb2,
+            },
+        ) => compare_paths(paths, pa1, pa2).then(compare_paths(paths, pb1, pb2)),
+        (SameWidth { .. }, _) => Ordering::Less,
+        (_, SameWidth { .. }) => Ordering::Greater,
+
+        (BlockPredecessorCount { path: a }, BlockPredecessorCount { path: b }) => {
+            compare_paths(paths, a, b)
+        }
+        (BlockPredecessorCount { .. }, _) => Ordering::Less,
+        (_, BlockPredecessorCount { .. }) => Ordering::Greater,
+
         (FitsInNativeWord { path: a }, FitsInNativeWord { path: b }) => compare_paths(paths, a, b),
         (FitsInNativeWord { .. }, _) => Ordering::Less,
         (_, FitsInNativeWord { .. }) => Ordering::Greater,
 
+        (
+            MemFlagsMatch {
+                path: a,
+                flags_mask: ma,
+                flags_value: va,
+            },
+            MemFlagsMatch {
+                path: b,
+                flags_mask: mb,
+                flags_value: vb,
+            },
+        ) => compare_paths(paths, a, b)
+            .then(ma.cmp(&mb))
+            .then(va.cmp(&vb)),
+        (MemFlagsMatch { .. }, _) => Ordering::Less,
+        (_, MemFlagsMatch { .. }) => Ordering::Greater,
+
+        (InPhase { phase: a }, InPhase { phase: b }) => a.cmp(&b),
+        (InPhase { .. }, _) => Ordering::Less,
+        (_, InPhase { .. }) => Ordering::Greater,
+
+        (IsEnabled, IsEnabled) => Ordering::Equal,
+        (IsEnabled, _) => Ordering::Less,
+        (_, IsEnabled) => Ordering::Greater,
+
         (Nop, Nop) => Ordering::Equal,
     }
 }
@@ -167,6 +502,27 @@ pub(crate) fn is_sorted_lexicographically(opts: &linear::Optimizations) -> bool
     })
 }
 
+/// Remove optimizations whose left-hand sides duplicate an earlier
+/// optimization's.
+///
+/// Two optimizations with identical match operations are equally specific, so
+/// neither is preferred by the generality sort. The automaton can only hold one
+/// of them, so we keep the one that was defined first in the source. Because
+/// both sorts are stable, duplicates are adjacent and still in source order by
+/// the time this pass runs.
+pub fn remove_duplicate_left_hand_sides(opts: &mut linear::Optimizations) {
+    debug_assert!(is_sorted_lexicographically(opts));
+
+    opts.optimizations.dedup_by(|later, earlier| {
+        later.increments.len() == earlier.increments.len()
+            && later
+                .increments
+                .iter()
+                .zip(earlier.increments.iter())
+                .all(|(a, b)| a.operation == b.operation && a.expected == b.expected)
+    });
+}
+
 /// Ensure that we emit match operations in a consistent order.
 ///
 /// There are many linear optimizations, each of which have their own sequence
@@ -278,12 +634,58 @@ pub fn remove_unnecessary_nops(opts: &mut linear::Optimizations) {
     }
 }
 
+/// Remove redundant `GetLhs` actions that get the same path as an earlier
+/// `GetLhs` in the same optimization.
+///
+/// A right-hand side that uses the same left-hand side value more than once,
+/// like the `$x`s in `(imul (iadd $x $y) $x)`, gets a `GetLhs` for each use.
+/// Only the first is necessary: this pass drops the rest, and makes every action
+/// that referred to a dropped `GetLhs` refer to the first one instead.
+///
+/// An optimization's result is remapped just like its actions' operands.
+pub fn remove_redundant_get_lhs(opts: &mut linear::Optimizations) {
+    for opt in &mut opts.optimizations {
+        let num_actions: usize = opt.increments.iter().map(|i| i.actions.len()).sum();
+
+        // Map from each action's old `RhsId` to its new one.
+        let mut remap = Vec::with_capacity(num_actions);
+        let mut first_get_lhs = HashMap::new();
+        let mut num_kept = 0;
+
+        for inc in &mut opt.increments {
+            let old_actions = std::mem::take(&mut inc.actions);
+            for mut action in old_actions {
+                if let linear::Action::GetLhs { path } = action {
+                    if let Some(&id) = first_get_lhs.get(&path) {
+                        remap.push(id);
+                        continue;
+                    }
+                    first_get_lhs.entry(path).or_insert(linear::RhsId(num_kept));
+                }
+
+                action.for_each_rhs_id_mut(|id| *id = remap[id.0 as usize]);
+                inc.actions.push(action);
+                remap.push(linear::RhsId(num_kept));
+                num_kept += 1;
+            }
+        }
+
+        if let Some(result) = &mut opt.result {
+            *result = remap[result.0 as usize];
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ast::*;
     use linear::MatchOp::*;
-    use peepmatic_runtime::{operator::Operator, paths::*};
+    use peepmatic_runtime::{
+        operator::Operator,
+        paths::*,
+        r#type::{BitWidth, Kind, Type},
+    };
 
     macro_rules! sorts_to {
         ($test_name:ident, $source:expr, $make_expected:expr) => {
@@ -315,6 +717,7 @@ mod tests {
                     mut paths,
                     mut integers,
                     optimizations,
+                    ..
                 } = opts;
 
                 let actual: Vec<Vec<_>> = optimizations
@@ -441,4 +844,60 @@ mod tests {
             ]
         ]
     );
+
+    #[test]
+    fn redundant_get_lhs_are_removed() {
+        let source = "
+(=> (iadd $x $y) (imul (iadd $x $y) $x))
+(=> (iadd $x $x) $x)
+";
+        let buf = wast::parser::ParseBuffer::new(source).expect("should lex OK");
+        let opts = wast::parser::parse::<Optimizations>(&buf).expect("should parse OK");
+        crate::verify(&opts).expect("should verify OK");
+
+        let mut opts = crate::linearize(&opts);
+        remove_redundant_get_lhs(&mut opts);
+
+        let mut p = |p: &[u8]| opts.paths.intern(Path::new(&p));
+        let x = p(&[0, 0]);
+        let y = p(&[0, 1]);
+
+        let actions = |opt: &linear::Optimization| -> Vec<_> {
+            opt.increments
+                .iter()
+                .flat_map(|i| i.actions.iter().cloned())
+                .collect()
+        };
+        let ty = Type {
+            kind: Kind::Int,
+            bit_width: BitWidth::Polymorphic,
+        };
+
+        // The second `GetLhs` of `$x` is removed, and the `imul` uses the
+        // first one instead.
+        assert_eq!(
+            actions(&opts.optimizations[0]),
+            vec![
+                linear::Action::GetLhs { path: x },
+                linear::Action::GetLhs { path: y },
+                linear::Action::MakeBinaryInst {
+                    operator: Operator::Iadd,
+                    r#type: ty,
+                    operands: [linear::RhsId(0), linear::RhsId(1)],
+                },
+                linear::Action::MakeBinaryInst {
+                    operator: Operator::Imul,
+                    r#type: ty,
+                    operands: [linear::RhsId(2), linear::RhsId(0)],
+                },
+            ]
+        );
+        assert_eq!(opts.optimizations[0].result, Some(linear::RhsId(3)));
+
+        // A sole `GetLhs` is the result, and is left alone.
+        assert_eq!(
+            actions(&opts.optimizations[1]),
+            vec![linear::Action::GetLhs { path: x }]
+        );
+    }
 }
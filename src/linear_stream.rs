@@ -0,0 +1,413 @@
+//! Streaming serialization of linear optimizations.
+//!
+//! [linear_from_json][crate::linear_from_json] has to hold the whole document,
+//! and every optimization in it, in memory at once. For very large rule sets,
+//! this format can instead be read one optimization at a time with a
+//! [LinearStreamReader][crate::LinearStreamReader].
+//!
+//! ## Format
+//!
+//! A stream is a sequence of records. Each record is a little-endian `u32`
+//! byte length, followed by that many bytes of JSON.
+//!
+//! The first record is a header:
+//!
+//! ```json
+//! { "version": 2, "predicates": ["is-hot"], "functions": [] }
+//! ```
+//!
+//! Version 2 added the header's `functions`, and each optimization's `location`
+//! and `result`.
+//! Streams of any other version are rejected when reading their header.
+//!
+//! Every following record is one optimization, along with the interned paths,
+//! integers, and integer sets that it is the first to reference, so that the
+//! reader can rebuild the interners as it goes:
+//!
+//! ```json
+//! {
+//!   "paths": [[0], [0, 1]],
+//!   "integers": [0],
+//!   "integer_sets": [],
+//!   "doc": null,
+//!   "location": { "line": 1, "column": 1 },
+//!   "result": 0,
+//!   "increments": [
+//!     {
+//!       "operation": { "Opcode": { "path": 0 } },
+//!       "expected": 16,
+//!       "actions": [{ "GetLhs": { "path": 1 } }]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! New interned values are listed in id order, continuing from the ids already
+//! used by earlier records, so ids in operations and actions mean the same
+//! thing as in the original `peepmatic_runtime::linear::Optimizations`.
+//! Because ids can't be skipped, an interned value that no optimization
+//! references is still written if some optimization references a later id.
+//! Only the values after the last referenced id of each kind are left out.
+
+use peepmatic_runtime::{
+    integer_interner::IntegerInterner,
+    linear,
+    paths::{Path, PathInterner},
+};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+
+/// The version of the format written by
+/// [write_linear_stream][crate::write_linear_stream].
+pub const LINEAR_STREAM_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    predicates: Vec<String>,
+    // Version 1 headers don't have any functions, and are only read far
+    // enough to reject their version.
+    #[serde(default)]
+    functions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    paths: Vec<Vec<u8>>,
+    integers: Vec<u128>,
+    integer_sets: Vec<Vec<u128>>,
+    doc: Option<String>,
+    location: Option<linear::SourceLocation>,
+    result: Option<linear::RhsId>,
+    increments: Vec<Increment>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Increment {
+    operation: linear::MatchOp,
+    expected: Option<u32>,
+    actions: Vec<linear::Action>,
+}
+
+fn write_record(w: &mut impl io::Write, record: &impl Serialize) -> anyhow::Result<()> {
+    let bytes = serde_json::to_vec(record)?;
+    let len = u32::try_from(bytes.len())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Write the given linear optimizations to `w` in the streaming format.
+///
+/// See the module documentation of `src/linear_stream.rs` for the format.
+pub fn write_linear_stream(
+    opts: &linear::Optimizations,
+    w: &mut impl io::Write,
+) -> anyhow::Result<()> {
+    write_record(
+        w,
+        &Header {
+            version: LINEAR_STREAM_VERSION,
+            predicates: opts.predicates.clone(),
+            functions: opts.functions.clone(),
+        },
+    )?;
+
+    let paths: Vec<_> = opts.paths.iter().collect();
+    let integers: Vec<_> = opts.integers.iter().collect();
+    let integer_sets: Vec<_> = opts.integers.iter_sets().collect();
+
+    let (mut num_paths, mut num_integers, mut num_integer_sets) = (0, 0, 0);
+    for opt in &opts.optimizations {
+        let needed_paths = opt
+            .referenced_paths()
+            .iter()
+            .map(|p| p.0 as usize + 1)
+            .fold(num_paths, std::cmp::max);
+        let needed_integers = opt
+            .referenced_integers(&opts.integers)
+            .iter()
+            .map(|x| x.0 as usize + 1)
+            .fold(num_integers, std::cmp::max);
+        let needed_integer_sets = opt
+            .increments
+            .iter()
+            .filter_map(|inc| match inc.operation {
+                linear::MatchOp::IntegerInSet { set, .. } => Some(set.0 as usize + 1),
+                _ => None,
+            })
+            .fold(num_integer_sets, std::cmp::max);
+
+        write_record(
+            w,
+            &Record {
+                paths: paths[num_paths..needed_paths]
+                    .iter()
+                    .map(|p| p.0.to_vec())
+                    .collect(),
+                integers: integers[num_integers..needed_integers].to_vec(),
+                integer_sets: integer_sets[num_integer_sets..needed_integer_sets]
+                    .iter()
+                    .map(|set| set.iter().map(|id| opts.integers.lookup(*id)).collect())
+                    .collect(),
+                doc: opt.doc.clone(),
+                location: opt.location,
+                result: opt.result,
+                increments: opt
+                    .increments
+                    .iter()
+                    .map(|inc| Increment {
+                        operation: inc.operation,
+                        expected: inc.expected,
+                        actions: inc.actions.clone(),
+                    })
+                    .collect(),
+            },
+        )?;
+
+        num_paths = needed_paths;
+        num_integers = needed_integers;
+        num_integer_sets = needed_integer_sets;
+    }
+
+    Ok(())
+}
+
+/// Reads linear optimizations one at a time from a stream written by
+/// [write_linear_stream][crate::write_linear_stream].
+///
+/// This is an iterator over the stream's optimizations. The ids in each
+/// optimization refer to this reader's [paths][LinearStreamReader::paths] and
+/// [integers][LinearStreamReader::integers], which grow as more optimizations
+/// are read.
+#[derive(Debug)]
+pub struct LinearStreamReader<R> {
+    reader: R,
+    paths: PathInterner,
+    integers: IntegerInterner,
+    predicates: Vec<String>,
+    functions: Vec<String>,
+    num_paths: usize,
+    num_integers: usize,
+    num_integer_sets: usize,
+}
+
+impl<R: io::Read> LinearStreamReader<R> {
+    /// Start reading a stream of linear optimizations from `reader`.
+    ///
+    /// Returns an error if the stream's header is malformed or has a different
+    /// version.
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let header: Header = match read_record(&mut reader)? {
+            Some(header) => header,
+            None => anyhow::bail!("missing linear stream header"),
+        };
+        if header.version != LINEAR_STREAM_VERSION {
+            anyhow::bail!(
+                "unsupported linear stream version {}; expected version {}",
+                header.version,
+                LINEAR_STREAM_VERSION
+            );
+        }
+        Ok(LinearStreamReader {
+            reader,
+            paths: PathInterner::new(),
+            integers: IntegerInterner::new(),
+            predicates: header.predicates,
+            functions: header.functions,
+            num_paths: 0,
+            num_integers: 0,
+            num_integer_sets: 0,
+        })
+    }
+
+    /// The paths referenced by the optimizations read so far.
+    pub fn paths(&self) -> &PathInterner {
+        &self.paths
+    }
+
+    /// The integers referenced by the optimizations read so far.
+    pub fn integers(&self) -> &IntegerInterner {
+        &self.integers
+    }
+
+    /// The names of the custom predicates referenced by the stream's
+    /// optimizations.
+    pub fn predicates(&self) -> &[String] {
+        &self.predicates
+    }
+
+    /// The names of the host functions called by the stream's optimizations.
+    pub fn functions(&self) -> &[String] {
+        &self.functions
+    }
+
+    /// Read all of the remaining optimizations in the stream.
+    pub fn read_all(mut self) -> anyhow::Result<linear::Optimizations> {
+        let optimizations = self.by_ref().collect::<anyhow::Result<_>>()?;
+        Ok(linear::Optimizations {
+            optimizations,
+            paths: self.paths,
+            integers: self.integers,
+            predicates: self.predicates,
+            functions: self.functions,
+        })
+    }
+
+    fn read_optimization(&mut self) -> anyhow::Result<Option<linear::Optimization>> {
+        let record: Record = match read_record(&mut self.reader)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        // Interning the new values in order assigns them the ids that the
+        // writer used, as long as there are no duplicates.
+        for p in &record.paths {
+            if self.paths.intern(Path::new(p)).0 as usize != self.num_paths {
+                anyhow::bail!("duplicate path {:?}", p);
+            }
+            self.num_paths += 1;
+        }
+        for x in &record.integers {
+            if self.integers.intern(*x).0 as usize != self.num_integers {
+                anyhow::bail!("duplicate integer {}", x);
+            }
+            self.num_integers += 1;
+        }
+        for set in &record.integer_sets {
+            let ids: Vec<_> = set.iter().map(|x| self.integers.intern(*x)).collect();
+            if self.integers.intern_set(ids).0 as usize != self.num_integer_sets {
+                anyhow::bail!("duplicate integer set {:?}", set);
+            }
+            self.num_integer_sets += 1;
+        }
+
+        Ok(Some(linear::Optimization {
+            doc: record.doc,
+            location: record.location,
+            result: record.result,
+            increments: record
+                .increments
+                .into_iter()
+                .map(|inc| linear::Increment {
+                    operation: inc.operation,
+                    expected: inc.expected,
+                    actions: inc.actions,
+                })
+                .collect(),
+        }))
+    }
+}
+
+impl<R: io::Read> Iterator for LinearStreamReader<R> {
+    type Item = anyhow::Result<linear::Optimization>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_optimization().transpose()
+    }
+}
+
+/// Read the next record, or `None` if the stream ended cleanly before it.
+fn read_record<T>(r: &mut impl io::Read) -> anyhow::Result<Option<T>>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match r.read(&mut len[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => anyhow::bail!("truncated linear stream record length"),
+            n => filled += n,
+        }
+    }
+
+    // Don't trust the length with an up-front allocation: a corrupt length
+    // must not be able to allocate more than the stream actually holds.
+    let len = u32::from_le_bytes(len);
+    let mut bytes = vec![];
+    r.take(u64::from(len)).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        anyhow::bail!("truncated linear stream record");
+    }
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streaming_matches_batch() {
+        let source = "
+;; Adding zero is a no-op.
+(=> (iadd $x 0) $x)
+(=> (when (imul $x $C) (in-set $C 2 4 8)) (ishl $x $(log2 $C)))
+(=> (when (isub $x $y) (equivalent-values $x $y)) 0)
+(=> (bor (icmp eq $a $b) (icmp slt $a $b)) (icmp sle $a $b))
+(=> (when (bnot $x) (predicate is-hot $x)) $x)
+";
+        let opts = crate::compile_to_linear(source, std::path::Path::new("stream")).unwrap();
+        let mut bytes = vec![];
+        write_linear_stream(&opts, &mut bytes).unwrap();
+
+        // Each optimization is available as soon as its record is read.
+        let mut reader = LinearStreamReader::new(&bytes[..]).unwrap();
+        let first = reader.next().unwrap().unwrap();
+        assert_eq!(first, opts.optimizations[0]);
+
+        let streamed = LinearStreamReader::new(&bytes[..])
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(opts.optimizations, streamed.optimizations);
+        let docs = |opts: &linear::Optimizations| -> Vec<_> {
+            opts.optimizations.iter().map(|o| o.doc.clone()).collect()
+        };
+        assert_eq!(docs(&opts), docs(&streamed));
+        let locations = |opts: &linear::Optimizations| -> Vec<_> {
+            opts.optimizations.iter().map(|o| o.location).collect()
+        };
+        assert_eq!(locations(&opts), locations(&streamed));
+        assert_eq!(opts.predicates, streamed.predicates);
+        assert_eq!(crate::format_linear(&opts), crate::format_linear(&streamed));
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let opts =
+            crate::compile_to_linear("(=> (iadd $x 0) $x)", std::path::Path::new("t")).unwrap();
+        let mut bytes = vec![];
+        write_linear_stream(&opts, &mut bytes).unwrap();
+        bytes.pop();
+
+        let mut reader = LinearStreamReader::new(&bytes[..]).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn oversized_record_length() {
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"{}");
+
+        let err = LinearStreamReader::new(&bytes[..]).err().unwrap();
+        assert_eq!(err.to_string(), "truncated linear stream record");
+    }
+
+    #[test]
+    fn version_1_header() {
+        let header = br#"{ "version": 1, "predicates": [] }"#;
+        let mut bytes = (header.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header);
+
+        let err = LinearStreamReader::new(&bytes[..]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "unsupported linear stream version 1; expected version {}",
+                LINEAR_STREAM_VERSION
+            )
+        );
+    }
+}
@@ -85,40 +85,235 @@
 //! linear optimization translation function.
 
 use crate::ast::*;
+use crate::templates;
 use crate::traversals::Dfs;
 use peepmatic_runtime::{
     integer_interner::IntegerInterner,
     linear,
+    operator::{Operator, UnquoteOperator},
     paths::{Path, PathId, PathInterner},
 };
-use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryFrom;
 use wast::Id;
 
+/// The default maximum nesting depth of right-hand sides.
+///
+/// See [`check_rhs_depth`][crate::check_rhs_depth].
+pub const DEFAULT_MAX_RHS_DEPTH: usize = 1024;
+
+/// Check that no right-hand side in the given optimizations is nested more than
+/// `max_depth` levels deep.
+///
+/// A lone variable, constant, or literal right-hand side has depth 1, and each
+/// operation, unquote, or `let` around it adds another level. This should be
+/// checked before verifying or linearizing, so that an absurdly deep
+/// right-hand side is reported as an error instead of building a huge chain of
+/// actions.
+pub fn check_rhs_depth(opts: &Optimizations, max_depth: usize) -> wast::Result<()> {
+    use crate::traversals::TraversalEvent as TE;
+
+    for opt in &opts.optimizations {
+        let mut depth = 0;
+        for (event, node) in Dfs::new(&opt.rhs) {
+            match (event, node) {
+                (TE::Enter, DynAstRef::Rhs(rhs)) => {
+                    depth += 1;
+                    if depth > max_depth {
+                        return Err(wast::Error::new(
+                            rhs.span(),
+                            format!(
+                                "right-hand side is nested more than {} levels deep",
+                                max_depth
+                            ),
+                        ));
+                    }
+                }
+                (TE::Exit, DynAstRef::Rhs(_)) => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that no left-hand side pattern matches an operand that its opcode
+/// doesn't have.
+///
+/// Each operand of a pattern operation becomes a path one step deeper than the
+/// operation's own path, and those paths are only meaningful once the
+/// operation's `Opcode` match has succeeded. An operand index beyond that
+/// opcode's arity (or the arity of any of its `one-of` alternatives) would
+/// never be found in an instruction, so we reject it here rather than emitting
+/// matches along a path that can't exist.
+pub fn check_operand_arity(opts: &Optimizations) -> wast::Result<()> {
+    use crate::traversals::TraversalEvent as TE;
+
+    for opt in &opts.optimizations {
+        for (event, node) in Dfs::new(&opt.lhs) {
+            let op = match (event, node) {
+                (TE::Enter, DynAstRef::PatternOperation(op)) => op,
+                _ => continue,
+            };
+            for operator in std::iter::once(&op.operator).chain(&op.alternatives) {
+                let arity = operator.immediates_arity() as usize + operator.params_arity() as usize;
+                if let Some(extra) = op.operands.get(arity) {
+                    return Err(wast::Error::new(
+                        extra.span(),
+                        format!(
+                            "`{}` has {} operand(s), so there is no operand {} to match",
+                            operator,
+                            arity,
+                            arity + 1
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that every type variable, ascribed like `(iadd{$T} ...)`, is used
+/// consistently.
+///
+/// A type variable is bound by the left-hand side's operations that are
+/// ascribed with it, and linearizing them emits `SameWidth` matches that link
+/// those operations together. A right-hand side can only use a type variable
+/// that its left-hand side binds, and a type variable cannot share its name
+/// with a variable or constant of the same optimization, since it does not
+/// stand for a value.
+pub fn check_type_variables(opts: &Optimizations) -> wast::Result<()> {
+    use crate::traversals::TraversalEvent as TE;
+
+    for opt in &opts.optimizations {
+        let mut values = HashSet::new();
+        let mut type_variables = vec![];
+        for (event, node) in Dfs::new(&opt.lhs) {
+            match (event, node) {
+                (TE::Enter, DynAstRef::Variable(v)) => {
+                    values.insert(v.id.name());
+                }
+                (TE::Enter, DynAstRef::Constant(c)) => {
+                    values.insert(c.id.name());
+                }
+                (TE::Enter, DynAstRef::PatternOperation(op)) => {
+                    type_variables.extend(op.type_variable);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(id) = type_variables.iter().find(|id| values.contains(id.name())) {
+            return Err(wast::Error::new(
+                id.span(),
+                format!(
+                    "`${}` is used as both a type variable and a value",
+                    id.name()
+                ),
+            ));
+        }
+
+        for (event, node) in Dfs::new(&opt.rhs) {
+            let id = match (event, node) {
+                (TE::Enter, DynAstRef::RhsOperation(op)) => match op.type_variable {
+                    Some(id) => id,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if !type_variables.iter().any(|tv| tv.name() == id.name()) {
+                return Err(wast::Error::new(
+                    id.span(),
+                    format!(
+                        "type variable `${}` is not bound in the left-hand side",
+                        id.name()
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Translate the given AST optimizations into linear optimizations.
 pub fn linearize(opts: &Optimizations) -> linear::Optimizations {
     let mut optimizations = vec![];
     let mut paths = PathInterner::new();
     let mut integers = IntegerInterner::new();
+    let mut predicates = vec![];
+    let mut functions = vec![];
     for opt in &opts.optimizations {
-        let lin_opt = linearize_optimization(&mut paths, &mut integers, opt);
-        optimizations.push(lin_opt);
+        for (rhs, guards) in rhs_alternatives(opt) {
+            let lin_opt = linearize_optimization(
+                &mut paths,
+                &mut integers,
+                &mut predicates,
+                &mut functions,
+                opt,
+                rhs,
+                &guards,
+            );
+            let lin_opts = expand_one_of(&mut paths, opt, lin_opt);
+            optimizations.extend(expand_swapped_icmps(&mut paths, opt, rhs, lin_opts));
+        }
     }
     linear::Optimizations {
         optimizations,
         paths,
         integers,
+        predicates,
+        functions,
     }
 }
 
+/// Get each right-hand side that the given optimization can replace its
+/// left-hand side with, along with the `cond` guards that must either hold
+/// (`true`) or fail (`false`) for that right-hand side to be chosen.
+///
+/// An optimization without a `cond` has just the one right-hand side. For a
+/// `cond`, each arm requires that its own guard holds and the guards of the
+/// arms before it fail, and the default requires that every guard fails, so
+/// that exactly one of the alternatives matches.
+fn rhs_alternatives<'a, 'b>(
+    opt: &'b Optimization<'a>,
+) -> Vec<(&'b Rhs<'a>, Vec<(&'b Precondition<'a>, bool)>)> {
+    let cond = match &opt.rhs {
+        Rhs::Cond(cond) => cond,
+        rhs => return vec![(rhs, vec![])],
+    };
+
+    let mut alternatives = vec![];
+    let mut failed = vec![];
+    for arm in &cond.arms {
+        let mut guards = failed.clone();
+        guards.push((&arm.guard, true));
+        alternatives.push((&arm.rhs, guards));
+        failed.push((&arm.guard, false));
+    }
+    alternatives.push((&*cond.default, failed));
+    alternatives
+}
+
 /// Translate an AST optimization into a linear optimization!
+///
+/// The `rhs` is either the optimization's right-hand side, or one of its
+/// `cond`'s alternatives, which is only chosen when the given `guards` hold or
+/// fail as expected. See `rhs_alternatives`.
 fn linearize_optimization(
     paths: &mut PathInterner,
     integers: &mut IntegerInterner,
+    predicates: &mut Vec<String>,
+    functions: &mut Vec<String>,
     opt: &Optimization,
+    rhs: &Rhs,
+    guards: &[(&Precondition, bool)],
 ) -> linear::Optimization {
     let mut increments: Vec<linear::Increment> = vec![];
 
     let mut lhs_id_to_path = LhsIdToPath::new();
+    let mut type_variable_paths = BTreeMap::new();
 
     // We do a pre-order traversal of the LHS because we don't know whether a
     // child actually exists to match on until we've matched its parent, and we
@@ -149,21 +344,276 @@ fn linearize_optimization(
                 });
             }
         }
+
+        // Operations ascribed with the same type variable must have the same
+        // bit width as the first one, whatever width that turns out to be.
+        if let Pattern::Operation(Operation {
+            type_variable: Some(id),
+            ..
+        }) = pattern
+        {
+            match type_variable_paths.entry(id.name()) {
+                Entry::Vacant(v) => {
+                    v.insert(path);
+                }
+                Entry::Occupied(o) => increments.push(linear::Increment {
+                    operation: linear::MatchOp::SameWidth {
+                        path_a: *o.get(),
+                        path_b: path,
+                    },
+                    expected: Some(1),
+                    actions: vec![],
+                }),
+            }
+        }
     }
 
     // Now that we've added all the increments for the LHS pattern, add the
     // increments for its preconditions.
     for pre in &opt.lhs.preconditions {
-        increments.push(pre.to_linear_increment(&lhs_id_to_path));
+        increments.push(pre.to_linear_increment(paths, integers, predicates, &lhs_id_to_path));
+    }
+
+    // If the right-hand side is one alternative of a `cond`, check that its
+    // guards hold, or fail, as required to choose it.
+    for (guard, holds) in guards {
+        let mut increment = guard.to_linear_increment(paths, integers, predicates, &lhs_id_to_path);
+        increment.expected = Some(*holds as u32);
+        increments.push(increment);
+    }
+
+    // A `fold` in the right-hand side can only be built if its instruction
+    // actually folds, and a `stored-value` only if its load's value can be
+    // forwarded, so check for that as part of matching, too.
+    for rhs in RhsPostOrder::new(rhs) {
+        let operation = match rhs {
+            Rhs::Fold(f) => linear::MatchOp::IsFoldable {
+                path: lhs_id_to_path.unwrap_first_occurrence(&f.value.id),
+            },
+            Rhs::StoredValue(s) => linear::MatchOp::IsForwardableLoad {
+                path: lhs_id_to_path.unwrap_first_occurrence(&s.load.id),
+            },
+            _ => continue,
+        };
+        increments.push(linear::Increment {
+            operation,
+            expected: Some(1),
+            actions: vec![],
+        });
+    }
+
+    // Only delete instructions whose results are dead.
+    if let Rhs::Delete(_) = rhs {
+        increments.push(linear::Increment {
+            operation: linear::MatchOp::IsUnused {
+                path: paths.intern(Path::new(&[0])),
+            },
+            expected: Some(1),
+            actions: vec![],
+        });
+    }
+
+    // And if the optimization is tagged with a phase, check that we are running
+    // that phase.
+    if let Some(phase) = opt.phase {
+        increments.push(linear::Increment {
+            operation: linear::MatchOp::InPhase { phase },
+            expected: Some(1),
+            actions: vec![],
+        });
+    }
+
+    // Optimizations that are disabled by default only apply when the optimizer
+    // has opted into them.
+    if !opt.enabled {
+        increments.push(linear::Increment {
+            operation: linear::MatchOp::IsEnabled,
+            expected: Some(1),
+            actions: vec![],
+        });
     }
 
     assert!(!increments.is_empty());
 
     // Finally, generate the RHS-building actions and attach them to the first increment.
-    let mut rhs_builder = RhsBuilder::new(&opt.rhs);
-    rhs_builder.add_rhs_build_actions(integers, &lhs_id_to_path, &mut increments[0].actions);
+    let mut rhs_builder = RhsBuilder::new(rhs);
+    rhs_builder.add_rhs_build_actions(
+        integers,
+        functions,
+        &lhs_id_to_path,
+        &mut increments[0].actions,
+    );
 
-    linear::Optimization { increments }
+    // Deleting the root, or replacing each of its results, doesn't designate a
+    // single replacement value.
+    let result = match rhs {
+        Rhs::Delete(_) | Rhs::Results(_) => None,
+        _ => Some(rhs_builder.get_rhs_id(rhs)),
+    };
+
+    linear::Optimization {
+        increments,
+        result,
+        doc: opt.doc.clone(),
+        location: opt.location,
+    }
+}
+
+/// Expand any `one-of` operator sets in the given optimization's left-hand side
+/// into one linear optimization per operator.
+///
+/// The expanded optimizations only differ in the expected opcode of their
+/// `Opcode` increments, so they end up as multiple edges to the same states in
+/// the final automata.
+fn expand_one_of(
+    paths: &mut PathInterner,
+    opt: &Optimization,
+    lin_opt: linear::Optimization,
+) -> Vec<linear::Optimization> {
+    let mut expanded = vec![lin_opt];
+
+    let mut patterns = PatternPreOrder::new(&opt.lhs.pattern);
+    while let Some((path, pattern)) = patterns.next(paths) {
+        let alternatives = match pattern {
+            Pattern::Operation(op) if !op.alternatives.is_empty() => &op.alternatives,
+            _ => continue,
+        };
+
+        let mut new = vec![];
+        for lin_opt in &expanded {
+            let index = lin_opt
+                .increments
+                .iter()
+                .position(|inc| inc.operation == linear::MatchOp::Opcode { path })
+                .expect("should have an opcode increment for every operation pattern");
+            for alt in alternatives {
+                let mut lin_opt = lin_opt.clone();
+                lin_opt.increments[index].expected = Some(*alt as u32);
+                new.push(lin_opt);
+            }
+        }
+        expanded.extend(new);
+    }
+
+    expanded
+}
+
+/// The maximum number of `icmp` patterns in one optimization whose swapped
+/// forms `expand_swapped_icmps` also matches.
+const MAX_SWAPPED_ICMPS: usize = 3;
+
+/// Expand each `icmp` pattern with an ordering condition code into another
+/// linear optimization that matches the same comparison with its operands
+/// swapped and its condition code flipped.
+///
+/// For example, `(icmp slt $a $b)` also matches `(icmp sgt $b $a)`, so that a
+/// single optimization covers both ways of writing the comparison.
+/// Equality comparisons are left alone, since their condition code doesn't
+/// change when swapped.
+///
+/// Optimizations whose right-hand side builds an `icmp` anywhere are not
+/// expanded: the replacement might match the swapped form of the
+/// optimization's own left-hand side, and then we would rewrite back and forth
+/// forever.
+///
+/// Each expanded comparison doubles the number of linear optimizations, so
+/// only the first `MAX_SWAPPED_ICMPS` comparisons, in pre-order, are expanded.
+fn expand_swapped_icmps(
+    paths: &mut PathInterner,
+    opt: &Optimization,
+    rhs: &Rhs,
+    lin_opts: Vec<linear::Optimization>,
+) -> Vec<linear::Optimization> {
+    let builds_icmp = RhsPostOrder::new(rhs).any(|r| match r {
+        Rhs::Operation(op) => op.operator == Operator::Icmp,
+        _ => false,
+    });
+    if builds_icmp {
+        return lin_opts;
+    }
+
+    // Each expanded optimization, along with the prefixes of the comparisons
+    // whose operands it has swapped, in the order they were swapped.
+    let mut expanded: Vec<_> = lin_opts.into_iter().map(|o| (o, vec![])).collect();
+
+    let mut num_swapped = 0;
+    let mut patterns = PatternPreOrder::new(&opt.lhs.pattern);
+    while let Some((path, pattern)) = patterns.next(paths) {
+        if num_swapped == MAX_SWAPPED_ICMPS {
+            break;
+        }
+        let swapped_cc = match pattern {
+            Pattern::Operation(Operation {
+                operator: Operator::Icmp,
+                alternatives,
+                operands,
+                ..
+            }) if alternatives.is_empty() => match operands.first() {
+                Some(Pattern::ValueLiteral(ValueLiteral::ConditionCode(ConditionCode {
+                    cc,
+                    ..
+                }))) => match cc.swap_operands() {
+                    Some(swapped) if swapped != *cc => swapped,
+                    _ => continue,
+                },
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let icmp_path = paths.lookup(path).0.to_vec();
+        num_swapped += 1;
+
+        let mut new = vec![];
+        for (lin_opt, swaps) in &expanded {
+            // Find this `icmp` in the expanded optimization, whose enclosing
+            // comparisons may already have been swapped.
+            let prefix = swaps.iter().fold(icmp_path.clone(), |p, s: &Vec<u8>| {
+                swap_operand_path(s, &p).unwrap_or(p)
+            });
+            let mut cc_path = prefix.clone();
+            cc_path.push(0);
+            let cc_path = paths.intern(Path::new(&cc_path));
+
+            let mut lin_opt = lin_opt.clone();
+            let mut remap = |p: &mut PathId| {
+                if let Some(swapped) = swap_operand_path(&prefix, paths.lookup(*p).0) {
+                    *p = paths.intern(Path::new(&swapped));
+                }
+            };
+            for inc in &mut lin_opt.increments {
+                if inc.operation == (linear::MatchOp::ConditionCode { path: cc_path }) {
+                    inc.expected = Some(swapped_cc as u32);
+                }
+                inc.operation.for_each_path_mut(&mut remap);
+                for action in &mut inc.actions {
+                    action.for_each_path_mut(&mut remap);
+                }
+            }
+
+            let mut swaps = swaps.clone();
+            swaps.push(prefix);
+            new.push((lin_opt, swaps));
+        }
+        expanded.extend(new);
+    }
+
+    expanded.into_iter().map(|(o, _)| o).collect()
+}
+
+/// If `path` is inside the first or second operand of the `icmp` at
+/// `icmp_path`, get the path to the same place inside the other operand.
+fn swap_operand_path(icmp_path: &[u8], path: &[u8]) -> Option<Vec<u8>> {
+    if path.len() <= icmp_path.len() || !path.starts_with(icmp_path) {
+        return None;
+    }
+    let operand = match path[icmp_path.len()] {
+        1 => 2,
+        2 => 1,
+        _ => return None,
+    };
+    let mut swapped = path.to_vec();
+    swapped[icmp_path.len()] = operand;
+    Some(swapped)
 }
 
 /// A post-order, depth-first traversal of right-hand sides.
@@ -299,6 +749,26 @@ struct RhsBuilder<'a> {
     // `RhsId` is roughly equivalent to its index in the post-order traversal of
     // the RHS.
     rhs_span_to_id: BTreeMap<wast::Span, linear::RhsId>,
+
+    // The number of actions emitted so far, which is also the next `RhsId` to
+    // be defined. This differs from `rhs_span_to_id.len()` because `let`
+    // expressions and references to `let`-bound names don't emit actions of
+    // their own; they reuse the `RhsId` of what they refer to.
+    num_actions: u32,
+
+    // A map from the span of each `let` binding's value to the name it is bound
+    // to.
+    let_value_span_to_name: BTreeMap<wast::Span, &'a str>,
+
+    // A map from `let`-bound names to the `RhsId` of their value. Verification
+    // ensures that names are never rebound within a right-hand side, and that
+    // they are only referenced while in scope.
+    let_bound: BTreeMap<&'a str, linear::RhsId>,
+
+    // The spans of `extractlane` and `insertlane` lane indices, which are part
+    // of the action that makes their instruction rather than RHSes of their
+    // own.
+    lane_spans: BTreeSet<wast::Span>,
 }
 
 impl<'a> RhsBuilder<'a> {
@@ -306,9 +776,30 @@ impl<'a> RhsBuilder<'a> {
     fn new(rhs: &'a Rhs<'a>) -> Self {
         let rhs_post_order = RhsPostOrder::new(rhs);
         let rhs_span_to_id = Default::default();
+        let let_value_span_to_name = Dfs::new(rhs)
+            .filter_map(|(_, node)| match node {
+                DynAstRef::LetBinding(b) => Some((b.value.span(), b.var.id.name())),
+                _ => None,
+            })
+            .collect();
+        let lane_spans = Dfs::new(rhs)
+            .filter_map(|(_, node)| match node {
+                DynAstRef::RhsOperation(op)
+                    if op.operator == Operator::Extractlane
+                        || op.operator == Operator::Insertlane =>
+                {
+                    Some(op.operands[0].span())
+                }
+                _ => None,
+            })
+            .collect();
         Self {
             rhs_post_order,
             rhs_span_to_id,
+            num_actions: 0,
+            let_value_span_to_name,
+            let_bound: Default::default(),
+            lane_spans,
         }
     }
 
@@ -330,30 +821,107 @@ impl<'a> RhsBuilder<'a> {
     fn add_rhs_build_actions(
         &mut self,
         integers: &mut IntegerInterner,
+        functions: &mut Vec<String>,
         lhs_id_to_path: &LhsIdToPath,
         actions: &mut Vec<linear::Action>,
     ) {
         while let Some(rhs) = self.rhs_post_order.next() {
-            actions.push(self.rhs_to_linear_action(integers, lhs_id_to_path, rhs));
-            let id = linear::RhsId(self.rhs_span_to_id.len() as u32);
+            if self.lane_spans.contains(&rhs.span()) {
+                continue;
+            }
+            let id = match rhs {
+                // A `let` is just its body, which we've already built.
+                Rhs::Let(l) => self.get_rhs_id(&l.body),
+                Rhs::Variable(Variable { id, .. }) if self.let_bound.contains_key(id.name()) => {
+                    self.let_bound[id.name()]
+                }
+                Rhs::ExpandIabs(e) => {
+                    let x = self.get_rhs_id(&e.operand);
+                    let r#type = e
+                        .r#type
+                        .get()
+                        .expect("should be initialized after type checking");
+                    let amount = self.push_action(
+                        actions,
+                        linear::Action::MakeSignShiftAmount {
+                            bit_width: r#type.bit_width,
+                        },
+                    );
+                    let mut make = |operator, operands| {
+                        self.push_action(
+                            actions,
+                            linear::Action::MakeBinaryInst {
+                                operator,
+                                r#type,
+                                operands,
+                            },
+                        )
+                    };
+                    // `$s` is all ones when `$x` is negative, and zero
+                    // otherwise, so `(isub (bxor $x $s) $s)` is either `$x`
+                    // or its two's complement negation.
+                    let s = make(Operator::Sshr, [x, amount]);
+                    let flipped = make(Operator::Bxor, [x, s]);
+                    make(Operator::Isub, [flipped, s])
+                }
+                Rhs::Template(t) => {
+                    let template = templates::lookup(t.name).expect("checked in verification");
+                    let r#type = t
+                        .r#type
+                        .get()
+                        .expect("should be initialized after type checking");
+                    let operands: Vec<_> = t.operands.iter().map(|o| self.get_rhs_id(o)).collect();
+                    template.inline(r#type, &operands, |action| {
+                        self.push_action(actions, action)
+                    })
+                }
+                _ => {
+                    let action =
+                        self.rhs_to_linear_action(integers, functions, lhs_id_to_path, rhs);
+                    self.push_action(actions, action)
+                }
+            };
             self.rhs_span_to_id.insert(rhs.span(), id);
+            if let Some(name) = self.let_value_span_to_name.get(&rhs.span()) {
+                self.let_bound.insert(*name, id);
+            }
         }
     }
 
+    /// Emit the given action, returning the `linear::RhsId` that it defines.
+    fn push_action(
+        &mut self,
+        actions: &mut Vec<linear::Action>,
+        action: linear::Action,
+    ) -> linear::RhsId {
+        actions.push(action);
+        let id = linear::RhsId(self.num_actions);
+        self.num_actions += 1;
+        id
+    }
+
     fn rhs_to_linear_action(
         &self,
         integers: &mut IntegerInterner,
+        functions: &mut Vec<String>,
         lhs_id_to_path: &LhsIdToPath,
         rhs: &Rhs,
     ) -> linear::Action {
         match rhs {
-            Rhs::ValueLiteral(ValueLiteral::Integer(i)) => linear::Action::MakeIntegerConst {
-                value: integers.intern(i.value as u64),
-                bit_width: i
+            Rhs::ValueLiteral(ValueLiteral::Integer(i)) => {
+                let bit_width = i
                     .bit_width
                     .get()
-                    .expect("should be initialized after type checking"),
-            },
+                    .expect("should be initialized after type checking");
+                match i.value {
+                    0 => linear::Action::MakeZero { bit_width },
+                    1 => linear::Action::MakeOne { bit_width },
+                    value => linear::Action::MakeIntegerConst {
+                        value: integers.intern(value as u128),
+                        bit_width,
+                    },
+                }
+            }
             Rhs::ValueLiteral(ValueLiteral::Boolean(b)) => linear::Action::MakeBooleanConst {
                 value: b.value,
                 bit_width: b
@@ -368,7 +936,53 @@ impl<'a> RhsBuilder<'a> {
                 let path = lhs_id_to_path.unwrap_first_occurrence(id);
                 linear::Action::GetLhs { path }
             }
+            Rhs::IconstLike(c) => linear::Action::MakeIconstMatchingWidth {
+                value: integers.intern(c.value.value as u128),
+                like: lhs_id_to_path.unwrap_first_occurrence(&c.like.id),
+            },
+            Rhs::Fold(f) => linear::Action::FoldConst {
+                path: lhs_id_to_path.unwrap_first_occurrence(&f.value.id),
+            },
+            Rhs::StoredValue(s) => linear::Action::GetStoredValue {
+                path: lhs_id_to_path.unwrap_first_occurrence(&s.load.id),
+            },
+            Rhs::Delete(_) => linear::Action::DeleteInstruction,
+            Rhs::Results(r) => linear::Action::ReplaceResults {
+                results: r.values.iter().map(|v| self.get_rhs_id(v)).collect(),
+            },
+            Rhs::Call(call) => {
+                // Functions are numbered in the order their names first
+                // appear.
+                let function = match functions.iter().position(|f| f == call.name) {
+                    Some(i) => i,
+                    None => {
+                        functions.push(call.name.to_string());
+                        functions.len() - 1
+                    }
+                };
+                let mut operands = [None; 2];
+                for (slot, operand) in operands.iter_mut().zip(&call.operands) {
+                    *slot = Some(self.get_rhs_id(operand));
+                }
+                linear::Action::CallFunction {
+                    function: function as u32,
+                    operands,
+                }
+            }
             Rhs::Unquote(unq) => match unq.operands.len() {
+                1 if unq.operator == UnquoteOperator::InvertCc => {
+                    linear::Action::InvertConditionCode {
+                        operand: self.get_rhs_id(&unq.operands[0]),
+                    }
+                }
+                2 if unq.operator == UnquoteOperator::CombineCc => {
+                    linear::Action::CombineConditionCodes {
+                        operands: [
+                            self.get_rhs_id(&unq.operands[0]),
+                            self.get_rhs_id(&unq.operands[1]),
+                        ],
+                    }
+                }
                 1 => linear::Action::UnaryUnquote {
                     operator: unq.operator,
                     operand: self.get_rhs_id(&unq.operands[0]),
@@ -383,6 +997,31 @@ impl<'a> RhsBuilder<'a> {
                 n => unreachable!("no unquote operators of arity {}", n),
             },
             Rhs::Operation(op) => match op.operands.len() {
+                1 if op.operator == Operator::Load => linear::Action::MakeLoad {
+                    operand: self.get_rhs_id(&op.operands[0]),
+                    offset: op.mem_offset.unwrap_or(0),
+                    flags: op.mem_flags,
+                    bit_width: op
+                        .r#type
+                        .get()
+                        .expect("should be initialized after type checking")
+                        .bit_width,
+                },
+                2 if op.operator == Operator::Extractlane => linear::Action::MakeExtractLane {
+                    operand: self.get_rhs_id(&op.operands[1]),
+                    lane: lane_immediate(op),
+                },
+                3 if op.operator == Operator::Insertlane => linear::Action::MakeInsertLane {
+                    vector: self.get_rhs_id(&op.operands[1]),
+                    value: self.get_rhs_id(&op.operands[2]),
+                    lane: lane_immediate(op),
+                },
+                2 if op.operator == Operator::Store => linear::Action::MakeStore {
+                    value: self.get_rhs_id(&op.operands[0]),
+                    addr: self.get_rhs_id(&op.operands[1]),
+                    offset: op.mem_offset.unwrap_or(0),
+                    flags: op.mem_flags,
+                },
                 1 => linear::Action::MakeUnaryInst {
                     operator: op.operator,
                     r#type: op
@@ -402,6 +1041,19 @@ impl<'a> RhsBuilder<'a> {
                         self.get_rhs_id(&op.operands[1]),
                     ],
                 },
+                3 if op.operator == Operator::SelectSpectreGuard => {
+                    linear::Action::MakeSelectSpectreGuard {
+                        r#type: op
+                            .r#type
+                            .get()
+                            .expect("should be initialized after type checking"),
+                        operands: [
+                            self.get_rhs_id(&op.operands[0]),
+                            self.get_rhs_id(&op.operands[1]),
+                            self.get_rhs_id(&op.operands[2]),
+                        ],
+                    }
+                }
                 3 => linear::Action::MakeTernaryInst {
                     operator: op.operator,
                     r#type: op
@@ -416,63 +1068,263 @@ impl<'a> RhsBuilder<'a> {
                 },
                 n => unreachable!("no instructions of arity {}", n),
             },
+            Rhs::Let(_) => unreachable!("`let`s don't emit actions of their own"),
+            Rhs::ExpandIabs(_) => unreachable!("`expand-iabs` emits several actions"),
+            Rhs::Template(_) => unreachable!("`template` emits several actions"),
+            Rhs::Cond(_) => unreachable!("each `cond` alternative is linearized separately"),
         }
     }
 }
 
+/// Get the lane index immediate of a right-hand side `extractlane` or
+/// `insertlane`.
+fn lane_immediate(op: &Operation<Rhs>) -> u8 {
+    match &op.operands[0] {
+        Rhs::ValueLiteral(ValueLiteral::Integer(i)) => {
+            u8::try_from(i.value).expect("lane indices are checked in verification")
+        }
+        _ => unreachable!("checked in verification"),
+    }
+}
+
+/// An increment that checks that `operation` holds, i.e. evaluates to `1`.
+fn check(operation: linear::MatchOp) -> linear::Increment {
+    linear::Increment {
+        operation,
+        expected: Some(1),
+        actions: vec![],
+    }
+}
+
+/// Intern the path to the parent of the instruction or value at `path`.
+fn parent_path(paths: &mut PathInterner, path: PathId) -> PathId {
+    let path = paths.lookup(path).0;
+    let parent = path[..path.len() - 1].to_vec();
+    paths.intern(Path::new(&parent))
+}
+
 impl Precondition<'_> {
+    /// Get the path to the first occurrence of this precondition's `i`th
+    /// operand, which must be a constant or a variable.
+    fn operand_path(&self, i: usize, lhs_id_to_path: &LhsIdToPath) -> PathId {
+        match &self.operands[i] {
+            ConstraintOperand::Constant(Constant { id, .. })
+            | ConstraintOperand::Variable(Variable { id, .. }) => {
+                lhs_id_to_path.unwrap_first_occurrence(id)
+            }
+            _ => unreachable!("checked in verification"),
+        }
+    }
+
+    /// Get the value of this precondition's `i`th operand, which must be an
+    /// integer literal.
+    fn integer_operand(&self, i: usize) -> i128 {
+        match &self.operands[i] {
+            ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer { value, .. })) => *value,
+            _ => unreachable!("checked in verification"),
+        }
+    }
+
     /// Convert this precondition into a `linear::Increment`.
-    fn to_linear_increment(&self, lhs_id_to_path: &LhsIdToPath) -> linear::Increment {
+    fn to_linear_increment(
+        &self,
+        paths: &mut PathInterner,
+        integers: &mut IntegerInterner,
+        predicates: &mut Vec<String>,
+        lhs_id_to_path: &LhsIdToPath,
+    ) -> linear::Increment {
+        let path = |i| self.operand_path(i, lhs_id_to_path);
         match self.constraint {
-            Constraint::IsPowerOfTwo => {
-                let id = match &self.operands[0] {
-                    ConstraintOperand::Constant(Constant { id, .. }) => id,
-                    _ => unreachable!("checked in verification"),
-                };
-                let path = lhs_id_to_path.unwrap_first_occurrence(&id);
+            Constraint::IsPowerOfTwo => check(linear::MatchOp::IsPowerOfTwo { path: path(0) }),
+            Constraint::IsByteMask => linear::Increment {
+                operation: linear::MatchOp::IsByteMask { path: path(0) },
+                expected: Some(self.integer_operand(1) as u32),
+                actions: vec![],
+            },
+            Constraint::IsShiftByConst => check(linear::MatchOp::IsShiftByConst { path: path(0) }),
+            Constraint::IsCallResult => check(linear::MatchOp::IsCallResult { path: path(0) }),
+            Constraint::SingleUse => check(linear::MatchOp::SingleUse { path: path(0) }),
+            Constraint::AllOperandsConst => {
+                check(linear::MatchOp::AllOperandsConst { path: path(0) })
+            }
+            Constraint::SelectConstArms => {
+                check(linear::MatchOp::SelectConstArms { path: path(0) })
+            }
+            Constraint::IsSextend | Constraint::IsUextend => {
+                let signed = self.constraint == Constraint::IsSextend;
+                let source_width = self.integer_operand(1) as u8;
                 linear::Increment {
-                    operation: linear::MatchOp::IsPowerOfTwo { path },
-                    expected: Some(1),
+                    operation: linear::MatchOp::IsExtension { path: path(0) },
+                    expected: Some(linear::MatchOp::extension_value(signed, source_width)),
                     actions: vec![],
                 }
             }
+            Constraint::IsMaskConst => linear::Increment {
+                operation: linear::MatchOp::IsMaskConst { path: path(0) },
+                expected: Some(self.integer_operand(1) as u32),
+                actions: vec![],
+            },
+            Constraint::PopcountEq => check(linear::MatchOp::PopcountEq {
+                path: path(0),
+                count: self.integer_operand(1) as u8,
+            }),
+            Constraint::PopcountLe => check(linear::MatchOp::PopcountLe {
+                path: path(0),
+                count: self.integer_operand(1) as u8,
+            }),
+            Constraint::NegationFitsInBits => check(linear::MatchOp::NegationFitsInBits {
+                path: path(0),
+                bits: self.integer_operand(1) as u8,
+            }),
+            Constraint::IsOne => check(linear::MatchOp::IsOne { path: path(0) }),
+            Constraint::KnownNonNegative => {
+                check(linear::MatchOp::KnownNonNegative { path: path(0) })
+            }
+            Constraint::IsEncodableLogicalImm => check(linear::MatchOp::IsEncodableLogicalImm {
+                path: path(0),
+                width: self.integer_operand(1) as u8,
+            }),
+            Constraint::IsAddOfNegConst => {
+                // The constant is the second operand of its `iadd`, so the
+                // `iadd` itself is at the constant's parent path.
+                let iadd = parent_path(paths, path(0));
+                check(linear::MatchOp::IsAddOfNegConst { path: iadd })
+            }
+            Constraint::IsShiftedImm => check(linear::MatchOp::IsShiftedImm {
+                path: path(0),
+                bits: self.integer_operand(1) as u8,
+                shift: self.integer_operand(2) as u8,
+            }),
+            Constraint::IsShiftedImm8 => check(linear::MatchOp::IsShiftedImm8 { path: path(0) }),
+            Constraint::FitsInImm12 => check(linear::MatchOp::FitsInBits {
+                path: path(0),
+                bits: 12,
+            }),
+            Constraint::FitsInImm32 => check(linear::MatchOp::FitsInBits {
+                path: path(0),
+                bits: 32,
+            }),
+            Constraint::IsWidthMinus => check(linear::MatchOp::ConstEqWidthMinus {
+                path: path(0),
+                delta: self.integer_operand(1) as u8,
+            }),
             Constraint::BitWidth => {
-                let id = match &self.operands[0] {
-                    ConstraintOperand::Constant(Constant { id, .. })
-                    | ConstraintOperand::Variable(Variable { id, .. }) => id,
-                    _ => unreachable!("checked in verification"),
-                };
-                let path = lhs_id_to_path.unwrap_first_occurrence(&id);
-
-                let width = match &self.operands[1] {
-                    ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
-                        value,
-                        ..
-                    })) => *value,
-                    _ => unreachable!("checked in verification"),
-                };
+                let width = self.integer_operand(1);
                 debug_assert!(width <= 128);
                 debug_assert!((width as u8).is_power_of_two());
-
                 linear::Increment {
-                    operation: linear::MatchOp::BitWidth { path },
+                    operation: linear::MatchOp::BitWidth { path: path(0) },
                     expected: Some(width as u32),
                     actions: vec![],
                 }
             }
+            Constraint::BlockPredecessors => linear::Increment {
+                // This always checks the root instruction's block.
+                operation: linear::MatchOp::BlockPredecessorCount { path: PathId::ROOT },
+                expected: Some(self.integer_operand(0) as u32),
+                actions: vec![],
+            },
             Constraint::FitsInNativeWord => {
-                let id = match &self.operands[0] {
-                    ConstraintOperand::Constant(Constant { id, .. })
-                    | ConstraintOperand::Variable(Variable { id, .. }) => id,
+                check(linear::MatchOp::FitsInNativeWord { path: path(0) })
+            }
+            Constraint::EquivalentValues => check(linear::MatchOp::EquivalentValues {
+                path_a: path(0),
+                path_b: path(1),
+            }),
+            Constraint::IsOneLessThan => check(linear::MatchOp::IsOneLessThan {
+                path_a: path(0),
+                path_b: path(1),
+            }),
+            Constraint::IsScaledBy => check(linear::MatchOp::IsScaledBy {
+                path_a: path(0),
+                path_b: path(1),
+                factor: integers.intern(self.integer_operand(2) as u128),
+            }),
+            Constraint::ShiftsCombine => {
+                // Each shift amount is its shift's second operand, so the
+                // shifts themselves are at the amounts' parent paths.
+                let inner = parent_path(paths, path(0));
+                let outer = parent_path(paths, path(1));
+                check(linear::MatchOp::NestedShiftCombinable { outer, inner })
+            }
+            Constraint::RedundantMaskForShift => check(linear::MatchOp::RedundantMaskForShift {
+                mask: path(0),
+                amount: path(1),
+            }),
+            Constraint::TrailingZeros => linear::Increment {
+                operation: linear::MatchOp::TrailingZeros { path: path(0) },
+                expected: Some(self.integer_operand(1) as u32),
+                actions: vec![],
+            },
+            Constraint::IcmpZero => {
+                let cc = match &self.operands[1] {
+                    ConstraintOperand::ValueLiteral(ValueLiteral::ConditionCode(
+                        ConditionCode { cc, .. },
+                    )) => *cc,
                     _ => unreachable!("checked in verification"),
                 };
-                let path = lhs_id_to_path.unwrap_first_occurrence(&id);
                 linear::Increment {
-                    operation: linear::MatchOp::FitsInNativeWord { path },
-                    expected: Some(1),
+                    operation: linear::MatchOp::IcmpZero { path: path(0) },
+                    expected: Some(cc as u32),
                     actions: vec![],
                 }
             }
+            Constraint::IsDoubleInvolution => {
+                // The variable is the inner instruction's operand, which is
+                // itself the outer instruction's operand.
+                let inner = parent_path(paths, path(0));
+                let outer = parent_path(paths, inner);
+                check(linear::MatchOp::IsDoubleInvolution { outer, inner })
+            }
+            Constraint::CcsCombine => {
+                // Each condition code is its `icmp`'s first operand, so the
+                // comparisons themselves are at the condition codes' parent
+                // paths.
+                let path_a = parent_path(paths, path(0));
+                let path_b = parent_path(paths, path(1));
+                check(linear::MatchOp::ConditionCodesCombine { path_a, path_b })
+            }
+            Constraint::InSet => {
+                let members: Vec<_> = (1..self.operands.len())
+                    .map(|i| integers.intern(self.integer_operand(i) as u128))
+                    .collect();
+                let set = integers.intern_set(members);
+                check(linear::MatchOp::IntegerInSet { path: path(0), set })
+            }
+            Constraint::BoundedBy => check(linear::MatchOp::BoundedBy {
+                path: path(0),
+                lo: integers.intern(self.integer_operand(1) as u128),
+                hi: integers.intern(self.integer_operand(2) as u128),
+            }),
+            Constraint::IsAligned => check(linear::MatchOp::IsAligned {
+                path: path(0),
+                align: self.integer_operand(1) as u8,
+            }),
+            Constraint::HighBitsZero => check(linear::MatchOp::HighBitsZero {
+                path: path(0),
+                from_width: self.integer_operand(1) as u8,
+            }),
+            Constraint::MemFlags => check(linear::MatchOp::MemFlagsMatch {
+                path: path(0),
+                flags_mask: self.integer_operand(1) as u8,
+                flags_value: self.integer_operand(2) as u8,
+            }),
+            Constraint::Predicate => {
+                // Predicates are numbered in the order their names first
+                // appear.
+                let name = self.predicate.expect("parsed with a predicate name");
+                let predicate = match predicates.iter().position(|p| p == name) {
+                    Some(i) => i,
+                    None => {
+                        predicates.push(name.to_string());
+                        predicates.len() - 1
+                    }
+                };
+                check(linear::MatchOp::CustomPredicate {
+                    path: path(0),
+                    predicate: predicate as u32,
+                })
+            }
         }
     }
 }
@@ -492,7 +1344,7 @@ impl Pattern<'_> {
         match self {
             Pattern::ValueLiteral(ValueLiteral::Integer(Integer { value, .. })) => (
                 linear::MatchOp::IntegerValue { path },
-                Some(integers.intern(*value as u64).into()),
+                Some(integers.intern(*value as u128).into()),
             ),
             Pattern::ValueLiteral(ValueLiteral::Boolean(Boolean { value, .. })) => {
                 (linear::MatchOp::BooleanValue { path }, Some(*value as u32))
@@ -502,9 +1354,11 @@ impl Pattern<'_> {
             }
             Pattern::Constant(Constant { id, .. }) => {
                 if let Some(path_b) = lhs_id_to_path.get_first_occurrence(id) {
+                    // Repeated constants only need the same value, not the
+                    // same `iconst` instruction.
                     debug_assert!(path != path_b);
                     (
-                        linear::MatchOp::Eq {
+                        linear::MatchOp::ConstEq {
                             path_a: path,
                             path_b,
                         },
@@ -577,13 +1431,21 @@ mod tests {
                 let mut p = |p: &[u8]| paths.intern(Path::new(&p));
 
                 let mut integers = IntegerInterner::new();
-                let mut i = |i: u64| integers.intern(i);
+                let mut i = |i: u128| integers.intern(i);
 
                 #[allow(unused_variables)]
                 let expected = $make_expected(&mut p, &mut i);
                 dbg!(&expected);
 
-                let actual = linearize_optimization(&mut paths, &mut integers, &opts.optimizations[0]);
+                let actual = linearize_optimization(
+                    &mut paths,
+                    &mut integers,
+                    &mut vec![],
+                    &mut vec![],
+                    &opts.optimizations[0],
+                    &opts.optimizations[0].rhs,
+                    &[],
+                );
                 dbg!(&actual);
 
                 assert_eq!(expected, actual);
@@ -598,7 +1460,7 @@ mod tests {
           (is-power-of-two $C))
     (ishl $x $C))
         ",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![
                     linear::Increment {
@@ -633,6 +1495,9 @@ mod tests {
                         actions: vec![],
                     },
                 ],
+                result: Some(linear::RhsId(2)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -640,13 +1505,16 @@ mod tests {
     linearizes_to!(
         variable_pattern_id_optimization,
         "(=> $x $x)",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![linear::Increment {
                     operation: Nop,
                     expected: None,
                     actions: vec![GetLhs { path: p(&[0]) }],
                 }],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -654,13 +1522,16 @@ mod tests {
     linearizes_to!(
         constant_pattern_id_optimization,
         "(=> $C $C)",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![linear::Increment {
                     operation: IsConst { path: p(&[0]) },
                     expected: Some(1),
                     actions: vec![GetLhs { path: p(&[0]) }],
                 }],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -668,7 +1539,7 @@ mod tests {
     linearizes_to!(
         boolean_literal_id_optimization,
         "(=> true true)",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![linear::Increment {
                     operation: BooleanValue { path: p(&[0]) },
@@ -678,6 +1549,9 @@ mod tests {
                         bit_width: BitWidth::Polymorphic,
                     }],
                 }],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -685,7 +1559,7 @@ mod tests {
     linearizes_to!(
         number_literal_id_optimization,
         "(=> 5 5)",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![linear::Increment {
                     operation: IntegerValue { path: p(&[0]) },
@@ -695,6 +1569,9 @@ mod tests {
                         bit_width: BitWidth::Polymorphic,
                     }],
                 }],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -702,7 +1579,7 @@ mod tests {
     linearizes_to!(
         operation_id_optimization,
         "(=> (iconst $C) (iconst $C))",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![
                     linear::Increment {
@@ -726,6 +1603,9 @@ mod tests {
                         actions: vec![],
                     },
                 ],
+                result: Some(linear::RhsId(1)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -733,7 +1613,7 @@ mod tests {
     linearizes_to!(
         redundant_bor,
         "(=> (bor $x (bor $x $y)) (bor $x $y))",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![
                     linear::Increment {
@@ -778,6 +1658,9 @@ mod tests {
                         actions: vec![],
                     },
                 ],
+                result: Some(linear::RhsId(2)),
+                doc: None,
+                location: None,
             }
         },
     );
@@ -786,31 +1669,166 @@ mod tests {
         large_integers,
         // u64::MAX
         "(=> 18446744073709551615 0)",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![linear::Increment {
                     operation: IntegerValue { path: p(&[0]) },
-                    expected: Some(i(std::u64::MAX).into()),
+                    expected: Some(i(std::u64::MAX.into()).into()),
+                    actions: vec![MakeZero {
+                        bit_width: BitWidth::Polymorphic,
+                    }],
+                }],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
+            }
+        }
+    );
+
+    linearizes_to!(
+        integers_wider_than_64_bits,
+        "(=> 0x1_0000_0000_0000_0001 0xffff_ffff_ffff_ffff_ffff_ffff_ffff_ffff)",
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
+            linear::Optimization {
+                increments: vec![linear::Increment {
+                    operation: IntegerValue { path: p(&[0]) },
+                    expected: Some(i(0x1_0000_0000_0000_0001).into()),
                     actions: vec![MakeIntegerConst {
-                        value: i(0),
+                        value: i(std::u128::MAX),
                         bit_width: BitWidth::Polymorphic,
                     }],
                 }],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
             }
         }
     );
 
+    linearizes_to!(
+        let_bound_rhs_is_built_once,
+        "(=> (iadd $x $y) (let (($t (iadd $x $y))) (imul $t $t)))",
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
+            linear::Optimization {
+                increments: vec![
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0]) },
+                        expected: Some(Operator::Iadd as _),
+                        actions: vec![
+                            GetLhs { path: p(&[0, 0]) },
+                            GetLhs { path: p(&[0, 1]) },
+                            MakeBinaryInst {
+                                operator: Operator::Iadd,
+                                r#type: Type {
+                                    kind: Kind::Int,
+                                    bit_width: BitWidth::Polymorphic,
+                                },
+                                operands: [linear::RhsId(0), linear::RhsId(1)],
+                            },
+                            MakeBinaryInst {
+                                operator: Operator::Imul,
+                                r#type: Type {
+                                    kind: Kind::Int,
+                                    bit_width: BitWidth::Polymorphic,
+                                },
+                                operands: [linear::RhsId(2), linear::RhsId(2)],
+                            },
+                        ],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                ],
+                result: Some(linear::RhsId(3)),
+                doc: None,
+                location: None,
+            }
+        },
+    );
+
+    linearizes_to!(
+        template_is_inlined,
+        "(=> (imul (iadd $x $y) -1) (template negate (iadd $x $y)))",
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
+            let int = Type {
+                kind: Kind::Int,
+                bit_width: BitWidth::Polymorphic,
+            };
+            linear::Optimization {
+                increments: vec![
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0]) },
+                        expected: Some(Operator::Imul as _),
+                        actions: vec![
+                            GetLhs {
+                                path: p(&[0, 0, 0]),
+                            },
+                            GetLhs {
+                                path: p(&[0, 0, 1]),
+                            },
+                            MakeBinaryInst {
+                                operator: Operator::Iadd,
+                                r#type: int,
+                                operands: [linear::RhsId(0), linear::RhsId(1)],
+                            },
+                            // The template's own ids are renumbered to follow
+                            // the actions before it, and its operand is the
+                            // `iadd` built above.
+                            MakeZero {
+                                bit_width: BitWidth::Polymorphic,
+                            },
+                            MakeBinaryInst {
+                                operator: Operator::Isub,
+                                r#type: int,
+                                operands: [linear::RhsId(3), linear::RhsId(2)],
+                            },
+                        ],
+                    },
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0, 0]) },
+                        expected: Some(Operator::Iadd as _),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: IntegerValue { path: p(&[0, 1]) },
+                        expected: Some(i(-1i128 as u128).into()),
+                        actions: vec![],
+                    },
+                ],
+                result: Some(linear::RhsId(4)),
+                doc: None,
+                location: None,
+            }
+        },
+    );
+
     linearizes_to!(
         ireduce_with_type_ascription,
         "(=> (ireduce{i32} $x) 0)",
-        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u64) -> IntegerId| {
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
             linear::Optimization {
                 increments: vec![
                     linear::Increment {
                         operation: Opcode { path: p(&[0]) },
                         expected: Some(Operator::Ireduce as _),
-                        actions: vec![MakeIntegerConst {
-                            value: i(0),
+                        actions: vec![MakeZero {
                             bit_width: BitWidth::ThirtyTwo,
                         }],
                     },
@@ -825,7 +1843,495 @@ mod tests {
                         actions: vec![],
                     },
                 ],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
+            }
+        }
+    );
+
+    linearizes_to!(
+        extract_lane_of_insert_lane,
+        "(=> (extractlane 1 (insertlane 0 $v $x)) (extractlane 1 $v))",
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
+            linear::Optimization {
+                increments: vec![
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0]) },
+                        expected: Some(Operator::Extractlane as _),
+                        actions: vec![
+                            GetLhs {
+                                path: p(&[0, 1, 1]),
+                            },
+                            MakeExtractLane {
+                                operand: linear::RhsId(0),
+                                lane: 1,
+                            },
+                        ],
+                    },
+                    linear::Increment {
+                        operation: IntegerValue { path: p(&[0, 0]) },
+                        expected: Some(i(1).into()),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0, 1]) },
+                        expected: Some(Operator::Insertlane as _),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: IntegerValue {
+                            path: p(&[0, 1, 0]),
+                        },
+                        expected: Some(i(0).into()),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                ],
+                result: Some(linear::RhsId(1)),
+                doc: None,
+                location: None,
             }
         }
     );
+
+    linearizes_to!(
+        shared_type_variable,
+        "(=> (iadd (bor{$T} $a $b) (band{$T} $c $d)) 0)",
+        |p: &mut dyn FnMut(&[u8]) -> PathId, i: &mut dyn FnMut(u128) -> IntegerId| {
+            linear::Optimization {
+                increments: vec![
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0]) },
+                        expected: Some(Operator::Iadd as _),
+                        actions: vec![MakeZero {
+                            bit_width: BitWidth::Polymorphic,
+                        }],
+                    },
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0, 0]) },
+                        expected: Some(Operator::Bor as _),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Opcode { path: p(&[0, 1]) },
+                        expected: Some(Operator::Band as _),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: SameWidth {
+                            path_a: p(&[0, 0]),
+                            path_b: p(&[0, 1]),
+                        },
+                        expected: Some(1),
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                    linear::Increment {
+                        operation: Nop,
+                        expected: None,
+                        actions: vec![],
+                    },
+                ],
+                result: Some(linear::RhsId(0)),
+                doc: None,
+                location: None,
+            }
+        }
+    );
+
+    fn linearize_str(source: &str) -> linear::Optimizations {
+        let buf = wast::parser::ParseBuffer::new(source).expect("should lex OK");
+        let mut opts = wast::parser::parse::<Optimizations>(&buf).expect("should parse OK");
+        crate::attach_doc_comments(source, &mut opts);
+        crate::verify(&opts).expect("should verify OK");
+        linearize(&opts)
+    }
+
+    #[test]
+    fn gc_integers() {
+        let mut opts = linearize_str(
+            "
+(=> (iadd $x 0) $x)
+(=> (imul $x 1) $x)
+(=> (isub $x 2) 3)
+",
+        );
+        assert!(opts.integers.already_interned(1u64).is_some());
+
+        // Remove the `imul` optimization, orphaning the `1` constant.
+        opts.optimizations.remove(1);
+        opts.gc_integers();
+
+        assert!(opts.integers.already_interned(1u64).is_none());
+
+        let referenced = opts.referenced_integers();
+        assert_eq!(referenced.len(), 3);
+        let mut values: Vec<_> = referenced
+            .into_iter()
+            .map(|id| {
+                assert!(id.0 < 3, "ids should be compacted");
+                opts.integers.lookup(id)
+            })
+            .collect();
+        values.sort();
+        assert_eq!(values, vec![0, 2, 3]);
+
+        let isub = &opts.optimizations[1];
+        let two = opts.integers.already_interned(2u64).unwrap();
+        assert!(isub.increments.iter().any(|inc| {
+            matches!(inc.operation, IntegerValue { .. }) && inc.expected == Some(two.into())
+        }));
+        let three = opts.integers.already_interned(3u64).unwrap();
+        assert!(isub.increments.iter().any(|inc| {
+            inc.actions.iter().any(|a| match a {
+                MakeIntegerConst { value, .. } => *value == three,
+                _ => false,
+            })
+        }));
+    }
+
+    #[test]
+    fn gc_integers_in_sets() {
+        let mut opts = linearize_str(
+            "
+(=> (imul $x 1) $x)
+(=> (when (imul $x $C) (in-set $C 2 4 8)) (imul_imm $C $x))
+",
+        );
+
+        // Remove the `imul` by one optimization, orphaning the `1` constant.
+        opts.optimizations.remove(0);
+        opts.gc_integers();
+        assert!(opts.integers.already_interned(1u64).is_none());
+
+        let set = opts.optimizations[0]
+            .increments
+            .iter()
+            .find_map(|inc| match inc.operation {
+                IntegerInSet { set, .. } => Some(set),
+                _ => None,
+            })
+            .unwrap();
+        let mut values: Vec<_> = opts
+            .integers
+            .lookup_set(set)
+            .iter()
+            .map(|id| opts.integers.lookup(*id))
+            .collect();
+        values.sort();
+        assert_eq!(values, vec![2, 4, 8]);
+    }
+
+    #[test]
+    fn max_rhs_instructions() {
+        let opts = linearize_str(
+            "
+(=> (iadd $x 0) $x)
+(=> (imul $x 2) (ishl $x 1))
+(=> (isub $x $y) (iadd $x (bnot (iadd $y 0))))
+(=> (bnot $x) 5)
+",
+        );
+        assert_eq!(opts.max_rhs_instructions(), 3);
+
+        assert_eq!(
+            linearize_str("(=> (iadd $x 0) $x)").max_rhs_instructions(),
+            0
+        );
+    }
+
+    #[test]
+    fn gc_paths() {
+        let mut opts = linearize_str(
+            "
+(=> (bor (band $x $y) (bnot $z)) 0)
+(=> (iadd $x 0) $x)
+",
+        );
+
+        // Remove the `bor` optimization, orphaning its deeper paths.
+        opts.optimizations.remove(0);
+
+        let lookup_all = |opts: &linear::Optimizations| {
+            let mut paths: Vec<Vec<u8>> = opts
+                .referenced_paths()
+                .into_iter()
+                .map(|id| opts.paths.lookup(id).0.to_vec())
+                .collect();
+            paths.sort();
+            paths
+        };
+        let before = lookup_all(&opts);
+        let before_increments: Vec<_> = opts.optimizations[0]
+            .increments
+            .iter()
+            .map(|inc| inc.expected)
+            .collect();
+
+        opts.gc_paths();
+
+        let referenced = opts.referenced_paths();
+        assert!(referenced
+            .iter()
+            .all(|id| (id.0 as usize) < referenced.len()));
+        assert_eq!(before, lookup_all(&opts));
+        assert_eq!(before, vec![vec![0], vec![0, 0], vec![0, 1]]);
+
+        let root = opts.optimizations[0]
+            .increments
+            .iter()
+            .find_map(|inc| match inc.operation {
+                Opcode { path } => Some(path),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(opts.paths.lookup(root), Path::new(&[0]));
+        assert_eq!(
+            before_increments,
+            opts.optimizations[0]
+                .increments
+                .iter()
+                .map(|inc| inc.expected)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn filter_by_tag() {
+        let source = "
+(=> (phase lowering) (iadd $x $C) (iadd_imm $C $x))
+(=> (iadd $x 0) $x)
+(=> (phase canonicalize) (imul $x 7) (imul $x 8))
+(=> (phase lowering) (when (imul $x $C) (in-set $C 2 4)) (imul_imm $C $x))
+";
+        let opts = crate::compile_to_linear(source, std::path::Path::new("filter")).unwrap();
+        let lowering = opts.filter_by_tag(linear::Phase::Lowering);
+
+        let operators = lowering.root_operators();
+        assert_eq!(operators.len(), 2);
+        assert!(operators.contains(&Some(Operator::Iadd)));
+        assert!(operators.contains(&Some(Operator::Imul)));
+        assert!(lowering
+            .optimizations
+            .iter()
+            .all(|opt| opt.increments.iter().any(|inc| inc.operation
+                == InPhase {
+                    phase: linear::Phase::Lowering
+                })));
+
+        // Only the integers the lowering optimizations use are kept.
+        assert!(lowering.integers.already_interned(7u64).is_none());
+        assert!(lowering.integers.already_interned(0u64).is_none());
+        assert!(lowering.integers.already_interned(4u64).is_some());
+
+        // The filtered set is still valid input for building an automaton.
+        crate::automatize(&lowering).unwrap();
+    }
+
+    #[test]
+    fn root_opcode() {
+        let mut opts = linearize_str(
+            "
+(=> (iadd $x 0) $x)
+(=> (when (imul $x $C) (is-power-of-two $C)) (ishl $x $(log2 $C)))
+(=> (phase lowering) (iadd $x $C) (iadd_imm $C $x))
+(=> (bor (band $x $y) $x) $x)
+(=> $x $x)
+",
+        );
+        let opcodes: Vec<_> = opts
+            .optimizations
+            .iter()
+            .map(|opt| opt.root_opcode())
+            .collect();
+        assert_eq!(
+            opcodes,
+            vec![
+                Some(Operator::Iadd as u32),
+                Some(Operator::Imul as u32),
+                Some(Operator::Iadd as u32),
+                Some(Operator::Bor as u32),
+                None,
+            ]
+        );
+
+        // The root's opcode switch doesn't have to come first, and switches on
+        // the root's operands don't count.
+        let mut reordered = opts.optimizations[0].clone();
+        let operand = opts.paths.intern(Path::new(&[0, 0]));
+        reordered.increments.insert(
+            0,
+            linear::Increment {
+                operation: Opcode { path: operand },
+                expected: Some(Operator::Imul as u32),
+                actions: vec![],
+            },
+        );
+        reordered.increments.insert(
+            0,
+            linear::Increment {
+                operation: IsEnabled,
+                expected: Some(1),
+                actions: vec![],
+            },
+        );
+        assert_eq!(reordered.root_opcode(), Some(Operator::Iadd as u32));
+        opts.optimizations.push(reordered);
+        assert_eq!(opts.root_operators().last(), Some(&Some(Operator::Iadd)));
+    }
+
+    #[test]
+    fn swapped_icmps_are_bounded() {
+        // Each ordering comparison doubles the optimizations, up to a limit.
+        let opts = linearize_str("(=> (bor (icmp slt $a $b) (icmp ult $c $d)) true)");
+        assert_eq!(opts.optimizations.len(), 4);
+        let opts = linearize_str(
+            "
+(=> (bor (bor (icmp slt $a $b) (icmp slt $c $d))
+         (bor (icmp slt $e $f) (icmp slt $g $h)))
+    true)
+",
+        );
+        assert_eq!(opts.optimizations.len(), 1 << MAX_SWAPPED_ICMPS);
+
+        // Right-hand sides that build an `icmp`, at any depth, aren't expanded.
+        let opts = linearize_str("(=> (bnot (icmp slt $a $b)) (icmp sge $a $b))");
+        assert_eq!(opts.optimizations.len(), 1);
+        let opts = linearize_str("(=> (bint (bnot (icmp slt $a $b))) (bint (icmp sge $a $b)))");
+        assert_eq!(opts.optimizations.len(), 1);
+    }
+
+    #[test]
+    fn doc_comments_are_carried_through() {
+        let opts = linearize_str(
+            "
+;; Adding zero is a no-op.
+(=> (iadd $x 0) $x)
+
+(=> (imul $x 1) $x)
+",
+        );
+        let docs: Vec<_> = opts
+            .optimizations
+            .iter()
+            .map(|o| o.doc.as_deref())
+            .collect();
+        assert_eq!(docs, vec![Some("Adding zero is a no-op."), None]);
+    }
+
+    #[test]
+    fn optimization_results() {
+        let opts = linearize_str(
+            "
+(=> (iadd $x 0) $x)
+(=> (iadd $x $y) (iadd $y $x))
+(=> (iadd $x $y) (delete))
+(=> (iadd_cout $x 0) (results $x false))
+",
+        );
+        let results: Vec<_> = opts.optimizations.iter().map(|o| o.result).collect();
+        // The forwarding optimization's result is its one `GetLhs`, and the
+        // second optimization's result is the `iadd` built after getting `$y`
+        // and `$x`. Deleting the root, or replacing each of its results, has no
+        // single result, even though both define an action.
+        assert_eq!(
+            results,
+            vec![Some(linear::RhsId(0)), Some(linear::RhsId(2)), None, None]
+        );
+    }
+
+    fn check_rhs_depth_str(source: &str, max_depth: usize) -> wast::Result<()> {
+        let buf = wast::parser::ParseBuffer::new(source).expect("should lex OK");
+        let opts = wast::parser::parse::<Optimizations>(&buf).expect("should parse OK");
+        check_rhs_depth(&opts, max_depth)
+    }
+
+    #[test]
+    fn rhs_depth_at_limit() {
+        check_rhs_depth_str("(=> (iadd $x $y) (iadd (iadd $x $y) $y))", 3).unwrap();
+        check_rhs_depth_str("(=> (iadd $x $y) $x)", 1).unwrap();
+    }
+
+    fn check_operand_arity_str(source: &str) -> wast::Result<()> {
+        let buf = wast::parser::ParseBuffer::new(source).expect("should lex OK");
+        let opts = wast::parser::parse::<Optimizations>(&buf).expect("should parse OK");
+        check_operand_arity(&opts)
+    }
+
+    #[test]
+    fn operand_within_arity() {
+        check_operand_arity_str("(=> (iadd $x (imul $y $z)) $x)").unwrap();
+        check_operand_arity_str("(=> (when (select $c $x $y) (is-const $c)) $x)").unwrap();
+        check_operand_arity_str("(=> ((one-of (opcode iadd) (opcode imul)) $x 0) $x)").unwrap();
+    }
+
+    #[test]
+    fn operand_beyond_arity() {
+        let err = check_operand_arity_str("(=> (iadd $x $y $z) $x)").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`iadd` has 2 operand(s), so there is no operand 3 to match"));
+
+        let err = check_operand_arity_str("(=> (bor $x (bnot $y $z)) $x)").unwrap_err();
+        assert!(err.to_string().contains("no operand 2 to match"));
+    }
+
+    fn check_type_variables_str(source: &str) -> wast::Result<()> {
+        let buf = wast::parser::ParseBuffer::new(source).expect("should lex OK");
+        let opts = wast::parser::parse::<Optimizations>(&buf).expect("should parse OK");
+        check_type_variables(&opts)
+    }
+
+    #[test]
+    fn type_variables_bound() {
+        check_type_variables_str("(=> (iadd (bor{$T} $x $y) (band{$T} $x $y)) $x)").unwrap();
+        check_type_variables_str("(=> (iadd{$T} $x $y) (isub{$T} $x (ineg $y)))").unwrap();
+    }
+
+    #[test]
+    fn type_variable_not_bound() {
+        let err = check_type_variables_str("(=> (iadd $x $y) (iadd{$T} $y $x))").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("type variable `$T` is not bound in the left-hand side"));
+    }
+
+    #[test]
+    fn type_variable_used_as_value() {
+        let err = check_type_variables_str("(=> (iadd{$x} $x $y) $x)").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`$x` is used as both a type variable and a value"));
+    }
+
+    #[test]
+    fn rhs_depth_over_limit() {
+        let err = check_rhs_depth_str("(=> (iadd $x $y) (iadd (iadd (iadd $x $y) $y) $y))", 3)
+            .unwrap_err();
+        assert!(err.to_string().contains("nested more than 3 levels deep"));
+    }
 }
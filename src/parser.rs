@@ -8,7 +8,9 @@ The grammar for the DSL is given below:
 ```ebnf
 <optimizations> ::= <optimization>*
 
-<optimization> ::= '(' '=>' <lhs> <rhs> ')'
+<optimization> ::= '(' '=>' [<phase>] ['(' 'disabled' ')'] <lhs> <rhs> ')'
+
+<phase> ::= '(' 'phase' ('canonicalize' | 'lowering') ')'
 
 <left-hand-side> ::= <pattern>
                    | '(' 'when' <pattern> <precondition>* ')'
@@ -23,9 +25,20 @@ The grammar for the DSL is given below:
 
 <boolean> ::= 'true' | 'false'
 
-<operation<T>> ::= '(' <operator> [<type-ascription>] <T>* ')'
+<operation<T>> ::= '(' <operator-or-one-of> [<type-ascription>] <mem-arg>* <T>* ')'
+
+<type-ascription> ::= '{' (<type> | <type-variable>) '}'
+
+<type-variable> ::= '$' <identifier>
+
+<mem-arg> ::= 'offset=' <integer>
+            | 'flags=' <integer>
+
+<operator-or-one-of> ::= <operator>
+                       | '(' 'one-of' ('(' 'opcode' <operator> ')')+ ')'
 
 <precondition> ::= '(' <constraint> <constraint-operands>* ')'
+                 | '(' 'predicate' <predicate-name> <constraint-operand> ')'
 
 <constraint-operand> ::= <value-literal>
                        | <constant>
@@ -35,8 +48,42 @@ The grammar for the DSL is given below:
         | <constant>
         | <variable>
         | <unquote>
+        | <let>
+        | <iconst-like>
+        | <fold>
+        | <delete>
+        | <expand-iabs>
+        | <call>
+        | <template>
+        | <stored-value>
+        | <cond>
+        | <results>
         | <operation<rhs>>
 
+<let> ::= '(' 'let' '(' <let-binding>* ')' <rhs> ')'
+
+<let-binding> ::= '(' <variable> <rhs> ')'
+
+<iconst-like> ::= '(' 'iconst-like' <variable> <integer> ')'
+
+<fold> ::= '(' 'fold' <variable> ')'
+
+<delete> ::= '(' 'delete' ')'
+
+<expand-iabs> ::= '(' 'expand-iabs' <rhs> ')'
+
+<call> ::= '(' 'call' <function-name> <rhs>* ')'
+
+<template> ::= '(' 'template' <template-name> <rhs>* ')'
+
+<stored-value> ::= '(' 'stored-value' <variable> ')'
+
+<cond> ::= '(' 'cond' <cond-arm>* <rhs> ')'
+
+<cond-arm> ::= '(' <precondition> <rhs> ')'
+
+<results> ::= '(' 'results' <rhs>* ')'
+
 <unquote> ::= '$' '(' <unquote-operator> <unquote-operand>* ')'
 
 <unquote-operand> ::= <value-literal>
@@ -46,8 +93,13 @@ The grammar for the DSL is given below:
  */
 
 use crate::ast::*;
-use peepmatic_runtime::r#type::Type;
+use peepmatic_runtime::{
+    linear::{Phase, SourceLocation},
+    operator::Operator,
+    r#type::Type,
+};
 use std::cell::Cell;
+use std::convert::TryFrom;
 use std::marker::PhantomData;
 use wast::{
     parser::{Cursor, Parse, Parser, Peek, Result as ParseResult},
@@ -57,16 +109,69 @@ use wast::{
 mod tok {
     use wast::{custom_keyword, custom_reserved};
 
+    custom_keyword!(all_operands_const = "all-operands-const");
     custom_keyword!(bit_width = "bit-width");
+    custom_keyword!(block_predecessors = "block-predecessors");
+    custom_keyword!(bounded_by = "bounded-by");
+    custom_keyword!(call);
+    custom_keyword!(canonicalize);
+    custom_keyword!(ccs_combine = "ccs-combine");
+    custom_keyword!(cond);
     custom_reserved!(dollar = "$");
+    custom_keyword!(delete);
+    custom_keyword!(disabled);
+    custom_keyword!(equivalent_values = "equivalent-values");
+    custom_keyword!(expand_iabs = "expand-iabs");
     custom_keyword!(r#false = "false");
+    custom_keyword!(r#let = "let");
+    custom_keyword!(fits_in_imm12 = "fits-in-imm12");
+    custom_keyword!(fits_in_imm32 = "fits-in-imm32");
     custom_keyword!(fits_in_native_word = "fits-in-native-word");
+    custom_keyword!(fold);
+    custom_keyword!(high_bits_zero = "high-bits-zero");
+    custom_keyword!(icmp_zero = "icmp-zero");
+    custom_keyword!(iconst_like = "iconst-like");
+    custom_keyword!(in_set = "in-set");
+    custom_keyword!(is_add_of_neg_const = "is-add-of-neg-const");
+    custom_keyword!(is_aligned = "is-aligned");
+    custom_keyword!(is_byte_mask = "is-byte-mask");
+    custom_keyword!(is_call_result = "is-call-result");
+    custom_keyword!(is_double_involution = "is-double-involution");
+    custom_keyword!(is_encodable_logical_imm = "is-encodable-logical-imm");
+    custom_keyword!(is_mask_const = "is-mask-const");
+    custom_keyword!(is_one = "is-one");
+    custom_keyword!(is_one_less_than = "is-one-less-than");
     custom_keyword!(is_power_of_two = "is-power-of-two");
+    custom_keyword!(is_scaled_by = "is-scaled-by");
+    custom_keyword!(is_sextend = "is-sextend");
+    custom_keyword!(is_shift_by_const = "is-shift-by-const");
+    custom_keyword!(is_shifted_imm = "is-shifted-imm");
+    custom_keyword!(is_shifted_imm8 = "is-shifted-imm8");
+    custom_keyword!(is_uextend = "is-uextend");
+    custom_keyword!(is_width_minus = "is-width-minus");
+    custom_keyword!(known_non_negative = "known-non-negative");
     custom_reserved!(left_curly = "{");
     custom_keyword!(log2);
+    custom_keyword!(lowering);
+    custom_keyword!(mem_flags = "mem-flags");
     custom_keyword!(neg);
+    custom_keyword!(negation_fits_in_bits = "negation-fits-in-bits");
+    custom_keyword!(one_of = "one-of");
+    custom_keyword!(opcode);
+    custom_keyword!(phase);
+    custom_keyword!(popcount_eq = "popcount-eq");
+    custom_keyword!(popcount_le = "popcount-le");
+    custom_keyword!(predicate);
+    custom_keyword!(redundant_mask_for_shift = "redundant-mask-for-shift");
     custom_reserved!(replace = "=>");
+    custom_keyword!(results);
     custom_reserved!(right_curly = "}");
+    custom_keyword!(select_const_arms = "select-const-arms");
+    custom_keyword!(shifts_combine = "shifts-combine");
+    custom_keyword!(single_use = "single-use");
+    custom_keyword!(stored_value = "stored-value");
+    custom_keyword!(template);
+    custom_keyword!(trailing_zeros = "trailing-zeros");
     custom_keyword!(r#true = "true");
     custom_keyword!(when);
 
@@ -103,18 +208,52 @@ impl<'a> Parse<'a> for Optimization<'a> {
         let span = p.cur_span();
         p.parens(|p| {
             p.parse::<tok::replace>()?;
+            let phase = if p.peek::<LParen>() && p.peek2::<tok::phase>() {
+                Some(p.parens(|p| {
+                    p.parse::<tok::phase>()?;
+                    parse_phase(p)
+                })?)
+            } else {
+                None
+            };
+            let enabled = if p.peek::<LParen>() && p.peek2::<tok::disabled>() {
+                p.parens(|p| p.parse::<tok::disabled>())?;
+                false
+            } else {
+                true
+            };
             let lhs = p.parse()?;
             let rhs = p.parse()?;
-            Ok(Optimization { span, lhs, rhs })
+            Ok(Optimization {
+                span,
+                doc: None,
+                location: None,
+                phase,
+                enabled,
+                lhs,
+                rhs,
+            })
         })
     }
 }
 
+fn parse_phase(p: Parser) -> ParseResult<Phase> {
+    if p.peek::<tok::canonicalize>() {
+        p.parse::<tok::canonicalize>()?;
+        return Ok(Phase::Canonicalize);
+    }
+    if p.peek::<tok::lowering>() {
+        p.parse::<tok::lowering>()?;
+        return Ok(Phase::Lowering);
+    }
+    Err(p.error("expected a phase: `canonicalize` or `lowering`"))
+}
+
 impl<'a> Parse<'a> for Lhs<'a> {
     fn parse(p: Parser<'a>) -> ParseResult<Self> {
         let span = p.cur_span();
         let mut preconditions = vec![];
-        if p.peek::<wast::LParen>() && p.peek2::<tok::when>() {
+        if p.peek::<LParen>() && p.peek2::<tok::when>() {
             p.parens(|p| {
                 p.parse::<tok::when>()?;
                 let pattern = p.parse()?;
@@ -201,8 +340,8 @@ impl<'a> Parse<'a> for Integer<'a> {
         p.step(|c| {
             if let Some((i, rest)) = c.integer() {
                 let (s, base) = i.val();
-                let val = i64::from_str_radix(s, base)
-                    .or_else(|_| u128::from_str_radix(s, base).map(|i| i as i64));
+                let val = i128::from_str_radix(s, base)
+                    .or_else(|_| u128::from_str_radix(s, base).map(|i| i as i128));
                 return match val {
                     Ok(value) => Ok((
                         Integer {
@@ -405,16 +544,26 @@ where
     fn parse(p: Parser<'a>) -> ParseResult<Self> {
         let span = p.cur_span();
         p.parens(|p| {
-            let operator = p.parse()?;
+            let (operator, alternatives) = if p.peek::<LParen>() {
+                parse_one_of(p)?
+            } else {
+                (parse_operator(p)?, vec![])
+            };
 
-            let r#type = Cell::new(if p.peek::<tok::left_curly>() {
+            let mut r#type = None;
+            let mut type_variable = None;
+            if p.peek::<tok::left_curly>() {
                 p.parse::<tok::left_curly>()?;
-                let ty = p.parse::<Type>()?;
+                if p.peek::<Id>() {
+                    type_variable = Some(p.parse::<Id>()?);
+                } else {
+                    r#type = Some(p.parse::<Type>()?);
+                }
                 p.parse::<tok::right_curly>()?;
-                Some(ty)
-            } else {
-                None
-            });
+            }
+            let r#type = Cell::new(r#type);
+
+            let (mem_offset, mem_flags) = parse_mem_args(p)?;
 
             let mut operands = vec![];
             while p.peek::<T>() {
@@ -423,7 +572,11 @@ where
             Ok(Operation {
                 span,
                 operator,
+                alternatives,
                 r#type,
+                type_variable,
+                mem_offset,
+                mem_flags,
                 operands,
                 marker: PhantomData,
             })
@@ -431,6 +584,138 @@ where
     }
 }
 
+/// Attach each optimization's preceding comment lines to it as its `doc`.
+///
+/// The `wast` lexer discards comments, so this is done as a separate pass over
+/// the source text that the optimizations were parsed from. Only an unbroken
+/// run of `;;` lines directly above an optimization is attached: a blank line
+/// or a line with code on it ends the doc comment.
+pub fn attach_doc_comments(source: &str, opts: &mut Optimizations) {
+    for opt in &mut opts.optimizations {
+        let before = &source[..opt.span.offset()];
+        let mut lines = before.lines().rev();
+
+        // Skip any indentation on the optimization's own line, but don't
+        // attach comments to an optimization that doesn't start its line.
+        if !before.ends_with('\n') {
+            match lines.next() {
+                Some(line) if line.trim().is_empty() => {}
+                Some(_) => continue,
+                None => {}
+            }
+        }
+
+        let mut doc: Vec<&str> = lines
+            .map(|line| line.trim())
+            .take_while(|line| line.starts_with(';'))
+            .map(|line| {
+                let line = line.trim_start_matches(';');
+                line.strip_prefix(' ').unwrap_or(line)
+            })
+            .collect();
+
+        if !doc.is_empty() {
+            doc.reverse();
+            opt.doc = Some(doc.join("\n"));
+        }
+    }
+}
+
+/// Record where each optimization starts in the source text that it was parsed
+/// from, as its `location`.
+pub fn attach_source_locations(source: &str, opts: &mut Optimizations) {
+    for opt in &mut opts.optimizations {
+        let before = &source[..opt.span.offset()];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        opt.location = Some(SourceLocation {
+            line: before.matches('\n').count() as u32 + 1,
+            column: before[line_start..].chars().count() as u32 + 1,
+        });
+    }
+}
+
+/// Parse the optional `offset=<n>` and `flags=<n>` memory arguments of a
+/// `load` or `store`.
+fn parse_mem_args<'a>(p: Parser<'a>) -> ParseResult<(Option<i32>, Option<u8>)> {
+    let mut offset = None;
+    let mut flags = None;
+    loop {
+        let arg = p.step(|c| {
+            if let Some((kw, rest)) = c.keyword() {
+                if kw.starts_with("offset=") || kw.starts_with("flags=") {
+                    return Ok((Some(kw), rest));
+                }
+            }
+            Ok((None, c))
+        })?;
+
+        let arg = match arg {
+            Some(arg) => arg,
+            None => return Ok((offset, flags)),
+        };
+        if let Some(n) = arg.strip_prefix("offset=") {
+            if offset.is_some() {
+                return Err(p.error("duplicate `offset=` memory argument"));
+            }
+            let n = parse_mem_arg_int(n)
+                .and_then(|n| i32::try_from(n).ok())
+                .ok_or_else(|| p.error("invalid `offset=` memory argument"))?;
+            offset = Some(n);
+        } else if let Some(n) = arg.strip_prefix("flags=") {
+            if flags.is_some() {
+                return Err(p.error("duplicate `flags=` memory argument"));
+            }
+            let n = parse_mem_arg_int(n)
+                .and_then(|n| u8::try_from(n).ok())
+                .ok_or_else(|| p.error("invalid `flags=` memory argument"))?;
+            flags = Some(n);
+        }
+    }
+}
+
+fn parse_mem_arg_int(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { -n } else { n })
+}
+
+/// Parse a `(one-of (opcode <operator>)+)` set of operators, returning the
+/// first operator and the rest of the alternatives.
+/// Parse an operator, reporting an unknown operator (such as the typo `iaddd`)
+/// by name at its location.
+fn parse_operator<'a>(p: Parser<'a>) -> ParseResult<Operator> {
+    p.parse::<Operator>().or_else(|e| {
+        p.step(|c| match c.keyword() {
+            Some((kw, _)) => Err(c.error(format!("unknown operator `{}`", kw))),
+            None => Err(e),
+        })
+    })
+}
+
+fn parse_one_of<'a>(p: Parser<'a>) -> ParseResult<(Operator, Vec<Operator>)> {
+    p.parens(|p| {
+        p.parse::<tok::one_of>()?;
+        let mut operators = vec![];
+        while p.peek::<LParen>() {
+            operators.push(p.parens(|p| {
+                p.parse::<tok::opcode>()?;
+                parse_operator(p)
+            })?);
+        }
+        if operators.is_empty() {
+            return Err(p.error("expected at least one `(opcode ...)` in `one-of`"));
+        }
+        let operator = operators.remove(0);
+        Ok((operator, operators))
+    })
+}
+
 impl<'a, T> Peek for Operation<'a, T>
 where
     T: 'a + Ast<'a>,
@@ -450,6 +735,14 @@ impl<'a> Parse<'a> for Precondition<'a> {
         let span = p.cur_span();
         p.parens(|p| {
             let constraint = p.parse()?;
+            let predicate = if constraint == Constraint::Predicate {
+                Some(p.step(|c| match c.keyword() {
+                    Some((name, rest)) => Ok((name, rest)),
+                    None => Err(c.error("expected a predicate name")),
+                })?)
+            } else {
+                None
+            };
             let mut operands = vec![];
             while p.peek::<ConstraintOperand>() {
                 operands.push(p.parse()?);
@@ -458,6 +751,7 @@ impl<'a> Parse<'a> for Precondition<'a> {
                 span,
                 constraint,
                 operands,
+                predicate,
             })
         })
     }
@@ -469,14 +763,162 @@ impl<'a> Parse<'a> for Constraint {
             p.parse::<tok::is_power_of_two>()?;
             return Ok(Constraint::IsPowerOfTwo);
         }
+        if p.peek::<tok::is_byte_mask>() {
+            p.parse::<tok::is_byte_mask>()?;
+            return Ok(Constraint::IsByteMask);
+        }
+        if p.peek::<tok::is_mask_const>() {
+            p.parse::<tok::is_mask_const>()?;
+            return Ok(Constraint::IsMaskConst);
+        }
+        if p.peek::<tok::is_shift_by_const>() {
+            p.parse::<tok::is_shift_by_const>()?;
+            return Ok(Constraint::IsShiftByConst);
+        }
+        if p.peek::<tok::is_call_result>() {
+            p.parse::<tok::is_call_result>()?;
+            return Ok(Constraint::IsCallResult);
+        }
+        if p.peek::<tok::all_operands_const>() {
+            p.parse::<tok::all_operands_const>()?;
+            return Ok(Constraint::AllOperandsConst);
+        }
+        if p.peek::<tok::select_const_arms>() {
+            p.parse::<tok::select_const_arms>()?;
+            return Ok(Constraint::SelectConstArms);
+        }
+        if p.peek::<tok::single_use>() {
+            p.parse::<tok::single_use>()?;
+            return Ok(Constraint::SingleUse);
+        }
+        if p.peek::<tok::is_sextend>() {
+            p.parse::<tok::is_sextend>()?;
+            return Ok(Constraint::IsSextend);
+        }
+        if p.peek::<tok::is_uextend>() {
+            p.parse::<tok::is_uextend>()?;
+            return Ok(Constraint::IsUextend);
+        }
+        if p.peek::<tok::popcount_eq>() {
+            p.parse::<tok::popcount_eq>()?;
+            return Ok(Constraint::PopcountEq);
+        }
+        if p.peek::<tok::popcount_le>() {
+            p.parse::<tok::popcount_le>()?;
+            return Ok(Constraint::PopcountLe);
+        }
         if p.peek::<tok::bit_width>() {
             p.parse::<tok::bit_width>()?;
             return Ok(Constraint::BitWidth);
         }
+        if p.peek::<tok::block_predecessors>() {
+            p.parse::<tok::block_predecessors>()?;
+            return Ok(Constraint::BlockPredecessors);
+        }
+        if p.peek::<tok::negation_fits_in_bits>() {
+            p.parse::<tok::negation_fits_in_bits>()?;
+            return Ok(Constraint::NegationFitsInBits);
+        }
+        if p.peek::<tok::is_width_minus>() {
+            p.parse::<tok::is_width_minus>()?;
+            return Ok(Constraint::IsWidthMinus);
+        }
         if p.peek::<tok::fits_in_native_word>() {
             p.parse::<tok::fits_in_native_word>()?;
             return Ok(Constraint::FitsInNativeWord);
         }
+        if p.peek::<tok::equivalent_values>() {
+            p.parse::<tok::equivalent_values>()?;
+            return Ok(Constraint::EquivalentValues);
+        }
+        if p.peek::<tok::shifts_combine>() {
+            p.parse::<tok::shifts_combine>()?;
+            return Ok(Constraint::ShiftsCombine);
+        }
+        if p.peek::<tok::ccs_combine>() {
+            p.parse::<tok::ccs_combine>()?;
+            return Ok(Constraint::CcsCombine);
+        }
+        if p.peek::<tok::is_one_less_than>() {
+            p.parse::<tok::is_one_less_than>()?;
+            return Ok(Constraint::IsOneLessThan);
+        }
+        if p.peek::<tok::is_scaled_by>() {
+            p.parse::<tok::is_scaled_by>()?;
+            return Ok(Constraint::IsScaledBy);
+        }
+        if p.peek::<tok::in_set>() {
+            p.parse::<tok::in_set>()?;
+            return Ok(Constraint::InSet);
+        }
+        if p.peek::<tok::bounded_by>() {
+            p.parse::<tok::bounded_by>()?;
+            return Ok(Constraint::BoundedBy);
+        }
+        if p.peek::<tok::is_aligned>() {
+            p.parse::<tok::is_aligned>()?;
+            return Ok(Constraint::IsAligned);
+        }
+        if p.peek::<tok::high_bits_zero>() {
+            p.parse::<tok::high_bits_zero>()?;
+            return Ok(Constraint::HighBitsZero);
+        }
+        if p.peek::<tok::is_double_involution>() {
+            p.parse::<tok::is_double_involution>()?;
+            return Ok(Constraint::IsDoubleInvolution);
+        }
+        if p.peek::<tok::icmp_zero>() {
+            p.parse::<tok::icmp_zero>()?;
+            return Ok(Constraint::IcmpZero);
+        }
+        if p.peek::<tok::trailing_zeros>() {
+            p.parse::<tok::trailing_zeros>()?;
+            return Ok(Constraint::TrailingZeros);
+        }
+        if p.peek::<tok::fits_in_imm12>() {
+            p.parse::<tok::fits_in_imm12>()?;
+            return Ok(Constraint::FitsInImm12);
+        }
+        if p.peek::<tok::fits_in_imm32>() {
+            p.parse::<tok::fits_in_imm32>()?;
+            return Ok(Constraint::FitsInImm32);
+        }
+        if p.peek::<tok::is_one>() {
+            p.parse::<tok::is_one>()?;
+            return Ok(Constraint::IsOne);
+        }
+        if p.peek::<tok::known_non_negative>() {
+            p.parse::<tok::known_non_negative>()?;
+            return Ok(Constraint::KnownNonNegative);
+        }
+        if p.peek::<tok::redundant_mask_for_shift>() {
+            p.parse::<tok::redundant_mask_for_shift>()?;
+            return Ok(Constraint::RedundantMaskForShift);
+        }
+        if p.peek::<tok::is_encodable_logical_imm>() {
+            p.parse::<tok::is_encodable_logical_imm>()?;
+            return Ok(Constraint::IsEncodableLogicalImm);
+        }
+        if p.peek::<tok::is_add_of_neg_const>() {
+            p.parse::<tok::is_add_of_neg_const>()?;
+            return Ok(Constraint::IsAddOfNegConst);
+        }
+        if p.peek::<tok::is_shifted_imm>() {
+            p.parse::<tok::is_shifted_imm>()?;
+            return Ok(Constraint::IsShiftedImm);
+        }
+        if p.peek::<tok::is_shifted_imm8>() {
+            p.parse::<tok::is_shifted_imm8>()?;
+            return Ok(Constraint::IsShiftedImm8);
+        }
+        if p.peek::<tok::mem_flags>() {
+            p.parse::<tok::mem_flags>()?;
+            return Ok(Constraint::MemFlags);
+        }
+        if p.peek::<tok::predicate>() {
+            p.parse::<tok::predicate>()?;
+            return Ok(Constraint::Predicate);
+        }
         Err(p.error("expected a precondition constraint"))
     }
 }
@@ -520,6 +962,36 @@ impl<'a> Parse<'a> for Rhs<'a> {
         if p.peek::<Unquote>() {
             return Ok(Rhs::Unquote(p.parse()?));
         }
+        if p.peek::<Let>() {
+            return Ok(Rhs::Let(p.parse()?));
+        }
+        if p.peek::<IconstLike>() {
+            return Ok(Rhs::IconstLike(p.parse()?));
+        }
+        if p.peek::<Fold>() {
+            return Ok(Rhs::Fold(p.parse()?));
+        }
+        if p.peek::<Delete>() {
+            return Ok(Rhs::Delete(p.parse()?));
+        }
+        if p.peek::<ExpandIabs>() {
+            return Ok(Rhs::ExpandIabs(p.parse()?));
+        }
+        if p.peek::<Call>() {
+            return Ok(Rhs::Call(p.parse()?));
+        }
+        if p.peek::<Template>() {
+            return Ok(Rhs::Template(p.parse()?));
+        }
+        if p.peek::<StoredValue>() {
+            return Ok(Rhs::StoredValue(p.parse()?));
+        }
+        if p.peek::<Cond>() {
+            return Ok(Rhs::Cond(p.parse()?));
+        }
+        if p.peek::<Results>() {
+            return Ok(Rhs::Results(p.parse()?));
+        }
         if p.peek::<Operation<Self>>() {
             return Ok(Rhs::Operation(p.parse()?));
         }
@@ -533,6 +1005,16 @@ impl<'a> Peek for Rhs<'a> {
             || Constant::peek(c)
             || Variable::peek(c)
             || Unquote::peek(c)
+            || Let::peek(c)
+            || IconstLike::peek(c)
+            || Fold::peek(c)
+            || Delete::peek(c)
+            || ExpandIabs::peek(c)
+            || Call::peek(c)
+            || Template::peek(c)
+            || StoredValue::peek(c)
+            || Cond::peek(c)
+            || Results::peek(c)
             || Operation::<Self>::peek(c)
     }
 
@@ -541,6 +1023,330 @@ impl<'a> Peek for Rhs<'a> {
     }
 }
 
+impl<'a> Parse<'a> for Let<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::r#let>()?;
+            let bindings = p.parens(|p| {
+                let mut bindings = vec![];
+                while p.peek::<LParen>() {
+                    bindings.push(p.parse()?);
+                }
+                Ok(bindings)
+            })?;
+            let body = Box::new(p.parse()?);
+            Ok(Let {
+                span,
+                bindings,
+                body,
+            })
+        })
+    }
+}
+
+impl<'a> Peek for Let<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::r#let::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "let expression"
+    }
+}
+
+impl<'a> Parse<'a> for LetBinding<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            let var = p.parse()?;
+            let value = p.parse()?;
+            Ok(LetBinding { span, var, value })
+        })
+    }
+}
+
+impl<'a> Parse<'a> for IconstLike<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::iconst_like>()?;
+            let like = p.parse()?;
+            let value = p.parse()?;
+            Ok(IconstLike { span, like, value })
+        })
+    }
+}
+
+impl<'a> Peek for IconstLike<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::iconst_like::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "iconst-like expression"
+    }
+}
+
+impl<'a> Parse<'a> for Fold<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::fold>()?;
+            let value = p.parse()?;
+            Ok(Fold { span, value })
+        })
+    }
+}
+
+impl<'a> Peek for Fold<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::fold::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "fold expression"
+    }
+}
+
+impl<'a> Parse<'a> for Delete<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::delete>()?;
+            Ok(Delete {
+                span,
+                marker: PhantomData,
+            })
+        })
+    }
+}
+
+impl<'a> Peek for Delete<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::delete::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "delete expression"
+    }
+}
+
+impl<'a> Parse<'a> for ExpandIabs<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::expand_iabs>()?;
+            let operand = Box::new(p.parse()?);
+            Ok(ExpandIabs {
+                span,
+                r#type: Cell::new(None),
+                operand,
+            })
+        })
+    }
+}
+
+impl<'a> Peek for ExpandIabs<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::expand_iabs::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "expand-iabs expression"
+    }
+}
+
+impl<'a> Parse<'a> for Call<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::call>()?;
+            let name = p.step(|c| match c.keyword() {
+                Some((name, rest)) => Ok((name, rest)),
+                None => Err(c.error("expected a function name")),
+            })?;
+            let mut operands = vec![];
+            while p.peek::<Rhs>() {
+                operands.push(p.parse()?);
+            }
+            Ok(Call {
+                span,
+                name,
+                operands,
+            })
+        })
+    }
+}
+
+impl<'a> Peek for Call<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::call::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "call expression"
+    }
+}
+
+impl<'a> Parse<'a> for Template<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::template>()?;
+            let name = p.step(|c| match c.keyword() {
+                Some((name, rest)) => Ok((name, rest)),
+                None => Err(c.error("expected a template name")),
+            })?;
+            let mut operands = vec![];
+            while p.peek::<Rhs>() {
+                operands.push(p.parse()?);
+            }
+            Ok(Template {
+                span,
+                name,
+                r#type: Cell::new(None),
+                operands,
+            })
+        })
+    }
+}
+
+impl<'a> Peek for Template<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::template::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "template expression"
+    }
+}
+
+impl<'a> Parse<'a> for StoredValue<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::stored_value>()?;
+            let load = p.parse()?;
+            Ok(StoredValue { span, load })
+        })
+    }
+}
+
+impl<'a> Peek for StoredValue<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::stored_value::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "stored-value expression"
+    }
+}
+
+impl<'a> Parse<'a> for Cond<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::cond>()?;
+            let mut arms = vec![];
+            while p.peek::<LParen>() && p.peek2::<LParen>() {
+                arms.push(p.parse()?);
+            }
+            let default = Box::new(p.parse()?);
+            Ok(Cond {
+                span,
+                arms,
+                default,
+            })
+        })
+    }
+}
+
+impl<'a> Peek for Cond<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::cond::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "cond expression"
+    }
+}
+
+impl<'a> Parse<'a> for CondArm<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            let guard = p.parse()?;
+            let rhs = p.parse()?;
+            Ok(CondArm { span, guard, rhs })
+        })
+    }
+}
+
+impl<'a> Parse<'a> for Results<'a> {
+    fn parse(p: Parser<'a>) -> ParseResult<Self> {
+        let span = p.cur_span();
+        p.parens(|p| {
+            p.parse::<tok::results>()?;
+            let mut values = vec![];
+            while p.peek::<Rhs>() {
+                values.push(p.parse()?);
+            }
+            Ok(Results { span, values })
+        })
+    }
+}
+
+impl<'a> Peek for Results<'a> {
+    fn peek(c: Cursor) -> bool {
+        if let Some(c) = c.lparen() {
+            tok::results::peek(c)
+        } else {
+            false
+        }
+    }
+
+    fn display() -> &'static str {
+        "results expression"
+    }
+}
+
 impl<'a> Parse<'a> for Unquote<'a> {
     fn parse(p: Parser<'a>) -> ParseResult<Self> {
         let span = p.cur_span();
@@ -573,7 +1379,6 @@ impl<'a> Peek for Unquote<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use peepmatic_runtime::operator::Operator;
 
     macro_rules! test_parse {
         (
@@ -684,8 +1489,45 @@ mod test {
         parse_constraint<Constraint> {
             ok {
                 "is-power-of-two",
+                "is-byte-mask",
+                "is-mask-const",
+                "is-shift-by-const",
+                "is-call-result",
+                "single-use",
+                "all-operands-const",
+                "select-const-arms",
+                "is-sextend",
+                "is-uextend",
+                "popcount-eq",
+                "popcount-le",
                 "bit-width",
+                "block-predecessors",
+                "negation-fits-in-bits",
+                "is-width-minus",
                 "fits-in-native-word",
+                "equivalent-values",
+                "is-one-less-than",
+                "is-scaled-by",
+                "shifts-combine",
+                "ccs-combine",
+                "in-set",
+                "bounded-by",
+                "is-aligned",
+                "high-bits-zero",
+                "is-double-involution",
+                "icmp-zero",
+                "trailing-zeros",
+                "fits-in-imm12",
+                "fits-in-imm32",
+                "is-one",
+                "known-non-negative",
+                "redundant-mask-for-shift",
+                "is-encodable-logical-imm",
+                "is-add-of-neg-const",
+                "is-shifted-imm",
+                "is-shifted-imm8",
+                "mem-flags",
+                "predicate",
             }
             err {
                 "",
@@ -737,6 +1579,8 @@ mod test {
                 "0x123456789abcde",
                 "0x123456789abcdef",
                 "0xffff_ffff_ffff_ffff",
+                "0xffff_ffff_ffff_ffff_ffff_ffff_ffff_ffff",
+                "340282366920938463463374607431768211455",
             }
             err {
                 "",
@@ -746,6 +1590,22 @@ mod test {
                 "0xfffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
             }
         }
+        parse_let<Let> {
+            ok {
+                "(let () $x)",
+                "(let (($t (iadd $x $y))) (imul $t $t))",
+                "(let (($t (iadd $x $y)) ($u (imul $t $t))) (isub $u $t))",
+            }
+            err {
+                "",
+                "(let)",
+                "(let $x)",
+                "(let ($t (iadd $x $y)) $t)",
+                "(let (($t)) $t)",
+                "(let (($T 1)) $T)",
+                "(let (($t 1)))",
+            }
+        }
         parse_lhs<Lhs> {
             ok {
                 "(when (imul $C1 $C2) (is-power-of-two $C1) (is-power-of-two $C2))",
@@ -769,6 +1629,7 @@ mod test {
                 "(iadd 1 2)",
                 "(iadd $x $C)",
                 "(iadd{i32} $x $y)",
+                "(iadd{$T} $x $y)",
                 "(icmp eq $x $y)",
             }
             err {
@@ -777,6 +1638,8 @@ mod test {
                 "$var",
                 "$CONST",
                 "(ishl $x $(log2 $C))",
+                "(iadd{} $x $y)",
+                "(iadd{$T i32} $x $y)",
             }
         }
         parse_operation_rhs<Operation<Rhs>> {
@@ -817,12 +1680,20 @@ mod test {
                 "(=> (when (iadd $x $C) (is-power-of-two $C) (is-power-of-two $C)) (iadd $C $x))",
                 "(=> (when (iadd $x $C)) (iadd $C $x))",
                 "(=> (iadd $x $C) (iadd $C $x))",
+                "(=> (phase canonicalize) (iadd $x $C) (iadd $C $x))",
+                "(=> (phase lowering) (when (iadd $x $C)) (iadd_imm $C $x))",
+                "(=> (disabled) (iadd $x $C) (iadd $C $x))",
+                "(=> (phase lowering) (disabled) (iadd $x $C) (iadd_imm $C $x))",
             }
             err {
                 "",
                 "()",
                 "(=>)",
                 "(=> () ())",
+                "(=> (phase) (iadd $x $C) (iadd $C $x))",
+                "(=> (phase optimize) (iadd $x $C) (iadd $C $x))",
+                "(=> (disabled) (phase lowering) (iadd $x $C) (iadd_imm $C $x))",
+                "(=> (disabled 1) (iadd $x $C) (iadd $C $x))",
             }
         }
         parse_optimizations<Optimizations> {
@@ -850,11 +1721,15 @@ mod test {
                 "$C",
                 "$x",
                 "(iadd $x $y)",
+                "((one-of (opcode band) (opcode bor)) $x $y)",
+                "((one-of (opcode iadd)) $x $y)",
             }
             err {
                 "",
                 "()",
                 "abc",
+                "((one-of) $x $y)",
+                "((one-of band bor) $x $y)",
             }
         }
         parse_precondition<Precondition> {
@@ -862,6 +1737,7 @@ mod test {
                 "(is-power-of-two)",
                 "(is-power-of-two $C)",
                 "(is-power-of-two $C1 $C2)",
+                "(predicate is-hot $x)",
             }
             err {
                 "",
@@ -869,6 +1745,7 @@ mod test {
                 "()",
                 "$var",
                 "$CONST",
+                "(predicate $x)",
             }
         }
         parse_rhs<Rhs> {
@@ -878,10 +1755,55 @@ mod test {
                 "$x",
                 "$(log2 $C)",
                 "(iadd $x 1)",
+                "(let (($t (iadd $x 1))) (imul $t $t))",
+                "(iconst-like $x 0)",
+                "(icmp eq $x (iconst-like $x -1))",
+                "(fold $x)",
+                "(delete)",
+                "(expand-iabs $x)",
+                "(expand-iabs (iadd $x $y))",
+                "(call mask-for-width)",
+                "(call mask-for-width $C)",
+                "(call combine $C $x)",
+                "(template negate $x)",
+                "(template uavg-floor $x (iadd $x $y))",
+                "(stored-value $l)",
+                "(cond $x)",
+                "(cond ((fits-in-imm12 $C) (iadd_imm $C $x)) (iadd $x (iconst $C)))",
+                "(cond ((fits-in-imm12 $C) $x) ((fits-in-imm32 $C) $y) $z)",
+                "(results $x false)",
+                "(results (iadd $x $y) (icmp ult $x $y))",
+                "(load offset=8 $p)",
+                "(load{i32} offset=-8 flags=0x4 $p)",
+                "(store flags=6 offset=16 $x $p)",
             }
             err {
                 "",
                 "()",
+                "(iconst-like $x)",
+                "(iconst-like 0 $x)",
+                "(iconst-like $C 0)",
+                "(fold)",
+                "(fold 1)",
+                "(delete $x)",
+                "(expand-iabs)",
+                "(expand-iabs $x $y)",
+                "(call)",
+                "(call $f $x)",
+                "(template)",
+                "(template $x)",
+                "(stored-value)",
+                "(stored-value $l $m)",
+                "(stored-value (load $p))",
+                "(cond)",
+                "(cond ((fits-in-imm12 $C) $x))",
+                "(cond ((fits-in-imm12 $C)) $x)",
+                "(cond $x $y)",
+                "(results $x",
+                "(results (=> $x $y))",
+                "(load offset=8 offset=8 $p)",
+                "(load flags=256 $p)",
+                "(load offset=abc $p)",
             }
         }
         parse_unquote<Unquote> {
@@ -929,4 +1851,77 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn attach_doc_comments() {
+        let source = "
+;; Not attached: separated by a blank line.
+
+;; Fold adding zero.
+;;
+;;   x + 0 = x
+  (=> (iadd $x 0) $x) (=> (imul $x 1) $x)
+(=> (isub $x 0) $x)
+";
+        let buf = wast::parser::ParseBuffer::new(source).unwrap();
+        let mut opts = wast::parser::parse::<Optimizations>(&buf).unwrap();
+        super::attach_doc_comments(source, &mut opts);
+
+        let docs: Vec<_> = opts
+            .optimizations
+            .iter()
+            .map(|o| o.doc.as_deref())
+            .collect();
+        assert_eq!(
+            docs,
+            vec![Some("Fold adding zero.\n\n  x + 0 = x"), None, None]
+        );
+    }
+
+    #[test]
+    fn attach_source_locations() {
+        let source = "
+;; In a comment: (=> (iadd $x 0) $x)
+  (=> (iadd $x 0) $x) (=> (imul $x 1) $x)
+(=> (isub $x 0) $x)
+";
+        let buf = wast::parser::ParseBuffer::new(source).unwrap();
+        let mut opts = wast::parser::parse::<Optimizations>(&buf).unwrap();
+        super::attach_source_locations(source, &mut opts);
+
+        let locations: Vec<_> = opts
+            .optimizations
+            .iter()
+            .map(|o| {
+                let l = o.location.unwrap();
+                (l.line, l.column)
+            })
+            .collect();
+        assert_eq!(locations, vec![(3, 3), (3, 23), (4, 1)]);
+    }
+
+    #[test]
+    fn unknown_operator() {
+        for source in &[
+            "(=> (iaddd $x 0) $x)",
+            "(=> ((one-of (opcode iaddd)) $x 0) $x)",
+        ] {
+            let buf = wast::parser::ParseBuffer::new(source).unwrap();
+            let mut err = wast::parser::parse::<Optimizations>(&buf).unwrap_err();
+            err.set_text(source);
+            let msg = err.to_string();
+            assert!(msg.contains("unknown operator `iaddd`"), "{}", msg);
+        }
+
+        // The error points at the operator itself.
+        let source = "(=> (iaddd $x 0) $x)";
+        let buf = wast::parser::ParseBuffer::new(source).unwrap();
+        let mut err = wast::parser::parse::<Optimizations>(&buf).unwrap_err();
+        err.set_text(source);
+        assert!(err.to_string().contains(":1:6"), "{}", err);
+
+        let source = "(=> (iadd $x 0) $x)";
+        let buf = wast::parser::ParseBuffer::new(source).unwrap();
+        assert!(wast::parser::parse::<Optimizations>(&buf).is_ok());
+    }
 }
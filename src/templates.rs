@@ -0,0 +1,121 @@
+//! Built-in right-hand side templates.
+//!
+//! A `(template <name> <rhs>*)` right-hand side builds the named template's
+//! idiom out of its operands. Each template is a small, pre-built sequence of
+//! linear actions, which linearization inlines into the optimization that uses
+//! it, so that optimizations producing the same idiom don't each have to spell
+//! it out.
+
+use peepmatic_runtime::{
+    linear::{Action, RhsId},
+    operator::Operator,
+    r#type::{BitWidth, Kind, Type},
+};
+
+/// A pre-built sequence of actions that builds a value from its operands.
+#[derive(Debug)]
+pub(crate) struct Template {
+    /// The name that `(template <name> ...)` refers to this template by.
+    pub name: &'static str,
+
+    /// The number of operands that this template takes.
+    pub arity: usize,
+
+    /// The actions that build this template's result, which is the value
+    /// defined by the last action.
+    ///
+    /// `RhsId(i)` is the template's `i`th operand when `i < arity`, and the
+    /// value defined by `actions[i - arity]` otherwise. The operands, the
+    /// result, and every value in between have the same integer type, which is
+    /// written here as polymorphic.
+    pub actions: &'static [Action],
+}
+
+const INT: Type = Type {
+    kind: Kind::Int,
+    bit_width: BitWidth::Polymorphic,
+};
+
+const fn make(operator: Operator, a: u32, b: u32) -> Action {
+    Action::MakeBinaryInst {
+        operator,
+        r#type: INT,
+        operands: [RhsId(a), RhsId(b)],
+    }
+}
+
+static TEMPLATES: &[Template] = &[
+    // `(isub 0 $x)`
+    Template {
+        name: "negate",
+        arity: 1,
+        actions: &[
+            Action::MakeZero {
+                bit_width: BitWidth::Polymorphic,
+            },
+            make(Operator::Isub, 1, 0),
+        ],
+    },
+    // `(sshr $x <width - 1>)`, which is all ones when `$x` is negative and zero
+    // otherwise.
+    Template {
+        name: "sign-mask",
+        arity: 1,
+        actions: &[
+            Action::MakeSignShiftAmount {
+                bit_width: BitWidth::Polymorphic,
+            },
+            make(Operator::Sshr, 0, 1),
+        ],
+    },
+    // `(iadd (band $x $y) (ushr (bxor $x $y) 1))`, which is the unsigned
+    // average of `$x` and `$y`, rounded down, without overflowing.
+    Template {
+        name: "uavg-floor",
+        arity: 2,
+        actions: &[
+            make(Operator::Band, 0, 1),
+            make(Operator::Bxor, 0, 1),
+            Action::MakeOne {
+                bit_width: BitWidth::Polymorphic,
+            },
+            make(Operator::Ushr, 3, 4),
+            make(Operator::Iadd, 2, 5),
+        ],
+    },
+];
+
+/// Get the built-in template with the given name, if any.
+pub(crate) fn lookup(name: &str) -> Option<&'static Template> {
+    TEMPLATES.iter().find(|t| t.name == name)
+}
+
+impl Template {
+    /// Inline this template's actions, giving every value the type `ty`.
+    ///
+    /// `operands` are the ids of the values to use as the template's
+    /// operands, and `push` emits an action, returning the id of the value
+    /// that it defines. Returns the id of the template's result.
+    pub fn inline(
+        &self,
+        ty: Type,
+        operands: &[RhsId],
+        mut push: impl FnMut(Action) -> RhsId,
+    ) -> RhsId {
+        assert_eq!(operands.len(), self.arity);
+        let mut ids = operands.to_vec();
+        for action in self.actions {
+            let mut action = action.clone();
+            action.for_each_rhs_id_mut(|id| *id = ids[id.0 as usize]);
+            match &mut action {
+                Action::MakeZero { bit_width }
+                | Action::MakeOne { bit_width }
+                | Action::MakeSignShiftAmount { bit_width } => *bit_width = ty.bit_width,
+                Action::MakeBinaryInst { r#type, .. } => *r#type = ty,
+                _ => unreachable!("templates only build integers"),
+            }
+            ids.push(push(action));
+        }
+        *ids.last().unwrap()
+    }
+}
@@ -13,13 +13,14 @@
 //! implemented yet.
 
 use crate::ast::{Span as _, *};
+use crate::templates;
 use crate::traversals::{Dfs, TraversalEvent};
 use peepmatic_runtime::{
     operator::{Operator, TypingContext as TypingContextTrait},
     r#type::{BitWidth, Kind, Type},
 };
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::hash::Hash;
@@ -186,7 +187,7 @@ fn canonicalized_lhs_key(lhs: &Lhs) -> impl Hash + Eq {
     enum CanonicalBit {
         Var(u32),
         Const(u32),
-        Integer(i64),
+        Integer(i128),
         Boolean(bool),
         ConditionCode(peepmatic_runtime::cc::ConditionCode),
         Operation(Operator),
@@ -210,6 +211,10 @@ pub(crate) struct TypingContext<'a> {
     // A map from identifiers to the type variable describing its type.
     id_to_type_var: HashMap<Id<'a>, TypeVar<'a>>,
 
+    // A map from DSL type variables, ascribed like `(iadd{$T} ...)`, to the
+    // type of the first operation they were ascribed to.
+    type_variables: HashMap<Id<'a>, TypeVar<'a>>,
+
     // A list of type constraints, the span of the AST node where the constraint
     // originates from, and an optional message to be displayed if the
     // constraint is not satisfied.
@@ -221,6 +226,12 @@ pub(crate) struct TypingContext<'a> {
     boolean_literals: Vec<(&'a Boolean<'a>, TypeVar<'a>)>,
     integer_literals: Vec<(&'a Integer<'a>, TypeVar<'a>)>,
     rhs_operations: Vec<(&'a Operation<'a, Rhs<'a>>, TypeVar<'a>)>,
+
+    // A map from `expand-iabs` right-hand sides to their type variables.
+    iabs_expansions: Vec<(&'a ExpandIabs<'a>, TypeVar<'a>)>,
+
+    // A map from `template` right-hand sides to their type variables.
+    templates: Vec<(&'a Template<'a>, TypeVar<'a>)>,
 }
 
 impl<'a> TypingContext<'a> {
@@ -231,6 +242,7 @@ impl<'a> TypingContext<'a> {
             .variant("cpu_flags", &[])
             .variant("cc", &[])
             .variant("void", &[])
+            .variant("vector", &[])
             .finish("TypeKind");
         TypingContext {
             z3,
@@ -238,11 +250,14 @@ impl<'a> TypingContext<'a> {
             root_ty: None,
             operation_scope: Default::default(),
             id_to_type_var: Default::default(),
+            type_variables: Default::default(),
             type_kind_sort,
             constraints: vec![],
             boolean_literals: Default::default(),
             integer_literals: Default::default(),
             rhs_operations: Default::default(),
+            iabs_expansions: Default::default(),
+            templates: Default::default(),
         }
     }
 
@@ -254,11 +269,13 @@ impl<'a> TypingContext<'a> {
         let is_bool = self.is_bool(&root_ty);
         let is_void = self.is_void(&root_ty);
         let is_cpu_flags = self.is_cpu_flags(&root_ty);
+        let is_vector = self.is_vector(&root_ty);
         self.constraints.push((
-            is_int.or(&[&is_bool, &is_void, &is_cpu_flags]),
+            is_int.or(&[&is_bool, &is_void, &is_cpu_flags, &is_vector]),
             span,
             Some(
-                "the root of an optimization must be an integer, a boolean, void, or CPU flags"
+                "the root of an optimization must be an integer, a boolean, a vector, void, or CPU \
+                 flags"
                     .into(),
             ),
         ));
@@ -335,6 +352,23 @@ impl<'a> TypingContext<'a> {
         }
     }
 
+    // Constrain an operation ascribed with the given DSL type variable to have
+    // the same type as every other operation ascribed with it.
+    fn unify_type_variable(&mut self, span: Span, id: Id<'a>, ty: &TypeVar<'a>) {
+        match self.type_variables.get(&id).cloned() {
+            Some(bound) => {
+                let msg = format!(
+                    "type variable `${}` is used with different types",
+                    id.name()
+                );
+                self.assert_type_eq(span, &bound, ty, Some(msg.into()));
+            }
+            None => {
+                self.type_variables.insert(id, ty.clone());
+            }
+        }
+    }
+
     fn remember_boolean_literal(&mut self, b: &'a Boolean<'a>, ty: TypeVar<'a>) {
         self.assert_is_bool(b.span, &ty);
         self.boolean_literals.push((b, ty));
@@ -349,6 +383,14 @@ impl<'a> TypingContext<'a> {
         self.rhs_operations.push((op, ty));
     }
 
+    fn remember_iabs_expansion(&mut self, e: &'a ExpandIabs<'a>, ty: TypeVar<'a>) {
+        self.iabs_expansions.push((e, ty));
+    }
+
+    fn remember_template(&mut self, t: &'a Template<'a>, ty: TypeVar<'a>) {
+        self.templates.push((t, ty));
+    }
+
     fn is_int(&self, ty: &TypeVar<'a>) -> z3::ast::Bool<'a> {
         self.type_kind_sort.variants[0]
             .tester
@@ -389,6 +431,14 @@ impl<'a> TypingContext<'a> {
             .unwrap()
     }
 
+    fn is_vector(&self, ty: &TypeVar<'a>) -> z3::ast::Bool<'a> {
+        self.type_kind_sort.variants[5]
+            .tester
+            .apply(&[&ty.kind.clone().into()])
+            .as_bool()
+            .unwrap()
+    }
+
     fn assert_is_integer(&mut self, span: Span, ty: &TypeVar<'a>) {
         self.constraints.push((
             self.is_int(ty),
@@ -405,6 +455,24 @@ impl<'a> TypingContext<'a> {
         ));
     }
 
+    fn assert_is_bool_or_int(&mut self, span: Span, ty: &TypeVar<'a>) {
+        let is_int = self.type_kind_sort.variants[0]
+            .tester
+            .apply(&[&ty.kind.clone().into()])
+            .as_bool()
+            .unwrap();
+        let is_bool = self.type_kind_sort.variants[1]
+            .tester
+            .apply(&[&ty.kind.clone().into()])
+            .as_bool()
+            .unwrap();
+        self.constraints.push((
+            is_int.or(&[&is_bool]),
+            span,
+            Some("type error: must be either an int or a bool type".into()),
+        ));
+    }
+
     fn assert_is_cpu_flags(&mut self, span: Span, ty: &TypeVar<'a>) {
         self.constraints.push((
             self.is_cpu_flags(ty),
@@ -429,6 +497,24 @@ impl<'a> TypingContext<'a> {
         ));
     }
 
+    fn assert_is_vector(&mut self, span: Span, ty: &TypeVar<'a>) {
+        self.constraints.push((
+            self.is_vector(ty),
+            span,
+            Some("type error: expected vector".into()),
+        ));
+    }
+
+    fn assert_is_lane_width(&mut self, span: Span, ty: &TypeVar<'a>) {
+        let is_width = |w| z3::ast::BV::from_i64(self.z3, w, 8)._eq(&ty.width);
+        let (w8, w16, w32, w64) = (is_width(8), is_width(16), is_width(32), is_width(64));
+        self.constraints.push((
+            w8.or(&[&w16, &w32, &w64]),
+            span,
+            Some("type error: vector lanes must be 8, 16, 32, or 64 bits wide".into()),
+        ));
+    }
+
     fn assert_bit_width(&mut self, span: Span, ty: &TypeVar<'a>, width: u8) {
         debug_assert!(width == 0 || width.is_power_of_two());
         let width_var = z3::ast::BV::from_i64(self.z3, width as i64, 8);
@@ -541,12 +627,28 @@ impl<'a> TypingContext<'a> {
             let kind = self.op_ty_var_to_kind(&ty);
             let bit_width = match kind {
                 Kind::CpuFlags | Kind::Void => BitWidth::One,
-                Kind::Int | Kind::Bool => self.ty_var_to_width(&ty)?,
+                Kind::Int | Kind::Bool | Kind::Vector => self.ty_var_to_width(&ty)?,
             };
             debug_assert!(op.r#type.get().is_none());
             op.r#type.set(Some(Type { kind, bit_width }));
         }
 
+        for (e, ty) in mem::replace(&mut self.iabs_expansions, vec![]) {
+            let bit_width = self.ty_var_to_width(&ty)?;
+            e.r#type.set(Some(Type {
+                kind: Kind::Int,
+                bit_width,
+            }));
+        }
+
+        for (t, ty) in mem::replace(&mut self.templates, vec![]) {
+            let bit_width = self.ty_var_to_width(&ty)?;
+            t.r#type.set(Some(Type {
+                kind: Kind::Int,
+                bit_width,
+            }));
+        }
+
         Ok(())
     }
 
@@ -613,6 +715,7 @@ impl<'a> TypingContext<'a> {
             (Self::is_bool, Kind::Bool),
             (Self::is_cpu_flags, Kind::CpuFlags),
             (Self::is_void, Kind::Void),
+            (Self::is_vector, Kind::Vector),
         ]
         .iter()
         {
@@ -680,6 +783,23 @@ impl<'a> TypingContextTrait<'a> for TypingContext<'a> {
         ty
     }
 
+    fn vNN(&mut self, span: Span) -> TypeVar<'a> {
+        if let Some(ty) = self.operation_scope.get("vNN") {
+            return ty.clone();
+        }
+
+        // A vector's bit width is the width of its lanes, which are `iNN`.
+        let lane = self.iNN(span);
+        let ty = TypeVar {
+            width: lane.width,
+            ..self.new_type_var()
+        };
+        self.assert_is_vector(span, &ty);
+        self.assert_is_lane_width(span, &ty);
+        self.operation_scope.insert("vNN", ty.clone());
+        ty
+    }
+
     fn cpu_flags(&mut self, span: Span) -> TypeVar<'a> {
         if let Some(ty) = self.operation_scope.get("cpu_flags") {
             return ty.clone();
@@ -708,21 +828,18 @@ impl<'a> TypingContextTrait<'a> for TypingContext<'a> {
 
     fn bool_or_int(&mut self, span: Span) -> TypeVar<'a> {
         let ty = self.new_type_var();
-        let is_int = self.type_kind_sort.variants[0]
-            .tester
-            .apply(&[&ty.kind.clone().into()])
-            .as_bool()
-            .unwrap();
-        let is_bool = self.type_kind_sort.variants[1]
-            .tester
-            .apply(&[&ty.kind.clone().into()])
-            .as_bool()
-            .unwrap();
-        self.constraints.push((
-            is_int.or(&[&is_bool]),
-            span,
-            Some("type error: must be either an int or a bool type".into()),
-        ));
+        self.assert_is_bool_or_int(span, &ty);
+        ty
+    }
+
+    fn bool_or_int_t(&mut self, span: Span) -> TypeVar<'a> {
+        if let Some(ty) = self.operation_scope.get("bool_or_int_t") {
+            return ty.clone();
+        }
+
+        let ty = self.new_type_var();
+        self.assert_is_bool_or_int(span, &ty);
+        self.operation_scope.insert("bool_or_int_t", ty.clone());
         ty
     }
 
@@ -748,6 +865,7 @@ fn verify_optimization(z3: &z3::Context, opt: &Optimization) -> VerifyResult<()>
     collect_type_constraints(&mut context, opt)?;
     context.type_check(opt.span)?;
     context.assign_types()?;
+    verify_lane_immediates(opt)?;
 
     // TODO: add another pass here to check for counter-examples to this
     // optimization, i.e. inputs where the LHS and RHS are not equivalent.
@@ -777,6 +895,10 @@ fn collect_type_constraints<'a>(
     // types, and exiting a `Pattern` in the traversal pops them off.
     let mut expected_types = vec![lhs_ty];
 
+    // The types of each of the root operation's results, if the root is an
+    // operation.
+    let mut root_result_types = vec![];
+
     // Build up the type constraints for the left-hand side.
     for (event, node) in Dfs::new(&opt.lhs) {
         match (event, node) {
@@ -801,6 +923,8 @@ fn collect_type_constraints<'a>(
                 context.assert_is_cc(cc.span, ty);
             }
             (TE::Enter, DynAstRef::PatternOperation(op)) => {
+                let is_root =
+                    matches!(&opt.lhs.pattern, Pattern::Operation(root) if std::ptr::eq(root, op));
                 let result_ty;
                 let mut operand_types = vec![];
                 {
@@ -810,6 +934,10 @@ fn collect_type_constraints<'a>(
                         .immediate_types(&mut *scope, op.span, &mut operand_types);
                     op.operator
                         .param_types(&mut *scope, op.span, &mut operand_types);
+                    if is_root {
+                        op.operator
+                            .result_types(&mut *scope, op.span, &mut root_result_types);
+                    }
                 }
 
                 if op.operands.len() != operand_types.len() {
@@ -841,6 +969,61 @@ fn collect_type_constraints<'a>(
                     }
                 }
 
+                if op.mem_offset.is_some() || op.mem_flags.is_some() {
+                    return Err(WastError::new(
+                        op.span,
+                        "`offset=` and `flags=` are only allowed in right-hand sides; use the \
+                         `mem-flags` precondition to match memory flags"
+                            .into(),
+                    )
+                    .into());
+                }
+
+                for alt in &op.alternatives {
+                    let alt_result_ty;
+                    let mut alt_operand_types = vec![];
+                    {
+                        let mut scope = context.enter_operation_scope();
+                        alt_result_ty = alt.result_type(&mut *scope, op.span);
+                        alt.immediate_types(&mut *scope, op.span, &mut alt_operand_types);
+                        alt.param_types(&mut *scope, op.span, &mut alt_operand_types);
+                    }
+
+                    if alt.immediates_arity() != op.operator.immediates_arity()
+                        || alt.results_arity() != op.operator.results_arity()
+                        || alt_operand_types.len() != operand_types.len()
+                    {
+                        return Err(WastError::new(
+                            op.span,
+                            format!(
+                                "`{}` and `{}` have different signatures and cannot be used \
+                                 together in `one-of`",
+                                op.operator, alt
+                            ),
+                        )
+                        .into());
+                    }
+
+                    context.assert_type_eq(op.span, &result_ty, &alt_result_ty, None);
+                    for (a, b) in operand_types.iter().zip(alt_operand_types.iter()) {
+                        context.assert_type_eq(op.span, a, b, None);
+                    }
+                }
+
+                if !op.alternatives.is_empty()
+                    && std::iter::once(&op.operator)
+                        .chain(&op.alternatives)
+                        .any(|o| {
+                            matches!(o, Operator::Ireduce | Operator::Uextend | Operator::Sextend)
+                        })
+                {
+                    return Err(WastError::new(
+                        op.span,
+                        "`ireduce`, `sextend`, and `uextend` cannot be used in `one-of`".into(),
+                    )
+                    .into());
+                }
+
                 match op.operator {
                     Operator::Ireduce | Operator::Uextend | Operator::Sextend => {
                         if op.r#type.get().is_none() {
@@ -871,6 +1054,7 @@ fn collect_type_constraints<'a>(
                         Kind::Bool => context.assert_is_bool(op.span, &result_ty),
                         Kind::Int => context.assert_is_integer(op.span, &result_ty),
                         Kind::Void => context.assert_is_void(op.span, &result_ty),
+                        Kind::Vector => context.assert_is_vector(op.span, &result_ty),
                         Kind::CpuFlags => {
                             unreachable!("no syntax for ascribing CPU flags types right now")
                         }
@@ -880,6 +1064,10 @@ fn collect_type_constraints<'a>(
                     }
                 }
 
+                if let Some(id) = op.type_variable {
+                    context.unify_type_variable(op.span, id, &result_ty);
+                }
+
                 context.assert_type_eq(op.span, expected_types.last().unwrap(), &result_ty, None);
 
                 operand_types.reverse();
@@ -890,6 +1078,16 @@ fn collect_type_constraints<'a>(
             }
             (TE::Enter, DynAstRef::Precondition(pre)) => {
                 type_constrain_precondition(context, pre)?;
+                match pre.constraint {
+                    Constraint::ShiftsCombine => {
+                        verify_nested_shift_amounts(&opt.lhs.pattern, pre)?
+                    }
+                    Constraint::IsDoubleInvolution => {
+                        verify_double_involution(&opt.lhs.pattern, pre)?
+                    }
+                    Constraint::IsAddOfNegConst => verify_add_of_neg_const(&opt.lhs.pattern, pre)?,
+                    _ => {}
+                }
             }
             _ => continue,
         }
@@ -900,6 +1098,34 @@ fn collect_type_constraints<'a>(
     // of pattern operations.
     assert!(expected_types.is_empty());
 
+    // The `let` expressions we are currently inside of, innermost last. A
+    // binding is only in scope once we have finished traversing its value, so
+    // that it is visible to later bindings and the body, but not to itself or
+    // earlier bindings.
+    let mut let_scopes: Vec<LetScope<'a>> = vec![];
+
+    // Every name bound by a `let` anywhere in this right-hand side. We don't
+    // allow rebinding a name, even in a nested `let`.
+    let mut let_bound_names = HashSet::new();
+
+    // Replacing only the first result of a multi-result root would leave its
+    // other results dangling, so the right-hand side must either replace each
+    // of them or be another operation.
+    if root_result_types.len() > 1 {
+        match &opt.rhs {
+            Rhs::Results(_) | Rhs::Operation(_) => {}
+            rhs => {
+                return Err(WastError::new(
+                    rhs.span(),
+                    "the left-hand side's root has multiple results, so the right-hand side must \
+                     be a `results` expression or an operation"
+                        .into(),
+                )
+                .into())
+            }
+        }
+    }
+
     // Collect the type constraints for the right-hand side.
     expected_types.push(rhs_ty);
     for (event, node) in Dfs::new(&opt.rhs) {
@@ -918,12 +1144,304 @@ fn collect_type_constraints<'a>(
             }
             (TE::Enter, DynAstRef::Rhs(Rhs::Constant(Constant { span, id })))
             | (TE::Enter, DynAstRef::Rhs(Rhs::Variable(Variable { span, id }))) => {
-                let id_ty = context.get_type_var_for_id(*id)?;
+                let let_bound = let_scopes
+                    .iter()
+                    .rev()
+                    .flat_map(|scope| scope.bound.iter().rev())
+                    .find(|(bound_id, _)| bound_id == id)
+                    .map(|(_, ty)| ty.clone());
+                let id_ty = match let_bound {
+                    Some(ty) => ty,
+                    None if let_bound_names.contains(id) => {
+                        return Err(WastError::new(
+                            *span,
+                            format!("`${}` is not in scope here", id.name()),
+                        )
+                        .into())
+                    }
+                    None => context.get_type_var_for_id(*id)?,
+                };
                 context.assert_type_eq(*span, expected_types.last().unwrap(), &id_ty, None);
             }
+            (TE::Enter, DynAstRef::Rhs(Rhs::IconstLike(c))) => {
+                if let_bound_names.contains(&c.like.id) {
+                    return Err(WastError::new(
+                        c.like.span,
+                        "`iconst-like` must refer to a left-hand side variable, not a \
+                         `let`-bound variable"
+                            .into(),
+                    )
+                    .into());
+                }
+                let like_ty = context.get_type_var_for_id(c.like.id)?;
+                context.assert_is_integer(c.like.span, &like_ty);
+                context.assert_type_eq(c.span, expected_types.last().unwrap(), &like_ty, None);
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::Fold(f))) => {
+                if let_bound_names.contains(&f.value.id) {
+                    return Err(WastError::new(
+                        f.value.span,
+                        "`fold` must refer to a left-hand side variable, not a `let`-bound \
+                         variable"
+                            .into(),
+                    )
+                    .into());
+                }
+                let value_ty = context.get_type_var_for_id(f.value.id)?;
+                context.assert_type_eq(f.span, expected_types.last().unwrap(), &value_ty, None);
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::StoredValue(s))) => {
+                if let_bound_names.contains(&s.load.id) {
+                    return Err(WastError::new(
+                        s.load.span,
+                        "`stored-value` must refer to a left-hand side variable, not a \
+                         `let`-bound variable"
+                            .into(),
+                    )
+                    .into());
+                }
+                let load_ty = context.get_type_var_for_id(s.load.id)?;
+                context.assert_type_eq(s.span, expected_types.last().unwrap(), &load_ty, None);
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::Cond(c))) => {
+                match &opt.rhs {
+                    Rhs::Cond(root) if std::ptr::eq(root, c) => {}
+                    _ => {
+                        return Err(WastError::new(
+                            c.span,
+                            "`cond` is only valid as the whole right-hand side".into(),
+                        )
+                        .into())
+                    }
+                }
+                for arm in &c.arms {
+                    match arm.guard.constraint {
+                        Constraint::FitsInImm12 | Constraint::FitsInImm32 => {}
+                        _ => {
+                            return Err(WastError::new(
+                                arm.guard.span,
+                                "`cond` guards must be `fits-in-imm12` or `fits-in-imm32` \
+                                 preconditions"
+                                    .into(),
+                            )
+                            .into())
+                        }
+                    }
+                }
+
+                // Every alternative has the same type as the whole `cond`.
+                let ty = expected_types.last().unwrap().clone();
+                expected_types.extend(std::iter::repeat(ty).take(c.arms.len() + 1));
+            }
+            (TE::Enter, DynAstRef::Precondition(pre)) => {
+                // A `cond` arm's guard.
+                type_constrain_precondition(context, pre)?;
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::Results(r))) => {
+                match &opt.rhs {
+                    Rhs::Results(root) if std::ptr::eq(root, r) => {}
+                    _ => {
+                        return Err(WastError::new(
+                            r.span,
+                            "`results` is only valid as the whole right-hand side".into(),
+                        )
+                        .into())
+                    }
+                }
+                if root_result_types.len() < 2 {
+                    return Err(WastError::new(
+                        r.span,
+                        "`results` requires a left-hand side whose root has multiple results"
+                            .into(),
+                    )
+                    .into());
+                }
+                if r.values.len() != root_result_types.len() {
+                    return Err(WastError::new(
+                        r.span,
+                        format!(
+                            "the left-hand side's root has {} results, but `results` has {} \
+                             right-hand sides",
+                            root_result_types.len(),
+                            r.values.len()
+                        ),
+                    )
+                    .into());
+                }
+
+                // Each right-hand side has the type of the result it replaces.
+                expected_types.extend(root_result_types.iter().rev().cloned());
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::Delete(d))) => match &opt.rhs {
+                Rhs::Delete(root) if std::ptr::eq(root, d) => {}
+                _ => {
+                    return Err(WastError::new(
+                        d.span,
+                        "`delete` is only valid as the whole right-hand side".into(),
+                    )
+                    .into())
+                }
+            },
+            (TE::Enter, DynAstRef::Rhs(Rhs::ExpandIabs(e))) => {
+                // The absolute value has the same type as its operand.
+                let ty = expected_types.last().unwrap().clone();
+                context.assert_is_integer(e.span, &ty);
+                context.remember_iabs_expansion(e, ty.clone());
+                expected_types.push(ty);
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::Call(call))) => {
+                // The host function returns an integer constant of whatever
+                // type is expected here.
+                context.assert_is_integer(call.span, expected_types.last().unwrap());
+                if call.operands.len() > 2 {
+                    return Err(WastError::new(
+                        call.span,
+                        format!(
+                            "`call` accepts at most 2 operands, found {} operands",
+                            call.operands.len()
+                        ),
+                    )
+                    .into());
+                }
+                let mut operand_types = vec![];
+                for operand in &call.operands {
+                    match operand {
+                        Rhs::Constant(_) | Rhs::Variable(_) => {
+                            operand_types.push(context.new_type_var())
+                        }
+                        _ => {
+                            return Err(WastError::new(
+                                operand.span(),
+                                "`call` operands must be constants or variables".into(),
+                            )
+                            .into())
+                        }
+                    }
+                }
+                operand_types.reverse();
+                expected_types.extend(operand_types);
+            }
+            (TE::Enter, DynAstRef::Rhs(Rhs::Template(t))) => {
+                let template = match templates::lookup(t.name) {
+                    Some(template) => template,
+                    None => {
+                        return Err(WastError::new(
+                            t.span,
+                            format!("unknown template `{}`", t.name),
+                        )
+                        .into())
+                    }
+                };
+                if t.operands.len() != template.arity {
+                    return Err(WastError::new(
+                        t.span,
+                        format!(
+                            "the `{}` template requires exactly {} operands, found {} operands",
+                            t.name,
+                            template.arity,
+                            t.operands.len()
+                        ),
+                    )
+                    .into());
+                }
+
+                // The operands have the same integer type as the result.
+                let ty = expected_types.last().unwrap().clone();
+                context.assert_is_integer(t.span, &ty);
+                context.remember_template(t, ty.clone());
+                expected_types.extend(t.operands.iter().map(|_| ty.clone()));
+            }
+            (TE::Enter, DynAstRef::Let(l)) => {
+                let mut binding_tys = vec![];
+                for b in &l.bindings {
+                    if context.id_to_type_var.contains_key(&b.var.id) {
+                        return Err(WastError::new(
+                            b.var.span,
+                            format!(
+                                "`${}` is already bound in the left-hand side and cannot be \
+                                 rebound by a `let`",
+                                b.var.id.name()
+                            ),
+                        )
+                        .into());
+                    }
+                    if !let_bound_names.insert(b.var.id) {
+                        return Err(WastError::new(
+                            b.var.span,
+                            format!(
+                                "`${}` is already bound by a `let` in this right-hand side",
+                                b.var.id.name()
+                            ),
+                        )
+                        .into());
+                    }
+                    binding_tys.push(context.new_type_var());
+                }
+
+                // The body of a `let` must build something (or be a left-hand
+                // side binding) because the last thing built is the root of
+                // the whole right-hand side. An alias for an earlier binding
+                // would break that.
+                let mut body = &*l.body;
+                while let Rhs::Let(inner) = body {
+                    body = &inner.body;
+                }
+                if let Rhs::Variable(Variable { span, id }) = body {
+                    if let_bound_names.contains(id) {
+                        return Err(WastError::new(
+                            *span,
+                            "the body of a `let` cannot be a `let`-bound variable; use the bound \
+                             right-hand side directly instead"
+                                .into(),
+                        )
+                        .into());
+                    }
+                }
+
+                // The body has the `let`'s type, and each binding gets a fresh
+                // type variable. Push them in reverse traversal order.
+                expected_types.push(expected_types.last().unwrap().clone());
+                expected_types.extend(binding_tys.iter().rev().cloned());
+
+                binding_tys.reverse();
+                let_scopes.push(LetScope {
+                    pending: binding_tys,
+                    bound: vec![],
+                });
+            }
+            (TE::Exit, DynAstRef::LetBinding(b)) => {
+                let scope = let_scopes.last_mut().unwrap();
+                let ty = scope.pending.pop().unwrap();
+                scope.bound.push((b.var.id, ty));
+            }
+            (TE::Exit, DynAstRef::Let(_)) => {
+                let scope = let_scopes.pop().unwrap();
+                debug_assert!(scope.pending.is_empty());
+            }
             (TE::Enter, DynAstRef::RhsOperation(op)) => {
+                if !op.alternatives.is_empty() {
+                    return Err(WastError::new(
+                        op.span,
+                        "`one-of` is only allowed in left-hand side patterns".into(),
+                    )
+                    .into());
+                }
+
+                match op.operator {
+                    Operator::Load | Operator::Store => {}
+                    _ if op.mem_offset.is_some() || op.mem_flags.is_some() => {
+                        return Err(WastError::new(
+                            op.span,
+                            "`offset=` and `flags=` are only allowed on `load` and `store`".into(),
+                        )
+                        .into());
+                    }
+                    _ => {}
+                }
+
                 let result_ty;
                 let mut operand_types = vec![];
+                let mut result_types = vec![];
                 {
                     let mut scope = context.enter_operation_scope();
                     result_ty = op.operator.result_type(&mut *scope, op.span);
@@ -931,6 +1449,8 @@ fn collect_type_constraints<'a>(
                         .immediate_types(&mut *scope, op.span, &mut operand_types);
                     op.operator
                         .param_types(&mut *scope, op.span, &mut operand_types);
+                    op.operator
+                        .result_types(&mut *scope, op.span, &mut result_types);
                 }
 
                 if op.operands.len() != operand_types.len() {
@@ -945,6 +1465,28 @@ fn collect_type_constraints<'a>(
                     .into());
                 }
 
+                // A multi-result root replaced by an operation must be
+                // replaced by one with the same results, so that each of the
+                // old results has a replacement.
+                let is_root = matches!(&opt.rhs, Rhs::Operation(root) if std::ptr::eq(root, op));
+                if is_root && root_result_types.len() > 1 {
+                    if result_types.len() != root_result_types.len() {
+                        return Err(WastError::new(
+                            op.span,
+                            format!(
+                                "the left-hand side's root has {} results, but `{}` has {} results",
+                                root_result_types.len(),
+                                op.operator,
+                                result_types.len()
+                            ),
+                        )
+                        .into());
+                    }
+                    for (a, b) in root_result_types.iter().zip(&result_types) {
+                        context.assert_type_eq(op.span, a, b, None);
+                    }
+                }
+
                 for imm in op
                     .operands
                     .iter()
@@ -954,7 +1496,13 @@ fn collect_type_constraints<'a>(
                         Rhs::ValueLiteral(_)
                         | Rhs::Constant(_)
                         | Rhs::Variable(_)
-                        | Rhs::Unquote(_) => continue,
+                        | Rhs::Unquote(_)
+                        | Rhs::IconstLike(_)
+                        | Rhs::Fold(_)
+                        | Rhs::Call(_)
+                        | Rhs::Delete(_)
+                        | Rhs::Cond(_)
+                        | Rhs::Results(_) => continue,
                         Rhs::Operation(op) => return Err(WastError::new(
                             op.span,
                             "operations are invalid immediates; must be a value literal, unquote, \
@@ -962,6 +1510,36 @@ fn collect_type_constraints<'a>(
                                 .into(),
                         )
                         .into()),
+                        Rhs::Let(l) => return Err(WastError::new(
+                            l.span,
+                            "`let` expressions are invalid immediates; must be a value literal, \
+                             unquote, constant, or variable"
+                                .into(),
+                        )
+                        .into()),
+                        Rhs::ExpandIabs(e) => return Err(WastError::new(
+                            e.span,
+                            "`expand-iabs` expressions are invalid immediates; must be a value \
+                             literal, unquote, constant, or variable"
+                                .into(),
+                        )
+                        .into()),
+                        Rhs::Template(t) => {
+                            return Err(WastError::new(
+                                t.span,
+                                "`template` expressions are invalid immediates; must be a value \
+                             literal, unquote, constant, or variable"
+                                    .into(),
+                            )
+                            .into())
+                        }
+                        Rhs::StoredValue(s) => return Err(WastError::new(
+                            s.span,
+                            "`stored-value` expressions are invalid immediates; must be a value \
+                             literal, unquote, constant, or variable"
+                                .into(),
+                        )
+                        .into()),
                     }
                 }
 
@@ -995,6 +1573,7 @@ fn collect_type_constraints<'a>(
                         Kind::Bool => context.assert_is_bool(op.span, &result_ty),
                         Kind::Int => context.assert_is_integer(op.span, &result_ty),
                         Kind::Void => context.assert_is_void(op.span, &result_ty),
+                        Kind::Vector => context.assert_is_vector(op.span, &result_ty),
                         Kind::CpuFlags => {
                             unreachable!("no syntax for ascribing CPU flags types right now")
                         }
@@ -1004,6 +1583,10 @@ fn collect_type_constraints<'a>(
                     }
                 }
 
+                if let Some(id) = op.type_variable {
+                    context.unify_type_variable(op.span, id, &result_ty);
+                }
+
                 context.assert_type_eq(op.span, expected_types.last().unwrap(), &result_ty, None);
                 if op.r#type.get().is_none() {
                     context.remember_rhs_operation(op, result_ty);
@@ -1039,7 +1622,19 @@ fn collect_type_constraints<'a>(
                 for operand in &unq.operands {
                     match operand {
                         Rhs::ValueLiteral(_) | Rhs::Constant(_) => continue,
-                        Rhs::Variable(_) | Rhs::Unquote(_) | Rhs::Operation(_) => {
+                        Rhs::Variable(_)
+                        | Rhs::Unquote(_)
+                        | Rhs::Operation(_)
+                        | Rhs::Let(_)
+                        | Rhs::IconstLike(_)
+                        | Rhs::Fold(_)
+                        | Rhs::Delete(_)
+                        | Rhs::ExpandIabs(_)
+                        | Rhs::Call(_)
+                        | Rhs::Template(_)
+                        | Rhs::StoredValue(_)
+                        | Rhs::Cond(_)
+                        | Rhs::Results(_) => {
                             return Err(WastError::new(
                                 operand.span(),
                                 "unquote operands must be value literals or constants".into(),
@@ -1064,9 +1659,195 @@ fn collect_type_constraints<'a>(
     // Again, we should have popped off all the expected types when exiting
     // `Rhs` nodes in the traversal.
     assert!(expected_types.is_empty());
+    assert!(let_scopes.is_empty());
 
-    Ok(())
-}
+    return Ok(());
+
+    struct LetScope<'a> {
+        // Type variables for the bindings we haven't finished traversing yet,
+        // with the next binding's type last.
+        pending: Vec<TypeVar<'a>>,
+        // The bindings that are in scope, and their types.
+        bound: Vec<(Id<'a>, TypeVar<'a>)>,
+    }
+}
+
+/// Check that every `extractlane` and `insertlane` in the right-hand side has
+/// an integer literal lane index that is in range for its vector type.
+///
+/// This must run after types are assigned: both operators' result types have
+/// the vector's lane width.
+fn verify_lane_immediates(opt: &Optimization) -> VerifyResult<()> {
+    use crate::traversals::TraversalEvent as TE;
+
+    /// The number of lanes in the narrowest vectors, which have 64-bit lanes.
+    const MIN_LANES: u8 = 2;
+
+    for (event, node) in Dfs::new(&opt.rhs) {
+        let op = match (event, node) {
+            (TE::Enter, DynAstRef::RhsOperation(op))
+                if op.operator == Operator::Extractlane || op.operator == Operator::Insertlane =>
+            {
+                op
+            }
+            _ => continue,
+        };
+        let lane = match &op.operands[0] {
+            Rhs::ValueLiteral(ValueLiteral::Integer(i)) => i,
+            lane => {
+                return Err(WastError::new(
+                    lane.span(),
+                    format!(
+                        "the lane index of `{}` must be an integer literal",
+                        op.operator
+                    ),
+                )
+                .into())
+            }
+        };
+        let r#type = op
+            .r#type
+            .get()
+            .expect("should be initialized after type checking");
+        let lanes = r#type
+            .bit_width
+            .fixed_width()
+            .map_or(MIN_LANES, |w| 128 / w);
+        if lane.value < 0 || lane.value >= i128::from(lanes) {
+            return Err(WastError::new(
+                lane.span,
+                format!(
+                    "lane index {} is out of range for a vector with {} lanes",
+                    lane.value, lanes
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Check that a `shifts-combine` precondition's operands are the amounts of an
+/// inner shift and the shift directly around it, in that order.
+fn verify_nested_shift_amounts(pattern: &Pattern, pre: &Precondition) -> VerifyResult<()> {
+    use crate::traversals::TraversalEvent as TE;
+
+    fn shift_amount<'a>(op: &'a Operation<'a, Pattern<'a>>) -> Option<Id<'a>> {
+        match (op.operator, op.operands.get(1)) {
+            (Operator::Ishl, Some(Pattern::Constant(c)))
+            | (Operator::Ushr, Some(Pattern::Constant(c)))
+            | (Operator::Sshr, Some(Pattern::Constant(c))) => Some(c.id),
+            _ => None,
+        }
+    }
+
+    let (inner_id, outer_id) = match (&pre.operands[0], &pre.operands[1]) {
+        (ConstraintOperand::Constant(a), ConstraintOperand::Constant(b)) => (a.id, b.id),
+        _ => unreachable!("checked in `type_constrain_precondition`"),
+    };
+
+    for (event, node) in Dfs::new(pattern) {
+        let outer = match (event, node) {
+            (TE::Enter, DynAstRef::PatternOperation(op)) => op,
+            _ => continue,
+        };
+        let inner = match outer.operands.first() {
+            Some(Pattern::Operation(inner)) if inner.operator == outer.operator => inner,
+            _ => continue,
+        };
+        if shift_amount(outer) == Some(outer_id) && shift_amount(inner) == Some(inner_id) {
+            return Ok(());
+        }
+    }
+
+    Err(WastError::new(
+        pre.span,
+        "`shifts-combine` operands must be the constant amounts of an inner shift and the \
+         same kind of shift directly around it, like `$A` and `$B` in \
+         `(ishl (ishl $x $A) $B)`"
+            .into(),
+    )
+    .into())
+}
+
+/// Check that an `is-double-involution` precondition's variable is first bound
+/// as the only operand of a unary operation, which is itself the only operand of
+/// another, like `$x` in `(bnot (bnot $x))`.
+fn verify_double_involution(pattern: &Pattern, pre: &Precondition) -> VerifyResult<()> {
+    use crate::traversals::TraversalEvent as TE;
+
+    let id = match &pre.operands[0] {
+        ConstraintOperand::Variable(v) => v.id,
+        _ => unreachable!("checked in `type_constrain_precondition`"),
+    };
+
+    for (event, node) in Dfs::new(pattern) {
+        match (event, node) {
+            (TE::Enter, DynAstRef::PatternOperation(outer)) => {
+                let inner = match outer.operands.as_slice() {
+                    [Pattern::Operation(inner)] => inner,
+                    _ => continue,
+                };
+                match inner.operands.as_slice() {
+                    [Pattern::Variable(v)] if v.id == id => return Ok(()),
+                    _ => continue,
+                }
+            }
+            // The variable is bound somewhere else first.
+            (TE::Enter, DynAstRef::Pattern(Pattern::Variable(v))) if v.id == id => break,
+            _ => continue,
+        }
+    }
+
+    Err(WastError::new(
+        pre.span,
+        "the `is-double-involution` operand must first be bound as the only operand of a unary \
+         operation that is itself the only operand of another, like `$x` in \
+         `(bnot (bnot $x))`"
+            .into(),
+    )
+    .into())
+}
+
+/// Check that an `is-add-of-neg-const` precondition's constant is first bound
+/// as the second operand of an `iadd`, like `$C` in `(iadd $x $C)`.
+fn verify_add_of_neg_const(pattern: &Pattern, pre: &Precondition) -> VerifyResult<()> {
+    // Find the operation and operand index where `id` first occurs, in the
+    // same pre-order that linearization remembers first occurrences in.
+    fn first_occurrence<'a, 'b>(
+        pattern: &'b Pattern<'a>,
+        id: Id<'a>,
+        parent: Option<(&'b Operation<'a, Pattern<'a>>, usize)>,
+    ) -> Option<Option<(&'b Operation<'a, Pattern<'a>>, usize)>> {
+        match pattern {
+            Pattern::Constant(c) if c.id == id => Some(parent),
+            Pattern::Operation(op) => op
+                .operands
+                .iter()
+                .enumerate()
+                .find_map(|(i, operand)| first_occurrence(operand, id, Some((op, i)))),
+            _ => None,
+        }
+    }
+
+    let id = match &pre.operands[0] {
+        ConstraintOperand::Constant(c) => c.id,
+        _ => unreachable!("checked in `type_constrain_precondition`"),
+    };
+
+    match first_occurrence(pattern, id, None) {
+        Some(Some((op, 1))) if op.operator == Operator::Iadd && op.alternatives.is_empty() => {
+            Ok(())
+        }
+        _ => Err(WastError::new(
+            pre.span,
+            "the `is-add-of-neg-const` operand must first be bound as the second operand of an \
+             `iadd`, like `$C` in `(iadd $x $C)`"
+                .into(),
+        )
+        .into()),
+    }
+}
 
 fn type_constrain_precondition<'a>(
     context: &mut TypingContext<'a>,
@@ -1148,202 +1929,2160 @@ fn type_constrain_precondition<'a>(
                 .into()),
             }
         }
-        Constraint::FitsInNativeWord => {
+        Constraint::IsByteMask => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-byte-mask` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the first operand of `is-byte-mask` must be a constant binding".into(),
+                    )
+                    .into())
+                }
+            }
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 1 <= *value && *value <= 0xffff => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-byte-mask` precondition requires a non-zero bitmap of at most 16 \
+                     bytes"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsShiftByConst => {
             if pre.operands.len() != 1 {
                 return Err(WastError::new(
                     pre.span,
                     format!(
-                        "the `fits-in-native-word` precondition requires exactly 1 operand, found \
+                        "the `is-shift-by-const` precondition requires exactly 1 operand, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                    Ok(())
+                }
+                op => Err(WastError::new(
+                    op.span(),
+                    "`is-shift-by-const` operands must be variable bindings".into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsCallResult => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-call-result` precondition requires exactly 1 operand, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Variable(_) => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "`is-call-result` operands must be variable bindings".into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::SingleUse | Constraint::AllOperandsConst | Constraint::SelectConstArms => {
+            let name = match pre.constraint {
+                Constraint::SingleUse => "single-use",
+                Constraint::AllOperandsConst => "all-operands-const",
+                _ => "select-const-arms",
+            };
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `{}` precondition requires exactly 1 operand, found {} operands",
+                        name,
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Variable(_) => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    format!("`{}` operands must be variable bindings", name),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsSextend | Constraint::IsUextend => {
+            let name = if pre.constraint == Constraint::IsSextend {
+                "is-sextend"
+            } else {
+                "is-uextend"
+            };
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `{}` precondition requires exactly 2 operands, found {} operands",
+                        name,
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            let id = match &pre.operands[0] {
+                ConstraintOperand::Variable(Variable { id, .. }) => *id,
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        format!("the first operand of `{}` must be a variable binding", name),
+                    )
+                    .into())
+                }
+            };
+            match pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if value == 8 || value == 16 || value == 32 || value == 64 => {}
+                ref op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        format!(
+                            "the `{}` precondition requires a source bit width of 8, 16, 32, or \
+                             64",
+                            name
+                        ),
+                    )
+                    .into())
+                }
+            }
+            let ty = context.get_type_var_for_id(id)?;
+            context.assert_is_integer(pre.span(), &ty);
+            Ok(())
+        }
+        Constraint::PopcountEq | Constraint::PopcountLe => {
+            let name = if pre.constraint == Constraint::PopcountEq {
+                "popcount-eq"
+            } else {
+                "popcount-le"
+            };
+
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `{}` precondition requires exactly 2 operands, found {} operands",
+                        name,
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        format!(
+                        "the `{}` precondition requires a constant binding as its first operand",
+                        name
+                    ),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 <= *value && *value <= 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    format!(
+                        "the `{}` precondition requires an integer between 0 and 128 as its \
+                         second operand",
+                        name
+                    ),
+                )
+                .into()),
+            }
+        }
+        Constraint::BlockPredecessors => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `block-predecessors` precondition requires exactly 1 operand, found \
                          {} operands",
                         pre.operands.len(),
                     ),
                 )
                 .into());
             }
+            match &pre.operands[0] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 <= *value && *value <= i128::from(std::u32::MAX) => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `block-predecessors` precondition requires a non-negative integer \
+                     count"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::NegationFitsInBits => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `negation-fits-in-bits` precondition requires exactly 2 operands, \
+                         found {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
 
-            match pre.operands[0] {
-                ConstraintOperand::ValueLiteral(_) => {
-                    return Err(anyhow::anyhow!(
-                        "the `fits-in-native-word` precondition requires a constant or variable as \
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `negation-fits-in-bits` precondition requires a constant binding as \
                          its first operand"
+                            .into(),
                     )
                     .into())
                 }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 1 <= *value && *value <= 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `negation-fits-in-bits` precondition requires an integer between 1 and \
+                     128 as its second operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsOne => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-one` precondition requires exactly 1 operand, found {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
                 ConstraintOperand::Constant(Constant { id, .. })
                 | ConstraintOperand::Variable(Variable { id, .. }) => {
-                    context.get_type_var_for_id(id)?;
+                    context.get_type_var_for_id(*id)?;
                     Ok(())
                 }
-            }
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-one` precondition requires a constant or variable as its operand"
+                        .into(),
+                )
+                .into()),
+            }
         }
-    }
-}
+        Constraint::KnownNonNegative => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `known-non-negative` precondition requires exactly 1 operand, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                    Ok(())
+                }
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `known-non-negative` precondition requires a constant or variable as \
+                     its operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::FitsInImm12 | Constraint::FitsInImm32 => {
+            let name = if pre.constraint == Constraint::FitsInImm12 {
+                "fits-in-imm12"
+            } else {
+                "fits-in-imm32"
+            };
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `{}` precondition requires exactly 1 operand, found {} operands",
+                        name,
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                    Ok(())
+                }
+                op => Err(WastError::new(
+                    op.span(),
+                    format!(
+                        "the `{}` precondition requires a constant binding as its operand",
+                        name
+                    ),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsWidthMinus => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-width-minus` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `is-width-minus` precondition requires a constant binding as its \
+                         first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 <= *value && *value <= 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-width-minus` precondition requires an integer between 0 and 128 as \
+                     its second operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::FitsInNativeWord => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `fits-in-native-word` precondition requires exactly 1 operand, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match pre.operands[0] {
+                ConstraintOperand::ValueLiteral(_) => {
+                    return Err(anyhow::anyhow!(
+                        "the `fits-in-native-word` precondition requires a constant or variable as \
+                         its first operand"
+                    )
+                    .into())
+                }
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    context.get_type_var_for_id(id)?;
+                    Ok(())
+                }
+            }
+        }
+        Constraint::EquivalentValues => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `equivalent-values` precondition requires exactly 2 operands, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            let mut tys = vec![];
+            for op in &pre.operands {
+                match op {
+                    ConstraintOperand::ValueLiteral(_) => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "`equivalent-values` operands must be constants or variables".into(),
+                        )
+                        .into())
+                    }
+                    ConstraintOperand::Constant(Constant { id, .. })
+                    | ConstraintOperand::Variable(Variable { id, .. }) => {
+                        tys.push(context.get_type_var_for_id(*id)?);
+                    }
+                }
+            }
+
+            context.assert_type_eq(
+                pre.span,
+                &tys[0],
+                &tys[1],
+                Some("`equivalent-values` operands must have the same type".into()),
+            );
+            Ok(())
+        }
+        Constraint::TrailingZeros => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `trailing-zeros` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `trailing-zeros` precondition requires a constant binding as its \
+                         first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 <= *value && *value <= 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `trailing-zeros` precondition requires an integer between 0 and 128 as \
+                     its second operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IcmpZero => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `icmp-zero` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            let id = match &pre.operands[0] {
+                ConstraintOperand::Variable(Variable { id, .. }) => *id,
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the first operand of `icmp-zero` must be a variable binding".into(),
+                    )
+                    .into())
+                }
+            };
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::ConditionCode(_)) => {}
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the second operand of `icmp-zero` must be a condition code literal".into(),
+                    )
+                    .into())
+                }
+            }
+            let ty = context.get_type_var_for_id(id)?;
+            context.assert_is_bool(pre.span(), &ty);
+            Ok(())
+        }
+        Constraint::IsDoubleInvolution => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-double-involution` precondition requires exactly 1 operand, \
+                         found {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+            match &pre.operands[0] {
+                ConstraintOperand::Variable(_) => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "`is-double-involution` operands must be variable bindings".into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::ShiftsCombine => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `shifts-combine` precondition requires exactly 2 operands, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            for op in &pre.operands {
+                match op {
+                    ConstraintOperand::Constant(Constant { id, span }) => {
+                        let ty = context.get_type_var_for_id(*id)?;
+                        context.assert_is_integer(*span, &ty);
+                    }
+                    _ => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "`shifts-combine` operands must be constant bindings".into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            Ok(())
+        }
+        Constraint::RedundantMaskForShift => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `redundant-mask-for-shift` precondition requires exactly 2 operands, \
+                         found {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            for op in &pre.operands {
+                match op {
+                    ConstraintOperand::Constant(Constant { id, span }) => {
+                        let ty = context.get_type_var_for_id(*id)?;
+                        context.assert_is_integer(*span, &ty);
+                    }
+                    _ => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "`redundant-mask-for-shift` operands must be constant bindings".into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            Ok(())
+        }
+        Constraint::IsEncodableLogicalImm => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-encodable-logical-imm` precondition requires exactly 2 operands, \
+                         found {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `is-encodable-logical-imm` precondition requires a variable or \
+                         constant binding as its first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if *value == 32 || *value == 64 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-encodable-logical-imm` precondition requires a register width of 32 \
+                     or 64 as its second operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsAddOfNegConst => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-add-of-neg-const` precondition requires exactly 1 operand, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, span }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(*span, &ty);
+                    Ok(())
+                }
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-add-of-neg-const` operand must be a constant binding".into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsShiftedImm => {
+            if pre.operands.len() != 3 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-shifted-imm` precondition requires exactly 3 operands, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `is-shifted-imm` precondition requires a variable or constant \
+                         binding as its first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 1 <= *value && *value <= 128 => {}
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `is-shifted-imm` precondition requires a base width between 1 and \
+                         128 as its second operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[2] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 <= *value && *value < 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-shifted-imm` precondition requires a shift amount between 0 and 127 \
+                     as its third operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::IsShiftedImm8 => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-shifted-imm8` precondition requires exactly 1 operand, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                    Ok(())
+                }
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-shifted-imm8` operand must be a variable or constant binding".into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::CcsCombine => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `ccs-combine` precondition requires exactly 2 operands, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            for op in &pre.operands {
+                match op {
+                    ConstraintOperand::Constant(Constant { id, span }) => {
+                        let ty = context.get_type_var_for_id(*id)?;
+                        context.assert_is_cc(*span, &ty);
+                    }
+                    _ => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "`ccs-combine` operands must be constant bindings".into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            Ok(())
+        }
+        Constraint::IsOneLessThan => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-one-less-than` precondition requires exactly 2 operands, found \
+                         {} operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            let mut tys = vec![];
+            for op in &pre.operands {
+                match op {
+                    ConstraintOperand::Constant(Constant { id, .. }) => {
+                        tys.push(context.get_type_var_for_id(*id)?);
+                    }
+                    _ => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "`is-one-less-than` operands must be constant bindings".into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+
+            context.assert_type_eq(
+                pre.span,
+                &tys[0],
+                &tys[1],
+                Some("`is-one-less-than` operands must have the same type".into()),
+            );
+            Ok(())
+        }
+        Constraint::IsScaledBy => {
+            if pre.operands.len() != 3 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-scaled-by` precondition requires exactly 3 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            let mut tys = vec![];
+            for op in &pre.operands[..2] {
+                match op {
+                    ConstraintOperand::Constant(Constant { id, .. }) => {
+                        let ty = context.get_type_var_for_id(*id)?;
+                        context.assert_is_integer(op.span(), &ty);
+                        tys.push(ty);
+                    }
+                    _ => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "the first two `is-scaled-by` operands must be constant bindings"
+                                .into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            context.assert_type_eq(
+                pre.span,
+                &tys[0],
+                &tys[1],
+                Some("`is-scaled-by` operands must have the same type".into()),
+            );
+
+            match &pre.operands[2] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(_)) => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the last `is-scaled-by` operand must be an integer factor".into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::InSet => {
+            if pre.operands.is_empty() {
+                return Err(WastError::new(
+                    pre.span,
+                    "the `in-set` precondition requires a constant operand".into(),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `in-set` precondition requires a constant binding as its first \
+                         operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            for op in &pre.operands[1..] {
+                match op {
+                    ConstraintOperand::ValueLiteral(ValueLiteral::Integer(_)) => {}
+                    op => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "the `in-set` precondition's set members must be integers".into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            Ok(())
+        }
+        Constraint::BoundedBy => {
+            if pre.operands.len() != 3 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `bounded-by` precondition requires exactly 3 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `bounded-by` precondition requires a variable or constant binding \
+                         as its first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            let mut bounds = vec![];
+            for op in &pre.operands[1..] {
+                match op {
+                    ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                        value,
+                        ..
+                    })) if *value >= 0 => bounds.push(*value),
+                    op => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "the `bounded-by` precondition's bounds must be non-negative \
+                             integers"
+                                .into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+            if bounds[0] > bounds[1] {
+                return Err(WastError::new(
+                    pre.span,
+                    "the `bounded-by` precondition's lower bound must not be greater than its \
+                     upper bound"
+                        .into(),
+                )
+                .into());
+            }
+            Ok(())
+        }
+        Constraint::IsAligned => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-aligned` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `is-aligned` precondition requires a variable or constant binding \
+                         as its first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 < *value && *value <= 128 && (*value as u64).is_power_of_two() => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-aligned` precondition requires a power of two between 1 and 128 as \
+                     its second operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::HighBitsZero => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `high-bits-zero` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `high-bits-zero` precondition requires a variable or constant \
+                         binding as its first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 0 < *value && *value < 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `high-bits-zero` precondition requires a width between 1 and 127 as its \
+                     second operand"
+                        .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::MemFlags => {
+            if pre.operands.len() != 3 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `mem-flags` precondition requires exactly 3 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Variable(Variable { id, .. }) => {
+                    context.get_type_var_for_id(*id)?;
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the `mem-flags` precondition requires a variable bound to a load or \
+                         store as its first operand"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            let mut flags = vec![];
+            for op in &pre.operands[1..] {
+                match op {
+                    ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                        value,
+                        ..
+                    })) if 0 <= *value && *value <= 0xff => flags.push(*value),
+                    op => {
+                        return Err(WastError::new(
+                            op.span(),
+                            "the `mem-flags` precondition's mask and value must be integers \
+                             between 0 and 255"
+                                .into(),
+                        )
+                        .into())
+                    }
+                }
+            }
+
+            if flags[1] & !flags[0] != 0 {
+                return Err(WastError::new(
+                    pre.operands[2].span(),
+                    "the `mem-flags` precondition's value has bits set that are not in its mask"
+                        .into(),
+                )
+                .into());
+            }
+            Ok(())
+        }
+        Constraint::IsMaskConst => {
+            if pre.operands.len() != 2 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `is-mask-const` precondition requires exactly 2 operands, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    let ty = context.get_type_var_for_id(*id)?;
+                    context.assert_is_integer(pre.span(), &ty);
+                }
+                op => {
+                    return Err(WastError::new(
+                        op.span(),
+                        "the first operand of `is-mask-const` must be a constant or variable"
+                            .into(),
+                    )
+                    .into())
+                }
+            }
+
+            match &pre.operands[1] {
+                ConstraintOperand::ValueLiteral(ValueLiteral::Integer(Integer {
+                    value, ..
+                })) if 1 <= *value && *value <= 128 => Ok(()),
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `is-mask-const` precondition requires a number of bits between 1 and \
+                     128"
+                    .into(),
+                )
+                .into()),
+            }
+        }
+        Constraint::Predicate => {
+            if pre.operands.len() != 1 {
+                return Err(WastError::new(
+                    pre.span,
+                    format!(
+                        "the `predicate` precondition requires exactly 1 operand, found {} \
+                         operands",
+                        pre.operands.len(),
+                    ),
+                )
+                .into());
+            }
+
+            match &pre.operands[0] {
+                ConstraintOperand::Constant(Constant { id, .. })
+                | ConstraintOperand::Variable(Variable { id, .. }) => {
+                    context.get_type_var_for_id(*id)?;
+                    Ok(())
+                }
+                op => Err(WastError::new(
+                    op.span(),
+                    "the `predicate` precondition's operand must be a constant or variable".into(),
+                )
+                .into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! verify_ok {
+        ($name:ident, $src:expr) => {
+            #[test]
+            fn $name() {
+                let buf = wast::parser::ParseBuffer::new($src).expect("should lex OK");
+                let opts = match wast::parser::parse::<Optimizations>(&buf) {
+                    Ok(opts) => opts,
+                    Err(mut e) => {
+                        e.set_path(Path::new(stringify!($name)));
+                        e.set_text($src);
+                        eprintln!("{}", e);
+                        panic!("should parse OK")
+                    }
+                };
+                match verify(&opts) {
+                    Ok(_) => return,
+                    Err(mut e) => {
+                        e.set_path(Path::new(stringify!($name)));
+                        e.set_text($src);
+                        eprintln!("{}", e);
+                        panic!("should verify OK")
+                    }
+                }
+            }
+        };
+    }
+
+    macro_rules! verify_err {
+        ($name:ident, $src:expr) => {
+            #[test]
+            fn $name() {
+                let buf = wast::parser::ParseBuffer::new($src).expect("should lex OK");
+                let opts = match wast::parser::parse::<Optimizations>(&buf) {
+                    Ok(opts) => opts,
+                    Err(mut e) => {
+                        e.set_path(Path::new(stringify!($name)));
+                        e.set_text($src);
+                        eprintln!("{}", e);
+                        panic!("should parse OK")
+                    }
+                };
+                match verify(&opts) {
+                    Ok(_) => panic!("expected a verification error, but it verified OK"),
+                    Err(mut e) => {
+                        e.set_path(Path::new(stringify!($name)));
+                        e.set_text($src);
+                        eprintln!("{}", e);
+                        return;
+                    }
+                }
+            }
+        };
+    }
+
+    verify_ok!(bool_0, "(=> true true)");
+    verify_ok!(bool_1, "(=> false false)");
+    verify_ok!(bool_2, "(=> true false)");
+    verify_ok!(bool_3, "(=> false true)");
+
+    verify_err!(bool_is_not_int_0, "(=> true 42)");
+    verify_err!(bool_is_not_int_1, "(=> 42 true)");
+
+    verify_ok!(
+        bit_width_0,
+        "
+(=> (when (iadd $x $y)
+          (bit-width $x 32)
+          (bit-width $y 32))
+    (iadd $x $y))
+"
+    );
+    verify_err!(
+        bit_width_1,
+        "
+(=> (when (iadd $x $y)
+          (bit-width $x 32)
+          (bit-width $y 64))
+    (iadd $x $y))
+"
+    );
+    verify_err!(
+        bit_width_2,
+        "
+(=> (when (iconst $C)
+          (bit-width $C))
+    5)
+"
+    );
+    verify_err!(
+        bit_width_3,
+        "
+(=> (when (iconst $C)
+          (bit-width 32 32))
+    5)
+"
+    );
+    verify_err!(
+        bit_width_4,
+        "
+(=> (when (iconst $C)
+          (bit-width $C $C))
+    5)
+"
+    );
+    verify_err!(
+        bit_width_5,
+        "
+(=> (when (iconst $C)
+          (bit-width $C2 32))
+    5)
+"
+    );
+    verify_err!(
+        bit_width_6,
+        "
+(=> (when (iconst $C)
+          (bit-width $C2 33))
+    5)
+"
+    );
+
+    verify_ok!(
+        is_power_of_two_0,
+        "
+(=> (when (imul $x $C)
+          (is-power-of-two $C))
+    (ishl $x $(log2 $C)))
+"
+    );
+    verify_err!(
+        is_power_of_two_1,
+        "
+(=> (when (imul $x $C)
+          (is-power-of-two))
+    5)
+"
+    );
+    verify_err!(
+        is_power_of_two_2,
+        "
+(=> (when (imul $x $C)
+          (is-power-of-two $C $C))
+    5)
+"
+    );
+
+    verify_ok!(pattern_ops_0, "(=> (iadd $x $C) 5)");
+    verify_err!(pattern_ops_1, "(=> (iadd $x) 5)");
+    verify_err!(pattern_ops_2, "(=> (iadd $x $y $z) 5)");
+
+    verify_ok!(unquote_0, "(=> $C $(log2 $C))");
+    verify_err!(unquote_1, "(=> (iadd $C $D) $(log2 $C $D))");
+    verify_err!(unquote_2, "(=> $x $(log2))");
+    verify_ok!(unquote_3, "(=> $C $(neg $C))");
+    verify_err!(unquote_4, "(=> $x $(neg))");
+    verify_err!(unquote_5, "(=> (iadd $x $y) $(neg $x $y))");
+    verify_err!(unquote_6, "(=> $x $(neg $x))");
+
+    verify_ok!(rhs_0, "(=> $x (iadd $x (iconst 0)))");
+    verify_err!(rhs_1, "(=> $x (iadd $x))");
+    verify_err!(rhs_2, "(=> $x (iadd $x 0 0))");
+
+    verify_ok!(
+        let_0,
+        "(=> (iadd $x $y) (let (($t (iadd $x $y))) (imul $t $t)))"
+    );
+    verify_ok!(
+        let_1,
+        "(=> (iadd $x $y) (let (($t (iadd $x $y)) ($u (imul $t $t))) (isub $u $t)))"
+    );
+    verify_ok!(
+        let_2,
+        "(=> (iadd $x $y) (imul (let (($t (iadd $x $y))) (ishl $t $t)) $x))"
+    );
+    verify_err!(
+        let_3,
+        "(=> (iadd $x $y) (let (($t (iadd $x $u)) ($u (imul $x $x))) (iadd $t $u)))"
+    );
+    verify_err!(
+        let_4,
+        "(=> (iadd $x $y) (let (($x (iadd $x $y))) (imul $x $x)))"
+    );
+    verify_err!(
+        let_5,
+        "(=> (iadd $x $y) (let (($t (iadd $x $y)) ($t (imul $x $y))) (imul $t $t)))"
+    );
+    verify_err!(
+        let_6,
+        "(=> (iadd $x $y) (iadd (let (($t (iadd $x $y))) (ishl $t $t)) $t))"
+    );
+    verify_err!(let_7, "(=> (iadd $x $y) (let (($t (iadd $x $y))) $t))");
+    verify_err!(let_8, "(=> (bint $x) (let (($t (iadd $x 1))) (bint $t)))");
+
+    verify_ok!(
+        iconst_like_0,
+        "(=> (icmp ult $x 1) (icmp eq $x (iconst-like $x 0)))"
+    );
+    verify_ok!(iconst_like_1, "(=> (bxor $x $x) (iconst-like $x 0))");
+    verify_err!(iconst_like_2, "(=> (icmp ult $x 1) (iconst-like $x 0))");
+    verify_err!(iconst_like_3, "(=> (bxor $x $x) (iconst-like $y 0))");
+    verify_err!(
+        iconst_like_4,
+        "(=> (iadd $x $y) (let (($t (iadd $x $y))) (iadd $t (iconst-like $t 1))))"
+    );
+
+    verify_ok!(
+        block_predecessors_0,
+        "(=> (when (iadd $x 0) (block-predecessors 1)) $x)"
+    );
+    verify_err!(
+        block_predecessors_1,
+        "(=> (when (iadd $x 0) (block-predecessors $x)) $x)"
+    );
+    verify_err!(
+        block_predecessors_2,
+        "(=> (when (iadd $x 0) (block-predecessors -1)) $x)"
+    );
+    verify_err!(
+        block_predecessors_3,
+        "(=> (when (iadd $x 0) (block-predecessors)) $x)"
+    );
+    verify_ok!(
+        is_byte_mask_0,
+        "(=> (when (band $x $C) (is-byte-mask $C 2)) $x)"
+    );
+    verify_err!(
+        is_byte_mask_1,
+        "(=> (when (band $x $y) (is-byte-mask $y 2)) $x)"
+    );
+    verify_err!(
+        is_byte_mask_2,
+        "(=> (when (band $x $C) (is-byte-mask $C $C)) $x)"
+    );
+    verify_err!(
+        is_byte_mask_3,
+        "(=> (when (band $x $C) (is-byte-mask $C)) $x)"
+    );
+    verify_err!(
+        is_byte_mask_4,
+        "(=> (when (band $x $C) (is-byte-mask $C 0)) $x)"
+    );
+    verify_err!(
+        is_byte_mask_5,
+        "(=> (when (band $x $C) (is-byte-mask $C 0x10000)) $x)"
+    );
+    verify_ok!(
+        is_shift_by_const_0,
+        "(=> (when (iadd $x $y) (is-shift-by-const $x)) (iadd $y $x))"
+    );
+    verify_err!(
+        is_shift_by_const_1,
+        "(=> (when (iadd $x $C) (is-shift-by-const $C)) $x)"
+    );
+    verify_err!(
+        is_shift_by_const_2,
+        "(=> (when (iadd $x $y) (is-shift-by-const)) $x)"
+    );
+    verify_ok!(in_set_0, "(=> (when (imul $x $C) (in-set $C 1 2 4 8)) $x)");
+    verify_ok!(in_set_1, "(=> (when (imul $x $C) (in-set $C)) $x)");
+    verify_err!(in_set_2, "(=> (when (imul $x $y) (in-set $y 1 2)) $x)");
+    verify_err!(in_set_3, "(=> (when (imul $x $C) (in-set $C $x)) $x)");
+    verify_err!(in_set_4, "(=> (when (imul $x $C) (in-set)) $x)");
+    verify_ok!(
+        bounded_by_0,
+        "(=> (when (icmp ult $x 256) (bounded-by $x 0 255)) true)"
+    );
+    verify_ok!(
+        bounded_by_1,
+        "(=> (when (iadd $x $C) (bounded-by $C 1 1)) $x)"
+    );
+    verify_err!(
+        bounded_by_2,
+        "(=> (when (iadd $x $y) (bounded-by $x 9 1)) $x)"
+    );
+    verify_err!(
+        bounded_by_3,
+        "(=> (when (iadd $x $y) (bounded-by $x -1 1)) $x)"
+    );
+    verify_err!(
+        bounded_by_4,
+        "(=> (when (iadd $x $y) (bounded-by $x 0)) $x)"
+    );
+    verify_err!(
+        bounded_by_5,
+        "(=> (when (iadd $x $y) (bounded-by 0 0 1)) $x)"
+    );
+    verify_ok!(
+        is_aligned_0,
+        "(=> (when (band $p -8) (is-aligned $p 8)) $p)"
+    );
+    verify_ok!(
+        is_aligned_1,
+        "(=> (when (iadd $x $C) (is-aligned $C 1)) $x)"
+    );
+    verify_err!(
+        is_aligned_2,
+        "(=> (when (iadd $x $y) (is-aligned $x 12)) $x)"
+    );
+    verify_err!(
+        is_aligned_3,
+        "(=> (when (iadd $x $y) (is-aligned $x 256)) $x)"
+    );
+    verify_err!(is_aligned_4, "(=> (when (iadd $x $y) (is-aligned $x)) $x)");
+    verify_err!(is_aligned_5, "(=> (when (iadd $x $y) (is-aligned 8 8)) $x)");
+    verify_ok!(
+        high_bits_zero_0,
+        "(=> (when (band $x 255) (high-bits-zero $x 8)) $x)"
+    );
+    verify_ok!(
+        high_bits_zero_1,
+        "(=> (when (iadd $x $C) (high-bits-zero $C 1)) $x)"
+    );
+    verify_err!(
+        high_bits_zero_2,
+        "(=> (when (iadd $x $y) (high-bits-zero $x 0)) $x)"
+    );
+    verify_err!(
+        high_bits_zero_3,
+        "(=> (when (iadd $x $y) (high-bits-zero $x 128)) $x)"
+    );
+    verify_err!(
+        high_bits_zero_4,
+        "(=> (when (iadd $x $y) (high-bits-zero $x)) $x)"
+    );
+    verify_err!(
+        high_bits_zero_5,
+        "(=> (when (iadd $x $y) (high-bits-zero 8 8)) $x)"
+    );
+
+    verify_ok!(
+        is_double_involution_0,
+        "(=> (when (bnot (bnot $x)) (is-double-involution $x)) $x)"
+    );
+    verify_ok!(
+        is_double_involution_1,
+        "(=> (when ((one-of (opcode ineg) (opcode iabs)) ((one-of (opcode ineg) (opcode iabs)) $x))
+                   (is-double-involution $x))
+             $x)"
+    );
+    verify_err!(
+        is_double_involution_2,
+        "(=> (when (bnot $x) (is-double-involution $x)) $x)"
+    );
+    verify_err!(
+        is_double_involution_3,
+        "(=> (when (bnot (bnot $x)) (is-double-involution $x $x)) $x)"
+    );
+    verify_err!(
+        is_double_involution_4,
+        "(=> (when (bnot (bnot $C)) (is-double-involution $C)) $C)"
+    );
+    verify_err!(
+        is_double_involution_5,
+        "(=> (when (bxor $x (bnot (bnot $x))) (is-double-involution $x)) 0)"
+    );
+
+    verify_ok!(
+        trailing_zeros_0,
+        "(=> (when (iadd_imm $C $p) (trailing-zeros $C 3))
+             (iadd_imm $(align_down $C 16) $p))"
+    );
+    verify_err!(
+        trailing_zeros_1,
+        "(=> (when (iadd_imm $C $p) (trailing-zeros $p 3)) $p)"
+    );
+    verify_err!(
+        trailing_zeros_2,
+        "(=> (when (iadd_imm $C $p) (trailing-zeros $C 129)) $p)"
+    );
+    verify_err!(
+        trailing_zeros_3,
+        "(=> (when (iadd_imm $C $p) (trailing-zeros $C)) $p)"
+    );
+
+    verify_ok!(is_one_0, "(=> (when (imul $x $y) (is-one $y)) $x)");
+    verify_ok!(is_one_1, "(=> (when (band $x $C) (is-one $C)) $x)");
+    verify_err!(is_one_2, "(=> (when (imul $x $y) (is-one 1)) $x)");
+    verify_err!(is_one_3, "(=> (when (imul $x $y) (is-one $x $y)) $x)");
+    verify_err!(is_one_4, "(=> (when (imul $x $y) (is-one $z)) $x)");
+
+    verify_ok!(
+        known_non_negative_0,
+        "(=> (when (sdiv $x $C) (known-non-negative $x) (is-power-of-two $C)) \
+             (ushr $x $(log2 $C)))"
+    );
+    verify_err!(
+        known_non_negative_1,
+        "(=> (when (iadd $x $y) (known-non-negative 1)) $x)"
+    );
+    verify_err!(
+        known_non_negative_2,
+        "(=> (when (iadd $x $y) (known-non-negative $x $y)) $x)"
+    );
+    verify_err!(
+        known_non_negative_3,
+        "(=> (when (bint $x) (known-non-negative $x)) 1)"
+    );
+
+    verify_ok!(
+        redundant_mask_for_shift_0,
+        "(=> (when (ushr (band $x $MASK) $N) (redundant-mask-for-shift $MASK $N)) (ushr $x $N))"
+    );
+    verify_err!(
+        redundant_mask_for_shift_1,
+        "(=> (when (ushr (band $x $y) $N) (redundant-mask-for-shift $y $N)) (ushr $x $N))"
+    );
+    verify_err!(
+        redundant_mask_for_shift_2,
+        "(=> (when (ushr (band $x $MASK) $N) (redundant-mask-for-shift $MASK)) (ushr $x $N))"
+    );
+    verify_ok!(
+        is_encodable_logical_imm_0,
+        "(=> (when (band $x $C) (is-encodable-logical-imm $C 64)) (band_imm $C $x))"
+    );
+    verify_ok!(
+        is_encodable_logical_imm_1,
+        "(=> (when (bor $x $y) (is-encodable-logical-imm $y 32)) (bor $x $y))"
+    );
+    verify_err!(
+        is_encodable_logical_imm_2,
+        "(=> (when (band $x $C) (is-encodable-logical-imm $C 16)) (band_imm $C $x))"
+    );
+    verify_err!(
+        is_encodable_logical_imm_3,
+        "(=> (when (band $x $C) (is-encodable-logical-imm $C)) (band_imm $C $x))"
+    );
+    verify_err!(
+        is_encodable_logical_imm_4,
+        "(=> (when (band $x $C) (is-encodable-logical-imm 255 32)) (band_imm $C $x))"
+    );
+
+    verify_ok!(
+        is_add_of_neg_const_0,
+        "(=> (when (iadd $x $C) (is-add-of-neg-const $C)) (isub $x $(neg $C)))"
+    );
+    verify_ok!(
+        is_add_of_neg_const_1,
+        "(=> (when (bor $y (iadd $x $C)) (is-add-of-neg-const $C)) (bor $y (isub $x $(neg $C))))"
+    );
+    verify_err!(
+        is_add_of_neg_const_2,
+        "(=> (when (isub $x $C) (is-add-of-neg-const $C)) (iadd $x $(neg $C)))"
+    );
+    verify_err!(
+        is_add_of_neg_const_3,
+        "(=> (when (iadd $C $x) (is-add-of-neg-const $C)) (isub $x $(neg $C)))"
+    );
+    verify_err!(
+        is_add_of_neg_const_4,
+        "(=> (when (iadd $x $y) (is-add-of-neg-const $y)) (isub $x $y))"
+    );
+    verify_err!(
+        is_add_of_neg_const_5,
+        "(=> (when (iadd (bor $C $x) $C) (is-add-of-neg-const $C)) $x)"
+    );
+
+    verify_ok!(
+        is_shifted_imm_0,
+        "(=> (when (iadd $x $C) (is-shifted-imm $C 12 12)) (iadd_imm $(ushr $C 12) $x))"
+    );
+    verify_ok!(
+        is_shifted_imm_1,
+        "(=> (when (iadd $x $y) (is-shifted-imm $y 16 0)) (iadd $x $y))"
+    );
+    verify_err!(
+        is_shifted_imm_2,
+        "(=> (when (iadd $x $C) (is-shifted-imm $C 12)) (iadd_imm $C $x))"
+    );
+    verify_err!(
+        is_shifted_imm_3,
+        "(=> (when (iadd $x $C) (is-shifted-imm $C 0 12)) (iadd_imm $C $x))"
+    );
+    verify_err!(
+        is_shifted_imm_4,
+        "(=> (when (iadd $x $C) (is-shifted-imm $C 12 128)) (iadd_imm $C $x))"
+    );
+    verify_err!(
+        is_shifted_imm_5,
+        "(=> (when (iadd $x $C) (is-shifted-imm 4096 12 12)) (iadd_imm $C $x))"
+    );
+
+    verify_ok!(
+        is_shifted_imm8_0,
+        "(=> (when (iadd $x $C) (is-shifted-imm8 $C))
+             (iadd $x (ishl_imm $(imm8_shift $C) (iconst $(imm8 $C)))))"
+    );
+    verify_err!(
+        is_shifted_imm8_1,
+        "(=> (when (iadd $x $C) (is-shifted-imm8)) (iadd $x $C))"
+    );
+    verify_err!(
+        is_shifted_imm8_2,
+        "(=> (when (iadd $x $C) (is-shifted-imm8 $C 8)) (iadd $x $C))"
+    );
+    verify_err!(
+        is_shifted_imm8_3,
+        "(=> (when (iadd $x $C) (is-shifted-imm8 255)) (iadd $x $C))"
+    );
+
+    verify_ok!(
+        fits_in_imm_0,
+        "(=> (when (iadd $x (iconst $C)) (fits-in-imm32 $C)) (iadd_imm $C $x))"
+    );
+    verify_err!(
+        fits_in_imm_1,
+        "(=> (when (iadd $x (iconst $C)) (fits-in-imm12 $x)) (iadd_imm $C $x))"
+    );
+    verify_err!(
+        fits_in_imm_2,
+        "(=> (when (iadd $x (iconst $C)) (fits-in-imm12 $C 12)) (iadd_imm $C $x))"
+    );
+
+    verify_ok!(
+        cond_0,
+        "(=> (iadd $x (iconst $C))
+             (cond ((fits-in-imm12 $C) (iadd_imm $C $x))
+                   (iadd $x (iconst $C))))"
+    );
+    verify_ok!(
+        cond_1,
+        "(=> (iadd $x (iconst $C))
+             (cond ((fits-in-imm12 $C) (iadd_imm $C $x))
+                   ((fits-in-imm32 $C) (isub $x (iconst $(neg $C))))
+                   (iadd $x (iconst $C))))"
+    );
+    verify_err!(
+        cond_2,
+        "(=> (iadd $x (iconst $C))
+             (cond ((is-power-of-two $C) (iadd_imm $C $x))
+                   (iadd $x (iconst $C))))"
+    );
+    verify_err!(
+        cond_3,
+        "(=> (iadd $x (iconst $C))
+             (cond ((fits-in-imm12 $D) (iadd_imm $C $x))
+                   (iadd $x (iconst $C))))"
+    );
+    verify_err!(
+        cond_4,
+        "(=> (iadd $x (iconst $C))
+             (cond ((fits-in-imm12 $C) true)
+                   (iadd $x (iconst $C))))"
+    );
+    verify_err!(
+        cond_5,
+        "(=> (iadd $x (iconst $C)) (iadd $x (cond ((fits-in-imm12 $C) $x) $x)))"
+    );
+
+    verify_ok!(results_0, "(=> (iadd_cout $x 0) (results $x false))");
+    verify_ok!(results_1, "(=> (isub_bout $x $x) (results 0 false))");
+    verify_ok!(
+        results_2,
+        "(=> (iadd_cout (iconst $C) $x) (iadd_cout $x (iconst $C)))"
+    );
+    verify_ok!(results_3, "(=> (iadd (iadd_cout $x 0) $y) (iadd $x $y))");
+    verify_err!(results_4, "(=> (iadd_cout $x 0) $x)");
+    verify_err!(results_5, "(=> (iadd_cout $x $y) (iadd $x $y))");
+    verify_err!(results_6, "(=> (iadd_cout $x 0) (results $x))");
+    verify_err!(results_7, "(=> (iadd_cout $x 0) (results false $x))");
+    verify_err!(results_8, "(=> (iadd $x 0) (results $x false))");
+    verify_err!(
+        results_9,
+        "(=> (iadd_cout $x $y) (iadd_cout (results $x false) $y))"
+    );
+
+    verify_ok!(
+        type_variable_0,
+        "(=> (iadd (sextend{i64} (iadd{$T} $x $y)) (sextend{i64} (isub{$T} $x $z))) 0)"
+    );
+    verify_ok!(
+        type_variable_1,
+        "(=> (iadd{$T} $x (iadd{$T} $y $z)) (iadd{$T} (iadd $x $y) $z))"
+    );
+    verify_err!(
+        type_variable_2,
+        "(=> (iadd{$T} (ireduce{i32} (iadd{$T} $x $y)) $z) $z)"
+    );
+    verify_err!(
+        type_variable_3,
+        "(=> (iadd (ireduce{i32} (iadd{$T} $x $y)) $z) (iadd{$T} $z $z))"
+    );
+
+    verify_ok!(
+        icmp_zero_0,
+        "(=> (when (bint $c) (icmp-zero $c ne)) (bint $c))"
+    );
+    verify_err!(
+        icmp_zero_1,
+        "(=> (when (bint $c) (icmp-zero $c)) (bint $c))"
+    );
+    verify_err!(
+        icmp_zero_2,
+        "(=> (when (bint $c) (icmp-zero $c 0)) (bint $c))"
+    );
+    verify_err!(icmp_zero_3, "(=> (when (iadd $x $y) (icmp-zero $x eq)) 0)");
+    verify_err!(icmp_zero_4, "(=> (when (bint $C) (icmp-zero $C eq)) 0)");
+    verify_ok!(fold_0, "(=> (when $x (all-operands-const $x)) (fold $x))");
+    verify_ok!(fold_1, "(=> (iadd $x $y) (iadd $x (fold $y)))");
+    verify_err!(fold_2, "(=> (icmp eq $x $y) (fold $x))");
+    verify_err!(fold_3, "(=> $x (fold $y))");
+    verify_err!(
+        fold_4,
+        "(=> (iadd $x $y) (let (($t (iadd $x $y))) (fold $t)))"
+    );
+
+    verify_ok!(
+        shifts_combine_0,
+        "(=> (when (ishl (ishl $x $A) $B) (shifts-combine $A $B)) (ishl $x $(iadd $A $B)))"
+    );
+    verify_err!(
+        shifts_combine_1,
+        "(=> (when (ishl (ishl $x $A) $B) (shifts-combine $B $A)) (ishl $x $(iadd $A $B)))"
+    );
+    verify_err!(
+        shifts_combine_2,
+        "(=> (when (ishl (ushr $x $A) $B) (shifts-combine $A $B)) (ishl $x $(iadd $A $B)))"
+    );
+    verify_err!(
+        shifts_combine_3,
+        "(=> (when (ishl (ishl $x $A) $y) (shifts-combine $A $y)) (ishl $x $A))"
+    );
+
+    verify_ok!(
+        ccs_combine_0,
+        "(=> (when (bor (icmp $C1 $a $b) (icmp $C2 $a $b)) (ccs-combine $C1 $C2))
+             (icmp $(combine_cc $C1 $C2) $a $b))"
+    );
+    verify_err!(
+        ccs_combine_1,
+        "(=> (when (bor (icmp $C1 $a $b) (icmp $C2 $a $b)) (ccs-combine $C1))
+             (icmp $(combine_cc $C1 $C2) $a $b))"
+    );
+    verify_err!(
+        ccs_combine_2,
+        "(=> (when (bor (icmp $C1 $a $b) (icmp $C2 $a $b)) (ccs-combine $C1 $a))
+             (icmp $(combine_cc $C1 $C2) $a $b))"
+    );
+    verify_err!(
+        ccs_combine_3,
+        "(=> (when (iadd $C1 $C2) (ccs-combine $C1 $C2)) 0)"
+    );
+
+    verify_ok!(
+        predicate_0,
+        "(=> (when (iadd $x $y) (predicate is-hot $x)) $y)"
+    );
+    verify_err!(
+        predicate_1,
+        "(=> (when (iadd $x $y) (predicate is-hot)) $y)"
+    );
+    verify_err!(
+        predicate_2,
+        "(=> (when (iadd $x $y) (predicate is-hot $x $y)) $y)"
+    );
+    verify_err!(
+        predicate_3,
+        "(=> (when (iadd $x $y) (predicate is-hot 5)) $y)"
+    );
+    verify_err!(
+        predicate_4,
+        "(=> (when (iadd $x $y) (predicate is-hot $z)) $y)"
+    );
+
+    verify_ok!(
+        is_mask_const_0,
+        "(=> (when (band $x $m) (is-mask-const $m 8)) (band $x 255))"
+    );
+    verify_ok!(
+        is_mask_const_1,
+        "(=> (when (band $x $C) (is-mask-const $C 16)) (band $x $C))"
+    );
+    verify_err!(
+        is_mask_const_2,
+        "(=> (when (band $x $m) (is-mask-const $m)) (band $x $m))"
+    );
+    verify_err!(
+        is_mask_const_3,
+        "(=> (when (band $x $m) (is-mask-const $m 0)) (band $x $m))"
+    );
+    verify_err!(
+        is_mask_const_4,
+        "(=> (when (band $x $m) (is-mask-const 255 8)) (band $x $m))"
+    );
+    verify_ok!(delete_0, "(=> (iadd $x $y) (delete))");
+    verify_ok!(delete_1, "(=> (store $x $p) (delete))");
+    verify_err!(delete_2, "(=> (iadd $x $y) (iadd $x (delete)))");
+    verify_err!(delete_3, "(=> (iadd $x $y) (let (($t (delete))) $x))");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    verify_ok!(expand_iabs_0, "(=> (iabs $x) (expand-iabs $x))");
+    verify_ok!(
+        expand_iabs_1,
+        "(=> (iabs (iadd $x $y)) (expand-iabs (iadd $x $y)))"
+    );
+    verify_ok!(
+        expand_iabs_2,
+        "(=> (iadd (iabs $x) $y) (iadd (expand-iabs $x) $y))"
+    );
+    verify_err!(expand_iabs_3, "(=> (bnot $x) (expand-iabs $x))");
+    verify_err!(expand_iabs_4, "(=> (icmp eq $x $y) (expand-iabs $x))");
+    verify_err!(
+        expand_iabs_5,
+        "(=> (iadd_imm $C $x) (iadd_imm (expand-iabs $C) $x))"
+    );
+    verify_ok!(template_0, "(=> (imul $x -1) (template negate $x))");
+    verify_ok!(
+        template_1,
+        "(=> (iadd (band $x $y) (ushr (bxor $x $y) 1)) (template uavg-floor $x $y))"
+    );
+    verify_ok!(
+        template_2,
+        "(=> (bxor (sshr $x 31) $y) (bxor (template sign-mask $x) $y))"
+    );
+    verify_err!(template_3, "(=> (imul $x -1) (template negative $x))");
+    verify_err!(template_4, "(=> (imul $x -1) (template negate $x $x))");
+    verify_err!(template_5, "(=> (icmp eq $x $y) (template negate $x))");
+    verify_err!(
+        template_6,
+        "(=> (iadd_imm $C $x) (iadd_imm (template negate $C) $x))"
+    );
+    verify_ok!(stored_value_0, "(=> $l (stored-value $l))");
+    verify_ok!(
+        stored_value_1,
+        "(=> (when (iadd $l $x) (mem-flags $l 1 1)) (iadd (stored-value $l) $x))"
+    );
+    verify_err!(stored_value_2, "(=> $l (stored-value $m))");
+    verify_err!(
+        stored_value_3,
+        "(=> (iadd $x $y) (let (($l (load $x))) (stored-value $l)))"
+    );
+    verify_err!(
+        stored_value_4,
+        "(=> (iadd_imm $C $l) (iadd_imm (stored-value $l) $l))"
+    );
 
-    macro_rules! verify_ok {
-        ($name:ident, $src:expr) => {
-            #[test]
-            fn $name() {
-                let buf = wast::parser::ParseBuffer::new($src).expect("should lex OK");
-                let opts = match wast::parser::parse::<Optimizations>(&buf) {
-                    Ok(opts) => opts,
-                    Err(mut e) => {
-                        e.set_path(Path::new(stringify!($name)));
-                        e.set_text($src);
-                        eprintln!("{}", e);
-                        panic!("should parse OK")
-                    }
-                };
-                match verify(&opts) {
-                    Ok(_) => return,
-                    Err(mut e) => {
-                        e.set_path(Path::new(stringify!($name)));
-                        e.set_text($src);
-                        eprintln!("{}", e);
-                        panic!("should verify OK")
-                    }
-                }
-            }
-        };
-    }
+    verify_ok!(
+        call_0,
+        "(=> (band $x $C) (band $x (call mask-for-width $C)))"
+    );
+    verify_ok!(call_1, "(=> (iadd $x $y) (call combine $x $y))");
+    verify_ok!(call_2, "(=> (iadd_imm $C $x) (iadd_imm (call f) $x))");
+    verify_err!(call_3, "(=> (iadd $x $y) (call f $x $y $x))");
+    verify_err!(call_4, "(=> (iadd $x $y) (iadd $x (call f (bnot $y))))");
+    verify_err!(call_5, "(=> (icmp eq $x $y) (call f $x))");
+    verify_err!(call_6, "(=> (iadd $x $y) (call f $z))");
 
-    macro_rules! verify_err {
-        ($name:ident, $src:expr) => {
-            #[test]
-            fn $name() {
-                let buf = wast::parser::ParseBuffer::new($src).expect("should lex OK");
-                let opts = match wast::parser::parse::<Optimizations>(&buf) {
-                    Ok(opts) => opts,
-                    Err(mut e) => {
-                        e.set_path(Path::new(stringify!($name)));
-                        e.set_text($src);
-                        eprintln!("{}", e);
-                        panic!("should parse OK")
-                    }
-                };
-                match verify(&opts) {
-                    Ok(_) => panic!("expected a verification error, but it verified OK"),
-                    Err(mut e) => {
-                        e.set_path(Path::new(stringify!($name)));
-                        e.set_text($src);
-                        eprintln!("{}", e);
-                        return;
-                    }
-                }
-            }
-        };
-    }
+    verify_ok!(
+        all_operands_const_0,
+        "(=> (when $x (all-operands-const $x)) $x)"
+    );
+    verify_ok!(
+        all_operands_const_1,
+        "(=> (when (ineg $x) (all-operands-const $x)) (ineg $x))"
+    );
+    verify_err!(
+        all_operands_const_2,
+        "(=> (when (iadd $x $C) (all-operands-const $C)) $x)"
+    );
 
-    verify_ok!(bool_0, "(=> true true)");
-    verify_ok!(bool_1, "(=> false false)");
-    verify_ok!(bool_2, "(=> true false)");
-    verify_ok!(bool_3, "(=> false true)");
+    verify_ok!(
+        select_const_arms_0,
+        "(=> (when (iadd $x $s) (select-const-arms $s)) (iadd $s $x))"
+    );
+    verify_err!(
+        select_const_arms_1,
+        "(=> (when (iadd $x $C) (select-const-arms $C)) $x)"
+    );
+    verify_err!(
+        select_const_arms_2,
+        "(=> (when (iadd $x $s) (select-const-arms $s $x)) $x)"
+    );
 
-    verify_err!(bool_is_not_int_0, "(=> true 42)");
-    verify_err!(bool_is_not_int_1, "(=> 42 true)");
+    verify_ok!(
+        single_use_0,
+        "(=> (when (ineg $x) (single-use $x)) (ineg $x))"
+    );
+    verify_err!(single_use_1, "(=> (when (iadd $x $C) (single-use $C)) $x)");
+    verify_err!(
+        single_use_2,
+        "(=> (when (iadd $x $y) (single-use $x $y)) $x)"
+    );
 
     verify_ok!(
-        bit_width_0,
-        "
-(=> (when (iadd $x $y)
-          (bit-width $x 32)
-          (bit-width $y 32))
-    (iadd $x $y))
-"
+        is_extension_0,
+        "(=> (when (sshr $x 32) (is-sextend $x 32)) (sshr $x 63))"
+    );
+    verify_ok!(
+        is_extension_1,
+        "(=> (when (ushr $x 32) (is-uextend $x 32)) 0)"
     );
     verify_err!(
-        bit_width_1,
-        "
-(=> (when (iadd $x $y)
-          (bit-width $x 32)
-          (bit-width $y 64))
-    (iadd $x $y))
-"
+        is_extension_2,
+        "(=> (when (iadd $x $C) (is-sextend $C 8)) (iadd $x $C))"
     );
     verify_err!(
-        bit_width_2,
-        "
-(=> (when (iconst $C)
-          (bit-width $C))
-    5)
-"
+        is_extension_3,
+        "(=> (when (iadd $x $y) (is-uextend $x 12)) (iadd $x $y))"
     );
     verify_err!(
-        bit_width_3,
-        "
-(=> (when (iconst $C)
-          (bit-width 32 32))
-    5)
-"
+        is_extension_4,
+        "(=> (when (iadd $x $y) (is-uextend $x)) (iadd $x $y))"
+    );
+
+    verify_ok!(
+        is_call_result_0,
+        "(=> (when (iadd $x 0) (is-call-result $x)) $x)"
     );
     verify_err!(
-        bit_width_4,
-        "
-(=> (when (iconst $C)
-          (bit-width $C $C))
-    5)
-"
+        is_call_result_1,
+        "(=> (when (iadd $x $C) (is-call-result $C)) $x)"
     );
     verify_err!(
-        bit_width_5,
-        "
-(=> (when (iconst $C)
-          (bit-width $C2 32))
-    5)
-"
+        is_call_result_2,
+        "(=> (when (iadd $x 0) (is-call-result)) $x)"
+    );
+    verify_ok!(
+        bool_band,
+        "(=> (when (select $c $a false) (bit-width $c 1)) (band $c $a))"
     );
     verify_err!(
-        bit_width_6,
-        "
-(=> (when (iconst $C)
-          (bit-width $C2 33))
-    5)
-"
+        bool_int_band,
+        "(=> (when (band (bint $b) $x) (bit-width $b 1)) (band $b $x))"
+    );
+    verify_ok!(popcount_0, "(=> (when (imul $x $C) (popcount-eq $C 1)) $x)");
+    verify_ok!(popcount_1, "(=> (when (imul $x $C) (popcount-le $C 2)) $x)");
+    verify_err!(popcount_2, "(=> (when (imul $x $y) (popcount-eq $y 1)) $x)");
+    verify_err!(popcount_3, "(=> (when (imul $x $C) (popcount-eq $C)) $x)");
+    verify_err!(
+        popcount_4,
+        "(=> (when (imul $x $C) (popcount-le $C 129)) $x)"
+    );
+    verify_err!(
+        popcount_5,
+        "(=> (when (imul $x $C) (popcount-le $C $C)) $x)"
     );
-
     verify_ok!(
-        is_power_of_two_0,
-        "
-(=> (when (imul $x $C)
-          (is-power-of-two $C))
-    (ishl $x $(log2 $C)))
-"
+        negation_fits_in_bits_0,
+        "(=> (when (iadd_imm $C $x) (negation-fits-in-bits $C 12)) (isub $x $(neg $C)))"
     );
     verify_err!(
-        is_power_of_two_1,
-        "
-(=> (when (imul $x $C)
-          (is-power-of-two))
-    5)
-"
+        negation_fits_in_bits_1,
+        "(=> (when (iadd_imm $C $x) (negation-fits-in-bits $C 0)) (isub $x $(neg $C)))"
     );
     verify_err!(
-        is_power_of_two_2,
-        "
-(=> (when (imul $x $C)
-          (is-power-of-two $C $C))
-    5)
-"
+        negation_fits_in_bits_2,
+        "(=> (when (iadd_imm $C $x) (negation-fits-in-bits $C)) (isub $x $(neg $C)))"
+    );
+    verify_err!(
+        negation_fits_in_bits_3,
+        "(=> (when (iadd $y $x) (negation-fits-in-bits $y 12)) (isub $x $y))"
+    );
+    verify_ok!(
+        is_width_minus_0,
+        "(=> (when (sshr $x $C) (is-width-minus $C 1)) (sshr $x $C))"
+    );
+    verify_err!(
+        is_width_minus_1,
+        "(=> (when (sshr $x $C) (is-width-minus $C 129)) (sshr $x $C))"
+    );
+    verify_err!(
+        is_width_minus_2,
+        "(=> (when (sshr $x $C) (is-width-minus $C)) (sshr $x $C))"
+    );
+    verify_err!(
+        is_width_minus_3,
+        "(=> (when (sshr $x $y) (is-width-minus $y 1)) (sshr $x $y))"
+    );
+    verify_ok!(
+        is_one_less_than_0,
+        "(=> (when (isub (iadd $x $C2) $C1) (is-one-less-than $C1 $C2)) (iadd_imm 1 $x))"
+    );
+    verify_err!(
+        is_one_less_than_1,
+        "(=> (when (iadd $C $D) (is-one-less-than $C)) $C)"
+    );
+    verify_err!(
+        is_one_less_than_2,
+        "(=> (when (iadd $x $C) (is-one-less-than $x $C)) $x)"
+    );
+    verify_ok!(
+        is_scaled_by_0,
+        "(=> (when (iadd (imul $x $A) (imul $y $B)) (is-scaled-by $A $B 4)) (iadd $x $y))"
+    );
+    verify_err!(
+        is_scaled_by_1,
+        "(=> (when (iadd (imul $x $A) (imul $y $B)) (is-scaled-by $A $B)) (iadd $x $y))"
+    );
+    verify_err!(
+        is_scaled_by_2,
+        "(=> (when (iadd (imul $x $A) $y) (is-scaled-by $A $y 4)) (iadd $x $y))"
+    );
+    verify_err!(
+        is_scaled_by_3,
+        "(=> (when (iadd (imul $x $A) (imul $y $B)) (is-scaled-by $A $B $A)) (iadd $x $y))"
+    );
+    verify_ok!(mem_flags_0, "(=> (when (iadd $l 0) (mem-flags $l 4 4)) $l)");
+    verify_ok!(mem_flags_1, "(=> (when (iadd $l 0) (mem-flags $l 6 2)) $l)");
+    verify_err!(mem_flags_2, "(=> (when (iadd $l 0) (mem-flags $l 4)) $l)");
+    verify_err!(mem_flags_3, "(=> (when (iadd $L 0) (mem-flags $L 4 4)) $L)");
+    verify_err!(
+        mem_flags_4,
+        "(=> (when (iadd $l 0) (mem-flags $l 256 4)) $l)"
     );
+    verify_err!(mem_flags_5, "(=> (when (iadd $l 0) (mem-flags $l 4 2)) $l)");
+    verify_err!(mem_flags_6, "(=> (when (iadd $l 0) (mem-flags $m 4 4)) $l)");
 
-    verify_ok!(pattern_ops_0, "(=> (iadd $x $C) 5)");
-    verify_err!(pattern_ops_1, "(=> (iadd $x) 5)");
-    verify_err!(pattern_ops_2, "(=> (iadd $x $y $z) 5)");
+    verify_ok!(
+        invert_cc_0,
+        "(=> (bnot (icmp $CC $a $b)) (icmp $(invert_cc $CC) $a $b))"
+    );
+    verify_err!(invert_cc_1, "(=> (iadd $C $x) (iadd $(invert_cc $C) $x))");
+    verify_ok!(
+        invert_cc_2,
+        "(=> (bxor (icmp $CC $a $b) true) (icmp $(invert_cc $CC) $a $b))"
+    );
 
-    verify_ok!(unquote_0, "(=> $C $(log2 $C))");
-    verify_err!(unquote_1, "(=> (iadd $C $D) $(log2 $C $D))");
-    verify_err!(unquote_2, "(=> $x $(log2))");
-    verify_ok!(unquote_3, "(=> $C $(neg $C))");
-    verify_err!(unquote_4, "(=> $x $(neg))");
-    verify_err!(unquote_5, "(=> (iadd $x $y) $(neg $x $y))");
-    verify_err!(unquote_6, "(=> $x $(neg $x))");
+    verify_ok!(mem_args_0, "(=> (load (iadd_imm 8 $p)) (load offset=8 $p))");
+    verify_ok!(
+        mem_args_1,
+        "(=> (store $x (iadd_imm 8 $p)) (store offset=8 flags=4 $x $p))"
+    );
+    verify_err!(mem_args_2, "(=> (load offset=8 $p) (load $p))");
+    verify_err!(mem_args_3, "(=> (iadd $x $y) (iadd offset=8 $x $y))");
 
-    verify_ok!(rhs_0, "(=> $x (iadd $x (iconst 0)))");
-    verify_err!(rhs_1, "(=> $x (iadd $x))");
-    verify_err!(rhs_2, "(=> $x (iadd $x 0 0))");
+    verify_ok!(
+        one_of_0,
+        "(=> ((one-of (opcode band) (opcode bor)) $x $x) $x)"
+    );
+    verify_ok!(
+        one_of_1,
+        "(=> (iadd ((one-of (opcode iadd) (opcode isub) (opcode imul)) $x 0) $y) (iadd $x $y))"
+    );
+    verify_err!(
+        one_of_2,
+        "(=> ((one-of (opcode band) (opcode bnot)) $x $x) $x)"
+    );
+    verify_err!(
+        one_of_3,
+        "(=> (band $x $y) ((one-of (opcode band) (opcode bor)) $x $y))"
+    );
+    verify_err!(
+        one_of_4,
+        "(=> ((one-of (opcode uextend) (opcode sextend)){i64} $x) 0)"
+    );
 
     verify_err!(no_optimizations, "");
 
@@ -1408,6 +4147,27 @@ mod tests {
         "(=> (when (iadd $x $y) (fits-in-native-word true)) 0)"
     );
 
+    verify_ok!(
+        equivalent_values_0,
+        "(=> (when (isub $x $y) (equivalent-values $x $y)) 0)"
+    );
+    verify_ok!(
+        equivalent_values_1,
+        "(=> (when (iadd $x $C) (equivalent-values $x $C)) (ishl $x 1))"
+    );
+    verify_err!(
+        equivalent_values_2,
+        "(=> (when (isub $x $y) (equivalent-values $x)) 0)"
+    );
+    verify_err!(
+        equivalent_values_3,
+        "(=> (when (isub $x $y) (equivalent-values $x 0)) 0)"
+    );
+    verify_err!(
+        equivalent_values_4,
+        "(=> (when (isub $x $y) (equivalent-values $x $z)) 0)"
+    );
+
     verify_err!(reduce_extend_0, "(=> (sextend (ireduce -1)) 0)");
     verify_err!(reduce_extend_1, "(=> (uextend (ireduce -1)) 0)");
     verify_ok!(reduce_extend_2, "(=> (sextend{i64} (ireduce{i32} -1)) 0)");
@@ -1430,4 +4190,36 @@ mod tests {
         using_a_condition_code_as_the_root_of_an_optimization,
         "(=> eq eq)"
     );
+
+    verify_ok!(
+        lanes_0,
+        "(=> (extractlane 1 (insertlane 0 $v $x)) (extractlane 1 $v))"
+    );
+    verify_ok!(
+        lanes_1,
+        "(=> (insertlane{i32x4} 3 (insertlane 3 $v $x) $y) (insertlane 3 $v $y))"
+    );
+    verify_ok!(
+        lanes_2,
+        "(=> (extractlane{i8} 0 (insertlane 0 $v $x)) (extractlane 15 $v))"
+    );
+    verify_err!(
+        lanes_3,
+        "(=> (extractlane 1 (insertlane 0 $v $x)) (extractlane 2 $v))"
+    );
+    verify_err!(
+        lanes_4,
+        "(=> (extractlane{i32} 0 (insertlane 0 $v $x)) (extractlane 4 $v))"
+    );
+    verify_err!(
+        lanes_5,
+        "(=> (insertlane{i64x2} 0 (insertlane 0 $v $x) $y) (insertlane 2 $v $y))"
+    );
+    verify_err!(lanes_6, "(=> (extractlane -1 $v) (extractlane -1 $v))");
+    verify_err!(lanes_7, "(=> (extractlane $L $v) (extractlane $L $v))");
+    verify_err!(lanes_8, "(=> (extractlane{i128} 0 $v) 0)");
+    verify_err!(
+        lanes_9,
+        "(=> (extractlane 0 $v) (extractlane 0 (iadd $v $v)))"
+    );
 }